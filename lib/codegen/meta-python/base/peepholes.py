@@ -0,0 +1,157 @@
+"""
+Peephole rewrites for the `base` instruction set.
+
+These patterns replace small trees of instructions with cheaper equivalents.
+They are applied before legalization, so they only use base instructions. The
+last instruction in a source pattern is the one being rewritten, and the
+others must define its arguments.
+"""
+from __future__ import absolute_import
+from .immediates import imm64
+from .instructions import iconst, copy
+from .instructions import iadd, isub, imul, sdiv, udiv, srem, urem
+from .instructions import iadd_imm, irsub_imm, imul_imm
+from .instructions import sdiv_imm, udiv_imm, srem_imm, urem_imm
+from .instructions import band, bor, bxor, bnot
+from .instructions import band_imm, bor_imm, bxor_imm
+from .instructions import ishl, ushr, sshr, rotl, rotr
+from .instructions import ishl_imm, ushr_imm, sshr_imm, rotl_imm, rotr_imm
+from .instructions import icmp, icmp_imm
+from .instructions import uextend, sextend, fneg
+from cdsl.ast import Var
+from cdsl.xform import Rtl, XFormGroup
+
+simplify = XFormGroup('simplify', """
+        Simplify instructions with peephole rewrites.
+
+        Fold constant operands into immediates, and remove operations that
+        don't change their input.
+        """)
+
+a = Var('a')
+b = Var('b')
+c = Var('c')
+cc = Var('cc')
+x = Var('x')
+y = Var('y')
+
+# Fold constant operands into `_imm` instructions. Subtracting a constant is
+# handled by `simple_preopt` since it needs to negate the immediate.
+for inst, inst_imm in [
+        (iadd, iadd_imm),
+        (imul, imul_imm),
+        (sdiv, sdiv_imm),
+        (udiv, udiv_imm),
+        (srem, srem_imm),
+        (urem, urem_imm),
+        (band, band_imm),
+        (bor, bor_imm),
+        (bxor, bxor_imm),
+        (rotl, rotl_imm),
+        (rotr, rotr_imm),
+        (ishl, ishl_imm),
+        (ushr, ushr_imm),
+        (sshr, sshr_imm)]:
+    simplify.peephole(
+            Rtl(
+                b << iconst(y),
+                a << inst(x, b)
+            ),
+            Rtl(
+                a << inst_imm(x, y)
+            ))
+
+# Commutative operations can also have the constant on the left.
+for inst, inst_imm in [
+        (iadd, iadd_imm),
+        (imul, imul_imm),
+        (band, band_imm),
+        (bor, bor_imm),
+        (bxor, bxor_imm)]:
+    simplify.peephole(
+            Rtl(
+                b << iconst(y),
+                a << inst(b, x)
+            ),
+            Rtl(
+                a << inst_imm(x, y)
+            ))
+
+simplify.peephole(
+        Rtl(
+            b << iconst(y),
+            a << isub(b, x)
+        ),
+        Rtl(
+            a << irsub_imm(x, y)
+        ))
+
+simplify.peephole(
+        Rtl(
+            b << iconst(y),
+            c << icmp(cc, x, b)
+        ),
+        Rtl(
+            c << icmp_imm(cc, x, y)
+        ))
+
+# Operations with an identity immediate.
+for inst, identity in [
+        (iadd_imm, 0),
+        (imul_imm, 1),
+        (sdiv_imm, 1),
+        (udiv_imm, 1),
+        (band_imm, -1),
+        (bor_imm, 0),
+        (bxor_imm, 0),
+        (rotl_imm, 0),
+        (rotr_imm, 0),
+        (ishl_imm, 0),
+        (ushr_imm, 0),
+        (sshr_imm, 0)]:
+    simplify.peephole(
+            Rtl(a << inst(x, imm64(identity))),
+            Rtl(a << copy(x)))
+
+# Operations that always produce zero.
+for inst, zero in [
+        (imul_imm, 0),
+        (band_imm, 0),
+        (srem_imm, 1),
+        (urem_imm, 1)]:
+    simplify.peephole(
+            Rtl(a << inst(x, imm64(zero))),
+            Rtl(a << iconst(imm64(0))))
+
+for inst in [isub, bxor]:
+    simplify.peephole(
+            Rtl(a << inst(x, x)),
+            Rtl(a << iconst(imm64(0))))
+
+# Idempotent operations.
+for inst in [band, bor]:
+    simplify.peephole(
+            Rtl(a << inst(x, x)),
+            Rtl(a << copy(x)))
+
+# Involutions.
+for inst in [bnot, fneg]:
+    simplify.peephole(
+            Rtl(
+                b << inst(x),
+                a << inst(b)
+            ),
+            Rtl(
+                a << copy(x)
+            ))
+
+# Extending an already extended value.
+for inst in [uextend, sextend]:
+    simplify.peephole(
+            Rtl(
+                b << inst(x),
+                a << inst(b)
+            ),
+            Rtl(
+                a << inst(x)
+            ))
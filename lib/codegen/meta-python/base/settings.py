@@ -18,6 +18,28 @@ opt_level = EnumSetting(
         """,
         'default', 'best', 'fastest')
 
+regalloc = EnumSetting(
+        """
+        Register allocator to use:
+
+        - coloring: SSA-based register allocator that produces good code.
+        - linear_scan: Fast local allocator that keeps values in registers only
+          within an EBB. Meant to be used with `opt_level=fastest`.
+        """,
+        'coloring', 'linear_scan')
+
+scheduling = EnumSetting(
+        """
+        Instruction scheduler to run before register allocation:
+
+        - none: Keep instructions in the order they were built.
+        - latency: List scheduling within EBBs to hide the latency of loads,
+          multiplications, and other slow instructions.
+        - pressure: Like latency, but avoid raising the register pressure above
+          the number of available registers.
+        """,
+        'none', 'latency', 'pressure')
+
 enable_verifier = BoolSetting(
         """
         Run the Cranelift IR verifier at strategic times during compilation.
@@ -71,6 +93,18 @@ enable_nan_canonicalization = BoolSetting(
         """,
         default=False)
 
+enable_spectre_mitigation = BoolSetting(
+        """
+        Enable Spectre mitigation for heap and table accesses.
+
+        The address computed by a bounds-checked `heap_addr` or `table_addr`
+        is replaced by zero with a conditional move when the bounds check
+        fails, so speculatively executed accesses can't reach out of bounds.
+        This requires an ISA with encodings for `selectif`, and it disables
+        the elimination of redundant heap bounds checks.
+        """,
+        default=False)
+
 enable_simd = BoolSetting(
         """Enable the use of SIMD instructions.""",
         default=True)
@@ -141,4 +175,23 @@ jump_tables_enabled = BoolSetting(
         """,
         default=True)
 
+#
+# Loop optimization options.
+#
+loop_unroll_factor = NumSetting(
+        """
+        The number of iterations run at a time by unrolled loops.
+
+        When optimizing for `best`, counted loops are unrolled by this factor,
+        with the original loop running the remaining iterations. Loops whose
+        trip count is a constant no larger than this are unrolled completely.
+
+        The default is 0, which disables loop unrolling.
+        """)
+
+loop_peel_first_iteration = BoolSetting(
+        """
+        Peel the first iteration of counted loops when optimizing for `best`.
+        """)
+
 group.close(globals())
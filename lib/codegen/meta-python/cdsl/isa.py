@@ -13,6 +13,7 @@ try:
     from typing import Tuple, Union, Any, Iterable, Sequence, List, Set, Dict, TYPE_CHECKING  # noqa
     if TYPE_CHECKING:
         from .instructions import MaybeBoundInst, InstructionFormat  # noqa
+        from .instructions import Instruction  # noqa
         from .predicates import PredNode, PredKey  # noqa
         from .settings import SettingGroup  # noqa
         from .registers import RegBank  # noqa
@@ -49,6 +50,10 @@ class TargetISA(object):
         self.cpumodes = list()  # type: List[CPUMode]
         self.regbanks = list()  # type: List[RegBank]
         self.legalize_codes = OrderedDict()  # type: OrderedDict[XFormGroup, int]  # noqa
+        # ISA-specific peephole rewrites, chaining to the shared ones.
+        self.peepholes = None  # type: XFormGroup
+        # Result latencies in cycles for the instruction scheduler.
+        self.latencies = OrderedDict()  # type: OrderedDict[Instruction, int]  # noqa
         # Unique copies of all predicates.
         self._predicates = dict()  # type: Dict[PredKey, PredNode]
 
@@ -149,6 +154,20 @@ class TargetISA(object):
             self.legalize_codes[xgrp] = code
         return code
 
+    def latency(self, inst, cycles):
+        # type: (Instruction, int) -> None
+        """
+        Set the result latency of `inst` to `cycles`.
+
+        The latency is the number of cycles from when `inst` issues until its
+        results are available to dependent instructions. Instructions without
+        a specified latency are assumed to take a single cycle.
+        """
+        assert inst not in self.latencies,\
+            "Latency of {} is already set".format(inst)
+        assert 0 < cycles < 256, "Latency must fit in a u8"
+        self.latencies[inst] = cycles
+
     def unique_pred(self, pred):
         # type: (PredNode) -> PredNode
         """
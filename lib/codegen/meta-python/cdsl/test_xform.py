@@ -1,7 +1,7 @@
 from __future__ import absolute_import
 from unittest import TestCase
 from doctest import DocTestSuite
-from base.instructions import iadd, iadd_imm, iconst, icmp
+from base.instructions import iadd, iadd_imm, iconst, icmp, load
 from base.immediates import intcc
 from . import xform
 from .ast import Var
@@ -65,6 +65,32 @@ class TestXForm(TestCase):
         with self.assertRaisesRegexp(AssertionError, "'a' multiply defined"):
             XForm(src, dst)
 
+    def test_peephole(self):
+        src = Rtl(
+                b << iconst(y),
+                a << iadd(x, b))
+        dst = Rtl(a << iadd_imm(x, y))
+        XForm(src, dst).verify_peephole()
+
+    def test_peephole_unused(self):
+        # The iconst doesn't define an argument of the root.
+        src = Rtl(
+                b << iconst(y),
+                a << iadd(x, z))
+        dst = Rtl(a << iadd(x, z))
+        with self.assertRaisesRegexp(
+                AssertionError, "doesn't feed the root"):
+            XForm(src, dst).verify_peephole()
+
+    def test_peephole_side_effects(self):
+        # Following the use of `b` back to a load could skip a store.
+        src = Rtl(
+                b << load(c, x, y),
+                a << iadd(b, z))
+        dst = Rtl(a << iadd(z, z))
+        with self.assertRaisesRegexp(AssertionError, "has side effects"):
+            XForm(src, dst).verify_peephole()
+
     def test_subst_imm(self):
         src = Rtl(a << iconst(x))
         dst = Rtl(c << iconst(y))
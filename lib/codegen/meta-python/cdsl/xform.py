@@ -338,6 +338,46 @@ class XForm(object):
                 raise AssertionError(
                         '{} not defined in dest pattern'.format(d))
 
+    def verify_peephole(self):
+        # type: () -> None
+        """
+        Verify that this is a valid peephole XForm.
+
+        - The last instruction in the source pattern is the root which gets
+          rewritten. It can't be a branch since peephole rewrites don't update
+          the control flow graph.
+        - The other source instructions must define arguments of the later
+          ones. They are matched by following value definitions, so they can
+          be anywhere in the function and must be free of side effects.
+        - All values defined by the root must be defined in the destination
+          pattern, and no other source values can be redefined.
+        """
+        root = self.src.rtl[-1]
+        inst = root.expr.inst
+        if inst.is_branch or inst.is_terminator:
+            raise AssertionError(
+                    "peephole can't rewrite branch {}".format(inst))
+        for d in root.defs:
+            if not d.is_output():
+                raise AssertionError(
+                        '{} not defined in dest pattern'.format(d))
+
+        used = root.uses()
+        for node in reversed(self.src.rtl[:-1]):
+            if not any(d in used for d in node.defs):
+                raise AssertionError(
+                        '{} doesn\'t feed the root'.format(node))
+            inst = node.expr.inst
+            if (inst.can_load or inst.can_store or inst.can_trap or
+                    inst.other_side_effects or inst.writes_cpu_flags):
+                raise AssertionError(
+                        "{} has side effects".format(node))
+            for d in node.defs:
+                if d.is_output():
+                    raise AssertionError(
+                            '{} redefined in dest pattern'.format(d))
+            used = used.union(node.uses())
+
     def apply(self, r, suffix=None):
         # type: (Rtl, str) -> Rtl
         """
@@ -408,6 +448,19 @@ class XFormGroup(object):
         xform.verify_legalize()
         self.xforms.append(xform)
 
+    def peephole(self, src, dst):
+        # type: (Rtl, Rtl) -> None
+        """
+        Add a peephole rewrite pattern to this group.
+
+        :param src: `Rtl` list of instructions to match. The last one is
+                    rewritten, the others define its arguments.
+        :param dst: `Rtl` list of replacement instructions.
+        """
+        xform = XForm(src, dst)
+        xform.verify_peephole()
+        self.xforms.append(xform)
+
     def custom_legalize(self, inst, funcname):
         # type: (Instruction, str) -> None
         """
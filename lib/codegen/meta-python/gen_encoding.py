@@ -843,6 +843,23 @@ def emit_recipe_sizing(isa, fmt):
                     fmt.line('branch_range: None,')
 
 
+def emit_latencies(isa, fmt):
+    # type: (TargetISA, srcgen.Formatter) -> None
+    """
+    Emit a function returning the result latency of an opcode.
+    """
+    fmt.doc_comment('Get the result latency in cycles of `opcode`.')
+    if not isa.latencies:
+        with fmt.indented('fn inst_latency(_: ir::Opcode) -> u8 {', '}'):
+            fmt.line('1')
+        return
+    with fmt.indented('fn inst_latency(opcode: ir::Opcode) -> u8 {', '}'):
+        with fmt.indented('match opcode {', '}'):
+            for inst, cycles in isa.latencies.items():
+                fmt.format('ir::Opcode::{} => {},', inst.camel_name, cycles)
+            fmt.line('_ => 1,')
+
+
 def gen_isa(isa, fmt):
     # type: (TargetISA, srcgen.Formatter) -> None
 
@@ -884,12 +901,14 @@ def gen_isa(isa, fmt):
     emit_recipe_names(isa, fmt)
     emit_recipe_constraints(isa, fmt)
     emit_recipe_sizing(isa, fmt)
+    emit_latencies(isa, fmt)
 
     # Finally, tie it all together in an `EncInfo`.
     with fmt.indented('pub static INFO: isa::EncInfo = isa::EncInfo {', '};'):
         fmt.line('constraints: &RECIPE_CONSTRAINTS,')
         fmt.line('sizing: &RECIPE_SIZING,')
         fmt.line('names: &RECIPE_NAMES,')
+        fmt.line('latencies: inst_latency,')
 
 
 def generate(isas, out_dir):
@@ -6,12 +6,18 @@ macro-expansion form where the input pattern is a single instruction. We
 generate a Rust function for each `XFormGroup` which takes a `Cursor` pointing
 at the instruction to be legalized. The expanded destination pattern replaces
 the input instruction.
+
+The peephole rewrites defined in `base.peepholes` and the ISA packages use the
+same `XForm` patterns, except the source pattern can match a tree of
+instructions rooted at its last instruction. They are generated into the same
+files, with the shared rewrites going into `peepholes.rs`.
 """
 from __future__ import absolute_import
 from srcgen import Formatter
 from collections import defaultdict
-from base import instructions
+from base import instructions, peepholes
 from cdsl.ast import Var
+from cdsl.predicates import And
 from cdsl.ti import ti_rtl, TypeEnv, get_type_env, TypesEqual,\
     InTypeset, WiderOrEq
 from unique_table import UniqueTable
@@ -19,7 +25,7 @@ from gen_instr import gen_typesets_table
 from cdsl.typevar import TypeVar
 
 try:
-    from typing import Sequence, List, Dict, Set, DefaultDict # noqa
+    from typing import Sequence, List, Dict, Set, DefaultDict, Tuple # noqa
     from cdsl.isa import TargetISA  # noqa
     from cdsl.ast import Def, VarAtomMap  # noqa
     from cdsl.xform import XForm, XFormGroup  # noqa
@@ -211,6 +217,18 @@ def unwrap_inst(iref, node, fmt):
         if isinstance(v, Var) and v.has_free_typevar():
             fmt.format('let typeof_{0} = pos.func.dfg.value_type({0});', v)
 
+    return unwrap_results(node, fmt)
+
+
+def unwrap_results(node, fmt):
+    # type: (Def, Formatter) -> bool
+    """
+    Given the `Def` node being replaced, emit code that places its result
+    values in local variables named after the defined `Var` instances.
+
+    :returns: True if the instruction arguments were not detached, expecting a
+              replacement instruction to overwrite the original.
+    """
     # If the node has results, detach the values.
     # Place the values in locals.
     replace_inst = False
@@ -329,7 +347,16 @@ def gen_xform(xform, fmt, type_sets):
     # Unwrap the source instruction, create local variables for the input
     # variables.
     replace_inst = unwrap_inst('inst', xform.src.rtl[0], fmt)
+    gen_expansion(xform, replace_inst, fmt, type_sets)
+
 
+def gen_expansion(xform, replace_inst, fmt, type_sets):
+    # type: (XForm, bool, Formatter, UniqueTable) -> None
+    """
+    Emit the runtime type checks for `xform` and its destination pattern
+    guarded by `predicate`, assuming that its source pattern has been
+    unwrapped.
+    """
     # Emit any runtime checks.
     # These will rebind `predicate` emitted by unwrap_inst().
     for check in get_runtime_typechecks(xform):
@@ -403,11 +430,226 @@ def gen_xform_group(xgrp, fmt, type_sets):
             fmt.line('false')
 
 
+def unwrap_operands(iref, node, fmt, bound):
+    # type: (str, Def, Formatter, Set[Var]) -> None
+    """
+    Given a `Def` node of a peephole source pattern, emit code that extracts
+    the instruction fields from `pos.func.dfg[iref]`.
+
+    Create local variables named after the `Var` instances in `node` that are
+    not in `bound` yet, and add them to it. Operands whose variable is already
+    bound are compared against it instead, so a pattern can use the same value
+    more than once.
+
+    The local `predicate` variable is updated with the evaluated instruction
+    predicate.
+    """
+    fmt.comment('Unwrap {}'.format(node))
+    expr = node.expr
+    iform = expr.inst.format
+    nvops = iform.num_value_operands
+
+    names = []  # type: List[str]
+    repeated = []  # type: List[Tuple[Var, str]]
+    fresh = []  # type: List[Var]
+    for opnum, arg in enumerate(expr.args):
+        if not isinstance(arg, Var):
+            names.append('_')
+        elif arg in bound:
+            name = '{}_{}'.format(arg, opnum)
+            names.append(name)
+            repeated.append((arg, name))
+        else:
+            names.append(str(arg))
+            bound.add(arg)
+            fresh.append(arg)
+
+    with fmt.indented(
+            'let ({}) = if let ir::InstructionData::{} {{'
+            .format(', '.join(names + ['predicate']), iform.name), '};'):
+        for f in iform.imm_fields:
+            fmt.line('{},'.format(f.member))
+        if nvops == 1:
+            fmt.line('arg,')
+        elif iform.has_value_list or nvops > 1:
+            fmt.line('ref args,')
+        fmt.line('..')
+        fmt.outdented_line('}} = pos.func.dfg[{}] {{'.format(iref))
+        fmt.line('let func = &pos.func;')
+        if iref != 'inst':
+            # Instruction predicates refer to the instruction as `inst`.
+            fmt.format('let inst = {};', iref)
+        if iform.has_value_list:
+            fmt.line('let args = args.as_slice(&func.dfg.value_lists);')
+        elif nvops == 1:
+            fmt.line('let args = [arg];')
+        with fmt.indented('(', ')'):
+            for opnum, op in enumerate(expr.inst.ins):
+                if op.is_immediate():
+                    n = expr.inst.imm_opnums.index(opnum)
+                    fmt.format('{},', iform.imm_fields[n].member)
+                elif op.is_value():
+                    n = expr.inst.value_opnums.index(opnum)
+                    fmt.format('func.dfg.resolve_aliases(args[{}]),', n)
+            instp = expr.inst_predicate_with_ctrl_typevar()
+            if iref == 'inst':
+                fmt.line(instp.rust_predicate(0) if instp else 'true')
+            elif instp:
+                fmt.format(
+                        'predicate && {}',
+                        instp.rust_predicate(And.precedence))
+            else:
+                fmt.line('predicate')
+        fmt.outdented_line('} else {')
+        fmt.line('unreachable!("bad instruction format")')
+
+    for v, name in repeated:
+        fmt.format('let predicate = predicate && {} == {};', name, v)
+
+    # Get the types of any new variables where it is needed.
+    for opnum in expr.inst.value_opnums:
+        v = expr.args[opnum]
+        if v in fresh and v.has_free_typevar():
+            fmt.format('let typeof_{0} = pos.func.dfg.value_type({0});', v)
+            fresh.remove(v)
+
+
+def gen_peephole_match(xform, nodes, replace_inst, fmt, type_sets, bound):
+    # type: (XForm, Sequence[Def], bool, Formatter, UniqueTable, Set[Var]) -> None  # noqa
+    """
+    Emit code that finds the instructions defining the `nodes` of a peephole
+    source pattern, then emit the rewrite.
+
+    Each node in `nodes` must define a value that is used by the nodes
+    unwrapped before it, so we can follow that value to its definition.
+    """
+    if len(nodes) == 0:
+        gen_expansion(xform, replace_inst, fmt, type_sets)
+        return
+
+    node = nodes[0]
+    num, var = next((n, d) for n, d in enumerate(node.defs) if d in bound)
+    iref = 'inst_{}'.format(var)
+    with fmt.indented(
+            'if let ir::ValueDef::Result({}, {}) = '
+            'pos.func.dfg.value_def({}) {{'.format(iref, num, var), '}'):
+        with fmt.indented(
+                'if pos.func.dfg[{}].opcode() == ir::Opcode::{} {{'
+                .format(iref, node.expr.inst.camel_name), '}'):
+            unwrap_operands(iref, node, fmt, bound)
+
+            # Bind or check the other results of the instruction.
+            for n, d in enumerate(node.defs):
+                if d is var:
+                    continue
+                result = 'pos.func.dfg.inst_results({})[{}]'.format(iref, n)
+                if d in bound:
+                    fmt.format(
+                            'let predicate = predicate && {} == {};',
+                            d, result)
+                else:
+                    fmt.format('let {} = {};', d, result)
+                    bound.add(d)
+                    if d.has_free_typevar():
+                        fmt.format(
+                                'let typeof_{0} = '
+                                'pos.func.dfg.value_type({0});', d)
+
+            gen_peephole_match(
+                    xform, nodes[1:], replace_inst, fmt, type_sets, bound)
+
+
+def gen_peephole(xform, fmt, type_sets):
+    # type: (XForm, Formatter, UniqueTable) -> None
+    """
+    Emit code for the peephole `xform`, assuming that the opcode of its root
+    instruction has already been matched.
+
+    `inst: Inst` is the root instruction to be rewritten. It is pointed to by
+    `pos: Cursor`. The other instructions in the source pattern are found by
+    following the definitions of its arguments.
+    """
+    root = xform.src.rtl[-1]
+    bound = set()  # type: Set[Var]
+    unwrap_operands('inst', root, fmt, bound)
+    replace_inst = unwrap_results(root, fmt)
+    if replace_inst:
+        # The results stay attached, but we may still need their types.
+        for n, d in enumerate(root.defs):
+            if d.has_free_typevar():
+                fmt.format(
+                        'let typeof_{} = pos.func.dfg.value_type('
+                        'pos.func.dfg.inst_results(inst)[{}]);', d, n)
+    gen_peephole_match(
+            xform, tuple(reversed(xform.src.rtl[:-1])), replace_inst, fmt,
+            type_sets, bound)
+
+
+def peephole_rust_name(xgrp):
+    # type: (XFormGroup) -> str
+    """
+    Get the Rust name of the function implementing the peephole group `xgrp`.
+    """
+    if xgrp.isa:
+        return xgrp.name
+    else:
+        return '::peepholes::{}'.format(xgrp.name)
+
+
+def gen_peephole_group(xgrp, fmt, type_sets):
+    # type: (XFormGroup, Formatter, UniqueTable) -> None
+    fmt.doc_comment("Apply peephole rewrites to `inst`.")
+    fmt.line('#[allow(unused_variables,unused_assignments,non_snake_case)]')
+    with fmt.indented('pub fn {}('.format(xgrp.name)):
+        fmt.line('inst: ir::Inst,')
+        fmt.line('func: &mut ir::Function,')
+        fmt.line('isa: &::isa::TargetIsa,')
+    with fmt.indented(') -> bool {', '}'):
+        fmt.line('use ir::InstBuilder;')
+        fmt.line('use cursor::{Cursor, FuncCursor};')
+        fmt.line('let mut pos = FuncCursor::new(func).at_inst(inst);')
+        fmt.line('pos.use_srcloc(inst);')
+
+        # Group the xforms by the opcode of their root instruction.
+        # Preserve ordering.
+        xforms = defaultdict(list)  # type: DefaultDict[str, List[XForm]]
+        for xform in xgrp.xforms:
+            inst = xform.src.rtl[-1].expr.inst
+            xforms[inst.camel_name].append(xform)
+
+        with fmt.indented('{', '}'):
+            if len(xforms) == 1:
+                # Avoid a single-arm match.
+                camel_name, = xforms.keys()
+                with fmt.indented(
+                        'if pos.func.dfg[inst].opcode() == ir::Opcode::{} {{'
+                        .format(camel_name), '}'):
+                    for xform in xforms[camel_name]:
+                        gen_peephole(xform, fmt, type_sets)
+            else:
+                with fmt.indented(
+                        'match pos.func.dfg[inst].opcode() {', '}'):
+                    for camel_name in sorted(xforms.keys()):
+                        with fmt.indented(
+                                'ir::Opcode::{} => {{'.format(camel_name),
+                                '}'):
+                            for xform in xforms[camel_name]:
+                                gen_peephole(xform, fmt, type_sets)
+                    fmt.line('_ => {},')
+
+        # If we fall through, nothing was rewritten. Call the chain if any.
+        if xgrp.chain:
+            fmt.format(
+                    '{}(inst, pos.func, isa)', peephole_rust_name(xgrp.chain))
+        else:
+            fmt.line('false')
+
+
 def gen_isa(isa, fmt, shared_groups):
     # type: (TargetISA, Formatter, Set[XFormGroup]) -> None
     """
-    Generate legalization functions for `isa` and add any shared `XFormGroup`s
-    encountered to `shared_groups`.
+    Generate legalization and peephole functions for `isa` and add any shared
+    legalization `XFormGroup`s encountered to `shared_groups`.
 
     Generate `TYPE_SETS` and `LEGALIZE_ACTION` tables.
     """
@@ -419,6 +661,10 @@ def gen_isa(isa, fmt, shared_groups):
             assert xgrp.isa == isa
             gen_xform_group(xgrp, fmt, type_sets)
 
+    if isa.peepholes:
+        assert isa.peepholes.isa == isa
+        gen_peephole_group(isa.peepholes, fmt, type_sets)
+
     gen_typesets_table(fmt, type_sets)
 
     with fmt.indented(
@@ -444,3 +690,10 @@ def generate(isas, out_dir):
         gen_xform_group(xgrp, fmt, type_sets)
     gen_typesets_table(fmt, type_sets)
     fmt.update_file('legalizer.rs', out_dir)
+
+    # Shared peephole rewrites.
+    fmt = Formatter()
+    type_sets = UniqueTable()
+    gen_peephole_group(peepholes.simplify, fmt, type_sets)
+    gen_typesets_table(fmt, type_sets)
+    fmt.update_file('peepholes.rs', out_dir)
@@ -0,0 +1,46 @@
+"""
+x86 instruction latencies.
+
+These are the approximate result latencies of recent Intel and AMD cores. They
+only need to be accurate enough for the instruction scheduler to tell short
+and long operations apart.
+"""
+from __future__ import absolute_import
+from base import instructions as base
+from .defs import ISA
+from . import instructions as x86
+
+# Loads hitting the L1 cache.
+for inst in [
+        base.load, base.load_complex,
+        base.uload8, base.uload8_complex, base.sload8, base.sload8_complex,
+        base.uload16, base.uload16_complex, base.sload16, base.sload16_complex,
+        base.uload32, base.uload32_complex, base.sload32, base.sload32_complex,
+        base.fill]:
+    ISA.latency(inst, 4)
+
+# Integer multiplication and division.
+ISA.latency(base.imul, 3)
+ISA.latency(x86.umulx, 4)
+ISA.latency(x86.smulx, 4)
+ISA.latency(x86.udivmodx, 26)
+ISA.latency(x86.sdivmodx, 26)
+
+# Bit counting.
+ISA.latency(base.popcnt, 3)
+ISA.latency(x86.bsr, 3)
+ISA.latency(x86.bsf, 3)
+ISA.latency(base.clz, 3)
+ISA.latency(base.ctz, 3)
+
+# SSE floating point arithmetic.
+for inst in [base.fadd, base.fsub, base.fmul, x86.fmin, x86.fmax]:
+    ISA.latency(inst, 4)
+ISA.latency(base.fdiv, 14)
+ISA.latency(base.sqrt, 18)
+for inst in [base.ceil, base.floor, base.trunc, base.nearest]:
+    ISA.latency(inst, 8)
+
+# Conversions between integer and floating point.
+ISA.latency(base.fcvt_from_sint, 5)
+ISA.latency(x86.cvtt2si, 6)
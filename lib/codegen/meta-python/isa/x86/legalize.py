@@ -88,6 +88,9 @@ for cc,               rev_cc in [
                 a << insts.fcmp(rev_cc, y, x)
             ))
 
+# Integer selects use a conditional move. Other types need to modify the CFG.
+x86_expand.custom_legalize(insts.select, 'expand_select')
+
 # We need to modify the CFG for min/max legalization.
 x86_expand.custom_legalize(insts.fmin, 'expand_minmax')
 x86_expand.custom_legalize(insts.fmax, 'expand_minmax')
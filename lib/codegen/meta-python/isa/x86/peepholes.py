@@ -0,0 +1,45 @@
+"""
+Peephole rewrites for x86.
+"""
+from __future__ import absolute_import
+from cdsl.ast import Var
+from cdsl.xform import Rtl, XFormGroup
+from base.immediates import floatcc
+from base import peepholes as shared
+from base import instructions as insts
+from . import instructions as x86
+from .defs import ISA
+
+ISA.peepholes = XFormGroup(
+        'x86_simplify',
+        """
+        Simplify instructions with peephole rewrites.
+
+        Use x86-specific instructions when they match a pattern exactly.
+        """,
+        isa=ISA, chain=shared.simplify)
+
+a = Var('a')
+c = Var('c')
+x = Var('x')
+y = Var('y')
+
+# The x86 `minss` and `maxss` instructions return their second operand unless
+# the first one compares LT or GT, including when either operand is NaN.
+ISA.peepholes.peephole(
+        Rtl(
+            c << insts.fcmp(floatcc.lt, x, y),
+            a << insts.select(c, x, y)
+        ),
+        Rtl(
+            a << x86.fmin(x, y)
+        ))
+
+ISA.peepholes.peephole(
+        Rtl(
+            c << insts.fcmp(floatcc.gt, x, y),
+            a << insts.select(c, x, y)
+        ),
+        Rtl(
+            a << x86.fmax(x, y)
+        ))
@@ -26,6 +26,18 @@ has_bmi2 = BoolSetting("BMI2: CPUID.(EAX=07H, ECX=0H):EBX.BMI2[bit 8]")
 # CPUID.EAX=80000001H:ECX
 has_lzcnt = BoolSetting("LZCNT: CPUID.EAX=80000001H:ECX.LZCNT[bit 5]")
 
+# Code generation settings.
+
+omit_frame_pointer = BoolSetting(
+        """
+        Omit the frame pointer on x86-64 when possible.
+
+        Functions without dynamic stack adjustments address their stack slots
+        relative to RSP, which makes RBP available for register allocation.
+        Leaf functions may also skip the prologue entirely and keep small
+        spill areas in the System V red zone.
+        """)
+
 
 # The use_* settings here are used to determine if a feature can be used.
 
@@ -15,6 +15,32 @@ pub fn generate() -> SettingGroup {
         vec!["default", "best", "fastest"],
     );
 
+    settings.add_enum(
+        "regalloc",
+        r#"
+        Register allocator to use:
+
+        - coloring: SSA-based register allocator that produces good code.
+        - linear_scan: Fast local allocator that keeps values in registers only
+          within an EBB. Meant to be used with `opt_level=fastest`.
+        "#,
+        vec!["coloring", "linear_scan"],
+    );
+
+    settings.add_enum(
+        "scheduling",
+        r#"
+        Instruction scheduler to run before register allocation:
+
+        - none: Keep instructions in the order they were built.
+        - latency: List scheduling within EBBs to hide the latency of loads,
+          multiplications, and other slow instructions.
+        - pressure: Like latency, but avoid raising the register pressure above
+          the number of available registers.
+        "#,
+        vec!["none", "latency", "pressure"],
+    );
+
     settings.add_bool(
         "enable_verifier",
         r#"
@@ -84,6 +110,20 @@ pub fn generate() -> SettingGroup {
         false,
     );
 
+    settings.add_bool(
+        "enable_spectre_mitigation",
+        r#"
+            Enable Spectre mitigation for heap and table accesses.
+
+            The address computed by a bounds-checked `heap_addr` or `table_addr`
+            is replaced by zero with a conditional move when the bounds check
+            fails, so speculatively executed accesses can't reach out of bounds.
+            This requires an ISA with encodings for `selectif`, and it disables
+            the elimination of redundant heap bounds checks.
+            "#,
+        false,
+    );
+
     settings.add_bool("enable_simd", "Enable the use of SIMD instructions.", true);
 
     settings.add_bool(
@@ -160,5 +200,27 @@ pub fn generate() -> SettingGroup {
         true,
     );
 
+    // Loop optimization options.
+
+    settings.add_num(
+        "loop_unroll_factor",
+        r#"
+            The number of iterations run at a time by unrolled loops.
+
+            When optimizing for `best`, counted loops are unrolled by this factor,
+            with the original loop running the remaining iterations. Loops whose
+            trip count is a constant no larger than this are unrolled completely.
+
+            The default is 0, which disables loop unrolling.
+            "#,
+        0,
+    );
+
+    settings.add_bool(
+        "loop_peel_first_iteration",
+        "Peel the first iteration of counted loops when optimizing for `best`.",
+        false,
+    );
+
     settings.finish()
 }
@@ -32,6 +32,19 @@ pub fn define_settings(_shared: &SettingGroup) -> SettingGroup {
         false,
     );
 
+    settings.add_bool(
+        "omit_frame_pointer",
+        r#"
+        Omit the frame pointer on x86-64 when possible.
+
+        Functions without dynamic stack adjustments address their stack slots
+        relative to RSP, which makes RBP available for register allocation.
+        Leaf functions may also skip the prologue entirely and keep small
+        spill areas in the System V red zone.
+        "#,
+        false,
+    );
+
     settings.add_predicate("use_sse41", predicate!(has_sse41));
     settings.add_predicate("use_sse42", predicate!(has_sse41 && has_sse42));
     settings.add_predicate("use_popcnt", predicate!(has_popcnt && has_sse42));
@@ -0,0 +1,407 @@
+//! Alias analysis for memory operations.
+//!
+//! This decides whether two memory operations may access the same bytes of memory. It is a purely
+//! local analysis which looks at how the address of each access is computed:
+//!
+//! - Loads with the `readonly` flag access memory that is never written.
+//! - Accesses annotated with different alias regions (see `MemFlags::alias_region`) never alias.
+//! - Stack slots, heaps and tables are separate objects. A stack slot whose address is never
+//!   taken with `stack_addr` can only be accessed by `stack_load` and `stack_store`.
+//! - Accesses relative to the same base address alias only if their byte ranges overlap.
+
+use entity::EntitySet;
+use ir::{Function, Heap, Inst, InstructionData, MemFlags, Opcode, StackSlot, Table, Type, Value};
+
+/// The object that an address points into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Base {
+    /// A stack slot.
+    Slot(StackSlot),
+    /// The address computed by `heap_addr` for a heap and an index.
+    Heap(Heap, Value),
+    /// The address computed by `table_addr` for a table and an index.
+    Table(Table, Value),
+    /// Any other address value.
+    Value(Value),
+    /// An address that isn't a single value, as used by `load_complex` and `store_complex`.
+    Unknown,
+}
+
+/// The bytes of memory accessed by a load or a store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    /// The object the address points into.
+    pub base: Base,
+    /// The offset of the first byte accessed from `base`.
+    pub offset: i64,
+    /// The number of bytes accessed.
+    pub size: u32,
+    /// The flags on the instruction.
+    pub flags: MemFlags,
+}
+
+impl MemoryAccess {
+    /// Does this access cover exactly the same bytes as `other`?
+    pub fn same_location(&self, other: &Self) -> bool {
+        self.base != Base::Unknown
+            && self.base == other.base
+            && self.offset == other.offset
+            && self.size == other.size
+    }
+
+    /// Does this access cover all of the bytes accessed by `other`?
+    pub fn covers(&self, other: &Self) -> bool {
+        self.base != Base::Unknown
+            && self.base == other.base
+            && self.offset <= other.offset
+            && end(self) >= end(other)
+    }
+}
+
+/// The end offset of an access, which doesn't overflow for any valid access.
+fn end(access: &MemoryAccess) -> i64 {
+    access.offset.saturating_add(i64::from(access.size))
+}
+
+/// The effect an instruction has on memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryEffect {
+    /// The instruction doesn't access memory.
+    None,
+    /// The instruction reads the given memory.
+    Load(MemoryAccess),
+    /// The instruction writes the given memory.
+    Store(MemoryAccess),
+    /// A call, which may read and write any memory except stack slots whose address is never
+    /// taken.
+    Call,
+    /// The instruction may read and write any memory.
+    Unknown,
+}
+
+/// Alias analysis of the memory operations in a function.
+pub struct AliasAnalysis {
+    /// Stack slots whose address is taken by `stack_addr`.
+    escaped_slots: EntitySet<StackSlot>,
+}
+
+impl AliasAnalysis {
+    /// Analyze `func`.
+    ///
+    /// The analysis stays valid as long as no new `stack_addr` instructions are added.
+    pub fn new(func: &Function) -> Self {
+        let mut escaped_slots = EntitySet::new();
+        for ebb in func.layout.ebbs() {
+            for inst in func.layout.ebb_insts(ebb) {
+                if let InstructionData::StackLoad {
+                    opcode: Opcode::StackAddr,
+                    stack_slot,
+                    ..
+                } = func.dfg[inst]
+                {
+                    escaped_slots.insert(stack_slot);
+                }
+            }
+        }
+        Self { escaped_slots }
+    }
+
+    /// Can the stack slot `slot` only be accessed by `stack_load` and `stack_store`?
+    pub fn is_private_slot(&self, slot: StackSlot) -> bool {
+        !self.escaped_slots.contains(slot)
+    }
+
+    /// Get the effect of `inst` on memory.
+    pub fn effect(&self, func: &Function, inst: Inst) -> MemoryEffect {
+        let dfg = &func.dfg;
+        match dfg[inst] {
+            InstructionData::Load {
+                opcode,
+                arg,
+                flags,
+                offset,
+            } => {
+                let (base, base_offset) = address_base(func, arg);
+                MemoryEffect::Load(MemoryAccess {
+                    base,
+                    offset: base_offset.wrapping_add(offset.into()),
+                    size: access_size(opcode, dfg.ctrl_typevar(inst)),
+                    flags,
+                })
+            }
+            InstructionData::LoadComplex { opcode, flags, .. } => {
+                MemoryEffect::Load(MemoryAccess {
+                    base: Base::Unknown,
+                    offset: 0,
+                    size: access_size(opcode, dfg.ctrl_typevar(inst)),
+                    flags,
+                })
+            }
+            InstructionData::Store {
+                opcode,
+                args,
+                flags,
+                offset,
+            } => {
+                let (base, base_offset) = address_base(func, args[1]);
+                MemoryEffect::Store(MemoryAccess {
+                    base,
+                    offset: base_offset.wrapping_add(offset.into()),
+                    size: access_size(opcode, dfg.value_type(args[0])),
+                    flags,
+                })
+            }
+            InstructionData::StoreComplex { opcode, flags, .. } => {
+                let ty = dfg.value_type(dfg.inst_args(inst)[0]);
+                MemoryEffect::Store(MemoryAccess {
+                    base: Base::Unknown,
+                    offset: 0,
+                    size: access_size(opcode, ty),
+                    flags,
+                })
+            }
+            InstructionData::StackLoad {
+                opcode: Opcode::StackLoad,
+                stack_slot,
+                offset,
+            } => MemoryEffect::Load(MemoryAccess {
+                base: Base::Slot(stack_slot),
+                offset: offset.into(),
+                size: dfg.ctrl_typevar(inst).bytes(),
+                flags: MemFlags::new(),
+            }),
+            InstructionData::StackStore {
+                arg,
+                stack_slot,
+                offset,
+                ..
+            } => MemoryEffect::Store(MemoryAccess {
+                base: Base::Slot(stack_slot),
+                offset: offset.into(),
+                size: dfg.value_type(arg).bytes(),
+                flags: MemFlags::new(),
+            }),
+            ref data => {
+                let opcode = data.opcode();
+                if opcode.is_call() {
+                    MemoryEffect::Call
+                } else if opcode.can_load() || opcode.can_store() || opcode.other_side_effects() {
+                    MemoryEffect::Unknown
+                } else {
+                    MemoryEffect::None
+                }
+            }
+        }
+    }
+
+    /// May the accesses `a` and `b` touch the same bytes of memory, where at least one of them is
+    /// a store?
+    pub fn may_alias(&self, a: &MemoryAccess, b: &MemoryAccess) -> bool {
+        // Nothing writes to read-only memory.
+        if a.flags.readonly() || b.flags.readonly() {
+            return false;
+        }
+        // Accesses without a region may alias anything.
+        if let (Some(x), Some(y)) = (a.flags.alias_region(), b.flags.alias_region()) {
+            if x != y {
+                return false;
+            }
+        }
+        match (a.base, b.base) {
+            (Base::Slot(x), Base::Slot(y)) => x == y && overlap(a, b),
+            (Base::Heap(x, i), Base::Heap(y, j)) => x == y && (i != j || overlap(a, b)),
+            (Base::Table(x, i), Base::Table(y, j)) => x == y && (i != j || overlap(a, b)),
+            (Base::Value(x), Base::Value(y)) => x != y || overlap(a, b),
+            (Base::Slot(slot), Base::Value(_))
+            | (Base::Slot(slot), Base::Unknown)
+            | (Base::Value(_), Base::Slot(slot))
+            | (Base::Unknown, Base::Slot(slot)) => !self.is_private_slot(slot),
+            (Base::Value(_), _) | (_, Base::Value(_)) | (Base::Unknown, _) | (_, Base::Unknown) => {
+                true
+            }
+            // Stack slots, heaps and tables are distinct objects.
+            _ => false,
+        }
+    }
+
+    /// May an instruction with `effect` write any of the bytes accessed by `access`?
+    pub fn clobbers(&self, effect: &MemoryEffect, access: &MemoryAccess) -> bool {
+        match *effect {
+            MemoryEffect::None | MemoryEffect::Load(_) => false,
+            MemoryEffect::Store(ref store) => self.may_alias(store, access),
+            MemoryEffect::Call => !access.flags.readonly() && !self.is_private(access),
+            MemoryEffect::Unknown => !access.flags.readonly(),
+        }
+    }
+
+    /// May an instruction with `effect` read any of the bytes accessed by `access`?
+    ///
+    /// A `readonly` load never reads memory written by a store in the same function.
+    pub fn reads(&self, effect: &MemoryEffect, access: &MemoryAccess) -> bool {
+        match *effect {
+            MemoryEffect::None | MemoryEffect::Store(_) => false,
+            MemoryEffect::Load(ref load) => self.may_alias(load, access),
+            MemoryEffect::Call => !self.is_private(access),
+            MemoryEffect::Unknown => true,
+        }
+    }
+
+    /// Is `access` to a stack slot which only `stack_load` and `stack_store` can access?
+    fn is_private(&self, access: &MemoryAccess) -> bool {
+        match access.base {
+            Base::Slot(slot) => self.is_private_slot(slot),
+            _ => false,
+        }
+    }
+}
+
+/// Do the byte ranges of `a` and `b` overlap?
+fn overlap(a: &MemoryAccess, b: &MemoryAccess) -> bool {
+    a.offset < end(b) && b.offset < end(a)
+}
+
+/// Get the number of bytes accessed by a memory instruction with `opcode`, where `ty` is the type
+/// of the loaded or stored value.
+fn access_size(opcode: Opcode, ty: Type) -> u32 {
+    match opcode {
+        Opcode::Uload8
+        | Opcode::Sload8
+        | Opcode::Istore8
+        | Opcode::Uload8Complex
+        | Opcode::Sload8Complex
+        | Opcode::Istore8Complex => 1,
+        Opcode::Uload16
+        | Opcode::Sload16
+        | Opcode::Istore16
+        | Opcode::Uload16Complex
+        | Opcode::Sload16Complex
+        | Opcode::Istore16Complex => 2,
+        Opcode::Uload32
+        | Opcode::Sload32
+        | Opcode::Istore32
+        | Opcode::Uload32Complex
+        | Opcode::Sload32Complex
+        | Opcode::Istore32Complex => 4,
+        _ => ty.bytes(),
+    }
+}
+
+/// Split the address `addr` into the object it points into and an offset.
+fn address_base(func: &Function, addr: Value) -> (Base, i64) {
+    let dfg = &func.dfg;
+    let mut addr = dfg.resolve_aliases(addr);
+    let mut offset = 0i64;
+    // Chains of `iadd_imm` are short in practice, but unreachable code may even contain cycles.
+    for _ in 0..16 {
+        let inst = match dfg.value_def(addr) {
+            ::ir::ValueDef::Result(inst, _) => inst,
+            ::ir::ValueDef::Param(..) => break,
+        };
+        match dfg[inst] {
+            InstructionData::BinaryImm {
+                opcode: Opcode::IaddImm,
+                arg,
+                imm,
+            } => {
+                offset = offset.wrapping_add(imm.into());
+                addr = dfg.resolve_aliases(arg);
+            }
+            InstructionData::HeapAddr { heap, arg, .. } => {
+                return (Base::Heap(heap, dfg.resolve_aliases(arg)), offset);
+            }
+            InstructionData::TableAddr {
+                table,
+                arg,
+                offset: table_offset,
+                ..
+            } => {
+                let offset = offset.wrapping_add(table_offset.into());
+                return (Base::Table(table, dfg.resolve_aliases(arg)), offset);
+            }
+            InstructionData::StackLoad {
+                opcode: Opcode::StackAddr,
+                stack_slot,
+                offset: slot_offset,
+            } => {
+                return (Base::Slot(stack_slot), offset.wrapping_add(slot_offset.into()));
+            }
+            _ => break,
+        }
+    }
+    (Base::Value(addr), offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cursor::{Cursor, FuncCursor};
+    use ir::immediates::Offset32;
+    use ir::{types, AliasRegion, InstBuilder, StackSlotData, StackSlotKind};
+
+    #[test]
+    fn slots_and_offsets() {
+        let mut func = Function::new();
+        let ss0 = func.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 16));
+        let ss1 = func.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 16));
+        let ebb0 = func.dfg.make_ebb();
+        let p = func.dfg.append_ebb_param(ebb0, types::I64);
+        let mut pos = FuncCursor::new(&mut func);
+        pos.insert_ebb(ebb0);
+        let v = pos.ins().iconst(types::I32, 0);
+        let st0 = pos.ins().stack_store(v, ss0, 0);
+        let st4 = pos.ins().stack_store(v, ss0, 4);
+        let st1 = pos.ins().stack_store(v, ss1, 0);
+        let p8 = pos.ins().iadd_imm(p, 8);
+        let ld = pos.ins().load(types::I64, MemFlags::new(), p8, Offset32::new(-4));
+        let ld = pos.func.dfg.value_def(ld).unwrap_inst();
+        let st = pos.ins().store(MemFlags::new(), v, p, 0);
+
+        let aa = AliasAnalysis::new(&func);
+        let access = |inst| match aa.effect(&func, inst) {
+            MemoryEffect::Load(access) | MemoryEffect::Store(access) => access,
+            effect => panic!("unexpected effect {:?}", effect),
+        };
+        assert!(aa.may_alias(&access(st0), &access(st0)));
+        assert!(!aa.may_alias(&access(st0), &access(st4)));
+        assert!(!aa.may_alias(&access(st0), &access(st1)));
+        assert!(!aa.may_alias(&access(st0), &access(st)));
+        assert_eq!(access(ld).base, Base::Value(p));
+        assert_eq!(access(ld).offset, 4);
+        assert!(!aa.may_alias(&access(ld), &access(st)));
+        let mut wide = access(ld);
+        wide.offset = 0;
+        assert!(aa.may_alias(&wide, &access(st)));
+        assert!(wide.covers(&access(st)));
+        assert!(!access(st).covers(&wide));
+
+        assert!(!aa.clobbers(&MemoryEffect::Call, &access(st0)));
+        assert!(aa.clobbers(&MemoryEffect::Call, &access(ld)));
+        assert!(!aa.reads(&MemoryEffect::Call, &access(st0)));
+        assert!(aa.reads(&MemoryEffect::Call, &access(st)));
+        assert!(!aa.reads(&MemoryEffect::Load(access(ld)), &access(st)));
+    }
+
+    #[test]
+    fn flags() {
+        let p = Value::with_number(0).unwrap();
+        let mut a = MemoryAccess {
+            base: Base::Value(p),
+            offset: 0,
+            size: 4,
+            flags: MemFlags::new(),
+        };
+        let mut b = a;
+        let aa = AliasAnalysis {
+            escaped_slots: EntitySet::new(),
+        };
+        assert!(aa.may_alias(&a, &b));
+        a.flags.set_alias_region(Some(AliasRegion::Heap));
+        assert!(aa.may_alias(&a, &b));
+        b.flags.set_alias_region(Some(AliasRegion::Table));
+        assert!(!aa.may_alias(&a, &b));
+        b.flags.set_alias_region(Some(AliasRegion::Heap));
+        assert!(aa.may_alias(&a, &b));
+        b.flags.set_readonly();
+        assert!(!aa.may_alias(&a, &b));
+    }
+}
@@ -0,0 +1,294 @@
+//! Block placement.
+//!
+//! The order of the EBBs in the layout doesn't affect the semantics of a function, but it decides
+//! which jumps become fallthroughs in `binemit::relax_branches`. This pass reorders the EBBs so
+//! each EBB is followed by its most frequently executed successor, and moves the cold EBBs to the
+//! end of the function.
+//!
+//! The frequencies come from the `ebb_frequencies` hints of the function. EBBs without a hint are
+//! estimated from their loop depth. An EBB without a hint is also considered cold when it ends in
+//! a trap, when all its successors are cold, or when it is dominated by a cold EBB.
+//!
+//! Finally, a conditional branch to the next EBB is inverted so the jump after it can fall through
+//! instead:
+//!
+//! ```cranelift
+//!     brz v1, ebb2
+//!     jump ebb3
+//! ebb2:
+//! ```
+//!
+//! becomes:
+//!
+//! ```cranelift
+//!     brnz v1, ebb3
+//!     jump ebb2
+//! ebb2:
+//! ```
+
+use dominator_tree::DominatorTree;
+use entity::{EntitySet, SecondaryMap};
+use flowgraph::ControlFlowGraph;
+use ir::condcodes::CondCode;
+use ir::{Ebb, EbbFrequency, Function, Inst, InstBuilder, InstructionData, Opcode};
+use isa::TargetIsa;
+use loop_analysis::LoopAnalysis;
+use std::cmp;
+use std::vec::Vec;
+use timing;
+
+/// The estimated number of iterations of a loop, used to weigh the EBBs without a count. Edge
+/// profiling uses the same estimate to weigh the edges.
+pub const LOOP_WEIGHT: u64 = 8;
+
+/// The loop depth beyond which the estimated weight stops growing.
+pub const MAX_LOOP_DEPTH: usize = 6;
+
+/// How much more often an EBB hinted as hot is expected to execute than estimated.
+const HOT_WEIGHT: u64 = 64;
+
+/// Reorder the EBBs of `func` so the frequently executed paths fall through.
+pub fn do_block_placement(
+    func: &mut Function,
+    cfg: &mut ControlFlowGraph,
+    domtree: &mut DominatorTree,
+    loop_analysis: &LoopAnalysis,
+    isa: &TargetIsa,
+) {
+    let _tt = timing::block_placement();
+    debug_assert!(cfg.is_valid());
+    debug_assert!(domtree.is_valid());
+    debug_assert!(loop_analysis.is_valid());
+
+    let cold = find_cold_ebbs(func, cfg, domtree);
+    let mut weights = SecondaryMap::new();
+    for ebb in func.layout.ebbs() {
+        weights[ebb] = weight(func, loop_analysis, &cold, ebb);
+    }
+
+    let order = place(func, &cold, &weights);
+    func.layout.set_ebb_order(&order);
+
+    if invert_branches(func, cfg, isa) {
+        domtree.compute(func, cfg);
+    }
+}
+
+/// Find the EBBs that are rarely executed.
+fn find_cold_ebbs(
+    func: &Function,
+    cfg: &ControlFlowGraph,
+    domtree: &DominatorTree,
+) -> EntitySet<Ebb> {
+    let mut cold = EntitySet::new();
+    for ebb in func.layout.ebbs() {
+        if func.ebb_frequencies[ebb].is_cold() || !domtree.is_reachable(ebb) {
+            cold.insert(ebb);
+        }
+    }
+
+    // Visit the successors first, so the EBBs that can only lead to a trap are found.
+    for &ebb in domtree.cfg_postorder() {
+        if !func.ebb_frequencies[ebb].is_unknown() {
+            continue;
+        }
+        let traps = match func.layout.last_inst(ebb) {
+            Some(inst) => func.dfg[inst].opcode() == Opcode::Trap,
+            None => false,
+        };
+        let mut succs = cfg.succ_iter(ebb).peekable();
+        let leads_to_cold = succs.peek().is_some() && succs.all(|succ| cold.contains(succ));
+        if traps || leads_to_cold {
+            cold.insert(ebb);
+        }
+    }
+
+    // Then the EBBs that can only be reached through a cold EBB.
+    for &ebb in domtree.cfg_postorder().iter().rev() {
+        if !func.ebb_frequencies[ebb].is_unknown() || cold.contains(ebb) {
+            continue;
+        }
+        if let Some(idom) = domtree.idom(ebb) {
+            if cold.contains(func.layout.pp_ebb(idom)) {
+                cold.insert(ebb);
+            }
+        }
+    }
+
+    cold
+}
+
+/// Get the expected relative execution frequency of `ebb`.
+fn weight(func: &Function, loop_analysis: &LoopAnalysis, cold: &EntitySet<Ebb>, ebb: Ebb) -> u64 {
+    if cold.contains(ebb) {
+        return 0;
+    }
+    let depth = cmp::min(loop_analysis.loop_depth(ebb), MAX_LOOP_DEPTH);
+    let estimate = LOOP_WEIGHT.pow(depth as u32);
+    match func.ebb_frequencies[ebb] {
+        EbbFrequency::Count(count) => count,
+        EbbFrequency::Hot => estimate * HOT_WEIGHT,
+        _ => estimate,
+    }
+}
+
+/// Compute the new order of the EBBs.
+///
+/// Starting from the entry block, each EBB is followed by its heaviest successor that isn't
+/// already placed. When there is none, the next unplaced EBB in the original order continues the
+/// layout. The cold EBBs go last.
+fn place(func: &Function, cold: &EntitySet<Ebb>, weights: &SecondaryMap<Ebb, u64>) -> Vec<Ebb> {
+    let original: Vec<Ebb> = func.layout.ebbs().collect();
+    let mut order = Vec::with_capacity(original.len());
+    let mut placed = EntitySet::new();
+    let mut seeds = original.iter().filter(|&&ebb| !cold.contains(ebb));
+
+    let mut next = func.layout.entry_block();
+    while let Some(ebb) = next {
+        placed.insert(ebb);
+        order.push(ebb);
+        next = best_successor(func, cold, weights, &placed, ebb)
+            .or_else(|| seeds.find(|&&seed| !placed.contains(seed)).cloned());
+    }
+
+    for &ebb in &original {
+        if !placed.contains(ebb) {
+            order.push(ebb);
+        }
+    }
+    order
+}
+
+/// Find the successor of `ebb` that should follow it in the layout.
+///
+/// Only the destinations of the final jump and of a conditional branch right before it are
+/// considered, since those are the ones that can fall through. On equal weights, the destination of
+/// the jump is preferred.
+fn best_successor(
+    func: &Function,
+    cold: &EntitySet<Ebb>,
+    weights: &SecondaryMap<Ebb, u64>,
+    placed: &EntitySet<Ebb>,
+    ebb: Ebb,
+) -> Option<Ebb> {
+    let mut best = None;
+    let mut best_weight = 0;
+    for inst in func.layout.ebb_insts(ebb).rev().take(2) {
+        let dest = match func.dfg[inst].branch_destination() {
+            Some(dest) => dest,
+            None => break,
+        };
+        if placed.contains(dest) || (cold.contains(dest) && !cold.contains(ebb)) {
+            continue;
+        }
+        if best.is_none() || weights[dest] > best_weight {
+            best = Some(dest);
+            best_weight = weights[dest];
+        }
+    }
+    best
+}
+
+/// Invert the conditional branches to the next EBB, so the jump after them can fall through.
+///
+/// Returns `true` if any branch was inverted.
+fn invert_branches(func: &mut Function, cfg: &mut ControlFlowGraph, isa: &TargetIsa) -> bool {
+    let mut changed = false;
+    let ebbs: Vec<Ebb> = func.layout.ebbs().collect();
+    for (&ebb, &next) in ebbs.iter().zip(ebbs.iter().skip(1)) {
+        let jump = match func.layout.last_inst(ebb) {
+            Some(jump) if func.dfg[jump].opcode() == Opcode::Jump => jump,
+            _ => continue,
+        };
+        if func.dfg[jump].branch_destination() == Some(next) {
+            continue;
+        }
+        let branch = match func.layout.prev_inst(jump) {
+            Some(branch) if func.dfg[branch].branch_destination() == Some(next) => branch,
+            _ => continue,
+        };
+        if invert_branch(func, branch, jump, isa) {
+            cfg.recompute_ebb(func, ebb);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Swap the destinations of the conditional `branch` and the `jump` following it, inverting the
+/// condition of `branch`.
+///
+/// Returns `false` if the inverted branch can't be encoded.
+fn invert_branch(func: &mut Function, branch: Inst, jump: Inst, isa: &TargetIsa) -> bool {
+    let original = func.dfg[branch].clone();
+    let branch_dest = original.branch_destination().unwrap();
+    let branch_args = func.dfg.inst_variable_args(branch).to_vec();
+    let jump_dest = func.dfg[jump].branch_destination().unwrap();
+    let jump_args = func.dfg.inst_variable_args(jump).to_vec();
+    let fixed = func.dfg.inst_fixed_args(branch).to_vec();
+
+    match original {
+        InstructionData::Branch {
+            opcode: Opcode::Brz,
+            ..
+        } => {
+            func.dfg
+                .replace(branch)
+                .brnz(fixed[0], jump_dest, &jump_args);
+        }
+        InstructionData::Branch {
+            opcode: Opcode::Brnz,
+            ..
+        } => {
+            func.dfg
+                .replace(branch)
+                .brz(fixed[0], jump_dest, &jump_args);
+        }
+        InstructionData::BranchInt {
+            opcode: Opcode::Brif,
+            cond,
+            ..
+        } => {
+            func.dfg
+                .replace(branch)
+                .brif(cond.inverse(), fixed[0], jump_dest, &jump_args);
+        }
+        InstructionData::BranchFloat {
+            opcode: Opcode::Brff,
+            cond,
+            ..
+        } => {
+            func.dfg
+                .replace(branch)
+                .brff(cond.inverse(), fixed[0], jump_dest, &jump_args);
+        }
+        InstructionData::BranchIcmp {
+            opcode: Opcode::BrIcmp,
+            cond,
+            ..
+        } => {
+            func.dfg.replace(branch).br_icmp(
+                cond.inverse(),
+                fixed[0],
+                fixed[1],
+                jump_dest,
+                &jump_args,
+            );
+        }
+        _ => return false,
+    }
+
+    // Once legalized, the inverted branch needs a new encoding.
+    if func.encodings[branch].is_legal() {
+        match func.encode(branch, isa) {
+            Ok(encoding) => func.encodings[branch] = encoding,
+            Err(_) => {
+                func.dfg[branch] = original;
+                return false;
+            }
+        }
+    }
+
+    func.dfg.replace(jump).jump(branch_dest, &branch_args);
+    true
+}
@@ -0,0 +1,339 @@
+//! Redundant heap bounds check elimination.
+//!
+//! A `heap_addr` instruction traps unless `p + Size` is within the bound of the heap. Once it has
+//! executed, the same check of `p` with a smaller size is redundant, and so is a check of `p + c`
+//! for a constant `c` if `c + Size` is no larger. This relies on the bound of a heap never
+//! decreasing.
+//!
+//! This pass replaces a `heap_addr` by its address computation alone when:
+//!
+//! - a dominating `heap_addr` of the same heap already proved its range in bounds.
+//! - an earlier `heap_addr` in the same EBB checks the same offset, and nothing between them has
+//!   side effects or can leave the EBB. The earlier check is then widened to cover both.
+//! - its offset is an induction variable of a loop with a known range, and the loop has no side
+//!   effects and a single exit. The check of the largest offset is then hoisted out of the loop.
+//!   It is omitted entirely if that offset is below the guaranteed minimum size of the heap.
+
+use cursor::{Cursor, FuncCursor};
+use dominator_tree::DominatorTree;
+use flowgraph::{BasicBlock, ControlFlowGraph};
+use fx::FxHashMap;
+use induction_variables::{constant, mask, InductionVariables};
+use ir::instructions::BranchInfo;
+use ir::{Function, Heap, Inst, InstBuilder, InstructionData, Opcode, Value, ValueDef};
+use legalizer::expand_unchecked_heap_addr;
+use loop_analysis::{Loop, LoopAnalysis};
+use settings::Flags;
+use std::vec::Vec;
+use timing;
+
+/// Eliminate redundant heap bounds checks in `func`.
+pub fn do_bounds_check_elim(
+    func: &mut Function,
+    cfg: &ControlFlowGraph,
+    domtree: &DominatorTree,
+    loop_analysis: &LoopAnalysis,
+    flags: &Flags,
+) {
+    let _tt = timing::bounds_checks();
+    debug_assert!(cfg.is_valid());
+    debug_assert!(domtree.is_valid());
+    debug_assert!(loop_analysis.is_valid());
+
+    // With Spectre mitigation, each access clamps its own address using its own check.
+    if flags.enable_spectre_mitigation() {
+        return;
+    }
+
+    let mut ivs = InductionVariables::new();
+    ivs.compute(func, cfg, domtree, loop_analysis);
+    for lp in loop_analysis.loops() {
+        hoist_loop_checks(func, cfg, domtree, loop_analysis, &ivs, lp);
+    }
+    remove_redundant_checks(func, domtree);
+}
+
+/// Get the heap, the offset, and the access size checked by `inst`, if it is a `heap_addr`.
+fn heap_check(func: &Function, inst: Inst) -> Option<(Heap, Value, u32)> {
+    match func.dfg[inst] {
+        InstructionData::HeapAddr {
+            opcode: Opcode::HeapAddr,
+            heap,
+            arg,
+            imm,
+        } => Some((heap, func.dfg.resolve_aliases(arg), imm.into())),
+        _ => None,
+    }
+}
+
+/// Split `offset` into a value and a non-negative constant added to it.
+fn split_offset(func: &Function, offset: Value) -> (Value, u64) {
+    if let ValueDef::Result(inst, _) = func.dfg.value_def(offset) {
+        if let InstructionData::BinaryImm {
+            opcode: Opcode::IaddImm,
+            arg,
+            imm,
+        } = func.dfg[inst]
+        {
+            let imm: i64 = imm.into();
+            if imm >= 0 {
+                return (func.dfg.resolve_aliases(arg), imm as u64);
+            }
+        }
+    }
+    (offset, 0)
+}
+
+/// Test if instructions with `opcode` prevent merging the bounds checks around them, because they
+/// have side effects or may not continue to the next instruction.
+fn is_barrier(opcode: Opcode) -> bool {
+    opcode.is_branch()
+        || opcode.is_terminator()
+        || opcode.is_call()
+        || opcode.can_store()
+        || opcode.can_trap()
+        || opcode.other_side_effects()
+}
+
+/// Remove the checks proved by a dominating check, and merge the checks of the same offset in an
+/// EBB.
+fn remove_redundant_checks(func: &mut Function, domtree: &DominatorTree) {
+    // The checks that were kept, by heap and offset.
+    let mut checks: FxHashMap<(Heap, Value), Vec<Inst>> = FxHashMap();
+
+    for &ebb in domtree.cfg_postorder().iter().rev() {
+        // The checks in this EBB that can still be widened.
+        let mut open: Vec<Inst> = Vec::new();
+        let mut next_inst = func.layout.first_inst(ebb);
+        while let Some(inst) = next_inst {
+            next_inst = func.layout.next_inst(inst);
+            let (heap, offset, size) = match heap_check(func, inst) {
+                Some(check) => check,
+                None => {
+                    if is_barrier(func.dfg[inst].opcode()) {
+                        open.clear();
+                    }
+                    continue;
+                }
+            };
+            let (value, c) = split_offset(func, offset);
+            let needed = c + u64::from(size);
+
+            // Look for a dominating check proving the range.
+            let mut proved = false;
+            for &(key, extra) in &[(offset, u64::from(size)), (value, needed)] {
+                if let Some(dominating) = checks.get(&(heap, key)) {
+                    proved |= dominating.iter().any(|&check| {
+                        let (_, _, checked) = heap_check(func, check).unwrap();
+                        u64::from(checked) >= extra && domtree.dominates(check, inst, &func.layout)
+                    });
+                }
+            }
+
+            // Otherwise, widen an earlier check in the EBB. The sum `value + c` doesn't wrap
+            // around if `c` is less than the checked size.
+            if !proved && needed <= u64::from(u32::max_value()) {
+                for &check in &open {
+                    let (check_heap, check_offset, checked) = heap_check(func, check).unwrap();
+                    let widen = if check_offset == offset {
+                        Some(size)
+                    } else if check_offset == value && (c == 0 || c < u64::from(checked)) {
+                        Some(needed as u32)
+                    } else {
+                        None
+                    };
+                    if let Some(new_size) = widen {
+                        if check_heap == heap {
+                            if new_size > checked {
+                                if let InstructionData::HeapAddr { ref mut imm, .. } =
+                                    func.dfg[check]
+                                {
+                                    *imm = new_size.into();
+                                }
+                            }
+                            proved = true;
+                            break;
+                        }
+                    }
+                }
+            }
+
+            if proved {
+                expand_unchecked_heap_addr(inst, func);
+            } else {
+                checks
+                    .entry((heap, offset))
+                    .or_insert_with(Vec::new)
+                    .push(inst);
+                open.push(inst);
+            }
+        }
+    }
+}
+
+/// Hoist the checks in `lp` whose offset is an induction variable with a known range.
+fn hoist_loop_checks(
+    func: &mut Function,
+    cfg: &ControlFlowGraph,
+    domtree: &DominatorTree,
+    loop_analysis: &LoopAnalysis,
+    ivs: &InductionVariables,
+    lp: Loop,
+) {
+    let header = loop_analysis.loop_header(lp);
+    let mut entries = Vec::new();
+    let mut latches = Vec::new();
+    for BasicBlock { ebb, inst } in cfg.pred_iter(header) {
+        if loop_analysis.is_in_loop(ebb, lp) {
+            latches.push(inst);
+        } else if func.dfg[inst].opcode() == Opcode::Jump {
+            entries.push(inst);
+        } else {
+            // A hoisted check must only execute when the loop is entered.
+            return;
+        }
+    }
+
+    let mut checks = Vec::new();
+    for ebb in func.layout.ebbs() {
+        if !loop_analysis.is_in_loop(ebb, lp) {
+            continue;
+        }
+        for inst in func.layout.ebb_insts(ebb) {
+            if heap_check(func, inst).is_some() {
+                checks.push(inst);
+            }
+        }
+    }
+
+    for check in checks {
+        let (heap, offset, size) = heap_check(func, check).unwrap();
+        let function = match ivs.linear_function(offset) {
+            Some(function) => function,
+            None => continue,
+        };
+        if function.scale != 1 || function.base.is_some() {
+            continue;
+        }
+        let basic = *ivs.basic_induction_variable(function.iv).unwrap();
+        if basic.lp != lp {
+            continue;
+        }
+        let exit = match ivs.exit_test(func, cfg, domtree, loop_analysis, function.iv) {
+            Some(exit) => exit,
+            None => continue,
+        };
+        if !has_single_exit(func, loop_analysis, lp, exit.branch) {
+            continue;
+        }
+
+        // The check must execute on every iteration, so it traps if any offset is out of bounds.
+        // An earlier back edge in the same EBB would skip it.
+        if !latches
+            .iter()
+            .all(|&latch| domtree.dominates(check, latch, &func.layout))
+        {
+            continue;
+        }
+
+        // All the entries must start the induction variable at the same constant.
+        let index = match func.dfg.value_def(function.iv) {
+            ValueDef::Param(_, num) => num,
+            ValueDef::Result(_, _) => continue,
+        };
+        let mut inits = entries
+            .iter()
+            .map(|&entry| constant(func, func.dfg.inst_variable_args(entry)[index]));
+        let init = match inits.next() {
+            Some(Some(init)) => init,
+            _ => continue,
+        };
+        if inits.any(|other| other != Some(init)) {
+            continue;
+        }
+
+        // Compute the range of offsets checked on the iterations. The loop exits on iteration
+        // number `trips`, where the check only executes if it comes before the exit test.
+        let ty = func.dfg.value_type(offset);
+        let bits = ty.bits();
+        let trips = match exit.trip_count(bits, init, basic.step) {
+            Some(trips) => trips,
+            None => continue,
+        };
+        let executions = if domtree.dominates(check, exit.branch, &func.layout) {
+            trips + 1
+        } else {
+            trips
+        };
+        if executions == 0 {
+            continue;
+        }
+        let mask = mask(bits) as u64;
+        let first = init.wrapping_add(function.offset) as u64 & mask;
+        let distance = match (executions - 1).checked_mul(basic.step.wrapping_abs() as u64) {
+            Some(distance) => distance,
+            None => continue,
+        };
+        let largest = if basic.step > 0 {
+            match first.checked_add(distance) {
+                Some(last) if last <= mask => last,
+                _ => continue,
+            }
+        } else if distance <= first {
+            first
+        } else {
+            continue;
+        };
+
+        // Check the largest offset before entering the loop, unless it is below the minimum size.
+        let min_size: i64 = func.heaps[heap].min_size.into();
+        let in_bounds = match largest.checked_add(u64::from(size)) {
+            Some(end) => end <= min_size as u64,
+            None => false,
+        };
+        if !in_bounds {
+            let addr_ty = func.dfg.value_type(func.dfg.first_result(check));
+            let srcloc = func.srclocs[check];
+            for &entry in &entries {
+                let mut pos = FuncCursor::new(func).at_inst(entry);
+                pos.set_srcloc(srcloc);
+                let largest = pos.ins().iconst(ty, largest as i64);
+                pos.ins().heap_addr(addr_ty, heap, largest, size);
+            }
+        }
+        expand_unchecked_heap_addr(check, func);
+    }
+}
+
+/// Test if `lp` has no side effects, and only exits through `branch` or the instruction after it.
+fn has_single_exit(func: &Function, loop_analysis: &LoopAnalysis, lp: Loop, branch: Inst) -> bool {
+    let after_branch = func.layout.next_inst(branch);
+    for ebb in func.layout.ebbs() {
+        if !loop_analysis.is_in_loop(ebb, lp) {
+            continue;
+        }
+        for inst in func.layout.ebb_insts(ebb) {
+            if inst == branch || Some(inst) == after_branch {
+                continue;
+            }
+            let opcode = func.dfg[inst].opcode();
+            if opcode == Opcode::HeapAddr {
+                continue;
+            }
+            let exits = match func.dfg.analyze_branch(inst) {
+                BranchInfo::SingleDest(dest, _) => !loop_analysis.is_in_loop(dest, lp),
+                BranchInfo::Table(_, _) => true,
+                BranchInfo::NotABranch => opcode.is_terminator(),
+            };
+            if exits
+                || opcode.is_call()
+                || opcode.can_store()
+                || opcode.can_trap()
+                || opcode.other_side_effects()
+            {
+                return false;
+            }
+        }
+    }
+    true
+}
@@ -12,22 +12,33 @@
 use binemit::{
     relax_branches, shrink_instructions, CodeOffset, MemoryCodeSink, RelocSink, TrapSink,
 };
+use block_placement::do_block_placement;
+use bounds_checks::do_bounds_check_elim;
 use dce::do_dce;
+use dead_stores::do_dead_store_elim;
 use dominator_tree::DominatorTree;
+use edge_profile::EdgeProfile;
 use flowgraph::ControlFlowGraph;
-use ir::Function;
+use if_conversion::do_if_conversion;
+use ir::{ExternalName, Function};
 use isa::TargetIsa;
 use legalize_function;
 use licm::do_licm;
 use loop_analysis::LoopAnalysis;
+use loop_unrolling::do_loop_unrolling;
 use nan_canonicalization::do_nan_canonicalization;
+use peepholes::do_peepholes;
 use postopt::do_postopt;
+use redundant_loads::do_redundant_load_elim;
 use regalloc;
 use result::CodegenResult;
-use settings::{FlagsOrIsa, OptLevel};
+use scheduling::do_scheduling;
+use settings::{FlagsOrIsa, OptLevel, Scheduling};
 use simple_gvn::do_simple_gvn;
 use simple_preopt::do_preopt;
+use stack_coloring::do_stack_coloring;
 use std::vec::Vec;
+use strength_reduction::do_strength_reduction;
 use timing;
 use unreachable_code::eliminate_unreachable_code;
 use verifier::{verify_context, verify_locations, VerifierErrors, VerifierResult};
@@ -120,6 +131,17 @@ impl Context {
         if isa.flags().opt_level() != OptLevel::Fastest {
             self.preopt(isa)?;
         }
+        if isa.flags().opt_level() == OptLevel::Best {
+            self.redundant_load_elim(isa)?;
+            self.compute_domtree();
+            self.dead_store_elim(isa)?;
+            self.if_conversion(isa)?;
+            self.compute_loop_analysis();
+            self.unroll_loops(isa)?;
+            self.compute_loop_analysis();
+            self.reduce_strength(isa)?;
+            self.bounds_check_elim(isa)?;
+        }
         if isa.flags().enable_nan_canonicalization() {
             self.canonicalize_nans(isa)?;
         }
@@ -138,7 +160,17 @@ impl Context {
         if isa.flags().opt_level() != OptLevel::Fastest {
             self.dce(isa)?;
         }
+        if isa.flags().opt_level() == OptLevel::Best {
+            self.compute_loop_analysis();
+            self.block_placement(isa)?;
+        }
+        if isa.flags().scheduling() != Scheduling::None {
+            self.schedule(isa)?;
+        }
         self.regalloc(isa)?;
+        if isa.flags().opt_level() != OptLevel::Fastest {
+            self.color_stack_slots(isa)?;
+        }
         self.prologue_epilogue(isa)?;
         if isa.flags().opt_level() == OptLevel::Best {
             self.shrink_instructions(isa)?;
@@ -216,8 +248,30 @@ impl Context {
         Ok(())
     }
 
+    /// Perform dead store elimination on the function.
+    pub fn dead_store_elim<'a, FOI: Into<FlagsOrIsa<'a>>>(
+        &mut self,
+        fisa: FOI,
+    ) -> CodegenResult<()> {
+        do_dead_store_elim(&mut self.func, &self.domtree);
+        self.verify_if(fisa)
+    }
+
+    /// Convert small diamonds and triangles in the CFG to `select` instructions.
+    pub fn if_conversion<'a, FOI: Into<FlagsOrIsa<'a>>>(&mut self, fisa: FOI) -> CodegenResult<()> {
+        do_if_conversion(&mut self.func, &mut self.cfg, &mut self.domtree);
+        self.verify_if(fisa)
+    }
+
+    /// Apply the peephole rewrites for `isa` to the function.
+    pub fn peepholes(&mut self, isa: &TargetIsa) -> CodegenResult<()> {
+        do_peepholes(&mut self.func, isa);
+        self.verify_if(isa)
+    }
+
     /// Perform pre-legalization rewrites on the function.
     pub fn preopt(&mut self, isa: &TargetIsa) -> CodegenResult<()> {
+        do_peepholes(&mut self.func, isa);
         do_preopt(&mut self.func);
         self.verify_if(isa)?;
         Ok(())
@@ -274,6 +328,15 @@ impl Context {
         self.verify_if(fisa)
     }
 
+    /// Eliminate redundant loads and forward stored values to loads.
+    pub fn redundant_load_elim<'a, FOI: Into<FlagsOrIsa<'a>>>(
+        &mut self,
+        fisa: FOI,
+    ) -> CodegenResult<()> {
+        do_redundant_load_elim(&mut self.func, &self.cfg);
+        self.verify_if(fisa)
+    }
+
     /// Perform LICM on the function.
     pub fn licm(&mut self, isa: &TargetIsa) -> CodegenResult<()> {
         do_licm(
@@ -286,6 +349,50 @@ impl Context {
         self.verify_if(isa)
     }
 
+    /// Unroll and peel counted loops, as configured by the `loop_unroll_factor` and
+    /// `loop_peel_first_iteration` settings.
+    pub fn unroll_loops<'a, FOI: Into<FlagsOrIsa<'a>>>(&mut self, fisa: FOI) -> CodegenResult<()> {
+        let fisa = fisa.into();
+        do_loop_unrolling(
+            &mut self.func,
+            &mut self.cfg,
+            &mut self.domtree,
+            &self.loop_analysis,
+            fisa.flags,
+        );
+        self.verify_if(fisa)
+    }
+
+    /// Perform strength reduction and linear-function test replacement on loops.
+    pub fn reduce_strength<'a, FOI: Into<FlagsOrIsa<'a>>>(
+        &mut self,
+        fisa: FOI,
+    ) -> CodegenResult<()> {
+        do_strength_reduction(
+            &mut self.func,
+            &self.cfg,
+            &self.domtree,
+            &self.loop_analysis,
+        );
+        self.verify_if(fisa)
+    }
+
+    /// Eliminate redundant heap bounds checks.
+    pub fn bounds_check_elim<'a, FOI: Into<FlagsOrIsa<'a>>>(
+        &mut self,
+        fisa: FOI,
+    ) -> CodegenResult<()> {
+        let fisa = fisa.into();
+        do_bounds_check_elim(
+            &mut self.func,
+            &self.cfg,
+            &self.domtree,
+            &self.loop_analysis,
+            fisa.flags,
+        );
+        self.verify_if(fisa)
+    }
+
     /// Perform unreachable code elimination.
     pub fn eliminate_unreachable_code<'a, FOI>(&mut self, fisa: FOI) -> CodegenResult<()>
     where
@@ -295,12 +402,68 @@ impl Context {
         self.verify_if(fisa)
     }
 
+    /// Reorder the EBBs so the frequently executed paths fall through.
+    pub fn block_placement(&mut self, isa: &TargetIsa) -> CodegenResult<()> {
+        do_block_placement(
+            &mut self.func,
+            &mut self.cfg,
+            &mut self.domtree,
+            &self.loop_analysis,
+            isa,
+        );
+        self.verify_if(isa)
+    }
+
+    /// Reorder the instructions in each EBB to hide latencies, as configured by the `scheduling`
+    /// setting.
+    pub fn schedule(&mut self, isa: &TargetIsa) -> CodegenResult<()> {
+        do_scheduling(&mut self.func, &self.cfg, &self.domtree, isa);
+        self.verify_if(isa)
+    }
+
+    /// Insert counters on the CFG edges of the function to collect a profile.
+    ///
+    /// The counters are stored in the data object named `counters`, which must hold
+    /// `num_counters()` zero-initialized 64-bit integers of the returned profile.
+    pub fn instrument_edges(
+        &mut self,
+        isa: &TargetIsa,
+        counters: ExternalName,
+    ) -> CodegenResult<EdgeProfile> {
+        self.flowgraph();
+        self.compute_loop_analysis();
+        let profile = EdgeProfile::new(&self.func, &self.cfg, &self.domtree, &self.loop_analysis);
+        profile.instrument(&mut self.func, counters, isa);
+        self.flowgraph();
+        self.verify_if(isa)?;
+        Ok(profile)
+    }
+
+    /// Set the EBB frequency hints from the counters collected by a build instrumented with
+    /// `instrument_edges`.
+    ///
+    /// The function must be the same as the one that was instrumented.
+    pub fn apply_edge_profile(&mut self, counters: &[u64]) {
+        self.flowgraph();
+        self.compute_loop_analysis();
+        EdgeProfile::new(&self.func, &self.cfg, &self.domtree, &self.loop_analysis)
+            .set_ebb_frequencies(&mut self.func, counters);
+    }
+
     /// Run the register allocator.
     pub fn regalloc(&mut self, isa: &TargetIsa) -> CodegenResult<()> {
         self.regalloc
             .run(isa, &mut self.func, &self.cfg, &mut self.domtree)
     }
 
+    /// Merge stack slots that are never live at the same time.
+    pub fn color_stack_slots(&mut self, isa: &TargetIsa) -> CodegenResult<()> {
+        do_stack_coloring(&mut self.func);
+        self.verify_if(isa)?;
+        self.verify_locations_if(isa)?;
+        Ok(())
+    }
+
     /// Insert prologue and epilogues after computing the stack frame layout.
     pub fn prologue_epilogue(&mut self, isa: &TargetIsa) -> CodegenResult<()> {
         isa.prologue_epilogue(&mut self.func)?;
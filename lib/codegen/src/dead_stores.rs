@@ -0,0 +1,168 @@
+//! Dead store elimination.
+//!
+//! A store is dead if nothing can read the memory it writes before it is overwritten. This pass
+//! removes two kinds of dead stores:
+//!
+//! - Stores to stack slots that are never loaded from, and whose address is never taken.
+//! - Stores that are overwritten on every path before any instruction that may read the memory,
+//!   or leave the function. In other words, the overwriting stores post-dominate the dead store
+//!   and no read comes in between. This is checked by searching forward from the store through
+//!   the control flow graph, stopping at each overwriting store.
+//!
+//! Afterwards, explicit stack slots that are no longer referenced by any instruction because their
+//! stores were removed are removed as well.
+
+use alias_analysis::{AliasAnalysis, Base, MemoryAccess, MemoryEffect};
+use cursor::{Cursor, FuncCursor};
+use dominator_tree::DominatorTree;
+use entity::EntitySet;
+use ir::instructions::BranchInfo;
+use ir::{Ebb, Function, Inst, InstructionData, Opcode, StackSlot, StackSlotKind};
+use std::vec::Vec;
+use timing;
+
+/// The maximum number of instructions to search through from each store.
+const SEARCH_LIMIT: usize = 200;
+
+/// Perform dead store elimination on `func`.
+pub fn do_dead_store_elim(func: &mut Function, domtree: &DominatorTree) {
+    let _tt = timing::dead_stores();
+    debug_assert!(domtree.is_valid());
+
+    let aa = AliasAnalysis::new(func);
+    let mut loaded_slots = EntitySet::new();
+    for ebb in func.layout.ebbs() {
+        for inst in func.layout.ebb_insts(ebb) {
+            if let InstructionData::StackLoad {
+                opcode: Opcode::StackLoad,
+                stack_slot,
+                ..
+            } = func.dfg[inst]
+            {
+                loaded_slots.insert(stack_slot);
+            }
+        }
+    }
+
+    let mut emptied_slots = EntitySet::new();
+    for &ebb in domtree.cfg_postorder() {
+        let mut pos = FuncCursor::new(func).at_bottom(ebb);
+        while let Some(inst) = pos.prev_inst() {
+            let access = match aa.effect(pos.func, inst) {
+                MemoryEffect::Store(access) => access,
+                _ => continue,
+            };
+            let dead = match access.base {
+                Base::Slot(slot) if aa.is_private_slot(slot) && !loaded_slots.contains(slot) => {
+                    true
+                }
+                _ => is_overwritten(&aa, pos.func, inst, &access),
+            };
+            if dead {
+                if let Base::Slot(slot) = access.base {
+                    emptied_slots.insert(slot);
+                }
+                pos.remove_inst();
+            }
+        }
+    }
+
+    if !emptied_slots.is_empty() {
+        remove_unused_stack_slots(func, &emptied_slots);
+    }
+}
+
+/// Is the memory written by `store`, which makes `access`, overwritten on every path before it
+/// can be read?
+fn is_overwritten(aa: &AliasAnalysis, func: &Function, store: Inst, access: &MemoryAccess) -> bool {
+    // The contents of a stack slot disappear when the function returns or traps, but any other
+    // memory can be inspected afterwards.
+    let (local, may_trap) = match access.base {
+        Base::Slot(_) => (true, false),
+        _ => (false, !access.flags.notrap()),
+    };
+
+    let mut visited = EntitySet::<Ebb>::new();
+    let mut worklist = Vec::new();
+    let mut next = func.layout.next_inst(store);
+    for _ in 0..SEARCH_LIMIT {
+        let inst = match next.or_else(|| worklist.pop()) {
+            Some(inst) => inst,
+            // Every path ended in an overwriting store or left the function.
+            None => return true,
+        };
+        next = None;
+
+        let effect = aa.effect(func, inst);
+        if let MemoryEffect::Store(ref other) = effect {
+            if other.covers(access) {
+                continue;
+            }
+        }
+        if aa.reads(&effect, access) {
+            return false;
+        }
+
+        let opcode = func.dfg[inst].opcode();
+        if opcode.can_trap() && !local {
+            return false;
+        }
+        let mut dests = Vec::new();
+        match func.dfg[inst].analyze_branch(&func.dfg.value_lists) {
+            BranchInfo::SingleDest(dest, _) => dests.push(dest),
+            BranchInfo::Table(jt, default) => {
+                dests.extend(default);
+                dests.extend(func.jump_tables[jt].iter().cloned());
+            }
+            BranchInfo::NotABranch => {
+                if opcode.is_branch() {
+                    // An indirect branch with unknown destinations.
+                    return false;
+                }
+            }
+        }
+        for dest in dests {
+            if visited.insert(dest) {
+                worklist.push(func.layout.first_inst(dest).unwrap());
+            } else if may_trap {
+                // The search may have found a loop. Removing a store that may trap before a loop
+                // that never reaches the overwriting store would change the program's behavior.
+                return false;
+            }
+        }
+
+        if !opcode.is_terminator() {
+            next = func.layout.next_inst(inst);
+        } else if !opcode.is_branch() && !local {
+            // A return or a trap.
+            return false;
+        }
+    }
+    false
+}
+
+/// Remove the explicit stack slots in `candidates` which aren't referenced by any instruction.
+fn remove_unused_stack_slots(func: &mut Function, candidates: &EntitySet<StackSlot>) {
+    let mut used = EntitySet::new();
+    for ebb in func.layout.ebbs() {
+        for inst in func.layout.ebb_insts(ebb) {
+            match func.dfg[inst] {
+                InstructionData::StackLoad { stack_slot, .. }
+                | InstructionData::StackStore { stack_slot, .. }
+                | InstructionData::RegSpill {
+                    dst: stack_slot, ..
+                }
+                | InstructionData::RegFill {
+                    src: stack_slot, ..
+                } => {
+                    used.insert(stack_slot);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    func.retain_stack_slots(|ss, data| {
+        data.kind != StackSlotKind::ExplicitSlot || !candidates.contains(ss) || used.contains(ss)
+    });
+}
@@ -0,0 +1,560 @@
+//! Edge profiling.
+//!
+//! An instrumented build counts how many times the edges of the control flow graph are executed.
+//! The counters don't need to be placed on every edge: the number of times control enters an EBB
+//! equals the number of times it leaves it, so the counts of the edges in a spanning tree of the
+//! CFG can be recovered from the counts of the remaining edges. This pass picks a maximum spanning
+//! tree, where the edges in deep loops weigh the most, and only counts the edges outside of it.
+//!
+//! To make the flow balanced, a virtual edge enters the entry block and a virtual edge leaves each
+//! EBB that returns or traps. These edges meet in a virtual root node. The edge entering the
+//! function is always part of the tree, while the edges leaving it are the cheapest to count, right
+//! before the final instruction of their EBB. Exits through conditional traps are not counted, so
+//! the counts of a run that trapped are inexact.
+//!
+//! The counters are 64-bit integers stored in a data object supplied by the caller. Once the
+//! instrumented code has run, `EdgeProfile::set_ebb_frequencies` turns the counters into
+//! `EbbFrequency::Count` hints for a later compilation of the same function.
+
+use block_placement::{LOOP_WEIGHT, MAX_LOOP_DEPTH};
+use cursor::{Cursor, FuncCursor};
+use dominator_tree::DominatorTree;
+use entity::{EntityRef, SecondaryMap};
+use flowgraph::{BasicBlock, ControlFlowGraph};
+use ir::immediates::Imm64;
+use ir::instructions::BranchInfo;
+use ir::{
+    types, Ebb, EbbFrequency, ExternalName, Function, GlobalValue, GlobalValueData, Inst,
+    InstBuilder, InstructionData, MemFlags, Type,
+};
+use isa::TargetIsa;
+use loop_analysis::LoopAnalysis;
+use std::cmp;
+use std::vec::Vec;
+use timing;
+
+/// The size in bytes of each counter in the data object.
+pub const COUNTER_SIZE: usize = 8;
+
+/// An edge of the control flow graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edge {
+    /// The EBB containing the branch.
+    pub from: Ebb,
+    /// The branch or jump instruction.
+    pub branch: Inst,
+    /// The destination of the branch.
+    pub to: Ebb,
+}
+
+/// A node of the balanced flow graph.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Node {
+    /// The virtual node where the function is entered and left.
+    Root,
+    /// An EBB of the function.
+    Ebb(Ebb),
+}
+
+impl Node {
+    /// Get a dense index for this node.
+    fn index(self) -> usize {
+        match self {
+            Node::Root => 0,
+            Node::Ebb(ebb) => ebb.index() + 1,
+        }
+    }
+}
+
+/// What an edge of the balanced flow graph represents.
+#[derive(Clone, Copy)]
+enum EdgeKind {
+    /// Entering the function.
+    Entry,
+    /// Leaving the function through the final instruction of an EBB.
+    Exit,
+    /// The CFG edge `edges[n]`.
+    Cfg(usize),
+}
+
+/// An edge of the balanced flow graph.
+struct FlowEdge {
+    from: Node,
+    to: Node,
+    kind: EdgeKind,
+    /// The counter measuring this edge, or `None` for the edges of the spanning tree.
+    counter: Option<usize>,
+}
+
+/// The placement of the edge counters in a function.
+///
+/// The profile is computed from the function before instrumentation. A later compilation of the
+/// same function computes the same profile, so it can interpret the collected counters.
+pub struct EdgeProfile {
+    edges: Vec<Edge>,
+    flow: Vec<FlowEdge>,
+    num_counters: usize,
+}
+
+impl EdgeProfile {
+    /// Choose the edges of `func` to count.
+    pub fn new(
+        func: &Function,
+        cfg: &ControlFlowGraph,
+        domtree: &DominatorTree,
+        loop_analysis: &LoopAnalysis,
+    ) -> Self {
+        debug_assert!(cfg.is_valid());
+        debug_assert!(domtree.is_valid());
+        debug_assert!(loop_analysis.is_valid());
+
+        let mut profile = Self {
+            edges: Vec::new(),
+            flow: Vec::new(),
+            num_counters: 0,
+        };
+        let mut weights = Vec::new();
+
+        if let Some(entry) = func.layout.entry_block() {
+            profile.add_flow(Node::Root, Node::Ebb(entry), EdgeKind::Entry);
+            weights.push(u64::max_value());
+        }
+        for ebb in func.layout.ebbs() {
+            if domtree.is_reachable(ebb) && leaves_function(func, ebb) {
+                profile.add_flow(Node::Ebb(ebb), Node::Root, EdgeKind::Exit);
+                weights.push(0);
+            }
+        }
+        for to in func.layout.ebbs() {
+            for BasicBlock { ebb: from, inst } in cfg.pred_iter(to) {
+                if !domtree.is_reachable(from) {
+                    continue;
+                }
+                let depth = cmp::min(
+                    cmp::min(loop_analysis.loop_depth(from), loop_analysis.loop_depth(to)),
+                    MAX_LOOP_DEPTH,
+                );
+                let index = profile.edges.len();
+                profile.edges.push(Edge {
+                    from,
+                    branch: inst,
+                    to,
+                });
+                profile.add_flow(Node::Ebb(from), Node::Ebb(to), EdgeKind::Cfg(index));
+                weights.push(LOOP_WEIGHT.pow(depth as u32));
+            }
+        }
+
+        // On equal weights, prefer counting the edges that don't need to be split.
+        let (in_degree, out_degree) = profile.degrees();
+        for (edge, weight) in profile.flow.iter().zip(weights.iter_mut()) {
+            if let (Node::Ebb(from), Node::Ebb(to)) = (edge.from, edge.to) {
+                *weight = *weight * 2 + (out_degree[from] > 1 && in_degree[to] > 1) as u64;
+            }
+        }
+
+        // Build the maximum spanning tree with Kruskal's algorithm. The entry edge is the heaviest,
+        // so it is always part of the tree.
+        let mut order: Vec<usize> = (0..profile.flow.len()).collect();
+        order.sort_by(|&a, &b| weights[b].cmp(&weights[a]));
+        let mut sets: Vec<usize> = (0..func.dfg.num_ebbs() + 1).collect();
+        for index in order {
+            let from = find(&mut sets, profile.flow[index].from.index());
+            let to = find(&mut sets, profile.flow[index].to.index());
+            if from == to {
+                profile.flow[index].counter = Some(profile.num_counters);
+                profile.num_counters += 1;
+            } else {
+                sets[from] = to;
+            }
+        }
+
+        profile
+    }
+
+    fn add_flow(&mut self, from: Node, to: Node, kind: EdgeKind) {
+        self.flow.push(FlowEdge {
+            from,
+            to,
+            kind,
+            counter: None,
+        });
+    }
+
+    /// Count the edges of the balanced flow graph entering and leaving each EBB.
+    fn degrees(&self) -> (SecondaryMap<Ebb, usize>, SecondaryMap<Ebb, usize>) {
+        let mut in_degree = SecondaryMap::with_default(0);
+        let mut out_degree = SecondaryMap::with_default(0);
+        for edge in &self.flow {
+            if let Node::Ebb(ebb) = edge.from {
+                out_degree[ebb] += 1;
+            }
+            if let Node::Ebb(ebb) = edge.to {
+                in_degree[ebb] += 1;
+            }
+        }
+        (in_degree, out_degree)
+    }
+
+    /// Get the number of counters needed in the data object.
+    pub fn num_counters(&self) -> usize {
+        self.num_counters
+    }
+
+    /// Get the edges of the control flow graph, in the order used by `edge_counts`.
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    /// Insert the counter increments into `func`, which must be the function the profile was
+    /// computed from.
+    ///
+    /// The counters are stored in the data object named `counters`. It must hold `num_counters()`
+    /// zero-initialized 64-bit integers, each `COUNTER_SIZE` bytes apart.
+    pub fn instrument(&self, func: &mut Function, counters: ExternalName, isa: &TargetIsa) {
+        let _tt = timing::edge_instrumentation();
+        let base = func.create_global_value(GlobalValueData::Symbol {
+            name: counters,
+            offset: Imm64::new(0),
+            colocated: false,
+        });
+        let addr_type = isa.pointer_type();
+
+        // The degrees are counted before the CFG is modified, since the splits below only replace
+        // edges that are counted on their own.
+        let (in_degree, out_degree) = self.degrees();
+
+        for edge in &self.flow {
+            let counter = match edge.counter {
+                Some(counter) => counter,
+                None => continue,
+            };
+            let (from, to) = match (edge.from, edge.to) {
+                (Node::Ebb(from), Node::Ebb(to)) => (from, to),
+                (Node::Ebb(from), Node::Root) => {
+                    let inst = func.layout.last_inst(from).unwrap();
+                    let mut pos = FuncCursor::new(func).at_inst(inst);
+                    increment(&mut pos, base, addr_type, counter);
+                    continue;
+                }
+                _ => panic!("the entry edge is always in the spanning tree"),
+            };
+            let branch = match edge.kind {
+                EdgeKind::Cfg(index) => self.edges[index].branch,
+                _ => unreachable!(),
+            };
+
+            if in_degree[to] == 1 {
+                let mut pos = FuncCursor::new(func).at_first_insertion_point(to);
+                increment(&mut pos, base, addr_type, counter);
+            } else if out_degree[from] == 1 && !reads_flags(func, branch) {
+                let mut pos = FuncCursor::new(func).at_inst(branch);
+                increment(&mut pos, base, addr_type, counter);
+            } else {
+                let split = split_edge(func, branch, to);
+                let mut pos = FuncCursor::new(func).at_first_insertion_point(split);
+                increment(&mut pos, base, addr_type, counter);
+            }
+        }
+    }
+
+    /// Compute the execution count of every edge in `edges()` from the collected `counters`.
+    pub fn edge_counts(&self, counters: &[u64]) -> Vec<u64> {
+        let flow = self.solve(counters);
+        let mut counts = vec![0; self.edges.len()];
+        for (edge, &count) in self.flow.iter().zip(&flow) {
+            if let EdgeKind::Cfg(index) = edge.kind {
+                counts[index] = count;
+            }
+        }
+        counts
+    }
+
+    /// Compute the execution count of every reachable EBB from the collected `counters`.
+    pub fn ebb_counts(&self, counters: &[u64]) -> SecondaryMap<Ebb, u64> {
+        let flow = self.solve(counters);
+        let mut counts = SecondaryMap::new();
+        for (edge, &count) in self.flow.iter().zip(&flow) {
+            if let Node::Ebb(ebb) = edge.to {
+                counts[ebb] += count;
+            }
+        }
+        counts
+    }
+
+    /// Set the frequency hints of the reachable EBBs in `func` to the counts computed from the
+    /// collected `counters`.
+    pub fn set_ebb_frequencies(&self, func: &mut Function, counters: &[u64]) {
+        let counts = self.ebb_counts(counters);
+        for edge in &self.flow {
+            if let Node::Ebb(ebb) = edge.to {
+                func.ebb_frequencies[ebb] = EbbFrequency::Count(counts[ebb]);
+            }
+        }
+    }
+
+    /// Compute the count of every edge of the balanced flow graph.
+    fn solve(&self, counters: &[u64]) -> Vec<u64> {
+        assert_eq!(
+            counters.len(),
+            self.num_counters,
+            "wrong number of counters"
+        );
+        let mut counts: Vec<Option<u64>> = self
+            .flow
+            .iter()
+            .map(|edge| edge.counter.map(|counter| counters[counter]))
+            .collect();
+
+        // The edges incident to each node.
+        let mut incident: Vec<Vec<usize>> = Vec::new();
+        for (index, edge) in self.flow.iter().enumerate() {
+            for node in &[edge.from, edge.to] {
+                if incident.len() <= node.index() {
+                    incident.resize(node.index() + 1, Vec::new());
+                }
+                incident[node.index()].push(index);
+            }
+        }
+
+        // The counts entering and leaving a node are equal, so a node with a single unknown edge
+        // determines it. Removing the leaves of the spanning tree one by one solves all of them.
+        let mut progress = true;
+        while progress {
+            progress = false;
+            for (node, edges) in incident.iter().enumerate() {
+                let mut unknown = None;
+                let mut num_unknown = 0;
+                let mut entering = 0u64;
+                let mut leaving = 0u64;
+                for &index in edges {
+                    let edge = &self.flow[index];
+                    match counts[index] {
+                        Some(count) => {
+                            if edge.to.index() == node {
+                                entering = entering.saturating_add(count);
+                            }
+                            if edge.from.index() == node {
+                                leaving = leaving.saturating_add(count);
+                            }
+                        }
+                        None => {
+                            unknown = Some(index);
+                            num_unknown += 1;
+                        }
+                    }
+                }
+                if num_unknown != 1 {
+                    continue;
+                }
+                let index = unknown.unwrap();
+                counts[index] = Some(if self.flow[index].to.index() == node {
+                    leaving.saturating_sub(entering)
+                } else {
+                    entering.saturating_sub(leaving)
+                });
+                progress = true;
+            }
+        }
+
+        counts
+            .into_iter()
+            .map(|count| count.expect("the spanning tree is solved"))
+            .collect()
+    }
+}
+
+/// Find the representative of `set`, compressing the path to it.
+fn find(sets: &mut [usize], set: usize) -> usize {
+    let mut root = set;
+    while sets[root] != root {
+        root = sets[root];
+    }
+    let mut set = set;
+    while sets[set] != root {
+        let next = sets[set];
+        sets[set] = root;
+        set = next;
+    }
+    root
+}
+
+/// Does control leave the function at the end of `ebb`?
+fn leaves_function(func: &Function, ebb: Ebb) -> bool {
+    match func.layout.last_inst(ebb) {
+        Some(inst) => match func.dfg.analyze_branch(inst) {
+            BranchInfo::NotABranch => func.dfg[inst].opcode().is_terminator(),
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+/// Does `inst` read CPU flags, which the counter increment could clobber?
+fn reads_flags(func: &Function, inst: Inst) -> bool {
+    func.dfg
+        .inst_args(inst)
+        .iter()
+        .any(|&arg| func.dfg.value_type(arg).is_flags())
+}
+
+/// Redirect the edge from `branch` to `to` through a new EBB, which is returned.
+fn split_edge(func: &mut Function, branch: Inst, to: Ebb) -> Ebb {
+    let split = func.dfg.make_ebb();
+    func.layout.insert_ebb(split, to);
+
+    match func.dfg.analyze_branch(branch) {
+        BranchInfo::SingleDest(_, _) => {
+            for i in 0..func.dfg.num_ebb_params(to) {
+                let param = func.dfg.ebb_params(to)[i];
+                let ty = func.dfg.value_type(param);
+                func.dfg.append_ebb_param(split, ty);
+            }
+            *func.dfg[branch].branch_destination_mut().unwrap() = split;
+        }
+        BranchInfo::Table(jt, _) => {
+            // The jump table may be shared with other branches, so redirect a copy of it.
+            let mut data = func.jump_tables[jt].clone();
+            for dest in data.iter_mut() {
+                if *dest == to {
+                    *dest = split;
+                }
+            }
+            let new_jt = func.create_jump_table(data);
+            match func.dfg[branch] {
+                InstructionData::BranchTable {
+                    ref mut table,
+                    ref mut destination,
+                    ..
+                } => {
+                    *table = new_jt;
+                    if *destination == to {
+                        *destination = split;
+                    }
+                }
+                InstructionData::IndirectJump { ref mut table, .. } => *table = new_jt,
+                _ => unreachable!(),
+            }
+        }
+        BranchInfo::NotABranch => panic!("{} is not a branch", branch),
+    }
+
+    let args = func.dfg.ebb_params(split).to_vec();
+    let mut pos = FuncCursor::new(func).at_bottom(split);
+    pos.ins().jump(to, &args);
+    split
+}
+
+/// Increment the counter number `counter` in the data object at `base`.
+fn increment(pos: &mut FuncCursor, base: GlobalValue, addr_type: Type, counter: usize) {
+    let mut flags = MemFlags::new();
+    flags.set_notrap();
+    flags.set_aligned();
+    let offset = (counter * COUNTER_SIZE) as i32;
+    let addr = pos.ins().global_value(addr_type, base);
+    if addr_type.bits() >= 64 {
+        let count = pos.ins().load(types::I64, flags, addr, offset);
+        let count = pos.ins().iadd_imm(count, 1);
+        pos.ins().store(flags, count, addr, offset);
+        return;
+    }
+
+    // Without 64-bit loads, add the halves of the counter with a carry. The low half comes first,
+    // as on the little-endian 32-bit targets.
+    let low = pos.ins().load(types::I32, flags, addr, offset);
+    let high = pos.ins().load(types::I32, flags, addr, offset + 4);
+    let one = pos.ins().iconst(types::I32, 1);
+    let (low, carry) = pos.ins().iadd_cout(low, one);
+    let zero = pos.ins().iconst(types::I32, 0);
+    let high = pos.ins().iadd_cin(high, zero, carry);
+    pos.ins().store(flags, low, addr, offset);
+    pos.ins().store(flags, high, addr, offset + 4);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EdgeProfile;
+    use block_placement::{LOOP_WEIGHT, MAX_LOOP_DEPTH};
+use cursor::{Cursor, FuncCursor};
+    use dominator_tree::DominatorTree;
+    use entity::EntityRef;
+    use flowgraph::ControlFlowGraph;
+    use ir::{types, EbbFrequency, Function, InstBuilder};
+    use loop_analysis::LoopAnalysis;
+
+    #[test]
+    fn loop_with_diamond() {
+        let mut func = Function::new();
+        let ebb0 = func.dfg.make_ebb();
+        let ebb1 = func.dfg.make_ebb();
+        let ebb2 = func.dfg.make_ebb();
+        let ebb3 = func.dfg.make_ebb();
+        let ebb4 = func.dfg.make_ebb();
+        let ebb5 = func.dfg.make_ebb();
+        let cond = func.dfg.append_ebb_param(ebb0, types::I32);
+
+        {
+            let mut cur = FuncCursor::new(&mut func);
+            cur.insert_ebb(ebb0);
+            cur.ins().jump(ebb1, &[]);
+            cur.insert_ebb(ebb1);
+            cur.ins().brz(cond, ebb3, &[]);
+            cur.ins().jump(ebb2, &[]);
+            cur.insert_ebb(ebb2);
+            cur.ins().jump(ebb4, &[]);
+            cur.insert_ebb(ebb3);
+            cur.ins().jump(ebb4, &[]);
+            cur.insert_ebb(ebb4);
+            cur.ins().brnz(cond, ebb1, &[]);
+            cur.ins().jump(ebb5, &[]);
+            cur.insert_ebb(ebb5);
+            cur.ins().return_(&[]);
+        }
+
+        let cfg = ControlFlowGraph::with_function(&func);
+        let domtree = DominatorTree::with_function(&func, &cfg);
+        let mut loop_analysis = LoopAnalysis::new();
+        loop_analysis.compute(&func, &cfg, &domtree);
+        let profile = EdgeProfile::new(&func, &cfg, &domtree, &loop_analysis);
+
+        // 7 edges and 6 EBBs, plus the virtual entry and exit edges and the root node.
+        assert_eq!(profile.edges().len(), 7);
+        assert_eq!(profile.num_counters(), 9 - 7 + 1);
+
+        // One call, 10 iterations, 3 of them through ebb3.
+        let mut counters = vec![0; profile.num_counters()];
+        let expected = |from, to| match (from, to) {
+            (0, 1) => 1,
+            (1, 2) => 7,
+            (1, 3) => 3,
+            (2, 4) => 7,
+            (3, 4) => 3,
+            (4, 1) => 9,
+            (4, 5) => 1,
+            _ => panic!(),
+        };
+        for edge in &profile.flow {
+            if let Some(counter) = edge.counter {
+                counters[counter] = match (edge.from, edge.to) {
+                    (super::Node::Ebb(from), super::Node::Ebb(to)) => {
+                        expected(from.index(), to.index())
+                    }
+                    _ => 1,
+                };
+            }
+        }
+
+        let edge_counts = profile.edge_counts(&counters);
+        for (edge, &count) in profile.edges().iter().zip(&edge_counts) {
+            assert_eq!(count, expected(edge.from.index(), edge.to.index()));
+        }
+
+        profile.set_ebb_frequencies(&mut func, &counters);
+        assert_eq!(func.ebb_frequencies[ebb0], EbbFrequency::Count(1));
+        assert_eq!(func.ebb_frequencies[ebb1], EbbFrequency::Count(10));
+        assert_eq!(func.ebb_frequencies[ebb2], EbbFrequency::Count(7));
+        assert_eq!(func.ebb_frequencies[ebb3], EbbFrequency::Count(3));
+        assert_eq!(func.ebb_frequencies[ebb4], EbbFrequency::Count(10));
+        assert_eq!(func.ebb_frequencies[ebb5], EbbFrequency::Count(1));
+    }
+}
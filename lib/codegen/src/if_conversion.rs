@@ -0,0 +1,209 @@
+//! If-conversion.
+//!
+//! A conditional branch whose two paths meet again after a few cheap instructions is replaced by
+//! `select` instructions choosing the arguments passed to the EBB where the paths meet:
+//!
+//! ```cranelift
+//!     ebb0(v0: i32, v1: i32):
+//!         brz v0, ebb2(v1)
+//!         jump ebb1
+//!
+//!     ebb1:
+//!         v2 = iadd_imm v1, 1
+//!         jump ebb2(v2)
+//!
+//!     ebb2(v3: i32):
+//! ```
+//!
+//! becomes:
+//!
+//! ```cranelift
+//!     ebb0(v0: i32, v1: i32):
+//!         v2 = iadd_imm v1, 1
+//!         v4 = select v0, v2, v1
+//!         jump ebb2(v4)
+//!
+//!     ebb2(v3: i32):
+//! ```
+//!
+//! The instructions of the arms are executed on both paths, so they must be free of side effects
+//! and unable to trap.
+
+use cursor::{Cursor, FuncCursor};
+use dominator_tree::DominatorTree;
+use flowgraph::ControlFlowGraph;
+use ir::instructions::BranchInfo;
+use ir::{Ebb, Function, Inst, InstBuilder, Opcode, Value};
+use std::vec::Vec;
+use timing;
+
+/// The maximum number of instructions in each arm, not counting its final jump.
+const MAX_ARM_SIZE: usize = 4;
+
+/// Convert small diamonds and triangles in the CFG of `func` to `select` instructions.
+pub fn do_if_conversion(
+    func: &mut Function,
+    cfg: &mut ControlFlowGraph,
+    domtree: &mut DominatorTree,
+) {
+    let _tt = timing::if_conversion();
+    debug_assert!(cfg.is_valid());
+
+    let mut changed = false;
+    loop {
+        let mut converted = false;
+        let ebbs: Vec<Ebb> = func.layout.ebbs().collect();
+        for ebb in ebbs {
+            if func.layout.is_ebb_inserted(ebb) && convert(func, cfg, ebb) {
+                cfg.compute(func);
+                converted = true;
+            }
+        }
+        if !converted {
+            break;
+        }
+        changed = true;
+    }
+
+    if changed {
+        domtree.compute(func, cfg);
+    }
+}
+
+/// A path from a conditional branch to the EBB where the paths meet.
+struct Path {
+    /// The EBB executed on the path only, if any.
+    arm: Option<Ebb>,
+    /// The EBB where the paths meet.
+    join: Ebb,
+    /// The arguments passed to `join`.
+    args: Vec<Value>,
+}
+
+/// Follow the branch from `head` to `dest` passing `args`.
+fn path(func: &Function, cfg: &ControlFlowGraph, head: Ebb, dest: Ebb, args: &[Value]) -> Path {
+    let direct = Path {
+        arm: None,
+        join: dest,
+        args: args.to_vec(),
+    };
+    if dest == head || !func.dfg.ebb_params(dest).is_empty() || cfg.pred_iter(dest).count() != 1 {
+        return direct;
+    }
+
+    let jump = match func.layout.last_inst(dest) {
+        Some(jump) => jump,
+        None => return direct,
+    };
+    let mut size = 0;
+    for inst in func.layout.ebb_insts(dest) {
+        if inst == jump {
+            break;
+        }
+        size += 1;
+        if size > MAX_ARM_SIZE || !is_cheap(func, inst) {
+            return direct;
+        }
+    }
+    match func.dfg.analyze_branch(jump) {
+        BranchInfo::SingleDest(join, args)
+            if func.dfg[jump].opcode() == Opcode::Jump && join != dest =>
+        {
+            Path {
+                arm: Some(dest),
+                join,
+                args: args.to_vec(),
+            }
+        }
+        _ => direct,
+    }
+}
+
+/// Test if `inst` can be executed on both paths at little cost.
+fn is_cheap(func: &Function, inst: Inst) -> bool {
+    let opcode = func.dfg[inst].opcode();
+    !(opcode.is_branch()
+        || opcode.is_terminator()
+        || opcode.is_call()
+        || opcode.can_load()
+        || opcode.can_store()
+        || opcode.can_trap()
+        || opcode.other_side_effects()
+        || opcode.writes_cpu_flags()
+        // These aren't marked as trapping, but they are legalized into bounds checks that trap.
+        || opcode == Opcode::HeapAddr
+        || opcode == Opcode::TableAddr)
+        && func
+            .dfg
+            .inst_results(inst)
+            .iter()
+            .all(|&result| !func.dfg.value_type(result).is_flags())
+}
+
+/// Convert the conditional branch at the end of `head`, if its paths form a diamond or a triangle.
+fn convert(func: &mut Function, cfg: &ControlFlowGraph, head: Ebb) -> bool {
+    // The EBB must end with a `brz` or `brnz` followed by a `jump`.
+    let jump = match func.layout.last_inst(head) {
+        Some(jump) if func.dfg[jump].opcode() == Opcode::Jump => jump,
+        _ => return false,
+    };
+    let branch = match func.layout.prev_inst(jump) {
+        Some(branch) => branch,
+        None => return false,
+    };
+    let branch_opcode = func.dfg[branch].opcode();
+    if branch_opcode != Opcode::Brz && branch_opcode != Opcode::Brnz {
+        return false;
+    }
+
+    let taken = match func.dfg.analyze_branch(branch) {
+        BranchInfo::SingleDest(dest, args) => path(func, cfg, head, dest, args),
+        _ => return false,
+    };
+    let not_taken = match func.dfg.analyze_branch(jump) {
+        BranchInfo::SingleDest(dest, args) => path(func, cfg, head, dest, args),
+        _ => return false,
+    };
+    if taken.join != not_taken.join || (taken.arm.is_some() && taken.arm == not_taken.arm) {
+        return false;
+    }
+    for (&x, &y) in taken.args.iter().zip(&not_taken.args) {
+        if x != y && func.dfg.value_type(x).is_flags() {
+            return false;
+        }
+    }
+
+    // Execute the arms unconditionally.
+    for arm in not_taken.arm.iter().chain(taken.arm.iter()) {
+        while let Some(inst) = func.layout.first_inst(*arm) {
+            func.layout.remove_inst(inst);
+            if func.dfg[inst].opcode() == Opcode::Jump {
+                break;
+            }
+            func.layout.insert_inst(inst, branch);
+        }
+        func.layout.remove_ebb(*arm);
+    }
+
+    // Select the arguments of the join.
+    let ctrl = func.dfg.inst_args(branch)[0];
+    let (if_nonzero, if_zero) = if branch_opcode == Opcode::Brnz {
+        (&taken.args, &not_taken.args)
+    } else {
+        (&not_taken.args, &taken.args)
+    };
+    let srcloc = func.srclocs[branch];
+    let mut pos = FuncCursor::new(func).at_inst(branch);
+    pos.set_srcloc(srcloc);
+    let mut args = Vec::with_capacity(if_nonzero.len());
+    for (&x, &y) in if_nonzero.iter().zip(if_zero) {
+        if x == y {
+            args.push(x);
+        } else {
+            args.push(pos.ins().select(ctrl, x, y));
+        }
+    }
+    pos.remove_inst();
+    pos.func.dfg.replace(jump).jump(taken.join, &args);
+    true
+}
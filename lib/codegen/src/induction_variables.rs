@@ -0,0 +1,526 @@
+//! Induction variable analysis.
+//!
+//! A basic induction variable is a parameter of a loop header that every back edge passes
+//! incremented by the same constant. A derived induction variable is a value computed in the loop
+//! as a linear function of a basic induction variable, such as the `base + i * 8` address of an
+//! array element.
+
+use dominator_tree::DominatorTree;
+use entity::SecondaryMap;
+use flowgraph::{BasicBlock, ControlFlowGraph};
+use ir::condcodes::{CondCode, IntCC};
+use ir::instructions::BranchInfo;
+use ir::{Ebb, Function, Inst, InstructionData, Opcode, Value, ValueDef};
+use loop_analysis::{Loop, LoopAnalysis};
+use std::vec::Vec;
+use timing;
+
+/// A basic induction variable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BasicInductionVariable {
+    /// The loop whose header has the induction variable as a parameter.
+    pub lp: Loop,
+    /// The header parameter.
+    pub param: Value,
+    /// The constant added to the induction variable on each iteration.
+    pub step: i64,
+}
+
+/// A linear function of a basic induction variable: `scale * iv + offset + base`.
+///
+/// The arithmetic wraps around, like the instructions computing the function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LinearFunction {
+    /// The basic induction variable.
+    pub iv: Value,
+    /// The constant the induction variable is multiplied by.
+    pub scale: i64,
+    /// The constant added to the product.
+    pub offset: i64,
+    /// A loop-invariant value added to the product, if any.
+    pub base: Option<Value>,
+}
+
+impl LinearFunction {
+    fn add_constant(self, c: i64) -> Self {
+        Self {
+            offset: self.offset.wrapping_add(c),
+            ..self
+        }
+    }
+
+    fn multiply(self, c: i64) -> Option<Self> {
+        if self.base.is_some() {
+            return None;
+        }
+        Some(Self {
+            scale: self.scale.wrapping_mul(c),
+            offset: self.offset.wrapping_mul(c),
+            ..self
+        })
+    }
+}
+
+/// The test deciding when a loop exits, comparing a basic induction variable plus a constant with
+/// a constant.
+#[derive(Clone, Copy, Debug)]
+pub struct ExitTest {
+    /// The comparison instruction.
+    pub test: Inst,
+    /// The `brz` or `brnz` instruction using the result of the comparison.
+    pub branch: Inst,
+    /// Whether the loop exits when the comparison holds, rather than when it fails.
+    pub exits_when: bool,
+    /// The condition of the comparison.
+    pub cond: IntCC,
+    /// The constant added to the induction variable.
+    pub offset: i64,
+    /// The constant the sum is compared with.
+    pub bound: i64,
+}
+
+impl ExitTest {
+    /// Get the number of iterations before the loop exits through this test, when the
+    /// `bits`-bit induction variable starts at `init` and is incremented by `step`.
+    ///
+    /// The loop exits during the iteration numbered by the result, counting from 0. Returns
+    /// `None` if the count isn't known, or if the compared value wraps around before that.
+    pub fn trip_count(&self, bits: u16, init: i64, step: i64) -> Option<u64> {
+        let continues = if self.exits_when {
+            self.cond.inverse()
+        } else {
+            self.cond
+        };
+        trip_count(
+            continues,
+            bits,
+            init.wrapping_add(self.offset),
+            step,
+            self.bound,
+        )
+    }
+}
+
+/// Induction variables of all the loops in a function.
+pub struct InductionVariables {
+    basic: Vec<BasicInductionVariable>,
+    functions: SecondaryMap<Value, Option<LinearFunction>>,
+    valid: bool,
+}
+
+impl InductionVariables {
+    /// Allocate a new blank induction variable analysis. Use `compute` to compute it for a
+    /// function.
+    pub fn new() -> Self {
+        Self {
+            basic: Vec::new(),
+            functions: SecondaryMap::new(),
+            valid: false,
+        }
+    }
+
+    /// Compute the induction variables of `func`.
+    pub fn compute(
+        &mut self,
+        func: &Function,
+        cfg: &ControlFlowGraph,
+        domtree: &DominatorTree,
+        loop_analysis: &LoopAnalysis,
+    ) {
+        let _tt = timing::induction_variables();
+        self.clear();
+        for lp in loop_analysis.loops() {
+            self.find_basic(func, cfg, loop_analysis, lp);
+        }
+        if !self.basic.is_empty() {
+            // Visiting the EBBs in reverse post-order sees the definitions before their uses.
+            for &ebb in domtree.cfg_postorder().iter().rev() {
+                for inst in func.layout.ebb_insts(ebb) {
+                    if let Some(function) = self.derive(func, loop_analysis, inst) {
+                        self.functions[func.dfg.first_result(inst)] = Some(function);
+                    }
+                }
+            }
+        }
+        self.valid = true;
+    }
+
+    /// Clear all the data structures contained in the analysis.
+    pub fn clear(&mut self) {
+        self.basic.clear();
+        self.functions.clear();
+        self.valid = false;
+    }
+
+    /// Check if the analysis is in a valid state.
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+
+    /// Get all the basic induction variables.
+    pub fn basic_induction_variables(&self) -> &[BasicInductionVariable] {
+        &self.basic
+    }
+
+    /// Get the basic induction variable `param`, if it is one.
+    pub fn basic_induction_variable(&self, param: Value) -> Option<&BasicInductionVariable> {
+        self.basic.iter().find(|basic| basic.param == param)
+    }
+
+    /// Get `value` as a linear function of a basic induction variable, if it is one. A basic
+    /// induction variable is a linear function of itself.
+    pub fn linear_function(&self, value: Value) -> Option<LinearFunction> {
+        self.functions[value]
+    }
+
+    /// Find the exit test of the loop of the basic induction variable `iv`. It must be the only
+    /// comparison of `iv` plus a constant with a constant in the loop, and its result must only be
+    /// used by a `brz` or `brnz` instruction that is executed on every iteration. The loop must
+    /// exit either when the branch is taken, or right after it.
+    pub fn exit_test(
+        &self,
+        func: &Function,
+        cfg: &ControlFlowGraph,
+        domtree: &DominatorTree,
+        loop_analysis: &LoopAnalysis,
+        iv: Value,
+    ) -> Option<ExitTest> {
+        let dfg = &func.dfg;
+        let lp = self.basic_induction_variable(iv)?.lp;
+        let header = loop_analysis.loop_header(lp);
+        let ebbs: Vec<Ebb> = func
+            .layout
+            .ebbs()
+            .filter(|&ebb| loop_analysis.is_in_loop(ebb, lp))
+            .collect();
+        let offset = |value: Value| {
+            let function = self.linear_function(dfg.resolve_aliases(value))?;
+            if function.iv == iv && function.scale == 1 && function.base.is_none() {
+                Some(function.offset)
+            } else {
+                None
+            }
+        };
+
+        let mut found = None;
+        for &ebb in &ebbs {
+            for inst in func.layout.ebb_insts(ebb) {
+                let test = match dfg[inst] {
+                    InstructionData::IntCompare { cond, args, .. } => {
+                        match (offset(args[0]), constant(func, args[1])) {
+                            (Some(offset), Some(bound)) => Some((cond, offset, bound)),
+                            _ => match (constant(func, args[0]), offset(args[1])) {
+                                (Some(bound), Some(offset)) => {
+                                    Some((cond.reverse(), offset, bound))
+                                }
+                                _ => None,
+                            },
+                        }
+                    }
+                    InstructionData::IntCompareImm { cond, arg, imm, .. } => {
+                        offset(arg).map(|offset| (cond, offset, imm.into()))
+                    }
+                    _ => None,
+                };
+                if let Some((cond, offset, bound)) = test {
+                    if found.is_some() {
+                        // There are multiple tests.
+                        return None;
+                    }
+                    found = Some((inst, cond, offset, bound));
+                }
+            }
+        }
+        let (test, cond, offset, bound) = found?;
+
+        // Find the only use of the test.
+        let result = dfg.first_result(test);
+        let mut users = Vec::new();
+        for ebb in func.layout.ebbs() {
+            for inst in func.layout.ebb_insts(ebb) {
+                for &arg in dfg.inst_args(inst) {
+                    if dfg.resolve_aliases(arg) == result {
+                        users.push(inst);
+                    }
+                }
+            }
+        }
+        if users.len() != 1 {
+            return None;
+        }
+        let branch = users[0];
+        let exits_when_taken = match (dfg[branch].opcode(), dfg.analyze_branch(branch)) {
+            (Opcode::Brz, BranchInfo::SingleDest(dest, _))
+            | (Opcode::Brnz, BranchInfo::SingleDest(dest, _)) => {
+                !loop_analysis.is_in_loop(dest, lp)
+            }
+            _ => return None,
+        };
+        if !exits_when_taken {
+            // The loop must exit right after the branch instead.
+            let next = func.layout.next_inst(branch)?;
+            match dfg.analyze_branch(next) {
+                BranchInfo::SingleDest(dest, _)
+                    if dfg[next].opcode() == Opcode::Jump
+                        && !loop_analysis.is_in_loop(dest, lp) => {}
+                BranchInfo::NotABranch if dfg[next].opcode().is_terminator() => {}
+                _ => return None,
+            }
+        }
+
+        // The branch must be executed on every iteration, so it has to dominate every back edge.
+        // An earlier back edge in the same EBB skips the branch.
+        for BasicBlock { ebb, inst } in cfg.pred_iter(header) {
+            if loop_analysis.is_in_loop(ebb, lp) && !domtree.dominates(branch, inst, &func.layout) {
+                return None;
+            }
+        }
+
+        Some(ExitTest {
+            test,
+            branch,
+            exits_when: (dfg[branch].opcode() == Opcode::Brnz) == exits_when_taken,
+            cond,
+            offset,
+            bound,
+        })
+    }
+
+    /// Find the basic induction variables of `lp`.
+    fn find_basic(
+        &mut self,
+        func: &Function,
+        cfg: &ControlFlowGraph,
+        loop_analysis: &LoopAnalysis,
+        lp: Loop,
+    ) {
+        let dfg = &func.dfg;
+        let header = loop_analysis.loop_header(lp);
+        let latches: Vec<Inst> = cfg
+            .pred_iter(header)
+            .filter(|&BasicBlock { ebb, .. }| loop_analysis.is_in_loop(ebb, lp))
+            .map(|BasicBlock { inst, .. }| inst)
+            .collect();
+
+        for (index, &param) in dfg.ebb_params(header).iter().enumerate() {
+            let ty = dfg.value_type(param);
+            if !ty.is_int() || ty.bits() > 64 {
+                continue;
+            }
+            let mut step = None;
+            for &latch in &latches {
+                let arg = match dfg.analyze_branch(latch) {
+                    BranchInfo::SingleDest(_, args) => dfg.resolve_aliases(args[index]),
+                    _ => {
+                        step = None;
+                        break;
+                    }
+                };
+                let increment = increment(func, arg, param);
+                if increment.is_none() || (step.is_some() && step != increment) {
+                    step = None;
+                    break;
+                }
+                step = increment;
+            }
+            if let Some(step) = step {
+                if step != 0 {
+                    self.basic.push(BasicInductionVariable { lp, param, step });
+                    self.functions[param] = Some(LinearFunction {
+                        iv: param,
+                        scale: 1,
+                        offset: 0,
+                        base: None,
+                    });
+                }
+            }
+        }
+    }
+
+    /// Compute the result of `inst` as a linear function of a basic induction variable.
+    fn derive(
+        &self,
+        func: &Function,
+        loop_analysis: &LoopAnalysis,
+        inst: Inst,
+    ) -> Option<LinearFunction> {
+        let dfg = &func.dfg;
+        if dfg.inst_results(inst).len() != 1 {
+            return None;
+        }
+        let function = |value: Value| self.functions[dfg.resolve_aliases(value)];
+
+        // Add `value` to `function` if it is constant or invariant in the loop.
+        let add = |function: LinearFunction, value: Value| {
+            if let Some(c) = constant(func, value) {
+                return Some(function.add_constant(c));
+            }
+            let value = dfg.resolve_aliases(value);
+            let lp = self.basic_induction_variable(function.iv).unwrap().lp;
+            let ebb = match dfg.value_def(value) {
+                ValueDef::Result(inst, _) => func.layout.inst_ebb(inst)?,
+                ValueDef::Param(ebb, _) => ebb,
+            };
+            if function.base.is_some() || loop_analysis.is_in_loop(ebb, lp) {
+                return None;
+            }
+            Some(LinearFunction {
+                base: Some(value),
+                ..function
+            })
+        };
+
+        match dfg[inst] {
+            InstructionData::BinaryImm { opcode, arg, imm } => {
+                let x = function(arg)?;
+                let imm: i64 = imm.into();
+                match opcode {
+                    Opcode::IaddImm => Some(x.add_constant(imm)),
+                    Opcode::ImulImm => x.multiply(imm),
+                    Opcode::IshlImm => {
+                        let bits = dfg.value_type(arg).bits();
+                        x.multiply(1 << (imm as u64 % u64::from(bits)))
+                    }
+                    _ => None,
+                }
+            }
+            InstructionData::Binary { opcode, args } => match opcode {
+                Opcode::Iadd => match (function(args[0]), function(args[1])) {
+                    (Some(x), None) => add(x, args[1]),
+                    (None, Some(y)) => add(y, args[0]),
+                    _ => None,
+                },
+                Opcode::Isub => match (function(args[0]), constant(func, args[1])) {
+                    (Some(x), Some(c)) => Some(x.add_constant(c.wrapping_neg())),
+                    _ => None,
+                },
+                Opcode::Imul => match (function(args[0]), function(args[1])) {
+                    (Some(x), None) => x.multiply(constant(func, args[1])?),
+                    (None, Some(y)) => y.multiply(constant(func, args[0])?),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// If `value` is an integer constant, get it.
+pub fn constant(func: &Function, value: Value) -> Option<i64> {
+    match func.dfg.value_def(func.dfg.resolve_aliases(value)) {
+        ValueDef::Result(inst, _) => match func.dfg[inst] {
+            InstructionData::UnaryImm {
+                opcode: Opcode::Iconst,
+                imm,
+            } => Some(imm.into()),
+            _ => None,
+        },
+        ValueDef::Param(_, _) => None,
+    }
+}
+
+/// If `value` is `param` plus a constant, get the constant.
+fn increment(func: &Function, value: Value, param: Value) -> Option<i64> {
+    let dfg = &func.dfg;
+    let inst = match dfg.value_def(value) {
+        ValueDef::Result(inst, _) => inst,
+        ValueDef::Param(_, _) => return None,
+    };
+    match dfg[inst] {
+        InstructionData::BinaryImm {
+            opcode: Opcode::IaddImm,
+            arg,
+            imm,
+        } if dfg.resolve_aliases(arg) == param => Some(imm.into()),
+        InstructionData::Binary {
+            opcode: Opcode::Iadd,
+            args,
+        } => {
+            if dfg.resolve_aliases(args[0]) == param {
+                constant(func, args[1])
+            } else if dfg.resolve_aliases(args[1]) == param {
+                constant(func, args[0])
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// A mask of the low `bits` bits.
+pub fn mask(bits: u16) -> i64 {
+    if bits >= 64 {
+        -1
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+/// Count the number of times `cond(x, bound)` holds for the `bits`-bit integer `x`, starting at
+/// `start` and incremented by `step` each time, before it fails for the first time.
+///
+/// Returns `None` if the count isn't known, or if `x` wraps around before the condition fails.
+fn trip_count(cond: IntCC, bits: u16, start: i64, step: i64, bound: i64) -> Option<u64> {
+    let mask = mask(bits) as u64;
+    let sign = 1u64 << (bits - 1);
+    // Map the values so that the condition is an unsigned `<` or `<=` with a positive step.
+    let (signed, decreasing, inclusive) = match cond {
+        IntCC::UnsignedLessThan => (false, false, false),
+        IntCC::UnsignedLessThanOrEqual => (false, false, true),
+        IntCC::SignedLessThan => (true, false, false),
+        IntCC::SignedLessThanOrEqual => (true, false, true),
+        IntCC::UnsignedGreaterThan => (false, true, false),
+        IntCC::UnsignedGreaterThanOrEqual => (false, true, true),
+        IntCC::SignedGreaterThan => (true, true, false),
+        IntCC::SignedGreaterThanOrEqual => (true, true, true),
+        IntCC::NotEqual => {
+            // The condition fails when the distance to the bound is a multiple of the step.
+            let distance = (bound.wrapping_sub(start) as u64) & mask;
+            let step = step as u64 & mask;
+            return if step != 0 && distance % step == 0 {
+                Some(distance / step)
+            } else {
+                None
+            };
+        }
+        IntCC::Equal => return None,
+    };
+    let map = |x: i64| {
+        let mut x = x as u64 & mask;
+        if signed {
+            x ^= sign;
+        }
+        if decreasing {
+            x = !x & mask;
+        }
+        x
+    };
+    let (start, mut bound) = (map(start), map(bound));
+    let step = if decreasing {
+        step.wrapping_neg()
+    } else {
+        step
+    };
+    if step <= 0 || step as u64 > mask {
+        return None;
+    }
+    let step = step as u64;
+    if inclusive {
+        if bound == mask {
+            // The condition always holds.
+            return None;
+        }
+        bound += 1;
+    }
+    if start >= bound {
+        return Some(0);
+    }
+    let trips = (bound - start - 1) / step + 1;
+    // The value failing the condition must not wrap around.
+    match start.checked_add(trips.checked_mul(step)?) {
+        Some(last) if last <= mask => Some(trips),
+        _ => None,
+    }
+}
@@ -0,0 +1,613 @@
+//! Function inlining.
+//!
+//! `inline_call` replaces a `call` instruction with a copy of the body of the called function.
+//! Every entity in the callee that the copied instructions refer to is recreated in the caller:
+//! EBBs, values, stack slots, global values, heaps, tables, jump tables, signatures and external
+//! functions. The callee's `return` instructions become jumps to a new EBB holding the rest of
+//! the caller's EBB, whose parameters replace the results of the call.
+//!
+//! Deciding which calls are worth inlining is up to the user of this module. The
+//! `cranelift-module` crate has a driver that inlines small functions defined in the same module.
+
+use cursor::{Cursor, FuncCursor};
+use entity::SecondaryMap;
+use ir::instructions::BranchInfo;
+use ir::{
+    ArgumentPurpose, Ebb, EbbFrequency, FuncRef, Function, GlobalValue, GlobalValueData, Heap,
+    HeapStyle, Inst, InstructionData, JumpTable, JumpTableData, Opcode, SigRef, SourceLoc,
+    StackSlot, StackSlotKind, Table, Value, ValueList,
+};
+use packed_option::PackedOption;
+use std::vec::Vec;
+use timing;
+
+/// Check whether the `call` instruction in `caller` can be replaced by the body of `callee`.
+///
+/// This requires that:
+///
+/// - `call` is a direct call whose arguments and results match the signature of `callee`,
+/// - `callee` has a body that hasn't been through legalization yet, so all of its stack slots are
+///   explicit slots,
+/// - the entry block of `callee` isn't the destination of any branch, and
+/// - if `callee` uses its VM context, `call` passes the VM context of `caller` to it.
+///
+/// Note that this doesn't check whether `callee` is the function that `call` calls.
+pub fn can_inline(caller: &Function, call: Inst, callee: &Function) -> bool {
+    if caller.dfg[call].opcode() != Opcode::Call {
+        return false;
+    }
+    let entry = match callee.layout.entry_block() {
+        Some(ebb) => ebb,
+        None => return false,
+    };
+
+    let args = caller.dfg.inst_args(call);
+    let results = caller.dfg.inst_results(call);
+    let sig = &callee.signature;
+    if args.len() != sig.params.len()
+        || results.len() != sig.returns.len()
+        || args
+            .iter()
+            .zip(&sig.params)
+            .any(|(&arg, param)| caller.dfg.value_type(arg) != param.value_type)
+        || results
+            .iter()
+            .zip(&sig.returns)
+            .any(|(&result, ret)| caller.dfg.value_type(result) != ret.value_type)
+    {
+        return false;
+    }
+
+    if callee
+        .stack_slots
+        .values()
+        .any(|data| data.kind != StackSlotKind::ExplicitSlot)
+    {
+        return false;
+    }
+
+    for ebb in callee.layout.ebbs() {
+        for inst in callee.layout.ebb_insts(ebb) {
+            let branches_to_entry = match callee.dfg.analyze_branch(inst) {
+                BranchInfo::SingleDest(dest, _) => dest == entry,
+                BranchInfo::Table(jt, default) => {
+                    default == Some(entry) || callee.jump_tables[jt].branches_to(entry)
+                }
+                BranchInfo::NotABranch => false,
+            };
+            if branches_to_entry {
+                return false;
+            }
+        }
+    }
+
+    let uses_vmctx = callee.global_values.values().any(|data| match *data {
+        GlobalValueData::VMContext => true,
+        _ => false,
+    });
+    if uses_vmctx {
+        let callee_vmctx = sig.special_param_index(ArgumentPurpose::VMContext);
+        let caller_vmctx = caller.special_param(ArgumentPurpose::VMContext);
+        match (callee_vmctx, caller_vmctx) {
+            (Some(index), Some(vmctx)) if caller.dfg.resolve_aliases(args[index]) == vmctx => {}
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+/// Replace the `call` instruction in `caller` with the body of `callee`.
+///
+/// Returns `false`, leaving `caller` unchanged, if `can_inline` doesn't allow it.
+///
+/// The copied instructions keep the source locations they had in `callee`. Those that don't have
+/// one get the source location of `call`.
+pub fn inline_call(caller: &mut Function, call: Inst, callee: &Function) -> bool {
+    let _tt = timing::inline();
+    if !can_inline(caller, call, callee) {
+        return false;
+    }
+    let mut inliner = Inliner::new(caller, call, callee);
+    inliner.copy_entities();
+    inliner.copy_body();
+    true
+}
+
+/// The state of a single inlining operation, mapping the entities of the callee to the entities
+/// of the caller.
+struct Inliner<'a> {
+    caller: &'a mut Function,
+    callee: &'a Function,
+    call: Inst,
+    /// The EBB that follows the inlined body, or `None` if the callee consists of a single EBB
+    /// ending in a return, so the inlined body can be left in the caller's EBB.
+    continuation: Option<Ebb>,
+    ebbs: SecondaryMap<Ebb, PackedOption<Ebb>>,
+    values: SecondaryMap<Value, PackedOption<Value>>,
+    insts: Vec<Inst>,
+    stack_slots: SecondaryMap<StackSlot, PackedOption<StackSlot>>,
+    global_values: SecondaryMap<GlobalValue, PackedOption<GlobalValue>>,
+    heaps: SecondaryMap<Heap, PackedOption<Heap>>,
+    tables: SecondaryMap<Table, PackedOption<Table>>,
+    jump_tables: SecondaryMap<JumpTable, PackedOption<JumpTable>>,
+    signatures: SecondaryMap<SigRef, PackedOption<SigRef>>,
+    functions: SecondaryMap<FuncRef, PackedOption<FuncRef>>,
+}
+
+impl<'a> Inliner<'a> {
+    fn new(caller: &'a mut Function, call: Inst, callee: &'a Function) -> Self {
+        let single_ebb = callee.layout.ebbs().count() == 1
+            && callee
+                .layout
+                .last_inst(callee.layout.entry_block().unwrap())
+                .map_or(false, |inst| callee.dfg[inst].opcode() == Opcode::Return);
+        let continuation = if single_ebb {
+            None
+        } else {
+            Some(caller.dfg.make_ebb())
+        };
+        Self {
+            caller,
+            callee,
+            call,
+            continuation,
+            ebbs: SecondaryMap::new(),
+            values: SecondaryMap::new(),
+            insts: Vec::new(),
+            stack_slots: SecondaryMap::new(),
+            global_values: SecondaryMap::new(),
+            heaps: SecondaryMap::new(),
+            tables: SecondaryMap::new(),
+            jump_tables: SecondaryMap::new(),
+            signatures: SecondaryMap::new(),
+            functions: SecondaryMap::new(),
+        }
+    }
+
+    /// Recreate the entities declared in the callee's preamble in the caller.
+    fn copy_entities(&mut self) {
+        let callee = self.callee;
+
+        for (ss, data) in callee.stack_slots.iter() {
+            self.stack_slots[ss] = self.caller.create_stack_slot(data.clone()).into();
+        }
+
+        // Global values can refer to each other in any order, so create them all before mapping
+        // their bases.
+        for (gv, data) in callee.global_values.iter() {
+            let new_gv = match *data {
+                GlobalValueData::VMContext => self.caller_vmctx(),
+                _ => self.caller.create_global_value(data.clone()),
+            };
+            self.global_values[gv] = new_gv.into();
+        }
+        for (gv, data) in callee.global_values.iter() {
+            let new_gv = self.global_values[gv].unwrap();
+            match *data {
+                GlobalValueData::Load { base, .. } | GlobalValueData::IAddImm { base, .. } => {
+                    let new_base = self.global_values[base].unwrap();
+                    match self.caller.global_values[new_gv] {
+                        GlobalValueData::Load { ref mut base, .. }
+                        | GlobalValueData::IAddImm { ref mut base, .. } => *base = new_base,
+                        _ => unreachable!(),
+                    }
+                }
+                GlobalValueData::VMContext | GlobalValueData::Symbol { .. } => {}
+            }
+        }
+
+        for (heap, data) in callee.heaps.iter() {
+            let mut data = data.clone();
+            data.base = self.global_values[data.base].unwrap();
+            if let HeapStyle::Dynamic { ref mut bound_gv } = data.style {
+                *bound_gv = self.global_values[*bound_gv].unwrap();
+            }
+            self.heaps[heap] = self.caller.create_heap(data).into();
+        }
+
+        for (table, data) in callee.tables.iter() {
+            let mut data = data.clone();
+            data.base_gv = self.global_values[data.base_gv].unwrap();
+            data.bound_gv = self.global_values[data.bound_gv].unwrap();
+            self.tables[table] = self.caller.create_table(data).into();
+        }
+
+        for (sig, data) in callee.dfg.signatures.iter() {
+            self.signatures[sig] = self.caller.import_signature(data.clone()).into();
+        }
+
+        for (func, data) in callee.dfg.ext_funcs.iter() {
+            let mut data = data.clone();
+            data.signature = self.signatures[data.signature].unwrap();
+            self.functions[func] = self.caller.import_function(data).into();
+        }
+
+        // The EBBs must exist before the jump tables referring to them.
+        for ebb in callee.layout.ebbs() {
+            let new_ebb = if Some(ebb) == callee.layout.entry_block() {
+                self.caller.layout.inst_ebb(self.call).unwrap()
+            } else {
+                let new_ebb = self.caller.dfg.make_ebb();
+                for &param in callee.dfg.ebb_params(ebb) {
+                    let ty = callee.dfg.value_type(param);
+                    self.values[param] = self.caller.dfg.append_ebb_param(new_ebb, ty).into();
+                }
+                // Profile counts from the callee don't translate to the caller, but cold paths do.
+                if callee.ebb_frequencies[ebb].is_cold() {
+                    self.caller.ebb_frequencies[new_ebb] = EbbFrequency::Cold;
+                }
+                new_ebb
+            };
+            self.ebbs[ebb] = new_ebb.into();
+        }
+
+        for (jt, data) in callee.jump_tables.iter() {
+            let mut new_data = JumpTableData::with_capacity(data.len());
+            for &dest in data.iter() {
+                new_data.push_entry(self.ebbs[dest].unwrap());
+            }
+            self.jump_tables[jt] = self.caller.create_jump_table(new_data).into();
+        }
+    }
+
+    /// Find or create the global value for the caller's VM context.
+    fn caller_vmctx(&mut self) -> GlobalValue {
+        for (gv, data) in self.caller.global_values.iter() {
+            if let GlobalValueData::VMContext = *data {
+                return gv;
+            }
+        }
+        self.caller.create_global_value(GlobalValueData::VMContext)
+    }
+
+    /// Copy the instructions of the callee into the caller in place of the call.
+    fn copy_body(&mut self) {
+        let callee = self.callee;
+        let call = self.call;
+        let call_ebb = self.caller.layout.inst_ebb(call).unwrap();
+        let call_srcloc = self.caller.srclocs[call];
+
+        // The entry block parameters are the call arguments.
+        let entry = callee.layout.entry_block().unwrap();
+        for (&param, &arg) in callee
+            .dfg
+            .ebb_params(entry)
+            .iter()
+            .zip(self.caller.dfg.inst_args(call))
+        {
+            self.values[param] = self.caller.dfg.resolve_aliases(arg).into();
+        }
+
+        // Move the instructions following the call to the continuation EBB, whose parameters are
+        // the returned values.
+        if let Some(cont) = self.continuation {
+            let next = self.caller.layout.next_inst(call).unwrap();
+            self.caller.layout.split_ebb(cont, next);
+            for ret in &callee.signature.returns {
+                self.caller.dfg.append_ebb_param(cont, ret.value_type);
+            }
+        }
+
+        let mut returned = Vec::new();
+        for ebb in callee.layout.ebbs() {
+            let new_ebb = self.ebbs[ebb].unwrap();
+            if new_ebb != call_ebb {
+                let before = self.continuation.unwrap();
+                self.caller.layout.insert_ebb(new_ebb, before);
+            }
+            for inst in callee.layout.ebb_insts(ebb) {
+                let opcode = callee.dfg[inst].opcode();
+                let args = callee.dfg.inst_args(inst);
+                if opcode == Opcode::Return && self.continuation.is_none() {
+                    returned.extend_from_slice(args);
+                    continue;
+                }
+                let data = if opcode == Opcode::Return || opcode == Opcode::FallthroughReturn {
+                    InstructionData::Jump {
+                        opcode: Opcode::Jump,
+                        destination: self.continuation.unwrap(),
+                        args: ValueList::from_slice(args, &mut self.caller.dfg.value_lists),
+                    }
+                } else {
+                    self.copy_inst_data(inst)
+                };
+                let new_inst = self.caller.dfg.make_inst(data);
+                self.caller
+                    .dfg
+                    .make_inst_results(new_inst, callee.dfg.ctrl_typevar(inst));
+                for (&result, &new_result) in callee
+                    .dfg
+                    .inst_results(inst)
+                    .iter()
+                    .zip(self.caller.dfg.inst_results(new_inst))
+                {
+                    self.values[result] = new_result.into();
+                }
+                if new_ebb == call_ebb {
+                    self.caller.layout.insert_inst(new_inst, call);
+                } else {
+                    self.caller.layout.append_inst(new_inst, new_ebb);
+                }
+                let srcloc = callee.srclocs[inst];
+                self.caller.srclocs[new_inst] = if srcloc == SourceLoc::default() {
+                    call_srcloc
+                } else {
+                    srcloc
+                };
+                self.insts.push(new_inst);
+            }
+        }
+
+        // All the values are mapped now, so the arguments can be rewritten.
+        for &inst in &self.insts {
+            for arg in self.caller.dfg.inst_args_mut(inst) {
+                *arg = self.values[self.callee.dfg.resolve_aliases(*arg)].unwrap();
+            }
+        }
+
+        // Replace the results of the call and remove it.
+        let results = self.caller.dfg.inst_results(call).to_vec();
+        self.caller.dfg.clear_results(call);
+        let new_values: Vec<Value> = match self.continuation {
+            Some(cont) => self.caller.dfg.ebb_params(cont).to_vec(),
+            None => returned
+                .iter()
+                .map(|&value| self.values[callee.dfg.resolve_aliases(value)].unwrap())
+                .collect(),
+        };
+        for (result, value) in results.into_iter().zip(new_values) {
+            self.caller.dfg.change_to_alias(result, value);
+        }
+        let mut pos = FuncCursor::new(self.caller).at_inst(call);
+        pos.remove_inst();
+    }
+
+    /// Copy the data of the callee instruction `inst`, mapping everything but its value
+    /// arguments to the caller's entities.
+    fn copy_inst_data(&mut self, inst: Inst) -> InstructionData {
+        let mut data = self.callee.dfg[inst].clone();
+        if data.take_value_list().is_some() {
+            let args = self.callee.dfg.inst_args(inst);
+            data.put_value_list(ValueList::from_slice(
+                args,
+                &mut self.caller.dfg.value_lists,
+            ));
+        }
+
+        match data {
+            InstructionData::Jump {
+                ref mut destination,
+                ..
+            }
+            | InstructionData::Branch {
+                ref mut destination,
+                ..
+            }
+            | InstructionData::BranchInt {
+                ref mut destination,
+                ..
+            }
+            | InstructionData::BranchFloat {
+                ref mut destination,
+                ..
+            }
+            | InstructionData::BranchIcmp {
+                ref mut destination,
+                ..
+            } => *destination = self.ebbs[*destination].unwrap(),
+            InstructionData::BranchTable {
+                ref mut destination,
+                ref mut table,
+                ..
+            } => {
+                *destination = self.ebbs[*destination].unwrap();
+                *table = self.jump_tables[*table].unwrap();
+            }
+            InstructionData::BranchTableEntry { ref mut table, .. }
+            | InstructionData::BranchTableBase { ref mut table, .. }
+            | InstructionData::IndirectJump { ref mut table, .. } => {
+                *table = self.jump_tables[*table].unwrap()
+            }
+            InstructionData::Call {
+                ref mut func_ref, ..
+            }
+            | InstructionData::FuncAddr {
+                ref mut func_ref, ..
+            } => *func_ref = self.functions[*func_ref].unwrap(),
+            InstructionData::CallIndirect {
+                ref mut sig_ref, ..
+            } => *sig_ref = self.signatures[*sig_ref].unwrap(),
+            InstructionData::UnaryGlobalValue {
+                ref mut global_value,
+                ..
+            } => *global_value = self.global_values[*global_value].unwrap(),
+            InstructionData::StackLoad {
+                ref mut stack_slot, ..
+            }
+            | InstructionData::StackStore {
+                ref mut stack_slot, ..
+            } => *stack_slot = self.stack_slots[*stack_slot].unwrap(),
+            InstructionData::HeapAddr { ref mut heap, .. } => *heap = self.heaps[*heap].unwrap(),
+            InstructionData::TableAddr { ref mut table, .. } => {
+                *table = self.tables[*table].unwrap()
+            }
+            _ => {}
+        }
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cursor::{Cursor, FuncCursor};
+    use ir::types::*;
+    use ir::{
+        AbiParam, ExtFuncData, ExternalName, InstBuilder, Signature, SourceLoc, StackSlotData,
+    };
+    use isa::CallConv;
+    use settings;
+    use std::string::ToString;
+    use verifier::verify_function;
+
+    fn check(func: &Function) {
+        let flags = settings::Flags::new(settings::builder());
+        if let Err(errors) = verify_function(func, &flags) {
+            panic!("{}\n{}", func.display(None), errors)
+        }
+    }
+
+    fn signature(params: &[Type], returns: &[Type]) -> Signature {
+        let mut sig = Signature::new(CallConv::SystemV);
+        sig.params
+            .extend(params.iter().map(|&ty| AbiParam::new(ty)));
+        sig.returns
+            .extend(returns.iter().map(|&ty| AbiParam::new(ty)));
+        sig
+    }
+
+    /// Create a caller which calls a function with the signature `sig` with `args`, and returns
+    /// the sum of the results.
+    fn make_caller(sig: &Signature, args: &[i64]) -> (Function, Inst) {
+        let mut func =
+            Function::with_name_signature(ExternalName::testcase("caller"), signature(&[], &[I32]));
+        let sig_ref = func.import_signature(sig.clone());
+        let callee = func.import_function(ExtFuncData {
+            name: ExternalName::testcase("callee"),
+            signature: sig_ref,
+            colocated: true,
+        });
+        let ebb0 = func.dfg.make_ebb();
+        let mut pos = FuncCursor::new(&mut func);
+        pos.insert_ebb(ebb0);
+        let args: Vec<Value> = args.iter().map(|&x| pos.ins().iconst(I32, x)).collect();
+        pos.set_srcloc(SourceLoc::new(7));
+        let call = pos.ins().call(callee, &args);
+        let result = pos.func.dfg.first_result(call);
+        let sum = pos.ins().iadd_imm(result, 1);
+        pos.ins().return_(&[sum]);
+        (func, call)
+    }
+
+    #[test]
+    fn single_ebb() {
+        let sig = signature(&[I32, I32], &[I32]);
+        let mut callee =
+            Function::with_name_signature(ExternalName::testcase("callee"), sig.clone());
+        let ss = callee.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 4));
+        let ebb0 = callee.dfg.make_ebb();
+        let x = callee.dfg.append_ebb_param(ebb0, I32);
+        let y = callee.dfg.append_ebb_param(ebb0, I32);
+        {
+            let mut pos = FuncCursor::new(&mut callee);
+            pos.insert_ebb(ebb0);
+            pos.set_srcloc(SourceLoc::new(3));
+            let sum = pos.ins().iadd(x, y);
+            pos.ins().stack_store(sum, ss, 0);
+            let value = pos.ins().stack_load(I32, ss, 0);
+            pos.ins().return_(&[value]);
+        }
+
+        let (mut caller, call) = make_caller(&sig, &[1, 2]);
+        assert!(inline_call(&mut caller, call, &callee));
+        check(&caller);
+        let ebb0 = caller.layout.entry_block().unwrap();
+        let sum = caller.layout.ebb_insts(ebb0).nth(2).unwrap();
+        assert_eq!(caller.srclocs[sum], SourceLoc::new(3));
+        caller.srclocs.clear();
+        assert_eq!(
+            caller.to_string(),
+            "function %caller() -> i32 system_v {
+    ss0 = explicit_slot 4
+    sig0 = (i32, i32) -> i32 system_v
+    fn0 = colocated %callee sig0
+
+ebb0:
+    v0 = iconst.i32 1
+    v1 = iconst.i32 2
+    v4 = iadd v0, v1
+    stack_store v4, ss0
+    v5 = stack_load.i32 ss0
+    v2 -> v5
+    v3 = iadd_imm v2, 1
+    return v3
+}
+"
+        );
+    }
+
+    #[test]
+    fn multiple_ebbs() {
+        let sig = signature(&[I32], &[I32]);
+        let mut callee =
+            Function::with_name_signature(ExternalName::testcase("callee"), sig.clone());
+        let ebb0 = callee.dfg.make_ebb();
+        let ebb1 = callee.dfg.make_ebb();
+        let x = callee.dfg.append_ebb_param(ebb0, I32);
+        {
+            let mut pos = FuncCursor::new(&mut callee);
+            pos.insert_ebb(ebb0);
+            pos.ins().brz(x, ebb1, &[]);
+            let inverted = pos.ins().bnot(x);
+            pos.ins().return_(&[inverted]);
+            pos.insert_ebb(ebb1);
+            let one = pos.ins().iconst(I32, 1);
+            pos.ins().return_(&[one]);
+        }
+
+        let (mut caller, call) = make_caller(&sig, &[5]);
+        assert!(inline_call(&mut caller, call, &callee));
+        check(&caller);
+        // The callee has no source locations, so the inlined instructions get the call's.
+        let ebb0 = caller.layout.entry_block().unwrap();
+        let brz = caller.layout.ebb_insts(ebb0).nth(1).unwrap();
+        assert_eq!(caller.srclocs[brz], SourceLoc::new(7));
+        caller.srclocs.clear();
+        assert_eq!(
+            caller.to_string(),
+            "function %caller() -> i32 system_v {
+    sig0 = (i32) -> i32 system_v
+    fn0 = colocated %callee sig0
+
+ebb0:
+    v0 = iconst.i32 5
+    brz v0, ebb2
+    v4 = bnot v0
+    jump ebb1(v4)
+
+ebb2:
+    v5 = iconst.i32 1
+    jump ebb1(v5)
+
+ebb1(v3: i32):
+    v1 -> v3
+    v2 = iadd_imm v1, 1
+    return v2
+}
+"
+        );
+    }
+
+    #[test]
+    fn mismatched_signature() {
+        let sig = signature(&[I32], &[I32]);
+        let mut callee = Function::with_name_signature(
+            ExternalName::testcase("callee"),
+            signature(&[I64], &[I32]),
+        );
+        let ebb0 = callee.dfg.make_ebb();
+        callee.dfg.append_ebb_param(ebb0, I64);
+        {
+            let mut pos = FuncCursor::new(&mut callee);
+            pos.insert_ebb(ebb0);
+            let zero = pos.ins().iconst(I32, 0);
+            pos.ins().return_(&[zero]);
+        }
+
+        let (mut caller, call) = make_caller(&sig, &[5]);
+        let before = caller.to_string();
+        assert!(!inline_call(&mut caller, call, &callee));
+        assert_eq!(caller.to_string(), before);
+    }
+}
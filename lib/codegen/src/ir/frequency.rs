@@ -0,0 +1,104 @@
+//! Execution frequency hints.
+//!
+//! Each EBB can carry a hint about how often it is expected to execute. The hints come from the
+//! producer of the IR, from the legalizer, or from counts collected by an instrumented build, and
+//! they are used to lay out the code with the hot paths falling through.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// The expected execution frequency of an EBB.
+///
+/// In the textual IR format, the hint follows the EBB parameters:
+///
+/// ```clif
+///     ebb3(v1: i32) cold:
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EbbFrequency {
+    /// Nothing is known about the EBB.
+    Unknown,
+    /// The EBB is rarely executed, like a trap or an error path.
+    Cold,
+    /// The EBB is executed much more often than the code around it.
+    Hot,
+    /// The EBB executed this many times while profiling.
+    Count(u64),
+}
+
+impl EbbFrequency {
+    /// Is this the default `Unknown` frequency?
+    pub fn is_unknown(self) -> bool {
+        self == EbbFrequency::Unknown
+    }
+
+    /// Is the EBB expected to be rarely executed?
+    pub fn is_cold(self) -> bool {
+        match self {
+            EbbFrequency::Cold | EbbFrequency::Count(0) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Default for EbbFrequency {
+    fn default() -> Self {
+        EbbFrequency::Unknown
+    }
+}
+
+impl fmt::Display for EbbFrequency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EbbFrequency::Unknown => write!(f, "unknown"),
+            EbbFrequency::Cold => write!(f, "cold"),
+            EbbFrequency::Hot => write!(f, "hot"),
+            EbbFrequency::Count(n) => write!(f, "count={}", n),
+        }
+    }
+}
+
+impl FromStr for EbbFrequency {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "unknown" => Ok(EbbFrequency::Unknown),
+            "cold" => Ok(EbbFrequency::Cold),
+            "hot" => Ok(EbbFrequency::Hot),
+            _ => {
+                if s.starts_with("count=") {
+                    s["count=".len()..]
+                        .parse()
+                        .map(EbbFrequency::Count)
+                        .map_err(|_| ())
+                } else {
+                    Err(())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EbbFrequency;
+    use std::string::ToString;
+
+    #[test]
+    fn display() {
+        assert_eq!(EbbFrequency::Unknown.to_string(), "unknown");
+        assert_eq!(EbbFrequency::Cold.to_string(), "cold");
+        assert_eq!(EbbFrequency::Hot.to_string(), "hot");
+        assert_eq!(EbbFrequency::Count(17).to_string(), "count=17");
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!("cold".parse(), Ok(EbbFrequency::Cold));
+        assert_eq!("hot".parse(), Ok(EbbFrequency::Hot));
+        assert_eq!("count=0".parse(), Ok(EbbFrequency::Count(0)));
+        assert_eq!("count=".parse::<EbbFrequency>(), Err(()));
+        assert_eq!("warm".parse::<EbbFrequency>(), Err(()));
+    }
+}
@@ -6,12 +6,13 @@
 use binemit::CodeOffset;
 use entity::{PrimaryMap, SecondaryMap};
 use ir;
+use ir::ValueLoc;
 use ir::{DataFlowGraph, ExternalName, Layout, Signature};
 use ir::{
-    Ebb, ExtFuncData, FuncRef, GlobalValue, GlobalValueData, Heap, HeapData, JumpTable,
-    JumpTableData, SigRef, StackSlot, StackSlotData, Table, TableData,
+    Ebb, ExtFuncData, FuncRef, GlobalValue, GlobalValueData, Heap, HeapData, InstructionData,
+    JumpTable, JumpTableData, SigRef, StackSlot, StackSlotData, Table, TableData,
 };
-use ir::{EbbOffsets, InstEncodings, SourceLocs, StackSlots, ValueLocations};
+use ir::{EbbFrequencies, EbbOffsets, InstEncodings, SourceLocs, StackSlots, ValueLocations};
 use ir::{JumpTableOffsets, JumpTables};
 use isa::{CallConv, EncInfo, Encoding, Legalize, TargetIsa};
 use regalloc::RegDiversions;
@@ -73,6 +74,12 @@ pub struct Function {
     /// Track the original source location for each instruction. The source locations are not
     /// interpreted by Cranelift, only preserved.
     pub srclocs: SourceLocs,
+
+    /// Execution frequency hints.
+    ///
+    /// Track how often each EBB is expected to execute. This is only used to choose the layout of
+    /// the generated code.
+    pub ebb_frequencies: EbbFrequencies,
 }
 
 impl Function {
@@ -93,6 +100,7 @@ impl Function {
             offsets: SecondaryMap::new(),
             jt_offsets: SecondaryMap::new(),
             srclocs: SecondaryMap::new(),
+            ebb_frequencies: SecondaryMap::new(),
         }
     }
 
@@ -110,6 +118,7 @@ impl Function {
         self.locations.clear();
         self.offsets.clear();
         self.srclocs.clear();
+        self.ebb_frequencies.clear();
     }
 
     /// Create a new empty, anonymous function with a Fast calling convention.
@@ -128,6 +137,52 @@ impl Function {
         self.stack_slots.push(data)
     }
 
+    /// Remove the stack slots for which `keep` returns `false`, and renumber the remaining ones
+    /// in `stack_slots`, in all instructions, and in value locations.
+    ///
+    /// The removed stack slots must not be referenced by any instruction or value location.
+    pub fn retain_stack_slots<F>(&mut self, keep: F)
+    where
+        F: FnMut(StackSlot, &StackSlotData) -> bool,
+    {
+        let num_slots = self.stack_slots.keys().count();
+        let renumbered = self.stack_slots.retain(keep);
+        if self.stack_slots.keys().count() == num_slots {
+            return;
+        }
+
+        for ebb in self.layout.ebbs() {
+            for inst in self.layout.ebb_insts(ebb) {
+                match self.dfg[inst] {
+                    InstructionData::StackLoad {
+                        ref mut stack_slot, ..
+                    }
+                    | InstructionData::StackStore {
+                        ref mut stack_slot, ..
+                    }
+                    | InstructionData::RegSpill {
+                        dst: ref mut stack_slot,
+                        ..
+                    }
+                    | InstructionData::RegFill {
+                        src: ref mut stack_slot,
+                        ..
+                    } => {
+                        *stack_slot = renumbered[*stack_slot]
+                            .expect("removed stack slot is still referenced");
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for loc in self.locations.values_mut() {
+            if let ValueLoc::Stack(ref mut ss) = *loc {
+                *ss = renumbered[*ss].expect("removed stack slot is still assigned to a value");
+            }
+        }
+    }
+
     /// Adds a signature which can later be used to declare an external function import.
     pub fn import_signature(&mut self, signature: Signature) -> SigRef {
         self.dfg.signatures.push(signature)
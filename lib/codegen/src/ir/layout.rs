@@ -430,6 +430,36 @@ impl Layout {
         }
     }
 
+    /// Rearrange the EBBs in the layout into the order given by `ebbs`.
+    ///
+    /// The EBBs in `ebbs` must be exactly the EBBs currently in the layout. Their instructions move
+    /// with them.
+    pub fn set_ebb_order(&mut self, ebbs: &[Ebb]) {
+        debug_assert_eq!(
+            ebbs.len(),
+            self.ebbs().count(),
+            "New order must contain all the EBBs"
+        );
+        debug_assert!(
+            ebbs.iter().all(|&ebb| self.is_ebb_inserted(ebb)),
+            "EBB not in the layout"
+        );
+        let mut prev: Option<Ebb> = None;
+        for &ebb in ebbs {
+            self.ebbs[ebb].prev = prev.into();
+            match prev {
+                None => self.first_ebb = Some(ebb),
+                Some(p) => self.ebbs[p].next = ebb.into(),
+            }
+            prev = Some(ebb);
+        }
+        if let Some(last) = prev {
+            self.ebbs[last].next = None.into();
+        }
+        self.last_ebb = prev;
+        self.full_renumber();
+    }
+
     /// Return an iterator over all EBBs in layout order.
     pub fn ebbs(&self) -> Ebbs {
         Ebbs {
@@ -1090,6 +1120,38 @@ mod tests {
         assert_eq!(v1, [i2, i3]);
     }
 
+    #[test]
+    fn set_ebb_order() {
+        let mut layout = Layout::new();
+        let e0 = Ebb::new(0);
+        let e1 = Ebb::new(1);
+        let e2 = Ebb::new(2);
+        let i0 = Inst::new(0);
+        let i1 = Inst::new(1);
+        let i2 = Inst::new(2);
+
+        layout.append_ebb(e0);
+        layout.append_inst(i0, e0);
+        layout.append_ebb(e1);
+        layout.append_inst(i1, e1);
+        layout.append_ebb(e2);
+        layout.append_inst(i2, e2);
+
+        layout.set_ebb_order(&[e0, e2, e1]);
+        assert_eq!(layout.ebbs().collect::<Vec<_>>(), [e0, e2, e1]);
+        assert_eq!(layout.last_ebb(), Some(e1));
+        assert_eq!(layout.prev_ebb(e1), Some(e2));
+        assert_eq!(layout.cmp(i2, e1), Ordering::Less);
+        assert_eq!(layout.cmp(i0, e2), Ordering::Less);
+
+        layout.set_ebb_order(&[e2, e1, e0]);
+        assert_eq!(layout.ebbs().collect::<Vec<_>>(), [e2, e1, e0]);
+        assert_eq!(layout.entry_block(), Some(e2));
+        assert_eq!(layout.prev_ebb(e2), None);
+        assert_eq!(layout.inst_ebb(i0), Some(e0));
+        assert_eq!(layout.cmp(i1, i0), Ordering::Less);
+    }
+
     #[test]
     fn split_ebb() {
         let mut layout = Layout::new();
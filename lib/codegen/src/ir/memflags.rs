@@ -6,9 +6,31 @@ enum FlagBit {
     Notrap,
     Aligned,
     Readonly,
+    Heap,
+    Table,
+    Vmctx,
 }
 
-const NAMES: [&str; 3] = ["notrap", "aligned", "readonly"];
+const NAMES: [&str; 6] = ["notrap", "aligned", "readonly", "heap", "table", "vmctx"];
+
+/// The bits of the alias region flags.
+const REGION_BITS: u8 =
+    1 << FlagBit::Heap as u8 | 1 << FlagBit::Table as u8 | 1 << FlagBit::Vmctx as u8;
+
+/// A disjoint region of memory that a memory operation can be annotated with.
+///
+/// Memory operations annotated with different regions are assumed to never access the same
+/// memory, while an operation without a region may access any memory. Alias analysis uses this to
+/// reorder and eliminate memory operations that it couldn't otherwise tell apart.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum AliasRegion {
+    /// Memory inside a heap, such as a WebAssembly linear memory.
+    Heap,
+    /// Memory inside a table.
+    Table,
+    /// Memory inside the VM context structure.
+    Vmctx,
+}
 
 /// Flags for memory operations like load/store.
 ///
@@ -94,6 +116,38 @@ impl MemFlags {
     pub fn set_readonly(&mut self) {
         self.set(FlagBit::Readonly)
     }
+
+    /// Get the alias region set by the `heap`, `table` or `vmctx` flag, if any.
+    ///
+    /// Accessing memory in a region with an operation annotated with a different region results
+    /// in undefined behavior.
+    pub fn alias_region(self) -> Option<AliasRegion> {
+        if self.read(FlagBit::Heap) {
+            Some(AliasRegion::Heap)
+        } else if self.read(FlagBit::Table) {
+            Some(AliasRegion::Table)
+        } else if self.read(FlagBit::Vmctx) {
+            Some(AliasRegion::Vmctx)
+        } else {
+            None
+        }
+    }
+
+    /// Set the alias region, replacing any region that was set before.
+    pub fn set_alias_region(&mut self, region: Option<AliasRegion>) {
+        self.bits &= !REGION_BITS;
+        match region {
+            Some(AliasRegion::Heap) => self.set(FlagBit::Heap),
+            Some(AliasRegion::Table) => self.set(FlagBit::Table),
+            Some(AliasRegion::Vmctx) => self.set(FlagBit::Vmctx),
+            None => {}
+        }
+    }
+
+    /// Test if more than one alias region flag is set, which the verifier rejects.
+    pub fn has_conflicting_alias_regions(self) -> bool {
+        (self.bits & REGION_BITS).count_ones() > 1
+    }
 }
 
 impl fmt::Display for MemFlags {
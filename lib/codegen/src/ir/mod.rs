@@ -6,6 +6,7 @@ pub mod dfg;
 pub mod entities;
 mod extfunc;
 mod extname;
+mod frequency;
 pub mod function;
 mod globalvalue;
 mod heap;
@@ -30,6 +31,7 @@ pub use ir::entities::{
 };
 pub use ir::extfunc::{AbiParam, ArgumentExtension, ArgumentPurpose, ExtFuncData, Signature};
 pub use ir::extname::ExternalName;
+pub use ir::frequency::EbbFrequency;
 pub use ir::function::Function;
 pub use ir::globalvalue::GlobalValueData;
 pub use ir::heap::{HeapData, HeapStyle};
@@ -37,7 +39,7 @@ pub use ir::instructions::{InstructionData, Opcode, ValueList, ValueListPool, Va
 pub use ir::jumptable::JumpTableData;
 pub use ir::layout::Layout;
 pub use ir::libcall::{get_libcall_funcref, get_probestack_funcref, LibCall};
-pub use ir::memflags::MemFlags;
+pub use ir::memflags::{AliasRegion, MemFlags};
 pub use ir::progpoint::{ExpandedProgramPoint, ProgramOrder, ProgramPoint};
 pub use ir::sourceloc::SourceLoc;
 pub use ir::stackslot::{StackSlotData, StackSlotKind, StackSlots};
@@ -67,3 +69,6 @@ pub type JumpTableOffsets = SecondaryMap<JumpTable, binemit::CodeOffset>;
 
 /// Source locations for instructions.
 pub type SourceLocs = SecondaryMap<Inst, SourceLoc>;
+
+/// Execution frequency hints for EBBs.
+pub type EbbFrequencies = SecondaryMap<Ebb, EbbFrequency>;
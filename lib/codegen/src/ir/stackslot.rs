@@ -3,7 +3,7 @@
 //! The `StackSlotData` struct keeps track of a single stack slot in a function.
 //!
 
-use entity::{Iter, IterMut, Keys, PrimaryMap};
+use entity::{Iter, IterMut, Keys, PrimaryMap, SecondaryMap};
 use ir::{StackSlot, Type};
 use packed_option::PackedOption;
 use std::cmp;
@@ -239,6 +239,34 @@ impl StackSlots {
     pub fn next_key(&self) -> StackSlot {
         self.slots.next_key()
     }
+
+    /// Remove the stack slots for which `keep` returns false, and renumber the remaining ones.
+    ///
+    /// Returns a map from the old stack slots to the new ones, where removed stack slots map to
+    /// `None`. The caller is responsible for updating all references to stack slots, and there
+    /// must be none left to the removed ones.
+    pub fn retain<F>(&mut self, mut keep: F) -> SecondaryMap<StackSlot, PackedOption<StackSlot>>
+    where
+        F: FnMut(StackSlot, &StackSlotData) -> bool,
+    {
+        let mut renumbered = SecondaryMap::new();
+        let mut slots: PrimaryMap<StackSlot, StackSlotData> = PrimaryMap::new();
+        for (ss, data) in self.slots.iter() {
+            if keep(ss, data) {
+                renumbered[ss] = PackedOption::from(slots.push(data.clone()));
+            }
+        }
+        self.slots = slots;
+        let renumber = |list: &mut Vec<StackSlot>| {
+            list.retain(|&ss| renumbered[ss].is_some());
+            for ss in list.iter_mut() {
+                *ss = renumbered[*ss].unwrap();
+            }
+        };
+        renumber(&mut self.outgoing);
+        renumber(&mut self.emergency);
+        renumbered
+    }
 }
 
 impl Index<StackSlot> for StackSlots {
@@ -382,6 +410,28 @@ mod tests {
         assert_eq!(sss.get_outgoing_arg(types::I64, 8), ss2);
     }
 
+    #[test]
+    fn retain() {
+        let mut sss = StackSlots::new();
+
+        let ss0 = sss.push(StackSlotData::new(StackSlotKind::ExplicitSlot, 4));
+        let ss1 = sss.get_outgoing_arg(types::I32, 4);
+        let ss2 = sss.push(StackSlotData::new(StackSlotKind::ExplicitSlot, 8));
+        let ss3 = sss.get_outgoing_arg(types::I64, 8);
+
+        let renumbered = sss.retain(|ss, _| ss != ss0 && ss != ss3);
+        assert_eq!(renumbered[ss0].expand(), None);
+        assert_eq!(renumbered[ss1].expand(), Some(ss0));
+        assert_eq!(renumbered[ss2].expand(), Some(ss1));
+        assert_eq!(renumbered[ss3].expand(), None);
+        assert_eq!(sss.keys().count(), 2);
+        assert_eq!(sss[ss1].size, 8);
+
+        // The outgoing argument slots are still found.
+        assert_eq!(sss.get_outgoing_arg(types::I32, 4), ss0);
+        assert_eq!(sss.keys().count(), 2);
+    }
+
     #[test]
     fn alignment() {
         let slot = StackSlotData::new(StackSlotKind::SpillSlot, 8);
@@ -1,7 +1,7 @@
 //! The `Encoding` struct.
 
 use binemit::CodeOffset;
-use ir::{Function, Inst};
+use ir::{Function, Inst, Opcode};
 use isa::constraints::{BranchRange, RecipeConstraints};
 use regalloc::RegDiversions;
 use std::fmt;
@@ -116,6 +116,9 @@ pub struct EncInfo {
 
     /// Names of encoding recipes.
     pub names: &'static [&'static str],
+
+    /// Result latencies in cycles per opcode.
+    pub latencies: fn(Opcode) -> u8,
 }
 
 impl EncInfo {
@@ -154,4 +157,11 @@ impl EncInfo {
     pub fn branch_range(&self, enc: Encoding) -> Option<BranchRange> {
         self.sizing.get(enc.recipe()).and_then(|s| s.branch_range)
     }
+
+    /// Get the number of cycles before the results of an `opcode` instruction can be used.
+    ///
+    /// This is a rough estimate used for instruction scheduling.
+    pub fn latency(&self, opcode: Opcode) -> u32 {
+        u32::from((self.latencies)(opcode))
+    }
 }
@@ -166,6 +166,11 @@ impl settings::Configurable for Builder {
 pub type Legalize =
     fn(ir::Inst, &mut ir::Function, &mut flowgraph::ControlFlowGraph, &TargetIsa) -> bool;
 
+/// A group of peephole rewrites generated from the Python meta language.
+///
+/// The function tries to rewrite the instruction and returns true if it made any changes.
+pub type Peephole = fn(ir::Inst, &mut ir::Function, &TargetIsa) -> bool;
+
 /// This struct provides information that a frontend may need to know about a target to
 /// produce Cranelift IR for the target.
 #[derive(Clone, Copy)]
@@ -279,6 +284,14 @@ pub trait TargetIsa: fmt::Display + Sync {
     /// Get a data structure describing the instruction encodings in this ISA.
     fn encoding_info(&self) -> EncInfo;
 
+    /// Get the peephole rewrites to apply before legalization.
+    ///
+    /// The default is the ISA-independent `simplify` group. Targets that have their own rewrites
+    /// return a group that falls back to the shared one.
+    fn peepholes(&self) -> Peephole {
+        ::peepholes::simplify
+    }
+
     /// Legalize a function signature.
     ///
     /// This is used to legalize both the signature of the function being compiled and any called
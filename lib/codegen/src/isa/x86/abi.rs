@@ -1,8 +1,12 @@
 //! x86 ABI implementation.
 
 use super::registers::{FPR, GPR, RU};
+use super::settings as isa_settings;
 use abi::{legalize_args, ArgAction, ArgAssigner, ValueConversion};
 use cursor::{Cursor, CursorPosition, EncCursor};
+use dominator_tree::DominatorTree;
+use entity::EntitySet;
+use flowgraph::ControlFlowGraph;
 use ir;
 use ir::immediates::Imm64;
 use ir::stackslot::{StackOffset, StackSize};
@@ -13,9 +17,10 @@ use ir::{
 use isa::{CallConv, RegClass, RegUnit, TargetIsa};
 use regalloc::RegisterSet;
 use result::CodegenResult;
+use settings::OptLevel;
 use stack_layout::layout_stack;
 use std::i32;
-use target_lexicon::{PointerWidth, Triple};
+use target_lexicon::{OperatingSystem, PointerWidth, Triple};
 
 /// Argument registers for x86-64
 static ARG_GPRS: [RU; 6] = [RU::rdi, RU::rsi, RU::rdx, RU::rcx, RU::r8, RU::r9];
@@ -171,10 +176,16 @@ pub fn regclass_for_abi_type(ty: ir::Type) -> RegClass {
 }
 
 /// Get the set of allocatable registers for `func`.
-pub fn allocatable_registers(_func: &ir::Function, triple: &Triple) -> RegisterSet {
+pub fn allocatable_registers(
+    func: &ir::Function,
+    triple: &Triple,
+    isa_flags: &isa_settings::Flags,
+) -> RegisterSet {
     let mut regs = RegisterSet::new();
     regs.take(GPR, RU::rsp as RegUnit);
-    regs.take(GPR, RU::rbp as RegUnit);
+    if !omit_frame_pointer(func, triple, isa_flags) {
+        regs.take(GPR, RU::rbp as RegUnit);
+    }
 
     // 32-bit arch only has 8 registers.
     if triple.pointer_width().unwrap() != PointerWidth::U64 {
@@ -215,12 +226,98 @@ fn callee_saved_gprs(isa: &TargetIsa, call_conv: CallConv) -> &'static [RU] {
     }
 }
 
+/// Can `func` be compiled without a frame pointer?
+///
+/// Stack slots are always addressed relative to RSP, so the frame pointer is only needed by
+/// debuggers and profilers walking the stack, unless the function adjusts the stack pointer itself.
+/// Without a frame pointer, RBP is an ordinary callee-saved register.
+fn omit_frame_pointer(
+    func: &ir::Function,
+    triple: &Triple,
+    isa_flags: &isa_settings::Flags,
+) -> bool {
+    if !isa_flags.omit_frame_pointer() || triple.pointer_width().unwrap() != PointerWidth::U64 {
+        return false;
+    }
+    match func.signature.call_conv {
+        CallConv::Baldrdash | CallConv::Probestack => return false,
+        _ => {}
+    }
+
+    !func.layout.ebbs().any(|ebb| {
+        func.layout.ebb_insts(ebb).any(|inst| match func.dfg[inst].opcode() {
+            ir::Opcode::AdjustSpDown
+            | ir::Opcode::AdjustSpUpImm
+            | ir::Opcode::AdjustSpDownImm
+            | ir::Opcode::CopySpecial
+            | ir::Opcode::X86Push
+            | ir::Opcode::X86Pop => true,
+            _ => false,
+        })
+    })
+}
+
+/// Does `func` make no calls?
+fn is_leaf(func: &ir::Function) -> bool {
+    !func.layout.ebbs().any(|ebb| {
+        func.layout
+            .ebb_insts(ebb)
+            .any(|inst| func.dfg[inst].opcode().is_call())
+    })
+}
+
+/// Find the size of the local stack area of a leaf function that doesn't need to be allocated by
+/// adjusting the stack pointer.
+///
+/// Leaf functions don't need an aligned stack pointer, so a function without any local stack
+/// slots needs no local area at all. On System V, the 128-byte red zone below the stack pointer
+/// won't be clobbered by signal handlers, so small local areas can stay there too.
+///
+/// Returns the local stack size to allocate, and updates the frame size to match.
+fn leaf_stack_size(
+    func: &mut ir::Function,
+    isa: &TargetIsa,
+    csr_stack_size: i32,
+    local_stack_size: i64,
+) -> i64 {
+    const RED_ZONE_SIZE: i64 = 128;
+
+    if local_stack_size == 0 || !is_leaf(func) {
+        return local_stack_size;
+    }
+
+    let no_locals = func.stack_slots.values().all(|slot| match slot.kind {
+        ir::StackSlotKind::IncomingArg | ir::StackSlotKind::OutgoingArg => true,
+        _ => false,
+    });
+    let red_zone = match func.signature.call_conv {
+        CallConv::Fast | CallConv::Cold | CallConv::SystemV => {
+            isa.triple().operating_system != OperatingSystem::Windows
+                && func.special_param(ArgumentPurpose::StackLimit).is_none()
+                && local_stack_size <= RED_ZONE_SIZE
+        }
+        _ => false,
+    };
+    if !no_locals && !red_zone {
+        return local_stack_size;
+    }
+
+    // The stack pointer now points just below the pushed registers.
+    func.stack_slots.frame_size = Some(csr_stack_size as StackSize);
+    0
+}
+
 /// Get the set of callee-saved registers that are used.
-fn callee_saved_gprs_used(isa: &TargetIsa, func: &ir::Function) -> RegisterSet {
+///
+/// Without a frame pointer, RBP is callee-saved too.
+fn callee_saved_gprs_used(isa: &TargetIsa, func: &ir::Function, omit_fp: bool) -> RegisterSet {
     let mut all_callee_saved = RegisterSet::empty();
     for reg in callee_saved_gprs(isa, func.signature.call_conv) {
         all_callee_saved.free(GPR, *reg as RegUnit);
     }
+    if omit_fp {
+        all_callee_saved.free(GPR, RU::rbp as RegUnit);
+    }
 
     let mut used = RegisterSet::empty();
     for value_loc in func.locations.values() {
@@ -258,13 +355,161 @@ fn callee_saved_gprs_used(isa: &TargetIsa, func: &ir::Function) -> RegisterSet {
     used
 }
 
-pub fn prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> CodegenResult<()> {
+/// Does `ebb` need the stack frame or any of the callee-saved registers in `csrs`?
+fn needs_frame(func: &ir::Function, ebb: ir::Ebb, csrs: &RegisterSet) -> bool {
+    let in_frame = |value: ir::Value| match func.locations[value] {
+        ValueLoc::Reg(ru) => csrs.is_avail(GPR, ru),
+        ValueLoc::Stack(_) => true,
+        ValueLoc::Unassigned => false,
+    };
+
+    if func
+        .dfg
+        .ebb_params(ebb)
+        .iter()
+        .any(|&param| in_frame(param))
+    {
+        return true;
+    }
+
+    func.layout.ebb_insts(ebb).any(|inst| {
+        // Calls need an aligned stack pointer.
+        let direct = match func.dfg[inst] {
+            ir::InstructionData::StackLoad { .. }
+            | ir::InstructionData::StackStore { .. }
+            | ir::InstructionData::RegSpill { .. }
+            | ir::InstructionData::RegFill { .. } => true,
+            ir::InstructionData::RegMove { dst, .. } => csrs.is_avail(GPR, dst),
+            ref data => data.opcode().is_call(),
+        };
+        direct
+            || func.dfg.inst_args(inst).iter().any(|&arg| in_frame(arg))
+            || func.dfg.inst_results(inst).iter().any(|&res| in_frame(res))
+    })
+}
+
+/// Find a later EBB for the prologue than the entry block.
+///
+/// Only the EBBs that need the stack frame or a callee-saved register must run after the
+/// prologue. The prologue can be placed in their nearest common dominator, as long as every path
+/// from there leaves the function through a return that gets an epilogue. This holds when every
+/// EBB reachable from the prologue EBB is dominated by it, which also keeps the prologue out of
+/// loops. Otherwise, the prologue is moved up the dominator tree.
+///
+/// Returns the prologue EBB and the EBBs reachable from it, or `None` if the prologue should stay
+/// in the entry block.
+fn shrink_wrap(
+    func: &ir::Function,
+    isa: &TargetIsa,
+    csrs: &RegisterSet,
+    stack_size: i64,
+) -> Option<(ir::Ebb, EntitySet<ir::Ebb>)> {
+    if isa.flags().opt_level() == OptLevel::Fastest {
+        return None;
+    }
+
+    // The stack check and the stack probe clobber registers that may be in use later in the
+    // function, and the stack limit argument may not survive until then.
+    if func.special_param(ArgumentPurpose::StackLimit).is_some()
+        || (isa.flags().probestack_enabled()
+            && stack_size > (1 << isa.flags().probestack_size_log2()))
+    {
+        return None;
+    }
+
+    let entry = func.layout.entry_block()?;
+    let cfg = ControlFlowGraph::with_function(func);
+    let domtree = DominatorTree::with_function(func, &cfg);
+    let idom = |ebb| {
+        domtree
+            .idom(ebb)
+            .and_then(|inst| func.layout.inst_ebb(inst))
+            .unwrap_or(entry)
+    };
+
+    // Find the nearest common dominator of the EBBs that need a frame.
+    let mut save = None;
+    for ebb in func.layout.ebbs() {
+        if !domtree.is_reachable(ebb) || !needs_frame(func, ebb, csrs) {
+            continue;
+        }
+        let mut dom = save.unwrap_or(ebb);
+        while !domtree.dominates(dom, ebb, &func.layout) {
+            dom = idom(dom);
+        }
+        save = Some(dom);
+    }
+
+    let mut save = save?;
+    let mut region = EntitySet::new();
+    while save != entry {
+        if closed_region(&cfg, &domtree, func, save, &mut region)
+            && !has_live_in_flags(func, &region)
+        {
+            debug!("Shrink-wrapping prologue into {}", save);
+            return Some((save, region));
+        }
+        save = idom(save);
+    }
+    None
+}
+
+/// Does `region` use a CPU flags value defined outside it?
+///
+/// Adjusting the stack pointer in the prologue would clobber it.
+fn has_live_in_flags(func: &ir::Function, region: &EntitySet<ir::Ebb>) -> bool {
+    func.layout
+        .ebbs()
+        .filter(|&ebb| region.contains(ebb))
+        .flat_map(|ebb| func.layout.ebb_insts(ebb))
+        .flat_map(|inst| func.dfg.inst_args(inst))
+        .any(|&arg| {
+            func.dfg.value_type(arg).is_flags()
+                && match func.dfg.value_def(arg) {
+                    ir::ValueDef::Result(def, _) => !region.contains(func.layout.pp_ebb(def)),
+                    ir::ValueDef::Param(ebb, _) => !region.contains(ebb),
+                }
+        })
+}
+
+/// Collect the EBBs reachable from `header` in `region`.
+///
+/// Returns false if one of them is not dominated by `header`, or if `header` is in a loop.
+fn closed_region(
+    cfg: &ControlFlowGraph,
+    domtree: &DominatorTree,
+    func: &ir::Function,
+    header: ir::Ebb,
+    region: &mut EntitySet<ir::Ebb>,
+) -> bool {
+    region.clear();
+    region.insert(header);
+    let mut stack = vec![header];
+    while let Some(ebb) = stack.pop() {
+        for succ in cfg.succ_iter(ebb) {
+            if succ == header || !domtree.dominates(header, succ, &func.layout) {
+                return false;
+            }
+            if region.insert(succ) {
+                stack.push(succ);
+            }
+        }
+    }
+    true
+}
+
+pub fn prologue_epilogue(
+    func: &mut ir::Function,
+    isa: &TargetIsa,
+    isa_flags: &isa_settings::Flags,
+) -> CodegenResult<()> {
+    let omit_fp = omit_frame_pointer(func, isa.triple(), isa_flags);
     match func.signature.call_conv {
         // For now, just translate fast and cold as system_v.
         CallConv::Fast | CallConv::Cold | CallConv::SystemV => {
-            system_v_prologue_epilogue(func, isa)
+            system_v_prologue_epilogue(func, isa, omit_fp)
         }
-        CallConv::WindowsFastcall => fastcall_prologue_epilogue(func, isa),
+        CallConv::WindowsFastcall => fastcall_prologue_epilogue(func, isa, omit_fp),
         CallConv::Baldrdash => baldrdash_prologue_epilogue(func, isa),
         CallConv::Probestack => unimplemented!("probestack calling convention"),
     }
@@ -291,7 +536,11 @@ fn baldrdash_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Code
 
 /// Implementation of the fastcall-based Win64 calling convention described at [1]
 /// [1] https://msdn.microsoft.com/en-us/library/ms235286.aspx
-fn fastcall_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> CodegenResult<()> {
+fn fastcall_prologue_epilogue(
+    func: &mut ir::Function,
+    isa: &TargetIsa,
+    omit_fp: bool,
+) -> CodegenResult<()> {
     if isa.triple().pointer_width().unwrap() != PointerWidth::U64 {
         panic!("TODO: windows-fastcall: x86-32 not implemented yet");
     }
@@ -303,7 +552,7 @@ fn fastcall_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Codeg
     let word_size = isa.pointer_bytes() as usize;
     let reg_type = isa.pointer_type();
 
-    let csrs = callee_saved_gprs_used(isa, func);
+    let csrs = callee_saved_gprs_used(isa, func, omit_fp);
 
     // [1] "Space is allocated on the call stack as a shadow store for callees to save"
     // This shadow store contains the parameters which are passed through registers (ARG_GPRS)
@@ -316,12 +565,15 @@ fn fastcall_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Codeg
     // The reserved stack area is composed of:
     //   return address + frame pointer + all callee-saved registers + shadow space
     //
+    // The frame pointer is left out if it is omitted.
+    //
     // Pushing the return address is an implicit function of the `call`
     // instruction. Each of the others we will then push explicitly. Then we
     // will adjust the stack pointer to make room for the rest of the required
     // space for this frame.
     const SHADOW_STORE_SIZE: i32 = 32;
-    let csr_stack_size = ((csrs.iter(GPR).len() + 2) * word_size) as i32;
+    let fp_words = if omit_fp { 0 } else { 1 };
+    let csr_stack_size = ((csrs.iter(GPR).len() + 1 + fp_words) * word_size) as i32;
 
     // TODO: eventually use the 32 bytes (shadow store) as spill slot. This currently doesn't work
     //       since cranelift does not support spill slots before incoming args
@@ -333,16 +585,21 @@ fn fastcall_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Codeg
     });
 
     let total_stack_size = layout_stack(&mut func.stack_slots, stack_align)? as i32;
-    let local_stack_size = i64::from(total_stack_size - csr_stack_size);
+    let mut local_stack_size = i64::from(total_stack_size - csr_stack_size);
+    if omit_fp {
+        local_stack_size = leaf_stack_size(func, isa, csr_stack_size, local_stack_size);
+    }
 
     // Add CSRs to function signature
-    let fp_arg = ir::AbiParam::special_reg(
-        reg_type,
-        ir::ArgumentPurpose::FramePointer,
-        RU::rbp as RegUnit,
-    );
-    func.signature.params.push(fp_arg);
-    func.signature.returns.push(fp_arg);
+    if !omit_fp {
+        let fp_arg = ir::AbiParam::special_reg(
+            reg_type,
+            ir::ArgumentPurpose::FramePointer,
+            RU::rbp as RegUnit,
+        );
+        func.signature.params.push(fp_arg);
+        func.signature.returns.push(fp_arg);
+    }
 
     for csr in csrs.iter(GPR) {
         let csr_arg = ir::AbiParam::special_reg(reg_type, ir::ArgumentPurpose::CalleeSaved, csr);
@@ -352,18 +609,33 @@ fn fastcall_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Codeg
 
     // Set up the cursor and insert the prologue
     let entry_ebb = func.layout.entry_block().expect("missing entry block");
-    let mut pos = EncCursor::new(func, isa).at_first_insertion_point(entry_ebb);
-    insert_common_prologue(&mut pos, local_stack_size, reg_type, &csrs, isa);
+    let wrap = shrink_wrap(func, isa, &csrs, local_stack_size);
+    let save_ebb = wrap.as_ref().map_or(entry_ebb, |&(ebb, _)| ebb);
+    let mut pos = EncCursor::new(func, isa).at_first_insertion_point(save_ebb);
+    insert_common_prologue(
+        &mut pos,
+        entry_ebb,
+        local_stack_size,
+        reg_type,
+        &csrs,
+        omit_fp,
+        isa,
+    );
 
     // Reset the cursor and insert the epilogue
     let mut pos = pos.at_position(CursorPosition::Nowhere);
-    insert_common_epilogues(&mut pos, local_stack_size, reg_type, &csrs);
+    let region = wrap.as_ref().map(|wrap| &wrap.1);
+    insert_common_epilogues(&mut pos, region, local_stack_size, reg_type, &csrs, omit_fp);
 
     Ok(())
 }
 
 /// Insert a System V-compatible prologue and epilogue.
-fn system_v_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> CodegenResult<()> {
+fn system_v_prologue_epilogue(
+    func: &mut ir::Function,
+    isa: &TargetIsa,
+    omit_fp: bool,
+) -> CodegenResult<()> {
     // The original 32-bit x86 ELF ABI had a 4-byte aligned stack pointer, but
     // newer versions use a 16-byte aligned stack pointer.
     let stack_align = 16;
@@ -371,16 +643,19 @@ fn system_v_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Codeg
     let word_size = pointer_width.bytes() as usize;
     let reg_type = ir::Type::int(u16::from(pointer_width.bits())).unwrap();
 
-    let csrs = callee_saved_gprs_used(isa, func);
+    let csrs = callee_saved_gprs_used(isa, func, omit_fp);
 
     // The reserved stack area is composed of:
     //   return address + frame pointer + all callee-saved registers
     //
+    // The frame pointer is left out if it is omitted.
+    //
     // Pushing the return address is an implicit function of the `call`
     // instruction. Each of the others we will then push explicitly. Then we
     // will adjust the stack pointer to make room for the rest of the required
     // space for this frame.
-    let csr_stack_size = ((csrs.iter(GPR).len() + 2) * word_size) as i32;
+    let fp_words = if omit_fp { 0 } else { 1 };
+    let csr_stack_size = ((csrs.iter(GPR).len() + 1 + fp_words) * word_size) as i32;
     func.create_stack_slot(ir::StackSlotData {
         kind: ir::StackSlotKind::IncomingArg,
         size: csr_stack_size as u32,
@@ -388,16 +663,21 @@ fn system_v_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Codeg
     });
 
     let total_stack_size = layout_stack(&mut func.stack_slots, stack_align)? as i32;
-    let local_stack_size = i64::from(total_stack_size - csr_stack_size);
+    let mut local_stack_size = i64::from(total_stack_size - csr_stack_size);
+    if omit_fp {
+        local_stack_size = leaf_stack_size(func, isa, csr_stack_size, local_stack_size);
+    }
 
     // Add CSRs to function signature
-    let fp_arg = ir::AbiParam::special_reg(
-        reg_type,
-        ir::ArgumentPurpose::FramePointer,
-        RU::rbp as RegUnit,
-    );
-    func.signature.params.push(fp_arg);
-    func.signature.returns.push(fp_arg);
+    if !omit_fp {
+        let fp_arg = ir::AbiParam::special_reg(
+            reg_type,
+            ir::ArgumentPurpose::FramePointer,
+            RU::rbp as RegUnit,
+        );
+        func.signature.params.push(fp_arg);
+        func.signature.returns.push(fp_arg);
+    }
 
     for csr in csrs.iter(GPR) {
         let csr_arg = ir::AbiParam::special_reg(reg_type, ir::ArgumentPurpose::CalleeSaved, csr);
@@ -407,23 +687,39 @@ fn system_v_prologue_epilogue(func: &mut ir::Function, isa: &TargetIsa) -> Codeg
 
     // Set up the cursor and insert the prologue
     let entry_ebb = func.layout.entry_block().expect("missing entry block");
-    let mut pos = EncCursor::new(func, isa).at_first_insertion_point(entry_ebb);
-    insert_common_prologue(&mut pos, local_stack_size, reg_type, &csrs, isa);
+    let wrap = shrink_wrap(func, isa, &csrs, local_stack_size);
+    let save_ebb = wrap.as_ref().map_or(entry_ebb, |&(ebb, _)| ebb);
+    let mut pos = EncCursor::new(func, isa).at_first_insertion_point(save_ebb);
+    insert_common_prologue(
+        &mut pos,
+        entry_ebb,
+        local_stack_size,
+        reg_type,
+        &csrs,
+        omit_fp,
+        isa,
+    );
 
     // Reset the cursor and insert the epilogue
     let mut pos = pos.at_position(CursorPosition::Nowhere);
-    insert_common_epilogues(&mut pos, local_stack_size, reg_type, &csrs);
+    let region = wrap.as_ref().map(|wrap| &wrap.1);
+    insert_common_epilogues(&mut pos, region, local_stack_size, reg_type, &csrs, omit_fp);
 
     Ok(())
 }
 
 /// Insert the prologue for a given function.
 /// This is used by common calling conventions such as System V.
+///
+/// The frame pointer and callee-saved registers are appended as parameters to `entry_ebb`, but the
+/// saves are inserted at `pos`, which may be in a later EBB when shrink-wrapping.
 fn insert_common_prologue(
     pos: &mut EncCursor,
+    entry_ebb: ir::Ebb,
     stack_size: i64,
     reg_type: ir::types::Type,
     csrs: &RegisterSet,
+    omit_fp: bool,
     isa: &TargetIsa,
 ) {
     if stack_size > 0 {
@@ -441,18 +737,19 @@ fn insert_common_prologue(
         }
     }
 
-    // Append param to entry EBB
-    let ebb = pos.current_ebb().expect("missing ebb under cursor");
-    let fp = pos.func.dfg.append_ebb_param(ebb, reg_type);
-    pos.func.locations[fp] = ir::ValueLoc::Reg(RU::rbp as RegUnit);
+    if !omit_fp {
+        // Append param to entry EBB
+        let fp = pos.func.dfg.append_ebb_param(entry_ebb, reg_type);
+        pos.func.locations[fp] = ir::ValueLoc::Reg(RU::rbp as RegUnit);
 
-    pos.ins().x86_push(fp);
-    pos.ins()
-        .copy_special(RU::rsp as RegUnit, RU::rbp as RegUnit);
+        pos.ins().x86_push(fp);
+        pos.ins()
+            .copy_special(RU::rsp as RegUnit, RU::rbp as RegUnit);
+    }
 
     for reg in csrs.iter(GPR) {
         // Append param to entry EBB
-        let csr_arg = pos.func.dfg.append_ebb_param(ebb, reg_type);
+        let csr_arg = pos.func.dfg.append_ebb_param(entry_ebb, reg_type);
 
         // Assign it a location
         pos.func.locations[csr_arg] = ir::ValueLoc::Reg(reg);
@@ -531,22 +828,49 @@ fn insert_stack_check(pos: &mut EncCursor, stack_size: i64, stack_limit_arg: ir:
 }
 
 /// Find all `return` instructions and insert epilogues before them.
+///
+/// When the prologue has been shrink-wrapped into `region`, the returns outside it never saved
+/// anything. They simply return the incoming frame pointer and callee-saved registers.
 fn insert_common_epilogues(
     pos: &mut EncCursor,
+    region: Option<&EntitySet<ir::Ebb>>,
     stack_size: i64,
     reg_type: ir::types::Type,
     csrs: &RegisterSet,
+    omit_fp: bool,
 ) {
     while let Some(ebb) = pos.next_ebb() {
         pos.goto_last_inst(ebb);
         if let Some(inst) = pos.current_inst() {
             if pos.func.dfg[inst].opcode().is_return() {
-                insert_common_epilogue(inst, stack_size, pos, reg_type, csrs);
+                match region {
+                    Some(region) if !region.contains(ebb) => {
+                        pass_through_saved_regs(inst, pos, csrs, omit_fp)
+                    }
+                    _ => insert_common_epilogue(inst, stack_size, pos, reg_type, csrs, omit_fp),
+                }
             }
         }
     }
 }
 
+/// Return the incoming frame pointer and callee-saved registers from a `return` instruction that
+/// isn't preceded by an epilogue.
+fn pass_through_saved_regs(
+    inst: ir::Inst,
+    pos: &mut EncCursor,
+    csrs: &RegisterSet,
+    omit_fp: bool,
+) {
+    let entry_ebb = pos.func.layout.entry_block().expect("missing entry block");
+    let params = pos.func.dfg.ebb_params(entry_ebb);
+    let fp_words = if omit_fp { 0 } else { 1 };
+    let saved = params[params.len() - csrs.iter(GPR).len() - fp_words..].to_vec();
+    for value in saved {
+        pos.func.dfg.append_inst_arg(inst, value);
+    }
+}
+
 /// Insert an epilogue given a specific `return` instruction.
 /// This is used by common calling conventions such as System V.
 fn insert_common_epilogue(
@@ -555,6 +879,7 @@ fn insert_common_epilogue(
     pos: &mut EncCursor,
     reg_type: ir::types::Type,
     csrs: &RegisterSet,
+    omit_fp: bool,
 ) {
     if stack_size > 0 {
         pos.ins().adjust_sp_up_imm(Imm64::new(stack_size));
@@ -562,11 +887,13 @@ fn insert_common_epilogue(
 
     // Pop all the callee-saved registers, stepping backward each time to
     // preserve the correct order.
-    let fp_ret = pos.ins().x86_pop(reg_type);
-    pos.prev_inst();
+    if !omit_fp {
+        let fp_ret = pos.ins().x86_pop(reg_type);
+        pos.prev_inst();
 
-    pos.func.locations[fp_ret] = ir::ValueLoc::Reg(RU::rbp as RegUnit);
-    pos.func.dfg.append_inst_arg(inst, fp_ret);
+        pos.func.locations[fp_ret] = ir::ValueLoc::Reg(RU::rbp as RegUnit);
+        pos.func.dfg.append_inst_arg(inst, fp_ret);
+    }
 
     for reg in csrs.iter(GPR) {
         let csr_ret = pos.ins().x86_pop(reg_type);
@@ -12,6 +12,7 @@ use isa::enc_tables::*;
 use isa::encoding::base_size;
 use isa::encoding::RecipeSizing;
 use isa::RegUnit;
+use legalizer;
 use regalloc::RegDiversions;
 
 include!(concat!(env!("OUT_DIR"), "/encoding-x86.rs"));
@@ -220,6 +221,71 @@ fn expand_udivrem(
     pos.remove_inst();
 }
 
+/// Expand a `select` instruction.
+///
+/// Integer selects become a comparison and a `selectif`, which is encoded as a conditional move.
+/// Other types use the generic expansion into branches.
+fn expand_select(
+    inst: ir::Inst,
+    func: &mut ir::Function,
+    cfg: &mut ControlFlowGraph,
+    isa: &isa::TargetIsa,
+) {
+    let (ctrl, tval, fval) = match func.dfg[inst] {
+        ir::InstructionData::Ternary {
+            opcode: ir::Opcode::Select,
+            args,
+        } => (args[0], args[1], args[2]),
+        _ => panic!("Expected select: {}", func.dfg.display_inst(inst, None)),
+    };
+    let ty = func.dfg.ctrl_typevar(inst);
+    if ty != ir::types::I32 && ty != ir::types::I64 {
+        legalizer::expand_select(inst, func, cfg, isa);
+        return;
+    }
+
+    let mut pos = FuncCursor::new(func).at_inst(inst);
+    pos.use_srcloc(inst);
+
+    // Compare the operands of an integer comparison computing `ctrl` directly.
+    let is_wide = |ty: ir::Type| ty == ir::types::I32 || ty == ir::types::I64;
+    let ctrl_def = match pos.func.dfg.value_def(ctrl) {
+        ir::ValueDef::Result(def, _) => Some(pos.func.dfg[def].clone()),
+        ir::ValueDef::Param(_, _) => None,
+    };
+    let (cond, flags) = match ctrl_def {
+        Some(ir::InstructionData::IntCompare {
+            opcode: ir::Opcode::Icmp,
+            cond,
+            args,
+        }) if is_wide(pos.func.dfg.value_type(args[0])) => {
+            (cond, pos.ins().ifcmp(args[0], args[1]))
+        }
+        Some(ir::InstructionData::IntCompareImm {
+            opcode: ir::Opcode::IcmpImm,
+            cond,
+            arg,
+            imm,
+        }) if is_wide(pos.func.dfg.value_type(arg)) => (cond, pos.ins().ifcmp_imm(arg, imm)),
+        _ => {
+            // Test `ctrl` against zero.
+            let ctrl_ty = pos.func.dfg.value_type(ctrl);
+            let ctrl = if ctrl_ty.is_bool() {
+                pos.ins().bint(ir::types::I32, ctrl)
+            } else if !is_wide(ctrl_ty) {
+                pos.ins().uextend(ir::types::I32, ctrl)
+            } else {
+                ctrl
+            };
+            (IntCC::NotEqual, pos.ins().ifcmp_imm(ctrl, 0))
+        }
+    };
+    pos.func
+        .dfg
+        .replace(inst)
+        .selectif(ty, cond, flags, tval, fval);
+}
+
 /// Expand the `fmin` and `fmax` instructions using the x86 `x86_fmin` and `x86_fmax`
 /// instructions.
 fn expand_minmax(
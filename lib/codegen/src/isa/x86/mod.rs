@@ -13,7 +13,7 @@ use binemit::{emit_function, MemoryCodeSink};
 use ir;
 use isa::enc_tables::{self as shared_enc_tables, lookup_enclist, Encodings};
 use isa::Builder as IsaBuilder;
-use isa::{EncInfo, RegClass, RegInfo, TargetIsa};
+use isa::{EncInfo, Peephole, RegClass, RegInfo, TargetIsa};
 use regalloc;
 use result::CodegenResult;
 use std::boxed::Box;
@@ -105,6 +105,10 @@ impl TargetIsa for Isa {
         )
     }
 
+    fn peepholes(&self) -> Peephole {
+        enc_tables::x86_simplify
+    }
+
     fn legalize_signature(&self, sig: &mut ir::Signature, current: bool) {
         abi::legalize_signature(sig, &self.triple, current)
     }
@@ -114,7 +118,7 @@ impl TargetIsa for Isa {
     }
 
     fn allocatable_registers(&self, func: &ir::Function) -> regalloc::RegisterSet {
-        abi::allocatable_registers(func, &self.triple)
+        abi::allocatable_registers(func, &self.triple, &self.isa_flags)
     }
 
     #[cfg(feature = "testing_hooks")]
@@ -134,7 +138,7 @@ impl TargetIsa for Isa {
 
     fn prologue_epilogue(&self, func: &mut ir::Function) -> CodegenResult<()> {
         let _tt = timing::prologue_epilogue();
-        abi::prologue_epilogue(func, self)
+        abi::prologue_epilogue(func, self, &self.isa_flags)
     }
 }
 
@@ -14,7 +14,7 @@ pub fn expand_heap_addr(
     inst: ir::Inst,
     func: &mut ir::Function,
     cfg: &mut ControlFlowGraph,
-    _isa: &TargetIsa,
+    isa: &TargetIsa,
 ) {
     // Unpack the instruction.
     let (heap, offset, access_size) = match func.dfg[inst] {
@@ -30,16 +30,37 @@ pub fn expand_heap_addr(
         _ => panic!("Wanted heap_addr: {}", func.dfg.display_inst(inst, None)),
     };
 
+    let spectre = isa.flags().enable_spectre_mitigation();
     match func.heaps[heap].style {
         ir::HeapStyle::Dynamic { bound_gv } => {
-            dynamic_addr(inst, heap, offset, access_size, bound_gv, func)
-        }
-        ir::HeapStyle::Static { bound } => {
-            static_addr(inst, heap, offset, access_size, bound.into(), func, cfg)
+            dynamic_addr(inst, heap, offset, access_size, bound_gv, spectre, func)
         }
+        ir::HeapStyle::Static { bound } => static_addr(
+            inst,
+            heap,
+            offset,
+            access_size,
+            bound.into(),
+            spectre,
+            func,
+            cfg,
+        ),
     }
 }
 
+/// Expand a `heap_addr` instruction into its address computation only, without the bounds check.
+///
+/// This is used when the accessed range is already known to be in bounds.
+pub fn expand_unchecked_heap_addr(inst: ir::Inst, func: &mut ir::Function) {
+    let (heap, offset) = match func.dfg[inst] {
+        ir::InstructionData::HeapAddr { heap, arg, .. } => (heap, arg),
+        _ => panic!("Wanted heap_addr: {}", func.dfg.display_inst(inst, None)),
+    };
+    let offset_ty = func.dfg.value_type(offset);
+    let addr_ty = func.dfg.value_type(func.dfg.first_result(inst));
+    compute_addr(inst, heap, addr_ty, offset, offset_ty, None, func);
+}
+
 /// Expand a `heap_addr` for a dynamic heap.
 fn dynamic_addr(
     inst: ir::Inst,
@@ -47,6 +68,7 @@ fn dynamic_addr(
     offset: ir::Value,
     access_size: u32,
     bound_gv: ir::GlobalValue,
+    spectre: bool,
     func: &mut ir::Function,
 ) {
     let access_size = i64::from(access_size);
@@ -58,31 +80,48 @@ fn dynamic_addr(
 
     // Start with the bounds check. Trap if `offset + access_size > bound`.
     let bound = pos.ins().global_value(offset_ty, bound_gv);
-    let oob;
-    if access_size == 1 {
+    let mut overflow = None;
+    let (cc, lhs, rhs) = if access_size == 1 {
         // `offset > bound - 1` is the same as `offset >= bound`.
-        oob = pos
-            .ins()
-            .icmp(IntCC::UnsignedGreaterThanOrEqual, offset, bound);
+        (IntCC::UnsignedGreaterThanOrEqual, offset, bound)
     } else if access_size <= min_size {
         // We know that bound >= min_size, so here we can compare `offset > bound - access_size`
         // without wrapping.
         let adj_bound = pos.ins().iadd_imm(bound, -access_size);
-        oob = pos
-            .ins()
-            .icmp(IntCC::UnsignedGreaterThan, offset, adj_bound);
+        (IntCC::UnsignedGreaterThan, offset, adj_bound)
     } else {
         // We need an overflow check for the adjusted offset.
         let access_size_val = pos.ins().iconst(offset_ty, access_size);
-        let (adj_offset, overflow) = pos.ins().iadd_cout(offset, access_size_val);
-        pos.ins().trapnz(overflow, ir::TrapCode::HeapOutOfBounds);
-        oob = pos
-            .ins()
-            .icmp(IntCC::UnsignedGreaterThan, adj_offset, bound);
-    }
+        let (adj_offset, carry) = pos.ins().iadd_cout(offset, access_size_val);
+        pos.ins().trapnz(carry, ir::TrapCode::HeapOutOfBounds);
+        overflow = Some(carry);
+        (IntCC::UnsignedGreaterThan, adj_offset, bound)
+    };
+    let oob = pos.ins().icmp(cc, lhs, rhs);
     pos.ins().trapnz(oob, ir::TrapCode::HeapOutOfBounds);
 
-    compute_addr(inst, heap, addr_ty, offset, offset_ty, pos.func);
+    let spectre_oob = match (spectre, overflow) {
+        (false, _) => None,
+        (true, None) => Some((cc, lhs, rhs)),
+        (true, Some(carry)) => {
+            // The adjusted offset wraps around when the addition overflows, so a mispredicted
+            // overflow trap could let it pass the comparison. Include the carry in the clamp.
+            let carry = pos.ins().bint(offset_ty, carry);
+            let oob = pos.ins().bint(offset_ty, oob);
+            let any_oob = pos.ins().bor(carry, oob);
+            let zero = pos.ins().iconst(offset_ty, 0);
+            Some((IntCC::NotEqual, any_oob, zero))
+        }
+    };
+    compute_addr(
+        inst,
+        heap,
+        addr_ty,
+        offset,
+        offset_ty,
+        spectre_oob,
+        pos.func,
+    );
 }
 
 /// Expand a `heap_addr` for a static heap.
@@ -92,6 +131,7 @@ fn static_addr(
     offset: ir::Value,
     access_size: u32,
     bound: i64,
+    spectre: bool,
     func: &mut ir::Function,
     cfg: &mut ControlFlowGraph,
 ) {
@@ -121,29 +161,46 @@ fn static_addr(
 
     // We may be able to omit the check entirely for 32-bit offsets if the heap bound is 4 GB or
     // more.
+    let mut spectre_oob = None;
     if offset_ty != ir::types::I32 || limit < 0xffff_ffff {
-        let oob = if limit & 1 == 1 {
+        let (cc, imm) = if limit & 1 == 1 {
             // Prefer testing `offset >= limit - 1` when limit is odd because an even number is
             // likely to be a convenient constant on ARM and other RISC architectures.
-            pos.ins()
-                .icmp_imm(IntCC::UnsignedGreaterThanOrEqual, offset, limit - 1)
+            (IntCC::UnsignedGreaterThanOrEqual, limit - 1)
         } else {
-            pos.ins()
-                .icmp_imm(IntCC::UnsignedGreaterThan, offset, limit)
+            (IntCC::UnsignedGreaterThan, limit)
         };
+        let oob = pos.ins().icmp_imm(cc, offset, imm);
         pos.ins().trapnz(oob, ir::TrapCode::HeapOutOfBounds);
+        if spectre {
+            let imm = pos.ins().iconst(offset_ty, imm);
+            spectre_oob = Some((cc, offset, imm));
+        }
     }
 
-    compute_addr(inst, heap, addr_ty, offset, offset_ty, pos.func);
+    compute_addr(
+        inst,
+        heap,
+        addr_ty,
+        offset,
+        offset_ty,
+        spectre_oob,
+        pos.func,
+    );
 }
 
 /// Emit code for the base address computation of a `heap_addr` instruction.
+///
+/// With `spectre_oob`, the comparison that holds when the access is out of bounds, the address is
+/// replaced by zero when the comparison holds. This prevents speculatively executed accesses from
+/// reaching out of bounds after a mispredicted bounds check.
 fn compute_addr(
     inst: ir::Inst,
     heap: ir::Heap,
     addr_ty: ir::Type,
     mut offset: ir::Value,
     offset_ty: ir::Type,
+    spectre_oob: Option<(IntCC, ir::Value, ir::Value)>,
     func: &mut ir::Function,
 ) {
     let mut pos = FuncCursor::new(func).at_inst(inst);
@@ -157,5 +214,18 @@ fn compute_addr(
     // Add the heap base address base
     let base_gv = pos.func.heaps[heap].base;
     let base = pos.ins().global_value(addr_ty, base_gv);
-    pos.func.dfg.replace(inst).iadd(base, offset);
+    match spectre_oob {
+        None => {
+            pos.func.dfg.replace(inst).iadd(base, offset);
+        }
+        Some((cc, lhs, rhs)) => {
+            let addr = pos.ins().iadd(base, offset);
+            let zero = pos.ins().iconst(addr_ty, 0);
+            let flags = pos.ins().ifcmp(lhs, rhs);
+            pos.func
+                .dfg
+                .replace(inst)
+                .selectif(addr_ty, cc, flags, zero, addr);
+        }
+    }
 }
@@ -32,6 +32,7 @@ mod table;
 use self::call::expand_call;
 use self::globalvalue::expand_global_value;
 use self::heap::expand_heap_addr;
+pub use self::heap::expand_unchecked_heap_addr;
 use self::libcall::expand_as_libcall;
 use self::table::expand_table_addr;
 
@@ -146,32 +147,42 @@ fn expand_cond_trap(
         _ => panic!("Expected cond trap: {}", func.dfg.display_inst(inst, None)),
     };
 
-    // Split the EBB after `inst`:
+    // Split the EBB after `inst`, and move the trap to a cold EBB at the end of the function:
     //
     //     trapnz arg
     //
     // Becomes:
     //
-    //     brz arg, new_ebb
-    //     trap
+    //     brnz arg, trap_ebb
+    //     jump new_ebb
     //   new_ebb:
+    //     ...
+    //   trap_ebb cold:
+    //     trap
     //
     let old_ebb = func.layout.pp_ebb(inst);
     let new_ebb = func.dfg.make_ebb();
+    let trap_ebb = func.dfg.make_ebb();
     if trapz {
-        func.dfg.replace(inst).brnz(arg, new_ebb, &[]);
+        func.dfg.replace(inst).brz(arg, trap_ebb, &[]);
     } else {
-        func.dfg.replace(inst).brz(arg, new_ebb, &[]);
+        func.dfg.replace(inst).brnz(arg, trap_ebb, &[]);
     }
 
     let mut pos = FuncCursor::new(func).after_inst(inst);
     pos.use_srcloc(inst);
-    pos.ins().trap(code);
+    pos.ins().jump(new_ebb, &[]);
     pos.insert_ebb(new_ebb);
 
+    pos.func.layout.append_ebb(trap_ebb);
+    pos.func.ebb_frequencies[trap_ebb] = ir::EbbFrequency::Cold;
+    pos.goto_bottom(trap_ebb);
+    pos.ins().trap(code);
+
     // Finally update the CFG.
     cfg.recompute_ebb(pos.func, old_ebb);
     cfg.recompute_ebb(pos.func, new_ebb);
+    cfg.recompute_ebb(pos.func, trap_ebb);
 }
 
 /// Jump tables.
@@ -276,7 +287,7 @@ fn expand_br_table_conds(
 ///
 /// Conditional moves are available in some ISAs for some register classes. The remaining selects
 /// are handled by a branch.
-fn expand_select(
+pub fn expand_select(
     inst: ir::Inst,
     func: &mut ir::Function,
     cfg: &mut ControlFlowGraph,
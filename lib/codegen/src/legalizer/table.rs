@@ -15,7 +15,7 @@ pub fn expand_table_addr(
     inst: ir::Inst,
     func: &mut ir::Function,
     _cfg: &mut ControlFlowGraph,
-    _isa: &TargetIsa,
+    isa: &TargetIsa,
 ) {
     // Unpack the instruction.
     let (table, index, element_offset) = match func.dfg[inst] {
@@ -31,7 +31,8 @@ pub fn expand_table_addr(
         _ => panic!("Wanted table_addr: {}", func.dfg.display_inst(inst, None)),
     };
 
-    dynamic_addr(inst, table, index, element_offset, func);
+    let spectre = isa.flags().enable_spectre_mitigation();
+    dynamic_addr(inst, table, index, element_offset, spectre, func);
 }
 
 /// Expand a `table_addr` for a dynamic table.
@@ -40,6 +41,7 @@ fn dynamic_addr(
     table: ir::Table,
     index: ir::Value,
     element_offset: Offset32,
+    spectre: bool,
     func: &mut ir::Function,
 ) {
     let bound_gv = func.tables[table].bound_gv;
@@ -57,6 +59,11 @@ fn dynamic_addr(
         .icmp(IntCC::UnsignedGreaterThanOrEqual, index, bound);
     pos.ins().trapnz(oob, ir::TrapCode::TableOutOfBounds);
 
+    let spectre_oob = if spectre {
+        Some((IntCC::UnsignedGreaterThanOrEqual, index, bound))
+    } else {
+        None
+    };
     compute_addr(
         inst,
         table,
@@ -64,11 +71,15 @@ fn dynamic_addr(
         index,
         index_ty,
         element_offset,
+        spectre_oob,
         pos.func,
     );
 }
 
 /// Emit code for the base address computation of a `table_addr` instruction.
+///
+/// With `spectre_oob`, the comparison that holds when the index is out of bounds, the address is
+/// replaced by zero when the comparison holds.
 fn compute_addr(
     inst: ir::Inst,
     table: ir::Table,
@@ -76,6 +87,7 @@ fn compute_addr(
     mut index: ir::Value,
     index_ty: ir::Type,
     element_offset: Offset32,
+    spectre_oob: Option<(IntCC, ir::Value, ir::Value)>,
     func: &mut ir::Function,
 ) {
     let mut pos = FuncCursor::new(func).at_inst(inst);
@@ -105,7 +117,19 @@ fn compute_addr(
         offset = pos.ins().imul_imm(index, element_size);
     }
 
-    if element_offset == Offset32::new(0) {
+    if let Some((cc, lhs, rhs)) = spectre_oob {
+        let mut addr = pos.ins().iadd(base, offset);
+        if element_offset != Offset32::new(0) {
+            let imm: i64 = element_offset.into();
+            addr = pos.ins().iadd_imm(addr, imm);
+        }
+        let zero = pos.ins().iconst(addr_ty, 0);
+        let flags = pos.ins().ifcmp(lhs, rhs);
+        pos.func
+            .dfg
+            .replace(inst)
+            .selectif(addr_ty, cc, flags, zero, addr);
+    } else if element_offset == Offset32::new(0) {
         pos.func.dfg.replace(inst).iadd(base, offset);
     } else {
         let imm: i64 = element_offset.into();
@@ -78,7 +78,10 @@ pub mod cfg_printer;
 pub mod cursor;
 pub mod dbg;
 pub mod dominator_tree;
+pub mod edge_profile;
 pub mod flowgraph;
+pub mod induction_variables;
+pub mod inline;
 pub mod ir;
 pub mod isa;
 pub mod loop_analysis;
@@ -91,26 +94,37 @@ pub mod write;
 pub use entity::packed_option;
 
 mod abi;
+mod alias_analysis;
 mod bitset;
+mod block_placement;
+mod bounds_checks;
 mod constant_hash;
 mod context;
+mod dead_stores;
 mod dce;
 mod divconst_magic_numbers;
 mod fx;
+mod if_conversion;
 mod iterators;
 mod legalizer;
 mod licm;
+mod loop_unrolling;
 mod nan_canonicalization;
 mod partition_slice;
+mod peepholes;
 mod postopt;
 mod predicates;
+mod redundant_loads;
 mod ref_slice;
 mod regalloc;
 mod result;
+mod scheduling;
 mod scoped_hash_map;
 mod simple_gvn;
 mod simple_preopt;
+mod stack_coloring;
 mod stack_layout;
+mod strength_reduction;
 mod topo_order;
 mod unreachable_code;
 
@@ -70,6 +70,22 @@ impl LoopAnalysis {
         self.loops[lp].parent.expand()
     }
 
+    /// Returns the innermost loop containing `ebb`, if any.
+    pub fn innermost_loop(&self, ebb: Ebb) -> Option<Loop> {
+        self.ebb_loop_map[ebb].expand()
+    }
+
+    /// Returns the number of loops containing `ebb`.
+    pub fn loop_depth(&self, ebb: Ebb) -> usize {
+        let mut depth = 0;
+        let mut finger = self.innermost_loop(ebb);
+        while let Some(lp) = finger {
+            depth += 1;
+            finger = self.loop_parent(lp);
+        }
+        depth
+    }
+
     /// Determine if an Ebb belongs to a loop by running a finger along the loop tree.
     ///
     /// Returns `true` if `ebb` is in loop `lp`.
@@ -285,6 +301,9 @@ mod tests {
         assert_eq!(loop_analysis.is_in_loop(ebb2, loops[0]), true);
         assert_eq!(loop_analysis.is_in_loop(ebb3, loops[0]), true);
         assert_eq!(loop_analysis.is_in_loop(ebb0, loops[1]), false);
+        assert_eq!(loop_analysis.innermost_loop(ebb2), Some(loops[1]));
+        assert_eq!(loop_analysis.loop_depth(ebb0), 1);
+        assert_eq!(loop_analysis.loop_depth(ebb2), 2);
     }
 
     #[test]
@@ -0,0 +1,545 @@
+//! Loop unrolling and peeling.
+//!
+//! This pass handles counted loops consisting of a single EBB, where the loop exits when an
+//! induction variable stepped by a constant reaches a loop-invariant bound:
+//!
+//! ```text
+//! ebb1(v1: i32, v2: f64):
+//!     ...
+//!     v3 = iadd_imm v1, 1
+//!     v4 = icmp ult v3, v10
+//!     brnz v4, ebb1(v3, v5)
+//!     jump ebb2
+//! ```
+//!
+//! The body is copied `loop_unroll_factor` times into a new loop in front of the original one,
+//! with the exit tests removed. On each iteration, the new loop checks that enough iterations
+//! remain before running the copies, and branches to the original loop otherwise. The original
+//! loop is kept to run the remaining iterations, and it is the only place the loop exits from,
+//! so the values it defines are still available after the loop.
+//!
+//! With `loop_peel_first_iteration`, the first iteration is copied in front of the loop in the
+//! same way. When the trip count is a constant no larger than the unroll factor, all the
+//! iterations but the last one are copied in front of the loop, and no checks are needed.
+
+use cursor::{Cursor, FuncCursor};
+use dominator_tree::DominatorTree;
+use flowgraph::{BasicBlock, ControlFlowGraph};
+use fx::FxHashMap;
+use ir::condcodes::{CondCode, IntCC};
+use ir::instructions::BranchInfo;
+use ir::{Ebb, Function, Inst, InstBuilder, InstructionData, Opcode, Value, ValueDef, ValueList};
+use loop_analysis::{Loop, LoopAnalysis};
+use settings::Flags;
+use std::vec::Vec;
+use timing;
+
+/// The maximum number of instructions in the body of an unrolled loop.
+const MAX_UNROLLED_SIZE: usize = 256;
+
+/// Unroll and peel the counted loops in `func`, as configured by `flags`.
+///
+/// Changes the CFG and domtree in-place, and invalidates `loop_analysis`.
+pub fn do_loop_unrolling(
+    func: &mut Function,
+    cfg: &mut ControlFlowGraph,
+    domtree: &mut DominatorTree,
+    loop_analysis: &LoopAnalysis,
+    flags: &Flags,
+) {
+    let _tt = timing::unroll();
+    debug_assert!(cfg.is_valid());
+    debug_assert!(loop_analysis.is_valid());
+
+    let factor = usize::from(flags.loop_unroll_factor());
+    let peel = flags.loop_peel_first_iteration();
+    if factor < 2 && !peel {
+        return;
+    }
+
+    let loops: Vec<CountedLoop> = loop_analysis
+        .loops()
+        .filter_map(|lp| CountedLoop::new(func, cfg, loop_analysis, lp))
+        .collect();
+    if loops.is_empty() {
+        return;
+    }
+    for lp in &loops {
+        lp.unroll(func, factor, peel);
+    }
+
+    cfg.compute(func);
+    domtree.compute(func, cfg);
+}
+
+/// The bound an induction variable is compared to.
+#[derive(Clone, Copy)]
+enum Bound {
+    Value(Value),
+    Imm(i64),
+}
+
+/// A loop that can be unrolled.
+struct CountedLoop {
+    /// The header, which is the only EBB in the loop.
+    header: Ebb,
+    /// The instructions of the loop body, which are copied when unrolling. This excludes the two
+    /// branches of the loop.
+    body: Vec<Inst>,
+    /// The arguments passed back to the header.
+    back_args: Vec<Value>,
+    /// The branches entering the loop.
+    entries: Vec<Inst>,
+    /// The index of the induction variable among the header's parameters.
+    iv: usize,
+    /// The step added to the induction variable on each iteration.
+    step: i64,
+    /// The number of steps already added to the induction variable when it is compared to the
+    /// bound. This is 1 when the loop is tested after incrementing it, and 0 otherwise.
+    offset: i64,
+    /// The loop continues while `cond(iv + offset * step, bound)` holds.
+    cond: IntCC,
+    bound: Bound,
+}
+
+impl CountedLoop {
+    /// Recognize `lp` as a counted loop.
+    fn new(
+        func: &Function,
+        cfg: &ControlFlowGraph,
+        loop_analysis: &LoopAnalysis,
+        lp: Loop,
+    ) -> Option<Self> {
+        let header = loop_analysis.loop_header(lp);
+        let dfg = &func.dfg;
+        if func.layout.entry_block() == Some(header)
+            || func
+                .layout
+                .ebbs()
+                .any(|ebb| ebb != header && loop_analysis.is_in_loop(ebb, lp))
+        {
+            return None;
+        }
+
+        // The loop must end with a conditional branch followed by a jump. One of them goes back
+        // to the header, and the other one exits the loop.
+        let branches: Vec<Inst> = func
+            .layout
+            .ebb_insts(header)
+            .filter(|&inst| dfg[inst].opcode().is_branch())
+            .collect();
+        if branches.len() != 2 || func.layout.last_inst(header) != Some(branches[1]) {
+            return None;
+        }
+        let (test, terminator) = (branches[0], branches[1]);
+        let (test_dest, test_args) = match dfg.analyze_branch(test) {
+            BranchInfo::SingleDest(dest, args) => (dest, args),
+            _ => return None,
+        };
+        let (term_dest, term_args) = match dfg.analyze_branch(terminator) {
+            BranchInfo::SingleDest(dest, args) => (dest, args),
+            _ => return None,
+        };
+        let (exits_when_taken, back_args) = if test_dest == header && term_dest != header {
+            (false, test_args)
+        } else if term_dest == header && test_dest != header {
+            (true, term_args)
+        } else {
+            return None;
+        };
+        let back_args: Vec<Value> = back_args
+            .iter()
+            .map(|&arg| dfg.resolve_aliases(arg))
+            .collect();
+
+        // Find the condition under which the loop continues.
+        let defined_in_loop = |value: Value| match dfg.value_def(value) {
+            ValueDef::Result(inst, _) => func.layout.inst_ebb(inst) == Some(header),
+            ValueDef::Param(ebb, _) => ebb == header,
+        };
+        let (mut cond, lhs, rhs, negated) = match dfg[test] {
+            InstructionData::Branch { opcode, .. } => {
+                let value = dfg.resolve_aliases(dfg.inst_args(test)[0]);
+                let negated = opcode == Opcode::Brz;
+                let inst = match dfg.value_def(value) {
+                    ValueDef::Result(inst, _) if defined_in_loop(value) => inst,
+                    _ => return None,
+                };
+                match dfg[inst] {
+                    InstructionData::IntCompare { cond, args, .. } => (
+                        cond,
+                        dfg.resolve_aliases(args[0]),
+                        Bound::Value(dfg.resolve_aliases(args[1])),
+                        negated,
+                    ),
+                    InstructionData::IntCompareImm { cond, arg, imm, .. } => (
+                        cond,
+                        dfg.resolve_aliases(arg),
+                        Bound::Imm(imm.into()),
+                        negated,
+                    ),
+                    _ => return None,
+                }
+            }
+            InstructionData::BranchIcmp { cond, .. } => {
+                let args = dfg.inst_args(test);
+                (
+                    cond,
+                    dfg.resolve_aliases(args[0]),
+                    Bound::Value(dfg.resolve_aliases(args[1])),
+                    false,
+                )
+            }
+            _ => return None,
+        };
+        if negated != exits_when_taken {
+            cond = cond.inverse();
+        }
+
+        // One side of the comparison must be the induction variable, and the other one must be
+        // loop-invariant.
+        let params = dfg.ebb_params(header);
+        let induction = |value: Value| {
+            for (index, &param) in params.iter().enumerate() {
+                let step: i64 = match dfg.value_def(back_args[index]) {
+                    ValueDef::Result(inst, _) if defined_in_loop(back_args[index]) => {
+                        match dfg[inst] {
+                            InstructionData::BinaryImm {
+                                opcode: Opcode::IaddImm,
+                                arg,
+                                imm,
+                            } if dfg.resolve_aliases(arg) == param => imm.into(),
+                            _ => continue,
+                        }
+                    }
+                    _ => continue,
+                };
+                if step == 0 {
+                    continue;
+                }
+                if value == param {
+                    return Some((index, step, 0));
+                } else if value == back_args[index] {
+                    return Some((index, step, 1));
+                }
+            }
+            None
+        };
+        let ((iv, step, offset), bound) = match (induction(lhs), rhs) {
+            (Some(found), rhs) => (found, rhs),
+            (None, Bound::Value(rhs)) => {
+                cond = cond.reverse();
+                (induction(rhs)?, Bound::Value(lhs))
+            }
+            (None, Bound::Imm(_)) => return None,
+        };
+        if let Bound::Value(bound) = bound {
+            if defined_in_loop(bound) {
+                return None;
+            }
+        }
+        let ty = dfg.value_type(params[iv]);
+        if !ty.is_int() || ty.bits() > 64 {
+            return None;
+        }
+
+        // The condition must fail once, and only once, the induction variable passes the bound.
+        let monotonic = match cond {
+            IntCC::UnsignedLessThan
+            | IntCC::UnsignedLessThanOrEqual
+            | IntCC::SignedLessThan
+            | IntCC::SignedLessThanOrEqual => step > 0,
+            IntCC::UnsignedGreaterThan
+            | IntCC::UnsignedGreaterThanOrEqual
+            | IntCC::SignedGreaterThan
+            | IntCC::SignedGreaterThanOrEqual => step < 0,
+            _ => false,
+        };
+        if !monotonic {
+            return None;
+        }
+
+        let mut entries = Vec::new();
+        for BasicBlock { inst, .. } in cfg.pred_iter(header) {
+            if inst == test || inst == terminator {
+                continue;
+            }
+            match dfg.analyze_branch(inst) {
+                BranchInfo::SingleDest(_, _) => entries.push(inst),
+                _ => return None,
+            }
+        }
+        if entries.is_empty() {
+            return None;
+        }
+
+        let body = func
+            .layout
+            .ebb_insts(header)
+            .filter(|&inst| inst != test && inst != terminator)
+            .collect();
+        Some(Self {
+            header,
+            body,
+            back_args,
+            entries,
+            iv,
+            step,
+            offset,
+            cond,
+            bound,
+        })
+    }
+
+    /// Unroll the loop `factor` times, and peel its first iteration if `peel` is set.
+    fn unroll(&self, func: &mut Function, factor: usize, peel: bool) {
+        let factor = factor.min(MAX_UNROLLED_SIZE / self.body.len().max(1));
+        let trips = self.constant_trip_count(func, factor as u64 + 1);
+        if trips == Some(0) {
+            // The loop only runs once.
+            return;
+        }
+
+        let mut target = self.header;
+        match trips {
+            Some(trips) if trips <= factor as u64 => {
+                target = self.make_prologue(func, target, trips as usize, None);
+            }
+            _ => {
+                if factor > 1 {
+                    if let Some(steps) = self.guard_steps(func, factor) {
+                        target = self.make_unrolled_loop(func, factor, steps);
+                    }
+                }
+                if peel {
+                    // The guard of a single iteration is the exit test itself, which is known to
+                    // pass if the trip count is.
+                    let guard = if trips.is_some() {
+                        None
+                    } else {
+                        Some(self.offset * self.step)
+                    };
+                    target = self.make_prologue(func, target, 1, guard);
+                }
+            }
+        }
+
+        for &inst in &self.entries {
+            *func.dfg[inst].branch_destination_mut().unwrap() = target;
+        }
+    }
+
+    /// The number of steps added to the induction variable by the guard of an unrolled loop, if
+    /// it can't overflow.
+    fn guard_steps(&self, func: &Function, factor: usize) -> Option<i64> {
+        let bits = func
+            .dfg
+            .value_type(func.dfg.ebb_params(self.header)[self.iv])
+            .bits();
+        let steps = (factor as i64 - 1 + self.offset).checked_mul(self.step)?;
+        if bits < 64 && steps.checked_abs()? >= 1 << (bits - 1) {
+            None
+        } else {
+            Some(steps)
+        }
+    }
+
+    /// If the loop has a single entry, passing a constant initial value for the induction
+    /// variable, and the bound is a constant, count the number of times the exit test passes.
+    /// Counting stops at `limit`.
+    fn constant_trip_count(&self, func: &Function, limit: u64) -> Option<u64> {
+        let dfg = &func.dfg;
+        let constant = |value: Value| match dfg.value_def(dfg.resolve_aliases(value)) {
+            ValueDef::Result(inst, _) => match dfg[inst] {
+                InstructionData::UnaryImm {
+                    opcode: Opcode::Iconst,
+                    imm,
+                } => Some(imm.into()),
+                _ => None,
+            },
+            _ => None,
+        };
+        if self.entries.len() != 1 {
+            return None;
+        }
+        let entry = self.entries[0];
+        let args = dfg.inst_variable_args(entry);
+        let init: i64 = constant(args[self.iv])?;
+        let bound = match self.bound {
+            Bound::Value(value) => constant(value)?,
+            Bound::Imm(imm) => imm,
+        };
+
+        let bits = dfg.value_type(args[self.iv]).bits();
+        let mut iv = init;
+        for trips in 0..limit {
+            let value = iv.wrapping_add(self.offset.wrapping_mul(self.step));
+            if !icmp(self.cond, bits, value, bound) {
+                return Some(trips);
+            }
+            iv = iv.wrapping_add(self.step);
+        }
+        Some(limit)
+    }
+
+    /// Make a new EBB in front of `before`, with the same parameters as the header.
+    fn make_ebb(&self, func: &mut Function, before: Ebb) -> Ebb {
+        let ebb = func.dfg.make_ebb();
+        for i in 0..func.dfg.num_ebb_params(self.header) {
+            let ty = func.dfg.value_type(func.dfg.ebb_params(self.header)[i]);
+            func.dfg.append_ebb_param(ebb, ty);
+        }
+        func.layout.insert_ebb(ebb, before);
+        ebb
+    }
+
+    /// Make an EBB running `count` iterations of the loop, and then jumping to `next`.
+    ///
+    /// If `guard` is set, the EBB first checks that the exit test passes when the induction
+    /// variable is advanced by `guard`, and branches to the original loop otherwise.
+    fn make_prologue(
+        &self,
+        func: &mut Function,
+        next: Ebb,
+        count: usize,
+        guard: Option<i64>,
+    ) -> Ebb {
+        let ebb = self.make_ebb(func, next);
+        let mut pos = FuncCursor::new(func).at_bottom(ebb);
+        let params = pos.func.dfg.ebb_params(ebb).to_vec();
+        if let Some(steps) = guard {
+            self.insert_guard(&mut pos, &params, steps, false);
+        }
+        let values = self.insert_copies(&mut pos, params, count);
+        pos.ins().jump(next, &values);
+        ebb
+    }
+
+    /// Make a loop running `factor` iterations of the loop at a time, as long as the exit test
+    /// passes when the induction variable is advanced by `steps`.
+    fn make_unrolled_loop(&self, func: &mut Function, factor: usize, steps: i64) -> Ebb {
+        let ebb = self.make_ebb(func, self.header);
+        let mut pos = FuncCursor::new(func).at_bottom(ebb);
+        let params = pos.func.dfg.ebb_params(ebb).to_vec();
+        self.insert_guard(&mut pos, &params, steps, true);
+        let values = self.insert_copies(&mut pos, params, factor);
+        pos.ins().jump(ebb, &values);
+        ebb
+    }
+
+    /// Insert a branch to the original loop unless the exit test passes with the induction
+    /// variable in `values` advanced by `steps`.
+    ///
+    /// If `no_overflow` is set, also branch to the original loop if advancing the induction
+    /// variable overflows. This makes the check imply that all the exit tests up to that point
+    /// pass, since the condition is monotonic.
+    fn insert_guard(&self, pos: &mut FuncCursor, values: &[Value], steps: i64, no_overflow: bool) {
+        let iv = values[self.iv];
+        let value = if steps == 0 {
+            iv
+        } else {
+            pos.ins().iadd_imm(iv, steps)
+        };
+        let mut passes = match self.bound {
+            Bound::Value(bound) => pos.ins().icmp(self.cond, value, bound),
+            Bound::Imm(imm) => pos.ins().icmp_imm(self.cond, value, imm),
+        };
+        if no_overflow && steps != 0 {
+            let signed = match self.cond {
+                IntCC::SignedLessThan
+                | IntCC::SignedLessThanOrEqual
+                | IntCC::SignedGreaterThan
+                | IntCC::SignedGreaterThanOrEqual => true,
+                _ => false,
+            };
+            let advanced = match (signed, steps > 0) {
+                (true, true) => IntCC::SignedGreaterThan,
+                (true, false) => IntCC::SignedLessThan,
+                (false, true) => IntCC::UnsignedGreaterThan,
+                (false, false) => IntCC::UnsignedLessThan,
+            };
+            let no_wrap = pos.ins().icmp(advanced, value, iv);
+            passes = pos.ins().band(passes, no_wrap);
+        }
+        pos.ins().brz(passes, self.header, values);
+    }
+
+    /// Insert `count` copies of the loop body, without the exit test, starting with the header
+    /// parameters set to `values`. Returns the values passed back to the header by the last copy.
+    fn insert_copies(
+        &self,
+        pos: &mut FuncCursor,
+        mut values: Vec<Value>,
+        count: usize,
+    ) -> Vec<Value> {
+        let mut map = FxHashMap();
+        for _ in 0..count {
+            map.clear();
+            for (&param, &value) in pos.func.dfg.ebb_params(self.header).iter().zip(&values) {
+                map.insert(param, value);
+            }
+            for &inst in &self.body {
+                let mut data = pos.func.dfg[inst].clone();
+                if data.take_value_list().is_some() {
+                    let args = pos.func.dfg.inst_args(inst).to_vec();
+                    data.put_value_list(ValueList::from_slice(
+                        &args,
+                        &mut pos.func.dfg.value_lists,
+                    ));
+                }
+                let ctrl_typevar = pos.func.dfg.ctrl_typevar(inst);
+                let new_inst = pos.func.dfg.make_inst(data);
+                pos.func.dfg.make_inst_results(new_inst, ctrl_typevar);
+
+                let args: Vec<Value> = pos
+                    .func
+                    .dfg
+                    .inst_args(new_inst)
+                    .iter()
+                    .map(|&arg| {
+                        let arg = pos.func.dfg.resolve_aliases(arg);
+                        *map.get(&arg).unwrap_or(&arg)
+                    })
+                    .collect();
+                pos.func.dfg.inst_args_mut(new_inst).copy_from_slice(&args);
+                for (&result, &new_result) in pos
+                    .func
+                    .dfg
+                    .inst_results(inst)
+                    .iter()
+                    .zip(pos.func.dfg.inst_results(new_inst))
+                {
+                    map.insert(result, new_result);
+                }
+
+                pos.func.srclocs[new_inst] = pos.func.srclocs[inst];
+                pos.insert_inst(new_inst);
+            }
+            values = self
+                .back_args
+                .iter()
+                .map(|arg| *map.get(arg).unwrap_or(arg))
+                .collect();
+        }
+        values
+    }
+}
+
+/// Evaluate the integer comparison `cond` of the `bits`-bit integers `x` and `y`.
+fn icmp(cond: IntCC, bits: u16, x: i64, y: i64) -> bool {
+    let shift = 64 - u32::from(bits);
+    let (sx, sy) = ((x << shift) >> shift, (y << shift) >> shift);
+    let (ux, uy) = ((sx as u64) << shift, (sy as u64) << shift);
+    match cond {
+        IntCC::Equal => sx == sy,
+        IntCC::NotEqual => sx != sy,
+        IntCC::SignedLessThan => sx < sy,
+        IntCC::SignedGreaterThanOrEqual => sx >= sy,
+        IntCC::SignedGreaterThan => sx > sy,
+        IntCC::SignedLessThanOrEqual => sx <= sy,
+        IntCC::UnsignedLessThan => ux < uy,
+        IntCC::UnsignedGreaterThanOrEqual => ux >= uy,
+        IntCC::UnsignedGreaterThan => ux > uy,
+        IntCC::UnsignedLessThanOrEqual => ux <= uy,
+    }
+}
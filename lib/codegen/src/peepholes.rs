@@ -0,0 +1,41 @@
+//! Peephole rewrites.
+//!
+//! The rewrites are small patterns of instructions that can be replaced by cheaper equivalents.
+//! They are described by `XForm` patterns in `lib/codegen/meta-python/base/peepholes.py` and the
+//! ISA-specific `peepholes.py` files, and `gen_legalizer.py` turns them into matching functions.
+//!
+//! The shared rewrites only produce base instructions, so they can run before legalization on
+//! any target. ISA-specific rewrites can also produce instructions that are only legal on that
+//! ISA.
+
+use bitset::BitSet;
+use cursor::{Cursor, FuncCursor};
+use ir::{self, Function};
+use isa::TargetIsa;
+use timing;
+
+/// Apply the peephole rewrites for `isa` to every instruction in `func`.
+pub fn do_peepholes(func: &mut Function, isa: &TargetIsa) {
+    let _tt = timing::peepholes();
+    let peepholes = isa.peepholes();
+    let mut pos = FuncCursor::new(func);
+    while let Some(_ebb) = pos.next_ebb() {
+        // Keep track of the cursor position before the instruction being processed, so we can
+        // double back and try to rewrite the replacement too.
+        let mut prev_pos = pos.position();
+
+        while let Some(inst) = pos.next_inst() {
+            if peepholes(inst, pos.func, isa) {
+                pos.set_position(prev_pos);
+            } else {
+                prev_pos = pos.position();
+            }
+        }
+    }
+}
+
+// Include the rewrites that were generated by `gen_legalizer.py` from the `XForms` in
+// `lib/codegen/meta-python/base/peepholes.py`.
+//
+// Concretely, this defines the function `simplify()`.
+include!(concat!(env!("OUT_DIR"), "/peepholes.rs"));
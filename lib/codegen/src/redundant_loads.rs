@@ -0,0 +1,120 @@
+//! Redundant load elimination and store-to-load forwarding.
+//!
+//! A load is redundant if the same memory was loaded or stored earlier, and no instruction in
+//! between may have changed it. The load is then replaced by the value that was loaded or stored.
+//!
+//! For each load, this pass scans backwards through the instructions that are guaranteed to
+//! execute before it: the preceding instructions in its EBB, continuing into the predecessor when
+//! an EBB has only one. It uses the alias analysis to skip over stores that can't touch the loaded
+//! memory.
+
+use alias_analysis::{AliasAnalysis, MemoryAccess, MemoryEffect};
+use cursor::{Cursor, FuncCursor};
+use flowgraph::{BasicBlock, ControlFlowGraph};
+use ir::{Function, Inst, Opcode, Value};
+use timing;
+
+/// The maximum number of instructions to scan backwards from each load.
+const SCAN_LIMIT: usize = 100;
+
+/// Eliminate redundant loads in `func`.
+pub fn do_redundant_load_elim(func: &mut Function, cfg: &ControlFlowGraph) {
+    let _tt = timing::redundant_loads();
+    debug_assert!(cfg.is_valid());
+
+    let aa = AliasAnalysis::new(func);
+    let mut pos = FuncCursor::new(func);
+    while let Some(_ebb) = pos.next_ebb() {
+        while let Some(inst) = pos.next_inst() {
+            pos.func.dfg.resolve_aliases_in_arguments(inst);
+            let access = match aa.effect(pos.func, inst) {
+                MemoryEffect::Load(access) => access,
+                _ => continue,
+            };
+            if let Some(value) = available_value(&aa, pos.func, cfg, inst, &access) {
+                let result = pos.func.dfg.first_result(inst);
+                pos.func.dfg.clear_results(inst);
+                pos.func.dfg.change_to_alias(result, value);
+                pos.remove_inst_and_step_back();
+            }
+        }
+    }
+}
+
+/// Find a value which holds the result of the load `load`, which makes `access`.
+fn available_value(
+    aa: &AliasAnalysis,
+    func: &Function,
+    cfg: &ControlFlowGraph,
+    load: Inst,
+    access: &MemoryAccess,
+) -> Option<Value> {
+    let opcode = func.dfg[load].opcode();
+    let ty = func.dfg.ctrl_typevar(load);
+    let ebb = func.layout.inst_ebb(load);
+    let mut inst = load;
+    for _ in 0..SCAN_LIMIT {
+        inst = match func.layout.prev_inst(inst) {
+            Some(prev) => prev,
+            None => {
+                let branch = single_predecessor(func, cfg, inst)?;
+                // An unreachable loop can lead us back around to the load itself.
+                if func.layout.inst_ebb(branch) == ebb {
+                    return None;
+                }
+                branch
+            }
+        };
+        match aa.effect(func, inst) {
+            MemoryEffect::Load(ref other) => {
+                if other.same_location(access)
+                    && func.dfg[inst].opcode() == opcode
+                    && func.dfg.ctrl_typevar(inst) == ty
+                {
+                    return Some(func.dfg.first_result(inst));
+                }
+            }
+            MemoryEffect::Store(ref other) => {
+                if other.same_location(access) && is_plain_load(opcode) {
+                    let stored = func.dfg.inst_args(inst)[0];
+                    if is_plain_store(func.dfg[inst].opcode()) && func.dfg.value_type(stored) == ty
+                    {
+                        return Some(stored);
+                    }
+                }
+                if aa.may_alias(other, access) {
+                    return None;
+                }
+            }
+            ref effect => {
+                if aa.clobbers(effect, access) {
+                    return None;
+                }
+            }
+        }
+    }
+    None
+}
+
+/// If the EBB containing `inst` can only be entered from one branch, return that branch.
+fn single_predecessor(func: &Function, cfg: &ControlFlowGraph, inst: Inst) -> Option<Inst> {
+    let ebb = func.layout.inst_ebb(inst)?;
+    if func.layout.entry_block() == Some(ebb) {
+        return None;
+    }
+    let mut preds = cfg.pred_iter(ebb);
+    match (preds.next(), preds.next()) {
+        (Some(BasicBlock { inst: branch, .. }), None) => Some(branch),
+        _ => None,
+    }
+}
+
+/// Does `opcode` load a whole value without extending it?
+fn is_plain_load(opcode: Opcode) -> bool {
+    opcode == Opcode::Load || opcode == Opcode::StackLoad
+}
+
+/// Does `opcode` store a whole value without truncating it?
+fn is_plain_store(opcode: Opcode) -> bool {
+    opcode == Opcode::Store || opcode == Opcode::StackStore
+}
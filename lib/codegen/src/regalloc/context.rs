@@ -8,14 +8,18 @@ use dominator_tree::DominatorTree;
 use flowgraph::ControlFlowGraph;
 use ir::Function;
 use isa::TargetIsa;
+use loop_analysis::LoopAnalysis;
 use regalloc::coalescing::Coalescing;
 use regalloc::coloring::Coloring;
+use regalloc::linear_scan::LinearScan;
 use regalloc::live_value_tracker::LiveValueTracker;
 use regalloc::liveness::Liveness;
 use regalloc::reload::Reload;
 use regalloc::spilling::Spilling;
+use regalloc::splitting::Splitting;
 use regalloc::virtregs::VirtRegs;
 use result::CodegenResult;
+use settings::{OptLevel, Regalloc};
 use timing;
 use topo_order::TopoOrder;
 use verifier::{verify_context, verify_cssa, verify_liveness, verify_locations, VerifierErrors};
@@ -23,6 +27,8 @@ use verifier::{verify_context, verify_cssa, verify_liveness, verify_locations, V
 /// Persistent memory allocations for register allocation.
 pub struct Context {
     liveness: Liveness,
+    loop_analysis: LoopAnalysis,
+    splitting: Splitting,
     virtregs: VirtRegs,
     coalescing: Coalescing,
     topo: TopoOrder,
@@ -30,6 +36,7 @@ pub struct Context {
     spilling: Spilling,
     reload: Reload,
     coloring: Coloring,
+    linear_scan: LinearScan,
 }
 
 impl Context {
@@ -40,6 +47,8 @@ impl Context {
     pub fn new() -> Self {
         Self {
             liveness: Liveness::new(),
+            loop_analysis: LoopAnalysis::new(),
+            splitting: Splitting::new(),
             virtregs: VirtRegs::new(),
             coalescing: Coalescing::new(),
             topo: TopoOrder::new(),
@@ -47,12 +56,15 @@ impl Context {
             spilling: Spilling::new(),
             reload: Reload::new(),
             coloring: Coloring::new(),
+            linear_scan: LinearScan::new(),
         }
     }
 
     /// Clear all data structures in this context.
     pub fn clear(&mut self) {
         self.liveness.clear();
+        self.loop_analysis.clear();
+        self.splitting.clear();
         self.virtregs.clear();
         self.coalescing.clear();
         self.topo.clear();
@@ -60,6 +72,7 @@ impl Context {
         self.spilling.clear();
         self.reload.clear();
         self.coloring.clear();
+        self.linear_scan.clear();
     }
 
     /// Allocate registers in `func`.
@@ -78,6 +91,22 @@ impl Context {
 
         let mut errors = VerifierErrors::default();
 
+        // The linear scan allocator doesn't use any of the analyses below.
+        if isa.flags().regalloc() == Regalloc::LinearScan {
+            self.linear_scan.run(isa, func, domtree);
+
+            if isa.flags().enable_verifier() {
+                let ok = verify_context(func, cfg, domtree, isa, &mut errors).is_ok()
+                    && verify_locations(isa, func, None, &mut errors).is_ok();
+
+                if !ok {
+                    return Err(errors.into());
+                }
+            }
+
+            return Ok(());
+        }
+
         // `Liveness` and `Coloring` are self-clearing.
         self.virtregs.clear();
 
@@ -85,6 +114,9 @@ impl Context {
         // phases.
         self.tracker.clear();
 
+        // The loop analysis guides both live range splitting and spill costs.
+        self.loop_analysis.compute(func, cfg, domtree);
+
         // Pass: Liveness analysis.
         self.liveness.compute(isa, func, cfg);
 
@@ -96,6 +128,24 @@ impl Context {
             }
         }
 
+        // Pass: Live range splitting.
+        if isa.flags().opt_level() == OptLevel::Best
+            && self
+                .splitting
+                .run(isa, func, cfg, domtree, &self.loop_analysis, &self.liveness)
+        {
+            self.liveness.compute(isa, func, cfg);
+
+            if isa.flags().enable_verifier() {
+                let ok = verify_context(func, cfg, domtree, isa, &mut errors).is_ok()
+                    && verify_liveness(isa, func, cfg, &self.liveness, &mut errors).is_ok();
+
+                if !ok {
+                    return Err(errors.into());
+                }
+            }
+        }
+
         // Pass: Coalesce and create Conventional SSA form.
         self.coalescing.conventional_ssa(
             isa,
@@ -128,6 +178,7 @@ impl Context {
             isa,
             func,
             domtree,
+            &self.loop_analysis,
             &mut self.liveness,
             &self.virtregs,
             &mut self.topo,
@@ -0,0 +1,983 @@
+//! Linear scan register allocator for fast compilation.
+//!
+//! This is an alternative to the SSA coloring allocator implemented by the rest of the `regalloc`
+//! module. It is selected by the `regalloc=linear_scan` setting, and it trades code quality for
+//! compile time. It doesn't need the liveness analysis, virtual registers, or the constraint
+//! solver, and it produces the same kind of value locations as the coloring allocator.
+//!
+//! Values are divided into two groups:
+//!
+//! - *Local* values are only used in the EBB where they are defined, and they are not live across
+//!   a call. Their live range is a single interval in the EBB, and they stay in a register for the
+//!   whole interval.
+//! - All other values live in a stack slot. They are spilled right after they are defined, and
+//!   each use that needs a register gets a `fill` immediately before it.
+//!
+//! EBB parameters are passed in stack slots. A branch stores its arguments into the parameter
+//! slots of the destination EBB, and the destination fills its parameters into new values at the
+//! top. Since no register values are live across EBB boundaries, each EBB is allocated on its
+//! own.
+//!
+//! Each EBB is scanned twice. The first scan computes the intervals of the local values. When an
+//! instruction wouldn't have enough registers left for its operands, the local values that reach
+//! the farthest are demoted to the stack. The second scan rewrites the code and assigns registers,
+//! inserting `copy` and `regmove` instructions where an operand needs a value in a specific
+//! register.
+
+use cursor::{Cursor, EncCursor};
+use dominator_tree::DominatorTree;
+use entity::{EntitySet, SecondaryMap};
+use ir::{ArgumentLoc, Ebb, Function, Inst, InstBuilder, Type, Value, ValueDef, ValueLoc};
+use isa::{regs_overlap, ConstraintKind, EncInfo, RegClass, RegInfo, RegUnit, TargetIsa};
+use regalloc::register_set::RegisterSet;
+use std::vec::Vec;
+use timing;
+
+/// Persistent data structures for the linear scan allocator.
+pub struct LinearScan {
+    // Values that have at least one use.
+    used: EntitySet<Value>,
+
+    // Values that are used outside the EBB where they are defined.
+    nonlocal: EntitySet<Value>,
+
+    // Values that are kept in a register.
+    local: SecondaryMap<Value, bool>,
+
+    // Number of uses of each local value.
+    uses: SecondaryMap<Value, u32>,
+
+    // Position of the definition and the last use of each local value in its EBB.
+    def_pos: SecondaryMap<Value, u32>,
+    last_use: SecondaryMap<Value, u32>,
+
+    // Stack or register copy to use instead of a value that was spilled or filled.
+    replaced: SecondaryMap<Value, Option<Value>>,
+
+    // Local values in registers during a scan.
+    active: Vec<Active>,
+
+    // Register operands wanted by the current instruction, indexed by top-level register class.
+    demand: Vec<u32>,
+
+    // Number of allocatable registers, indexed by top-level register class.
+    limit: Vec<u32>,
+}
+
+/// A local value in a register.
+struct Active {
+    value: Value,
+
+    // Top-level register class of the value.
+    rc: RegClass,
+
+    // Current register, which differs from the value's location after a `regmove`.
+    reg: RegUnit,
+
+    // Number of uses not yet visited.
+    remaining: u32,
+
+    // Number of uses by the current instruction.
+    here: u32,
+}
+
+/// Register constraint on an instruction operand.
+#[derive(Clone, Copy)]
+enum Operand {
+    Reg(RegClass),
+    Tied(RegClass, usize),
+    Fixed(RegClass, RegUnit),
+    FixedTied(RegClass, RegUnit),
+    Stack,
+    Free,
+}
+
+/// Context data structure that gets instantiated once per pass.
+struct Context<'a> {
+    cur: EncCursor<'a>,
+    isa: &'a TargetIsa,
+    reginfo: RegInfo,
+    encinfo: EncInfo,
+    usable_regs: RegisterSet,
+
+    used: &'a mut EntitySet<Value>,
+    nonlocal: &'a mut EntitySet<Value>,
+    local: &'a mut SecondaryMap<Value, bool>,
+    uses: &'a mut SecondaryMap<Value, u32>,
+    def_pos: &'a mut SecondaryMap<Value, u32>,
+    last_use: &'a mut SecondaryMap<Value, u32>,
+    replaced: &'a mut SecondaryMap<Value, Option<Value>>,
+    active: &'a mut Vec<Active>,
+    demand: &'a mut Vec<u32>,
+    limit: &'a mut Vec<u32>,
+
+    // Registers not held by a local value or an operand of the current instruction.
+    avail: RegisterSet,
+}
+
+impl LinearScan {
+    /// Create a new linear scan allocator.
+    pub fn new() -> Self {
+        Self {
+            used: EntitySet::new(),
+            nonlocal: EntitySet::new(),
+            local: SecondaryMap::new(),
+            uses: SecondaryMap::new(),
+            def_pos: SecondaryMap::new(),
+            last_use: SecondaryMap::new(),
+            replaced: SecondaryMap::new(),
+            active: Vec::new(),
+            demand: Vec::new(),
+            limit: Vec::new(),
+        }
+    }
+
+    /// Clear all data structures in this allocator.
+    pub fn clear(&mut self) {
+        self.used.clear();
+        self.nonlocal.clear();
+        self.local.clear();
+        self.uses.clear();
+        self.def_pos.clear();
+        self.last_use.clear();
+        self.replaced.clear();
+        self.active.clear();
+        self.demand.clear();
+        self.limit.clear();
+    }
+
+    /// Allocate registers in `func`.
+    pub fn run(&mut self, isa: &TargetIsa, func: &mut Function, domtree: &DominatorTree) {
+        let _tt = timing::ra_linear_scan();
+        debug!("Linear scan allocation for:\n{}", func.display(isa));
+        self.clear();
+        let mut ctx = Context {
+            usable_regs: isa.allocatable_registers(func),
+            avail: RegisterSet::new(),
+            cur: EncCursor::new(func, isa),
+            isa,
+            reginfo: isa.register_info(),
+            encinfo: isa.encoding_info(),
+            used: &mut self.used,
+            nonlocal: &mut self.nonlocal,
+            local: &mut self.local,
+            uses: &mut self.uses,
+            def_pos: &mut self.def_pos,
+            last_use: &mut self.last_use,
+            replaced: &mut self.replaced,
+            active: &mut self.active,
+            demand: &mut self.demand,
+            limit: &mut self.limit,
+        };
+        ctx.run(domtree)
+    }
+}
+
+impl<'a> Context<'a> {
+    fn run(&mut self, domtree: &DominatorTree) {
+        self.compute_limits();
+        self.find_nonlocal_values();
+        let ebbs: Vec<Ebb> = self.cur.func.layout.ebbs().collect();
+        for &ebb in &ebbs {
+            self.plan_ebb(ebb);
+        }
+
+        // Every EBB parameter outside the entry block is passed in its own stack slot.
+        let entry = self.cur.func.layout.entry_block();
+        for &ebb in &ebbs {
+            if Some(ebb) != entry {
+                for idx in 0..self.cur.func.dfg.num_ebb_params(ebb) {
+                    let param = self.cur.func.dfg.ebb_params(ebb)[idx];
+                    let ty = self.cur.func.dfg.value_type(param);
+                    let ss = self.cur.func.stack_slots.make_spill_slot(ty);
+                    self.cur.func.locations[param] = ValueLoc::Stack(ss);
+                }
+            }
+        }
+
+        // Visit EBBs in reverse post-order so values are defined before they are used.
+        for &ebb in domtree.cfg_postorder().iter().rev() {
+            self.visit_ebb(ebb);
+        }
+    }
+
+    // Count the allocatable registers in each top-level register class.
+    fn compute_limits(&mut self) {
+        let classes = self.reginfo.classes;
+        self.limit.resize(classes.len(), 0);
+        self.demand.resize(classes.len(), 0);
+        for rc in classes {
+            if rc.index == rc.toprc {
+                self.limit[rc.index as usize] = self.usable_regs.iter(rc).count() as u32;
+            }
+        }
+    }
+
+    // Find the values that are used at all, and the values used outside their defining EBB.
+    fn find_nonlocal_values(&mut self) {
+        while let Some(_ebb) = self.cur.next_ebb() {
+            while let Some(inst) = self.cur.next_inst() {
+                self.cur.func.dfg.resolve_aliases_in_arguments(inst);
+            }
+        }
+
+        let func = &self.cur.func;
+        for ebb in func.layout.ebbs() {
+            for inst in func.layout.ebb_insts(ebb) {
+                if func.dfg[inst].opcode().is_ghost() {
+                    continue;
+                }
+                for &arg in func.dfg.inst_args(inst) {
+                    self.used.insert(arg);
+                    if def_ebb(func, arg) != ebb {
+                        self.nonlocal.insert(arg);
+                    }
+                }
+            }
+        }
+    }
+
+    // Get the top-level register class used for values of type `ty`.
+    fn toprc(&self, ty: Type) -> RegClass {
+        self.isa.regclass_for_abi_type(ty).toprc()
+    }
+
+    // Get the register constraints on the arguments of `inst`, in order.
+    fn input_operands(&self, inst: Inst, operands: &mut Vec<Operand>) {
+        let func = &self.cur.func;
+        let dfg = &func.dfg;
+        operands.clear();
+        operands.resize(dfg.inst_args(inst).len(), Operand::Free);
+
+        if let Some(constraints) = self.encinfo.operand_constraints(func.encodings[inst]) {
+            for (idx, op) in constraints.ins.iter().enumerate() {
+                operands[idx] = match op.kind {
+                    ConstraintKind::Reg => Operand::Reg(op.regclass),
+                    ConstraintKind::Tied(out) => Operand::Tied(op.regclass, out as usize),
+                    ConstraintKind::FixedReg(reg) => Operand::Fixed(op.regclass, reg),
+                    ConstraintKind::FixedTied(reg) => Operand::FixedTied(op.regclass, reg),
+                    ConstraintKind::Stack => Operand::Stack,
+                };
+            }
+        }
+
+        // Flags values never leave the flags register.
+        for (op, &arg) in operands.iter_mut().zip(dfg.inst_args(inst)) {
+            if dfg.value_type(arg).is_flags() {
+                *op = Operand::Free;
+            }
+        }
+
+        let abi = if let Some(sig) = dfg.call_signature(inst) {
+            &dfg.signatures[sig].params
+        } else if dfg[inst].opcode().is_return() {
+            &func.signature.returns
+        } else {
+            return;
+        };
+        let num_fixed = dfg.inst_fixed_args(inst).len();
+        for (idx, param) in abi.iter().enumerate() {
+            operands[num_fixed + idx] = match param.location {
+                ArgumentLoc::Reg(reg) => Operand::Fixed(self.toprc(param.value_type), reg),
+                ArgumentLoc::Stack(_) => Operand::Stack,
+                ArgumentLoc::Unassigned => Operand::Free,
+            };
+        }
+    }
+
+    // Get the register constraints on the results of `inst`, in order.
+    fn output_operands(&self, inst: Inst, operands: &mut Vec<Operand>) {
+        let func = &self.cur.func;
+        let dfg = &func.dfg;
+        operands.clear();
+        operands.resize(dfg.inst_results(inst).len(), Operand::Free);
+
+        if let Some(constraints) = self.encinfo.operand_constraints(func.encodings[inst]) {
+            for (idx, op) in constraints.outs.iter().enumerate() {
+                operands[idx] = match op.kind {
+                    ConstraintKind::Reg => Operand::Reg(op.regclass),
+                    ConstraintKind::Tied(arg) => Operand::Tied(op.regclass, arg as usize),
+                    ConstraintKind::FixedReg(reg) => Operand::Fixed(op.regclass, reg),
+                    ConstraintKind::FixedTied(reg) => Operand::FixedTied(op.regclass, reg),
+                    ConstraintKind::Stack => Operand::Stack,
+                };
+            }
+        }
+
+        if let Some(sig) = dfg.call_signature(inst) {
+            for (idx, ret) in dfg.signatures[sig].returns.iter().enumerate() {
+                operands[idx] = match ret.location {
+                    ArgumentLoc::Reg(reg) => Operand::Fixed(self.toprc(ret.value_type), reg),
+                    ArgumentLoc::Stack(_) => Operand::Stack,
+                    ArgumentLoc::Unassigned => Operand::Free,
+                };
+            }
+        }
+    }
+
+    // Count the registers needed by the operands of `inst` in `self.demand`.
+    fn compute_demand(&mut self, inst: Inst, operands: &mut Vec<Operand>) {
+        for d in self.demand.iter_mut() {
+            *d = 0;
+        }
+
+        self.input_operands(inst, operands);
+        for &op in operands.iter() {
+            if let Some(rc) = operand_regclass(op) {
+                self.demand[rc.toprc as usize] += 1;
+            }
+        }
+
+        self.output_operands(inst, operands);
+        for (&op, &res) in operands.iter().zip(self.cur.func.dfg.inst_results(inst)) {
+            if let Some(rc) = operand_regclass(op) {
+                if !self.cur.func.dfg.value_type(res).is_flags() {
+                    self.demand[rc.toprc as usize] += 1;
+                }
+            }
+        }
+
+        // Storing a stack value to an EBB parameter slot needs a temporary register.
+        if self.cur.func.dfg[inst].branch_destination().is_some() {
+            let mut banks = 0u32;
+            for &arg in self.cur.func.dfg.inst_variable_args(inst) {
+                let rc = self.toprc(self.cur.func.dfg.value_type(arg));
+                if banks & (1 << rc.index) == 0 {
+                    banks |= 1 << rc.index;
+                    self.demand[rc.index as usize] += 1;
+                }
+            }
+        }
+    }
+
+    // Decide which values defined in `ebb` are local values kept in registers.
+    fn plan_ebb(&mut self, ebb: Ebb) {
+        let mut operands = Vec::new();
+        let is_entry = self.cur.func.layout.entry_block() == Some(ebb);
+
+        // Parameters are defined at position 0, and instruction `n` uses its arguments at position
+        // `2n + 1` and defines its results at position `2n + 2`.
+        for idx in 0..self.cur.func.dfg.num_ebb_params(ebb) {
+            let param = self.cur.func.dfg.ebb_params(ebb)[idx];
+            let in_reg = if is_entry {
+                self.cur.func.signature.params[idx].location.is_reg()
+            } else {
+                !self.cur.func.dfg.value_type(param).is_flags()
+            };
+            if in_reg {
+                self.define(param, 0);
+            }
+        }
+
+        // First scan: count uses and find values live across calls.
+        let mut pos = 1;
+        let mut last_call = 0;
+        let mut inst = self.cur.func.layout.first_inst(ebb);
+        while let Some(i) = inst {
+            if !self.cur.func.dfg[i].opcode().is_ghost() {
+                for &arg in self.cur.func.dfg.inst_args(i) {
+                    if self.local[arg] {
+                        self.uses[arg] += 1;
+                        self.last_use[arg] = pos;
+                        if last_call > self.def_pos[arg] {
+                            self.local[arg] = false;
+                        }
+                    }
+                }
+                if self.cur.func.dfg[i].opcode().is_call() {
+                    last_call = pos;
+                }
+                self.output_operands(i, &mut operands);
+                for (idx, &op) in operands.iter().enumerate() {
+                    let result = self.cur.func.dfg.inst_results(i)[idx];
+                    let in_reg = match op {
+                        Operand::Stack => false,
+                        _ => !self.cur.func.dfg.value_type(result).is_flags(),
+                    };
+                    if in_reg {
+                        self.define(result, pos + 1);
+                    }
+                }
+            }
+            pos += 2;
+            inst = self.cur.func.layout.next_inst(i);
+        }
+
+        // Second scan: make sure there are enough registers for the operands of each instruction.
+        self.active.clear();
+        for idx in 0..self.cur.func.dfg.num_ebb_params(ebb) {
+            let param = self.cur.func.dfg.ebb_params(ebb)[idx];
+            self.activate(param);
+        }
+        if !is_entry {
+            // Parameters that aren't kept in a register need a register to be copied to their
+            // stack slot.
+            for d in self.demand.iter_mut() {
+                *d = 0;
+            }
+            for idx in 0..self.cur.func.dfg.num_ebb_params(ebb) {
+                let param = self.cur.func.dfg.ebb_params(ebb)[idx];
+                if self.used.contains(param) && !self.local[param] {
+                    let rc = self.toprc(self.cur.func.dfg.value_type(param));
+                    self.demand[rc.index as usize] = 1;
+                }
+            }
+            self.limit_pressure(0);
+        }
+
+        let mut pos = 1;
+        let mut inst = self.cur.func.layout.first_inst(ebb);
+        while let Some(i) = inst {
+            if !self.cur.func.dfg[i].opcode().is_ghost() {
+                self.compute_demand(i, &mut operands);
+                self.limit_pressure(pos);
+                let last_use = &self.last_use;
+                self.active.retain(|a| last_use[a.value] > pos);
+                for idx in 0..self.cur.func.dfg.inst_results(i).len() {
+                    let result = self.cur.func.dfg.inst_results(i)[idx];
+                    self.activate(result);
+                }
+            }
+            pos += 2;
+            inst = self.cur.func.layout.next_inst(i);
+        }
+        self.active.clear();
+    }
+
+    // Start tracking `value` as a local value defined at `pos` if it's used only in its EBB.
+    fn define(&mut self, value: Value, pos: u32) {
+        if self.used.contains(value) && !self.nonlocal.contains(value) {
+            self.local[value] = true;
+            self.uses[value] = 0;
+            self.def_pos[value] = pos;
+        }
+    }
+
+    // Add `value` to the active list if it is still a local value.
+    fn activate(&mut self, value: Value) {
+        if self.local[value] {
+            let rc = self.toprc(self.cur.func.dfg.value_type(value));
+            self.active.push(Active {
+                value,
+                rc,
+                reg: 0,
+                remaining: 0,
+                here: 0,
+            });
+        }
+    }
+
+    // Demote the active values reaching farthest to the stack until the values live through `pos`
+    // leave enough registers for `self.demand`.
+    fn limit_pressure(&mut self, pos: u32) {
+        for idx in 0..self.demand.len() {
+            if self.demand[idx] == 0 {
+                continue;
+            }
+            loop {
+                let mut through = 0;
+                let mut farthest = None;
+                for (i, a) in self.active.iter().enumerate() {
+                    let end = self.last_use[a.value];
+                    if a.rc.index as usize == idx && end > pos {
+                        through += 1;
+                        match farthest {
+                            Some((_, e)) if e >= end => {}
+                            _ => farthest = Some((i, end)),
+                        }
+                    }
+                }
+                if through + self.demand[idx] <= self.limit[idx] {
+                    break;
+                }
+                let i = match farthest {
+                    Some((i, _)) => i,
+                    None => break,
+                };
+                let value = self.active.swap_remove(i).value;
+                debug!("Demoting {} to the stack", value);
+                self.local[value] = false;
+            }
+        }
+    }
+
+    fn visit_ebb(&mut self, ebb: Ebb) {
+        debug!("Linear scan {}:", ebb);
+        self.avail = self.usable_regs.clone();
+        self.active.clear();
+        let mut next = self.cur.func.layout.first_inst(ebb);
+        self.visit_ebb_params(ebb);
+
+        // Instructions inserted by the allocator are skipped.
+        let mut inputs = Vec::new();
+        let mut outputs = Vec::new();
+        while let Some(inst) = next {
+            let mut last = inst;
+            if !self.cur.func.dfg[inst].opcode().is_ghost() {
+                self.cur.use_srcloc(inst);
+                last = self.visit_inst(inst, &mut inputs, &mut outputs);
+            }
+            next = self.cur.func.layout.next_inst(last);
+        }
+        debug_assert!(
+            self.active.is_empty(),
+            "Values still live at the end of {}",
+            ebb
+        );
+    }
+
+    // Assign registers to the parameters of `ebb`, or move them to the stack.
+    fn visit_ebb_params(&mut self, ebb: Ebb) {
+        let is_entry = self.cur.func.layout.entry_block() == Some(ebb);
+        self.cur.goto_first_inst(ebb);
+        for idx in 0..self.cur.func.dfg.num_ebb_params(ebb) {
+            let param = self.cur.func.dfg.ebb_params(ebb)[idx];
+            let ty = self.cur.func.dfg.value_type(param);
+            let rc = self.toprc(ty);
+            if is_entry {
+                let reg = match self.cur.func.signature.params[idx].location {
+                    ArgumentLoc::Reg(reg) => reg,
+                    // Stack parameters already have an incoming argument slot.
+                    _ => continue,
+                };
+                self.cur.func.locations[param] = ValueLoc::Reg(reg);
+                if self.local[param] {
+                    self.avail.take(rc, reg);
+                    self.push_active(param, param, rc, reg);
+                } else if self.used.contains(param) {
+                    let spilled = self.spill(param, ty);
+                    self.replaced[param] = Some(spilled);
+                }
+            } else if self.used.contains(param) {
+                let reg = self
+                    .avail
+                    .iter(rc)
+                    .next()
+                    .expect("No register to fill EBB parameter");
+                let filled = self.cur.ins().fill(param);
+                self.cur.func.locations[filled] = ValueLoc::Reg(reg);
+                if self.local[param] {
+                    self.avail.take(rc, reg);
+                    self.push_active(filled, param, rc, reg);
+                    self.replaced[param] = Some(filled);
+                } else {
+                    let spilled = self.spill(filled, ty);
+                    self.replaced[param] = Some(spilled);
+                }
+            }
+        }
+    }
+
+    // Track `value` as an active local value, with the uses counted for `planned`.
+    fn push_active(&mut self, value: Value, planned: Value, rc: RegClass, reg: RegUnit) {
+        self.active.push(Active {
+            value,
+            rc,
+            reg,
+            remaining: self.uses[planned],
+            here: 0,
+        });
+    }
+
+    // Insert a spill of `value` at the cursor into a new stack slot, and return the stack value.
+    fn spill(&mut self, value: Value, ty: Type) -> Value {
+        let ss = self.cur.func.stack_slots.make_spill_slot(ty);
+        let spilled = self.cur.ins().spill(value);
+        self.cur.func.locations[spilled] = ValueLoc::Stack(ss);
+        spilled
+    }
+
+    // Find the active entry for `value`.
+    fn find_active(&self, value: Value) -> Option<usize> {
+        self.active.iter().position(|a| a.value == value)
+    }
+
+    // Find the active entry holding a register that overlaps `reg` in `rc`.
+    fn find_occupant(&self, rc: RegClass, reg: RegUnit) -> Option<usize> {
+        self.active
+            .iter()
+            .position(|a| regs_overlap(a.rc, a.reg, rc, reg))
+    }
+
+    // Release one use of the active value at `idx`, freeing its register after the last use.
+    fn release(&mut self, idx: usize) {
+        self.active[idx].remaining -= 1;
+        if self.active[idx].remaining == 0 {
+            let a = self.active.swap_remove(idx);
+            self.avail.free(a.rc, a.reg);
+        }
+    }
+
+    // Move the active value at `idx` to a free register in `allowed`, using a `regmove` inserted
+    // before `inst`. Returns the register the value was moved from.
+    fn evict(&mut self, idx: usize, allowed: &RegisterSet, inst: Inst) -> RegUnit {
+        let mut choices = self.avail.clone();
+        choices.intersect(allowed);
+        let (value, rc, from) = {
+            let a = &self.active[idx];
+            (a.value, a.rc, a.reg)
+        };
+        let to = choices
+            .iter(rc)
+            .next()
+            .unwrap_or_else(|| panic!("No register to move {} out of the way", value));
+        debug!(
+            "Moving {} from {} to {}",
+            value,
+            self.reginfo.display_regunit(from),
+            self.reginfo.display_regunit(to)
+        );
+        self.cur.goto_inst(inst);
+        self.cur.ins().regmove(value, from, to);
+        self.avail.free(rc, from);
+        self.avail.take(rc, to);
+        self.active[idx].reg = to;
+        from
+    }
+
+    // Get a register copy of `value` in `reg`, inserted before `inst`.
+    fn load(&mut self, value: Value, reg: RegUnit, inst: Inst) -> Value {
+        self.cur.goto_inst(inst);
+        let copy = if self.find_active(value).is_some() {
+            self.cur.ins().copy(value)
+        } else {
+            self.cur.ins().fill(value)
+        };
+        self.cur.func.locations[copy] = ValueLoc::Reg(reg);
+        copy
+    }
+
+    // Rewrite the arguments of `inst` to use the values that replaced them.
+    fn rewrite_args(&mut self, inst: Inst) {
+        let replaced = &self.replaced;
+        for arg in self.cur.func.dfg.inst_args_mut(inst) {
+            if let Some(value) = replaced[*arg] {
+                *arg = value;
+            }
+        }
+    }
+
+    // Store the EBB arguments of the branch `inst` into the parameter slots of its destination.
+    fn store_ebb_args(&mut self, inst: Inst) {
+        let dest = match self.cur.func.dfg[inst].branch_destination() {
+            Some(dest) => dest,
+            None => return,
+        };
+        // Store the values in registers first, so their registers are available for loading the
+        // values on the stack.
+        for &from_reg in &[true, false] {
+            for idx in 0..self.cur.func.dfg.inst_variable_args(inst).len() {
+                let arg = self.cur.func.dfg.inst_variable_args(inst)[idx];
+                let param = self.cur.func.dfg.ebb_params(dest)[idx];
+                let target = self.cur.func.locations[param];
+                let active = self.find_active(arg);
+                if self.cur.func.locations[arg] == target || active.is_some() != from_reg {
+                    continue;
+                }
+                self.cur.goto_inst(inst);
+                let stored = match active {
+                    Some(a) => {
+                        let stored = self.cur.ins().spill(arg);
+                        self.release(a);
+                        stored
+                    }
+                    None => {
+                        let rc = self.toprc(self.cur.func.dfg.value_type(arg));
+                        let reg = self
+                            .avail
+                            .iter(rc)
+                            .next()
+                            .expect("No register to store EBB argument");
+                        let filled = self.cur.ins().fill(arg);
+                        self.cur.func.locations[filled] = ValueLoc::Reg(reg);
+                        self.cur.ins().spill(filled)
+                    }
+                };
+                self.cur.func.locations[stored] = target;
+                self.cur.func.dfg.inst_variable_args_mut(inst)[idx] = stored;
+            }
+        }
+    }
+
+    // Allocate registers for `inst` and its operands. Returns the last instruction inserted after
+    // `inst`, or `inst` itself.
+    fn visit_inst(
+        &mut self,
+        inst: Inst,
+        inputs: &mut Vec<Operand>,
+        outputs: &mut Vec<Operand>,
+    ) -> Inst {
+        debug!("Allocating {}", self.cur.display_inst(inst));
+        self.rewrite_args(inst);
+        self.store_ebb_args(inst);
+
+        // Count the uses of each active value by this instruction.
+        for a in self.active.iter_mut() {
+            a.here = 0;
+        }
+        for idx in 0..self.cur.func.dfg.inst_args(inst).len() {
+            let arg = self.cur.func.dfg.inst_args(inst)[idx];
+            if let Some(a) = self.find_active(arg) {
+                self.active[a].here += 1;
+            }
+        }
+        debug_assert!(
+            !self.cur.func.dfg[inst].opcode().is_call()
+                || self.active.iter().all(|a| a.remaining == a.here),
+            "Local value live across a call"
+        );
+
+        self.input_operands(inst, inputs);
+        self.output_operands(inst, outputs);
+
+        // Registers of the current operands that must not be used for moving other values.
+        let mut unblocked = RegisterSet::new();
+        // Registers held by operands of this instruction, to be freed after the inputs are read.
+        let mut temps = Vec::new();
+        // Registers of the register inputs, for tied outputs.
+        let mut arg_regs = vec![0; inputs.len()];
+
+        // Fixed registers: Move other values out of the way.
+        for &op in inputs.iter().chain(outputs.iter()) {
+            if let Operand::Fixed(rc, reg) | Operand::FixedTied(rc, reg) = op {
+                if unblocked.is_avail(rc, reg) {
+                    unblocked.take(rc, reg);
+                }
+            }
+        }
+        for idx in 0..inputs.len() + outputs.len() {
+            let (rc, reg) = match if idx < inputs.len() {
+                inputs[idx]
+            } else {
+                outputs[idx - inputs.len()]
+            } {
+                Operand::Fixed(rc, reg) | Operand::FixedTied(rc, reg) => (rc, reg),
+                _ => continue,
+            };
+            if let Some(a) = self.find_occupant(rc, reg) {
+                if !self.may_stay(a, rc, reg, inst, inputs, outputs) {
+                    self.evict(a, &unblocked, inst);
+                }
+            }
+        }
+
+        // Fixed input registers: Load the values that aren't already there.
+        for (idx, &op) in inputs.iter().enumerate() {
+            let (rc, reg) = match op {
+                Operand::Fixed(rc, reg) | Operand::FixedTied(rc, reg) => (rc, reg),
+                _ => continue,
+            };
+            let arg = self.cur.func.dfg.inst_args(inst)[idx];
+            if let Some(a) = self.find_occupant(rc, reg) {
+                debug_assert_eq!(self.active[a].value, arg);
+                continue;
+            }
+            let copy = self.load(arg, reg, inst);
+            self.avail.take(rc, reg);
+            temps.push((rc, reg));
+            self.cur.func.dfg.inst_args_mut(inst)[idx] = copy;
+        }
+
+        // Other register inputs: Use the value in place if possible.
+        for idx in 0..inputs.len() {
+            let arg = self.cur.func.dfg.inst_args(inst)[idx];
+            let (rc, tied) = match inputs[idx] {
+                Operand::Reg(rc) => (rc, false),
+                Operand::Tied(rc, _) => (rc, true),
+                Operand::Stack => {
+                    if self.find_active(arg).is_some() {
+                        let ty = self.cur.func.dfg.value_type(arg);
+                        self.cur.goto_inst(inst);
+                        let spilled = self.spill(arg, ty);
+                        self.cur.func.dfg.inst_args_mut(inst)[idx] = spilled;
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+            if let Some(a) = self.find_active(arg) {
+                let a = &self.active[a];
+                if rc.contains(a.reg) && (!tied || (a.remaining == 1 && a.here == 1)) {
+                    arg_regs[idx] = a.reg;
+                    continue;
+                }
+            }
+
+            // A tied input's register is reused for the output, so it can't be a fixed register.
+            let mut allowed = self.avail.clone();
+            if tied {
+                allowed.intersect(&unblocked);
+            }
+            let reg = match allowed.iter(rc).next() {
+                Some(reg) => reg,
+                None => {
+                    // The free registers are all outside `rc`. Move a value that isn't used by
+                    // `inst` out of the class.
+                    let a = self
+                        .active
+                        .iter()
+                        .position(|a| a.here == 0 && rc.contains(a.reg))
+                        .unwrap_or_else(|| panic!("No register for {} in {}", arg, rc));
+                    self.evict(a, &unblocked, inst)
+                }
+            };
+            let copy = self.load(arg, reg, inst);
+            self.avail.take(rc, reg);
+            temps.push((rc, reg));
+            arg_regs[idx] = reg;
+            self.cur.func.dfg.inst_args_mut(inst)[idx] = copy;
+        }
+
+        // The inputs have been read. Free the registers of the operands that die here.
+        let input_avail = self.avail.clone();
+        for (rc, reg) in temps {
+            self.avail.free(rc, reg);
+        }
+        let mut idx = 0;
+        while idx < self.active.len() {
+            let a = &mut self.active[idx];
+            a.remaining -= a.here;
+            if a.remaining == 0 {
+                let a = self.active.swap_remove(idx);
+                self.avail.free(a.rc, a.reg);
+            } else {
+                idx += 1;
+            }
+        }
+
+        // Now assign registers to the results.
+        let mut results = Vec::new();
+        for (idx, &op) in outputs.iter().enumerate() {
+            let result = self.cur.func.dfg.inst_results(inst)[idx];
+            let ty = self.cur.func.dfg.value_type(result);
+            if ty.is_flags() {
+                let reg = match op {
+                    Operand::Fixed(_, reg) => reg,
+                    op => operand_regclass(op).expect("Flags result").unit(0),
+                };
+                self.cur.func.locations[result] = ValueLoc::Reg(reg);
+                continue;
+            }
+            let (rc, reg) = match outputs[idx] {
+                Operand::Stack => {
+                    if !self.cur.func.locations[result].is_assigned() {
+                        let ss = self.cur.func.stack_slots.make_spill_slot(ty);
+                        self.cur.func.locations[result] = ValueLoc::Stack(ss);
+                    }
+                    continue;
+                }
+                Operand::Fixed(rc, reg) | Operand::FixedTied(rc, reg) => (rc, reg),
+                Operand::Tied(rc, arg) => (rc, arg_regs[arg]),
+                Operand::Reg(rc) => (rc, self.output_reg(rc, &unblocked, &input_avail, inst)),
+                Operand::Free => {
+                    let rc = self.toprc(ty);
+                    (rc, self.output_reg(rc, &unblocked, &input_avail, inst))
+                }
+            };
+            let rc = rc.toprc();
+            self.cur.func.locations[result] = ValueLoc::Reg(reg);
+            self.avail.take(rc, reg);
+            results.push((result, rc, reg));
+        }
+
+        // Keep the local results in registers, and spill the results that live on the stack.
+        let mut last = inst;
+        for (result, rc, reg) in results {
+            if self.local[result] {
+                self.push_active(result, result, rc, reg);
+                continue;
+            }
+            self.avail.free(rc, reg);
+            if self.used.contains(result) {
+                let ty = self.cur.func.dfg.value_type(result);
+                self.cur.goto_after_inst(last);
+                let spilled = self.spill(result, ty);
+                self.replaced[result] = Some(spilled);
+                last = self.cur.built_inst();
+            }
+        }
+        last
+    }
+
+    // Can the active value at `idx`, currently in `reg`, stay there while `inst` uses `reg` for a
+    // fixed operand?
+    fn may_stay(
+        &self,
+        idx: usize,
+        rc: RegClass,
+        reg: RegUnit,
+        inst: Inst,
+        inputs: &[Operand],
+        outputs: &[Operand],
+    ) -> bool {
+        let a = &self.active[idx];
+        let through = a.remaining > a.here;
+        for (&op, &arg) in inputs.iter().zip(self.cur.func.dfg.inst_args(inst)) {
+            let conflict = match op {
+                Operand::Fixed(orc, oreg) => regs_overlap(rc, reg, orc, oreg) && arg != a.value,
+                Operand::FixedTied(orc, oreg) => {
+                    regs_overlap(rc, reg, orc, oreg) && (arg != a.value || through)
+                }
+                _ => false,
+            };
+            if conflict {
+                return false;
+            }
+        }
+        // A fixed output register is clobbered by `inst`.
+        !through
+            || outputs.iter().all(|&op| match op {
+                Operand::Fixed(orc, oreg) | Operand::FixedTied(orc, oreg) => {
+                    !regs_overlap(rc, reg, orc, oreg)
+                }
+                _ => true,
+            })
+    }
+
+    // Pick a register in `rc` for a result of `inst`, moving a value out of the way if necessary.
+    fn output_reg(
+        &mut self,
+        rc: RegClass,
+        unblocked: &RegisterSet,
+        input_avail: &RegisterSet,
+        inst: Inst,
+    ) -> RegUnit {
+        let mut choices = self.avail.clone();
+        choices.intersect(unblocked);
+        if let Some(reg) = choices.iter(rc).next() {
+            return reg;
+        }
+        // The free registers are all outside `rc`. Move a value live through `inst` out of the
+        // class, to a register that isn't used by the inputs either.
+        let a = self
+            .active
+            .iter()
+            .position(|a| rc.contains(a.reg))
+            .unwrap_or_else(|| panic!("No register in {} for a result", rc));
+        let mut allowed = input_avail.clone();
+        allowed.intersect(unblocked);
+        self.evict(a, &allowed, inst)
+    }
+}
+
+/// Get the register class of a register operand.
+fn operand_regclass(op: Operand) -> Option<RegClass> {
+    match op {
+        Operand::Reg(rc)
+        | Operand::Tied(rc, _)
+        | Operand::Fixed(rc, _)
+        | Operand::FixedTied(rc, _) => Some(rc),
+        Operand::Stack | Operand::Free => None,
+    }
+}
+
+/// Get the EBB where `value` is defined.
+fn def_ebb(func: &Function, value: Value) -> Ebb {
+    match func.dfg.value_def(value) {
+        ValueDef::Result(inst, _) => func
+            .layout
+            .inst_ebb(inst)
+            .expect("Instruction not in layout"),
+        ValueDef::Param(ebb, _) => ebb,
+    }
+}
@@ -331,6 +331,24 @@ impl LiveValueTracker {
         }
     }
 
+    /// Remove deleted `values` from the live sets saved for immediate dominators.
+    ///
+    /// The saved sets are reused by later passes, which expect all the values to have a live
+    /// range.
+    pub fn forget_values(&mut self, values: &[Value]) {
+        let pool = &mut self.idom_pool;
+        for list in self.idom_sets.values_mut() {
+            let mut i = 0;
+            while let Some(value) = list.get(i, pool) {
+                if values.contains(&value) {
+                    list.swap_remove(i, pool);
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+
     /// Save the current set of live values so it is associated with `idom`.
     fn save_idom_live_set(&mut self, idom: Inst) {
         let values = self.live.values.iter().map(|lv| lv.value);
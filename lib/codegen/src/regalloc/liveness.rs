@@ -381,6 +381,11 @@ impl Liveness {
         mem::replace(&mut lr.affinity, Affinity::Stack)
     }
 
+    /// Remove the live range of `value` after all its uses have been removed.
+    pub fn remove(&mut self, value: Value) -> Option<LiveRange> {
+        self.ranges.remove(value)
+    }
+
     /// Compute the live ranges of all SSA values used in `func`.
     /// This clears out any existing analysis stored in this data structure.
     pub fn compute(&mut self, isa: &TargetIsa, func: &mut Function, cfg: &ControlFlowGraph) {
@@ -13,11 +13,15 @@ mod affinity;
 mod coalescing;
 mod context;
 mod diversion;
+mod linear_scan;
 mod pressure;
 mod reload;
 mod solver;
 mod spilling;
+mod splitting;
 
+pub use self::affinity::Affinity;
 pub use self::context::Context;
 pub use self::diversion::RegDiversions;
+pub use self::pressure::Pressure;
 pub use self::register_set::RegisterSet;
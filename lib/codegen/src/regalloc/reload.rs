@@ -8,17 +8,22 @@
 //! The secondary responsibility of the reload pass is to reuse values in registers as much as
 //! possible to minimize the number of `fill` instructions needed. This must not cause the register
 //! pressure limits to be exceeded.
+//!
+//! Values that the spilling pass chose to rematerialize have a stack affinity but no stack slot.
+//! Their defining instruction is removed, and a copy of it is inserted before each use instead of
+//! a `fill`.
 
 use cursor::{Cursor, EncCursor};
 use dominator_tree::DominatorTree;
 use entity::{SparseMap, SparseMapValue};
-use ir::{AbiParam, ArgumentLoc, InstBuilder};
-use ir::{Ebb, Function, Inst, InstructionData, Opcode, Value};
+use ir::{AbiParam, ArgumentLoc, InstBuilder, InstBuilderBase};
+use ir::{Ebb, Function, Inst, InstructionData, Opcode, Value, ValueDef};
 use isa::RegClass;
 use isa::{ConstraintKind, EncInfo, Encoding, RecipeConstraints, TargetIsa};
 use regalloc::affinity::Affinity;
 use regalloc::live_value_tracker::{LiveValue, LiveValueTracker};
 use regalloc::liveness::Liveness;
+use regalloc::spilling::is_rematerializable;
 use std::vec::Vec;
 use timing;
 use topo_order::TopoOrder;
@@ -27,6 +32,7 @@ use topo_order::TopoOrder;
 pub struct Reload {
     candidates: Vec<ReloadCandidate>,
     reloads: SparseMap<Value, ReloadedValue>,
+    remats: Vec<Value>,
 }
 
 /// Context data structure that gets instantiated once per pass.
@@ -44,6 +50,9 @@ struct Context<'a> {
 
     candidates: &'a mut Vec<ReloadCandidate>,
     reloads: &'a mut SparseMap<Value, ReloadedValue>,
+
+    // Rematerialized values whose definition has been removed.
+    remats: &'a mut Vec<Value>,
 }
 
 impl Reload {
@@ -52,6 +61,7 @@ impl Reload {
         Self {
             candidates: Vec::new(),
             reloads: SparseMap::new(),
+            remats: Vec::new(),
         }
     }
 
@@ -59,6 +69,7 @@ impl Reload {
     pub fn clear(&mut self) {
         self.candidates.clear();
         self.reloads.clear();
+        self.remats.clear();
     }
 
     /// Run the reload algorithm over `func`.
@@ -81,6 +92,7 @@ impl Reload {
             topo,
             candidates: &mut self.candidates,
             reloads: &mut self.reloads,
+            remats: &mut self.remats,
         };
         ctx.run(tracker)
     }
@@ -116,6 +128,12 @@ impl<'a> Context<'a> {
         while let Some(ebb) = self.topo.next(&self.cur.func.layout, self.domtree) {
             self.visit_ebb(ebb, tracker);
         }
+
+        // All the uses of the rematerialized values have been rewritten.
+        tracker.forget_values(self.remats);
+        for value in self.remats.drain(..) {
+            self.liveness.remove(value);
+        }
     }
 
     fn visit_ebb(&mut self, ebb: Ebb, tracker: &mut LiveValueTracker) {
@@ -125,7 +143,12 @@ impl<'a> Context<'a> {
 
         // visit_ebb_header() places us at the first interesting instruction in the EBB.
         while let Some(inst) = self.cur.current_inst() {
-            if !self.cur.func.dfg[inst].opcode().is_ghost() {
+            if self.is_remat_def(inst) {
+                // The value is recomputed at its uses instead.
+                debug!("Removing rematerialized {}", self.cur.display_inst(inst));
+                self.remats.push(self.cur.func.dfg.first_result(inst));
+                self.cur.remove_inst();
+            } else if !self.cur.func.dfg[inst].opcode().is_ghost() {
                 // This instruction either has an encoding or has ABI constraints, so visit it to
                 // insert spills and fills as needed.
                 let encoding = self.cur.func.encodings[inst];
@@ -291,26 +314,56 @@ impl<'a> Context<'a> {
         }
     }
 
+    /// Is `value` spilled without a stack slot, to be recomputed at its uses?
+    fn is_remat(&self, value: Value) -> bool {
+        let remat = self.liveness[value].affinity.is_stack()
+            && !self.cur.func.locations[value].is_assigned();
+        debug_assert!(!remat || is_rematerializable(&self.cur.func.dfg, value));
+        remat
+    }
+
+    /// Does `inst` define a value that is recomputed at its uses?
+    fn is_remat_def(&self, inst: Inst) -> bool {
+        let results = self.cur.func.dfg.inst_results(inst);
+        results.len() == 1 && self.is_remat(results[0])
+    }
+
+    /// Insert a copy of the instruction defining `value` at the cursor.
+    ///
+    /// Returns the new instruction.
+    fn rematerialize(&mut self, value: Value) -> Inst {
+        let def = match self.cur.func.dfg.value_def(value) {
+            ValueDef::Result(def, _) => def,
+            ValueDef::Param(_, _) => panic!("Can't rematerialize the EBB parameter {}", value),
+        };
+        let data = self.cur.func.dfg[def].clone();
+        let ctrl_type = self.cur.func.dfg.ctrl_typevar(def);
+        self.cur.ins().build(data, ctrl_type).0
+    }
+
     // Reload the current candidates for the given `inst`.
     fn reload_inst_candidates(&mut self, ebb: Ebb, inst: Inst) {
         // Insert fill instructions before `inst` and replace `cand.value` with the filled value.
-        for cand in self.candidates.iter_mut() {
-            if let Some(reload) = self.reloads.get(cand.value) {
-                cand.value = reload.reg;
+        for i in 0..self.candidates.len() {
+            let value = self.candidates[i].value;
+            if let Some(reload) = self.reloads.get(value) {
+                self.candidates[i].value = reload.reg;
                 continue;
             }
 
-            let reg = self.cur.ins().fill(cand.value);
-            let fill = self.cur.built_inst();
+            let fill = if self.is_remat(value) {
+                self.rematerialize(value)
+            } else {
+                self.cur.ins().fill(value);
+                self.cur.built_inst()
+            };
+            let reg = self.cur.func.dfg.first_result(fill);
 
-            self.reloads.insert(ReloadedValue {
-                stack: cand.value,
-                reg,
-            });
-            cand.value = reg;
+            self.reloads.insert(ReloadedValue { stack: value, reg });
+            self.candidates[i].value = reg;
 
             // Create a live range for the new reload.
-            let affinity = Affinity::Reg(cand.regclass.into());
+            let affinity = Affinity::Reg(self.candidates[i].regclass.into());
             self.liveness.create_dead(reg, fill, affinity);
             self.liveness
                 .extend_locally(reg, ebb, inst, &self.cur.func.layout);
@@ -339,7 +392,13 @@ impl<'a> Context<'a> {
         debug_assert!(self.candidates.is_empty() || self.candidates.len() == 1);
 
         if let Some(cand) = self.candidates.pop() {
-            self.cur.func.dfg.replace(inst).fill(cand.value);
+            if self.is_remat(cand.value) {
+                // Compute the value directly into the copy result.
+                let def = self.cur.func.dfg.value_def(cand.value).unwrap_inst();
+                self.cur.func.dfg[inst] = self.cur.func.dfg[def].clone();
+            } else {
+                self.cur.func.dfg.replace(inst).fill(cand.value);
+            }
             let ok = self.cur.func.update_encoding(inst, self.cur.isa).is_ok();
             debug_assert!(ok);
         }
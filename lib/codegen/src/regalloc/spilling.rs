@@ -14,12 +14,25 @@
 //! 2. When the same value is used more than once by an instruction, the operand constraints must
 //!    be compatible. Otherwise, the value must be copied into a new register for some of the
 //!    operands.
+//!
+//! Values that are cheap to compute, like constants and addresses, don't need a spill slot. When
+//! such a value is spilled, it gets a stack affinity without a stack location, and the reload pass
+//! recomputes it at each use instead of inserting a `fill`.
+//!
+//! Values used inside loops are more expensive to spill than values used outside, since their
+//! fills execute on every iteration. When choosing what to spill, values whose uses are in shallower
+//! loops are preferred.
 
 use cursor::{Cursor, EncCursor};
 use dominator_tree::DominatorTree;
-use ir::{ArgumentLoc, Ebb, Function, Inst, InstBuilder, SigRef, Value, ValueLoc};
+use entity::{EntitySet, SecondaryMap};
+use ir::{
+    ArgumentLoc, DataFlowGraph, Ebb, Function, Inst, InstBuilder, Opcode, SigRef, Value, ValueDef,
+    ValueLoc,
+};
 use isa::registers::{RegClass, RegClassIndex, RegClassMask, RegUnit};
 use isa::{ConstraintKind, EncInfo, RecipeConstraints, RegInfo, TargetIsa};
+use loop_analysis::LoopAnalysis;
 use regalloc::affinity::Affinity;
 use regalloc::live_value_tracker::{LiveValue, LiveValueTracker};
 use regalloc::liveness::Liveness;
@@ -43,6 +56,8 @@ fn toprc_containing_regunit(unit: RegUnit, reginfo: &RegInfo) -> RegClass {
 pub struct Spilling {
     spills: Vec<Value>,
     reg_uses: Vec<RegUse>,
+    pinned: EntitySet<Value>,
+    use_depths: SecondaryMap<Value, usize>,
 }
 
 /// Context data structure that gets instantiated once per pass.
@@ -70,6 +85,12 @@ struct Context<'a> {
 
     // Uses of register values in the current instruction.
     reg_uses: &'a mut Vec<RegUse>,
+
+    // Values that need a spill slot even if their definition could be rematerialized.
+    pinned: &'a EntitySet<Value>,
+
+    // The deepest loop nesting level of any use of each value.
+    use_depths: &'a SecondaryMap<Value, usize>,
 }
 
 impl Spilling {
@@ -78,6 +99,8 @@ impl Spilling {
         Self {
             spills: Vec::new(),
             reg_uses: Vec::new(),
+            pinned: EntitySet::new(),
+            use_depths: SecondaryMap::new(),
         }
     }
 
@@ -85,6 +108,8 @@ impl Spilling {
     pub fn clear(&mut self) {
         self.spills.clear();
         self.reg_uses.clear();
+        self.pinned.clear();
+        self.use_depths.clear();
     }
 
     /// Run the spilling algorithm over `func`.
@@ -93,6 +118,7 @@ impl Spilling {
         isa: &TargetIsa,
         func: &mut Function,
         domtree: &DominatorTree,
+        loop_analysis: &LoopAnalysis,
         liveness: &mut Liveness,
         virtregs: &VirtRegs,
         topo: &mut TopoOrder,
@@ -102,6 +128,10 @@ impl Spilling {
         debug!("Spilling for:\n{}", func.display(isa));
         let reginfo = isa.register_info();
         let usable_regs = isa.allocatable_registers(func);
+        self.pinned.clear();
+        find_pinned_values(isa, func, &mut self.pinned);
+        self.use_depths.clear();
+        compute_use_depths(func, loop_analysis, &mut self.use_depths);
         let mut ctx = Context {
             cur: EncCursor::new(func, isa),
             reginfo: isa.register_info(),
@@ -113,6 +143,8 @@ impl Spilling {
             pressure: Pressure::new(&reginfo, &usable_regs),
             spills: &mut self.spills,
             reg_uses: &mut self.reg_uses,
+            pinned: &self.pinned,
+            use_depths: &self.use_depths,
         };
         ctx.run(tracker)
     }
@@ -491,6 +523,11 @@ impl<'a> Context<'a> {
         //
         // We know that all candidate defs dominate the current instruction, so one of them will
         // dominate the others. That is the earliest def.
+        //
+        // Values that can be rematerialized are spilled first, since they don't need a stack slot
+        // and recomputing them costs no more than a fill. Among the rest, values that aren't used
+        // in deeply nested loops are preferred, since each of their uses in a loop would need a
+        // fill on every iteration.
         candidates
             .into_iter()
             .filter_map(|lv| {
@@ -506,14 +543,29 @@ impl<'a> Context<'a> {
                 None
             }).min_by(|&a, &b| {
                 // Find the minimum candidate according to the RPO of their defs.
-                self.domtree.rpo_cmp(
-                    self.cur.func.dfg.value_def(a),
-                    self.cur.func.dfg.value_def(b),
-                    &self.cur.func.layout,
-                )
+                self.can_rematerialize(b)
+                    .cmp(&self.can_rematerialize(a))
+                    .then_with(|| self.use_depths[a].cmp(&self.use_depths[b]))
+                    .then_with(|| {
+                        self.domtree.rpo_cmp(
+                            self.cur.func.dfg.value_def(a),
+                            self.cur.func.dfg.value_def(b),
+                            &self.cur.func.layout,
+                        )
+                    })
             })
     }
 
+    /// Can `value` be recomputed at its uses instead of being spilled to a stack slot?
+    ///
+    /// The value must not belong to a virtual register, since the other values in it would need
+    /// the same stack slot.
+    fn can_rematerialize(&self, value: Value) -> bool {
+        !self.pinned.contains(value)
+            && self.virtregs.get(value).is_none()
+            && is_rematerializable(&self.cur.func.dfg, value)
+    }
+
     /// Spill `value` immediately by
     ///
     /// 1. Changing its affinity to `Stack` which marks the spill.
@@ -532,6 +584,11 @@ impl<'a> Context<'a> {
             panic!("Cannot spill {} that was already on the stack", value);
         }
 
+        if self.can_rematerialize(value) {
+            debug!("Rematerializing {} at its uses", value);
+            return;
+        }
+
         // Assign a spill slot for the whole virtual register.
         let ss = self
             .cur
@@ -579,6 +636,85 @@ impl<'a> Context<'a> {
     }
 }
 
+/// Is `value` defined by an instruction that can be repeated at its uses for free?
+///
+/// The instruction must have no value arguments, so repeating it doesn't extend other live ranges.
+pub fn is_rematerializable(dfg: &DataFlowGraph, value: Value) -> bool {
+    let inst = match dfg.value_def(value) {
+        ValueDef::Result(inst, _) => inst,
+        ValueDef::Param(_, _) => return false,
+    };
+    match dfg[inst].opcode() {
+        Opcode::Iconst
+        | Opcode::Bconst
+        | Opcode::F32const
+        | Opcode::F64const
+        | Opcode::FuncAddr
+        | Opcode::SymbolValue
+        | Opcode::StackAddr => dfg.inst_results(inst).len() == 1 && dfg.inst_args(inst).is_empty(),
+        _ => false,
+    }
+}
+
+/// Find the values that must get a stack slot when they are spilled, even if their definition
+/// could be rematerialized:
+///
+/// - Values used by ghost instructions, which the reload pass doesn't rewrite.
+/// - Values used by stack operands.
+/// - Values used while CPU flags are live, since the rematerialized instruction could clobber them.
+fn find_pinned_values(isa: &TargetIsa, func: &Function, pinned: &mut EntitySet<Value>) {
+    let encinfo = isa.encoding_info();
+    for ebb in func.layout.ebbs() {
+        // Walk backwards, so the flags used later in the EBB are known.
+        let mut live_flags = None;
+        for inst in func.layout.ebb_insts(ebb).rev() {
+            let args = func.dfg.inst_args(inst);
+            if let Some(flags) = live_flags {
+                if func.dfg.inst_results(inst).contains(&flags) {
+                    live_flags = None;
+                }
+            }
+            for &arg in args {
+                if func.dfg.value_type(arg).is_flags() {
+                    live_flags = Some(arg);
+                }
+            }
+            if live_flags.is_some() || func.dfg[inst].opcode().is_ghost() {
+                for &arg in args {
+                    pinned.insert(arg);
+                }
+            } else if let Some(constraints) = encinfo.operand_constraints(func.encodings[inst]) {
+                for (op, &arg) in constraints.ins.iter().zip(args) {
+                    if op.kind == ConstraintKind::Stack {
+                        pinned.insert(arg);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Compute the loop depth of the deepest use of each value in `func`.
+fn compute_use_depths(
+    func: &Function,
+    loop_analysis: &LoopAnalysis,
+    use_depths: &mut SecondaryMap<Value, usize>,
+) {
+    for ebb in func.layout.ebbs() {
+        let depth = loop_analysis.loop_depth(ebb);
+        if depth == 0 {
+            continue;
+        }
+        for inst in func.layout.ebb_insts(ebb) {
+            for &arg in func.dfg.inst_args(inst) {
+                if use_depths[arg] < depth {
+                    use_depths[arg] = depth;
+                }
+            }
+        }
+    }
+}
+
 /// Struct representing a register use of a value.
 /// Used to detect multiple uses of the same value with incompatible register constraints.
 #[derive(Clone, Copy)]
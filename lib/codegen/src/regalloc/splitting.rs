@@ -0,0 +1,403 @@
+//! Live range splitting.
+//!
+//! The spilling pass assigns a whole SSA value to either a register or a stack slot. A value that
+//! is live across a loop or a call without being used there tends to be spilled for its whole
+//! live range, and every use then needs a fill.
+//!
+//! This pass runs before coalescing and splits such live ranges by inserting `copy` instructions.
+//! The spiller can then pick a stack slot for the new value covering the loop or the call while
+//! the original value stays in a register elsewhere. The copies turn into `spill` and `fill`
+//! instructions when that happens.
+//!
+//! Two kinds of splits are made:
+//!
+//! 1. A value that is live through a loop without being used inside it is copied before the loop
+//!    is entered and copied again at the loop exits where it is still used. This is only done for
+//!    loops that contain a call or that have more values live through them than there are
+//!    registers.
+//! 2. When a call separates two uses of a value in the same EBB, the value is copied right before
+//!    the call and again right after it.
+//!
+//! Values that can be rematerialized are left alone, since the spiller handles them better.
+
+use cursor::{Cursor, EncCursor};
+use dominator_tree::DominatorTree;
+use flowgraph::ControlFlowGraph;
+use fx::FxHashMap;
+use ir::{Ebb, ExpandedProgramPoint, Function, Inst, InstBuilder, Value};
+use isa::TargetIsa;
+use loop_analysis::{Loop, LoopAnalysis};
+use regalloc::affinity::Affinity;
+use regalloc::liveness::Liveness;
+use regalloc::pressure::Pressure;
+use regalloc::spilling::is_rematerializable;
+use std::vec::Vec;
+use timing;
+
+/// Persistent data structures for the live range splitting pass.
+pub struct Splitting {
+    // Instructions using each value.
+    uses: FxHashMap<Value, Vec<Inst>>,
+
+    // Values considered for splitting around the current loop.
+    candidates: Vec<Value>,
+
+    // For each value accessed in the current EBB, the last instruction accessing it and the number
+    // of calls seen in the EBB before that access.
+    accesses: FxHashMap<Value, (Inst, usize)>,
+
+    // Calls seen so far in the current EBB.
+    calls: Vec<Inst>,
+}
+
+/// Context data structure that gets instantiated once per pass.
+struct Context<'a> {
+    cur: EncCursor<'a>,
+    isa: &'a TargetIsa,
+    cfg: &'a ControlFlowGraph,
+    domtree: &'a DominatorTree,
+    loop_analysis: &'a LoopAnalysis,
+    liveness: &'a Liveness,
+
+    uses: &'a mut FxHashMap<Value, Vec<Inst>>,
+    candidates: &'a mut Vec<Value>,
+    accesses: &'a mut FxHashMap<Value, (Inst, usize)>,
+    calls: &'a mut Vec<Inst>,
+
+    // Have any copies been inserted?
+    changed: bool,
+}
+
+impl Splitting {
+    /// Create a new splitting data structure.
+    pub fn new() -> Self {
+        Self {
+            uses: FxHashMap(),
+            candidates: Vec::new(),
+            accesses: FxHashMap(),
+            calls: Vec::new(),
+        }
+    }
+
+    /// Clear all data structures in this splitting pass.
+    pub fn clear(&mut self) {
+        self.uses.clear();
+        self.candidates.clear();
+        self.accesses.clear();
+        self.calls.clear();
+    }
+
+    /// Split live ranges in `func` around loops and calls.
+    ///
+    /// The liveness analysis is used to find the values live through loops, but it is not
+    /// updated. Returns true if any copies were inserted, in which case the liveness must be
+    /// recomputed.
+    pub fn run(
+        &mut self,
+        isa: &TargetIsa,
+        func: &mut Function,
+        cfg: &ControlFlowGraph,
+        domtree: &DominatorTree,
+        loop_analysis: &LoopAnalysis,
+        liveness: &Liveness,
+    ) -> bool {
+        let _tt = timing::ra_splitting();
+        debug!("Splitting live ranges for:\n{}", func.display(isa));
+        self.clear();
+        let mut ctx = Context {
+            cur: EncCursor::new(func, isa),
+            isa,
+            cfg,
+            domtree,
+            loop_analysis,
+            liveness,
+            uses: &mut self.uses,
+            candidates: &mut self.candidates,
+            accesses: &mut self.accesses,
+            calls: &mut self.calls,
+            changed: false,
+        };
+        ctx.run();
+        ctx.changed
+    }
+}
+
+impl<'a> Context<'a> {
+    fn run(&mut self) {
+        self.compute_uses();
+
+        // Visit outer loops before inner loops.
+        let mut loops: Vec<Loop> = self.loop_analysis.loops().collect();
+        loops.sort_by_key(|&lp| {
+            self.loop_analysis
+                .loop_depth(self.loop_analysis.loop_header(lp))
+        });
+        for lp in loops {
+            self.split_loop(lp);
+        }
+
+        let ebbs: Vec<Ebb> = self.cur.func.layout.ebbs().collect();
+        for ebb in ebbs {
+            self.split_calls(ebb);
+        }
+    }
+
+    // Collect the uses of all values in the function.
+    fn compute_uses(&mut self) {
+        let func = &self.cur.func;
+        for ebb in func.layout.ebbs() {
+            for inst in func.layout.ebb_insts(ebb) {
+                for &arg in func.dfg.inst_args(inst) {
+                    self.uses.entry(arg).or_default().push(inst);
+                }
+            }
+        }
+    }
+
+    // Is `value` a candidate for splitting at all?
+    fn is_splittable(&self, value: Value) -> bool {
+        let dfg = &self.cur.func.dfg;
+        !dfg.value_type(value).is_flags() && !is_rematerializable(dfg, value)
+    }
+
+    // Split the values that are live through `lp` without being used inside it.
+    fn split_loop(&mut self, lp: Loop) {
+        let header = self.loop_analysis.loop_header(lp);
+        let entry = match self.domtree.idom(header) {
+            Some(inst) => inst,
+            None => return,
+        };
+        if flags_live_before(self.cur.func, entry) {
+            return;
+        }
+
+        self.find_loop_candidates(lp);
+        if self.candidates.is_empty() || !self.is_crowded(lp) {
+            return;
+        }
+        debug!(
+            "Splitting {} values around loop at {}",
+            self.candidates.len(),
+            header
+        );
+
+        let mut exits = Vec::new();
+        for ebb in self.cur.func.layout.ebbs() {
+            if self.loop_analysis.is_in_loop(ebb, lp) {
+                for succ in self.cfg.succ_iter(ebb) {
+                    if !self.loop_analysis.is_in_loop(succ, lp) && !exits.contains(&succ) {
+                        exits.push(succ);
+                    }
+                }
+            }
+        }
+
+        for idx in 0..self.candidates.len() {
+            let value = self.candidates[idx];
+            let through = self.split(value, entry, true);
+            let through_inst = self.cur.func.dfg.value_def(through).unwrap_inst();
+            for &exit in &exits {
+                let first = match self.cur.func.layout.first_inst(exit) {
+                    Some(inst) => inst,
+                    None => continue,
+                };
+                // An exit to the header of an outer loop isn't dominated by the copy, so `through`
+                // isn't available there.
+                if self
+                    .domtree
+                    .dominates(through_inst, first, &self.cur.func.layout)
+                    && self.has_dominated_use(through, first)
+                    && !flags_live_before(self.cur.func, first)
+                {
+                    self.split(through, first, true);
+                }
+            }
+        }
+    }
+
+    // Collect the values that could be split around `lp` into `self.candidates`.
+    //
+    // These are register values that are live into the loop header, defined outside the loop, and
+    // not used inside it. Values in a nested loop are only considered if they are used in the
+    // parent loop, otherwise they would already have been split around the parent.
+    fn find_loop_candidates(&mut self, lp: Loop) {
+        self.candidates.clear();
+        let loop_analysis = self.loop_analysis;
+        let header = loop_analysis.loop_header(lp);
+        let parent = loop_analysis.loop_parent(lp);
+        let func = &self.cur.func;
+        let ctx = self.liveness.context(&func.layout);
+
+        for value in func.dfg.values() {
+            let lr = match self.liveness.get(value) {
+                Some(lr) => lr,
+                None => continue,
+            };
+            if !lr.affinity.is_reg() || !lr.is_livein(header, ctx) || !self.is_splittable(value) {
+                continue;
+            }
+            let def_ebb = func.layout.pp_ebb(lr.def());
+            if loop_analysis.is_in_loop(def_ebb, lp) {
+                continue;
+            }
+            let uses = match self.uses.get(&value) {
+                Some(uses) => uses,
+                None => continue,
+            };
+            let use_in = |lp: Loop| {
+                uses.iter().any(|&inst| {
+                    let ebb = func.layout.inst_ebb(inst).expect("use not in layout");
+                    loop_analysis.is_in_loop(ebb, lp)
+                })
+            };
+            if use_in(lp) {
+                continue;
+            }
+            if let Some(parent) = parent {
+                if !use_in(parent) {
+                    continue;
+                }
+            }
+            self.candidates.push(value);
+        }
+    }
+
+    // Would the values live through `lp` likely be spilled?
+    //
+    // Either the loop contains a call that clobbers registers, or there are more values live
+    // through the loop header than there are registers to hold them.
+    fn is_crowded(&self, lp: Loop) -> bool {
+        let func = &self.cur.func;
+        for ebb in func.layout.ebbs() {
+            if self.loop_analysis.is_in_loop(ebb, lp)
+                && func
+                    .layout
+                    .ebb_insts(ebb)
+                    .any(|inst| func.dfg[inst].opcode().is_call())
+            {
+                return true;
+            }
+        }
+
+        let reginfo = self.isa.register_info();
+        let usable_regs = self.isa.allocatable_registers(func);
+        let mut pressure = Pressure::new(&reginfo, &usable_regs);
+        let header = self.loop_analysis.loop_header(lp);
+        let ctx = self.liveness.context(&func.layout);
+        for value in func.dfg.values() {
+            if let Some(lr) = self.liveness.get(value) {
+                if lr.is_livein(header, ctx) {
+                    if let Affinity::Reg(rci) = lr.affinity {
+                        if pressure.take_transient(reginfo.rc(rci)).is_err() {
+                            return true;
+                        }
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    // Split live ranges of values used on both sides of a call in `ebb`.
+    fn split_calls(&mut self, ebb: Ebb) {
+        self.accesses.clear();
+        self.calls.clear();
+
+        self.cur.goto_top(ebb);
+        while let Some(inst) = self.cur.next_inst() {
+            let num_args = self.cur.func.dfg.inst_args(inst).len();
+            for idx in 0..num_args {
+                let value = self.cur.func.dfg.inst_args(inst)[idx];
+                let value = match self.accesses.get(&value).cloned() {
+                    Some((_, seen)) if seen < self.calls.len() && self.is_splittable(value) => {
+                        let before = self.calls[seen];
+                        let after = *self.calls.last().unwrap();
+                        // The call itself may use the value. Keep that use on the original value,
+                        // so only the copy is live across the call.
+                        let across = self.split(value, before, false);
+                        let next = self.cur.func.layout.next_inst(after).unwrap();
+                        self.split(across, next, true)
+                    }
+                    _ => value,
+                };
+                let calls = self.calls.len();
+                self.accesses.insert(value, (inst, calls));
+            }
+
+            // The splits above may have moved the cursor.
+            self.cur.goto_inst(inst);
+            if self.cur.func.dfg[inst].opcode().is_call() {
+                self.calls.push(inst);
+            }
+            let calls = self.calls.len();
+            for &result in self.cur.func.dfg.inst_results(inst) {
+                self.accesses.insert(result, (inst, calls));
+            }
+        }
+    }
+
+    // Does `value` have any uses dominated by `inst`?
+    fn has_dominated_use(&self, value: Value, inst: Inst) -> bool {
+        match self.uses.get(&value) {
+            Some(uses) => uses
+                .iter()
+                .any(|&u| self.domtree.dominates(inst, u, &self.cur.func.layout)),
+            None => false,
+        }
+    }
+
+    // Insert a copy of `value` before `before`, and rename all uses of `value` dominated by the
+    // copy. Uses by `before` itself are only renamed when `rename_before` is set. Returns the new
+    // value.
+    fn split(&mut self, value: Value, before: Inst, rename_before: bool) -> Value {
+        self.cur.goto_inst(before);
+        let copy = self.cur.ins().copy(value);
+        let copy_inst = self.cur.built_inst();
+        debug!("Split {} at {}", value, self.cur.display_inst(copy_inst));
+        self.changed = true;
+
+        let old_uses = self.uses.remove(&value).unwrap_or_default();
+        let mut kept = Vec::with_capacity(old_uses.len() + 1);
+        let mut renamed = Vec::new();
+        for inst in old_uses {
+            let dominated = self.domtree.dominates(
+                ExpandedProgramPoint::Inst(copy_inst),
+                inst,
+                &self.cur.func.layout,
+            );
+            if dominated && (rename_before || inst != before) {
+                for arg in self.cur.func.dfg.inst_args_mut(inst) {
+                    if *arg == value {
+                        *arg = copy;
+                    }
+                }
+                renamed.push(inst);
+            } else {
+                kept.push(inst);
+            }
+        }
+        kept.push(copy_inst);
+        self.uses.insert(value, kept);
+        self.uses.insert(copy, renamed);
+        copy
+    }
+}
+
+/// Are CPU flags live immediately before `inst`?
+///
+/// This is the case when an instruction at or after `inst` in the same EBB uses a flags value that
+/// isn't defined in between. A copy inserted there could clobber the flags.
+fn flags_live_before(func: &Function, inst: Inst) -> bool {
+    let mut defined = Vec::new();
+    let mut pos = Some(inst);
+    while let Some(inst) = pos {
+        for &arg in func.dfg.inst_args(inst) {
+            if func.dfg.value_type(arg).is_flags() && !defined.contains(&arg) {
+                return true;
+            }
+        }
+        defined.extend_from_slice(func.dfg.inst_results(inst));
+        pos = func.layout.next_inst(inst);
+    }
+    false
+}
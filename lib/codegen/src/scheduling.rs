@@ -0,0 +1,535 @@
+//! Instruction scheduling.
+//!
+//! Instructions are emitted in the order they were built, which is rarely the best order for the
+//! CPU: a load followed immediately by its use stalls the pipeline while independent work further
+//! down the EBB could have been executed in the meantime. This pass reorders the instructions in
+//! each EBB with a list scheduler that uses the latency model in the ISA's `EncInfo`.
+//!
+//! Instructions are never moved across branches, calls, or instructions with other side effects,
+//! so each EBB is split into *regions* that are scheduled independently. Within a region, the
+//! order of instructions is constrained by:
+//!
+//! - Data dependencies, weighted by the latency of the defining instruction.
+//! - Memory dependencies. Loads are not reordered with stores or instructions that can trap, and
+//!   stores and trapping instructions are kept in their original order.
+//! - CPU flags. An instruction that clobbers the CPU flags can't be moved between the definition
+//!   and the uses of a flags value.
+//!
+//! All dependencies point forward in the original order, so the original order is always a valid
+//! schedule. The scheduler issues one instruction per cycle, picking the ready instruction with
+//! the longest path of latencies to the end of the region.
+//!
+//! In the `pressure` mode, the scheduler also tracks the number of live registers in each register
+//! class using the same liveness analysis and pressure tracker as the register allocator's
+//! spilling pass. When issuing an instruction would need more registers than are available, it
+//! prefers instructions that don't increase the register pressure, even if that means stalling.
+
+use dominator_tree::DominatorTree;
+use entity::SecondaryMap;
+use flowgraph::ControlFlowGraph;
+use fx::FxHashMap;
+use ir::{Ebb, Function, Inst, Opcode, Value, ValueDef};
+use isa::{EncInfo, RegClass, RegInfo, TargetIsa};
+use regalloc::live_value_tracker::{LiveValue, LiveValueTracker};
+use regalloc::liveness::Liveness;
+use regalloc::{Affinity, Pressure};
+use settings::Scheduling;
+use std::cmp::{self, Reverse};
+use std::vec::Vec;
+use timing;
+
+/// The maximum number of instructions in a region. Longer sequences are split into multiple
+/// regions to bound the quadratic cost of the scheduler.
+const MAX_REGION_SIZE: usize = 256;
+
+/// Reorder the instructions in each EBB of `func` according to the `scheduling` setting.
+pub fn do_scheduling(
+    func: &mut Function,
+    cfg: &ControlFlowGraph,
+    domtree: &DominatorTree,
+    isa: &TargetIsa,
+) {
+    let _tt = timing::scheduling();
+    debug_assert!(domtree.is_valid());
+
+    let mut liveness = None;
+    if isa.flags().scheduling() == Scheduling::Pressure {
+        let mut lv = Liveness::new();
+        lv.compute(isa, func, cfg);
+        liveness = Some(lv);
+    }
+
+    let reginfo = isa.register_info();
+    let usable_regs = isa.allocatable_registers(func);
+    let mut scheduler = Scheduler {
+        encinfo: isa.encoding_info(),
+        reginfo: &reginfo,
+        liveness: liveness.as_ref(),
+        tracker: LiveValueTracker::new(),
+        pressure: Pressure::new(&reginfo, &usable_regs),
+        nodes: Vec::new(),
+        node_of: SecondaryMap::new(),
+        edges: Vec::new(),
+        first_edge: Vec::new(),
+        tracked: FxHashMap(),
+    };
+
+    // Dominators must be visited first for the live value tracker.
+    for &ebb in domtree.cfg_postorder().iter().rev() {
+        scheduler.visit_ebb(func, domtree, ebb);
+    }
+}
+
+/// An instruction in the region being scheduled.
+struct Node {
+    inst: Inst,
+    /// Cycles until the results of this instruction are available.
+    latency: u32,
+    /// The longest path of latencies from this instruction to the end of the region.
+    height: u32,
+    /// Number of predecessors that haven't been scheduled yet.
+    preds: u32,
+    /// The earliest cycle where all operands are available.
+    ready_at: u32,
+}
+
+/// A dependency between two nodes in the region.
+#[derive(Clone, Copy)]
+struct Edge {
+    from: usize,
+    to: usize,
+    latency: u32,
+}
+
+/// A value that occupies a register while the region is scheduled.
+struct Tracked {
+    rc: RegClass,
+    /// Does the live range end at an instruction inside the region?
+    dies: bool,
+    /// Number of unscheduled instructions in the region that use the value.
+    uses: u32,
+    /// The last instruction using the value in the EBB.
+    endpoint: Inst,
+}
+
+struct Scheduler<'a> {
+    encinfo: EncInfo,
+    reginfo: &'a RegInfo,
+    liveness: Option<&'a Liveness>,
+    tracker: LiveValueTracker,
+    pressure: Pressure,
+
+    // The dependency graph of the current region. Nodes are numbered in the original order.
+    nodes: Vec<Node>,
+    node_of: SecondaryMap<Inst, usize>,
+    edges: Vec<Edge>,
+    first_edge: Vec<usize>,
+
+    // Values occupying registers in the current region, in pressure-aware mode.
+    tracked: FxHashMap<Value, Tracked>,
+}
+
+impl<'a> Scheduler<'a> {
+    fn visit_ebb(&mut self, func: &mut Function, domtree: &DominatorTree, ebb: Ebb) {
+        if let Some(liveness) = self.liveness {
+            self.tracker
+                .ebb_top(ebb, &func.dfg, liveness, &func.layout, domtree);
+            self.tracker.drop_dead_params();
+        }
+
+        let insts: Vec<Inst> = func.layout.ebb_insts(ebb).collect();
+        let mut region = Vec::new();
+        for inst in insts {
+            let boundary = is_boundary(func.dfg[inst].opcode());
+            if boundary || region.len() == MAX_REGION_SIZE {
+                self.schedule_region(func, &region, inst);
+                region.clear();
+            }
+            if !boundary {
+                if region.is_empty() {
+                    self.start_region();
+                }
+                region.push(inst);
+            }
+            self.advance(func, inst);
+        }
+        debug_assert!(region.is_empty(), "EBB must end with a terminator");
+    }
+
+    /// Move the live value tracker past `inst`, recording the registers defined in the region.
+    fn advance(&mut self, func: &Function, inst: Inst) {
+        let liveness = match self.liveness {
+            Some(liveness) => liveness,
+            None => return,
+        };
+        if func.dfg[inst].opcode().is_ghost() {
+            self.tracker.process_ghost(inst);
+        } else {
+            let (_, _, defs) = self.tracker.process_inst(inst, &func.dfg, liveness);
+            for lv in defs {
+                track(&mut self.tracked, self.reginfo, lv);
+            }
+        }
+        self.tracker.drop_dead(inst);
+    }
+
+    /// Record the registers that are live at the start of a new region.
+    fn start_region(&mut self) {
+        if self.liveness.is_none() {
+            return;
+        }
+        self.tracked.clear();
+        self.pressure.reset();
+        for lv in self.tracker.live() {
+            if !lv.is_dead {
+                if let Some(rc) = track(&mut self.tracked, self.reginfo, lv) {
+                    self.pressure.take(rc);
+                }
+            }
+        }
+    }
+
+    /// Schedule the instructions in `region` and move them in front of `next`.
+    fn schedule_region(&mut self, func: &mut Function, region: &[Inst], next: Inst) {
+        if region.len() < 2 {
+            return;
+        }
+        self.build_graph(func, region);
+        self.count_uses(func);
+
+        let order = self.list_schedule(func);
+        if order.iter().enumerate().all(|(i, &n)| i == n) {
+            return;
+        }
+        for n in order {
+            let inst = self.nodes[n].inst;
+            func.layout.remove_inst(inst);
+            func.layout.insert_inst(inst, next);
+        }
+    }
+
+    /// Build the dependency graph of the instructions in `region`.
+    fn build_graph(&mut self, func: &Function, region: &[Inst]) {
+        self.nodes.clear();
+        self.edges.clear();
+
+        let mut last_effect = None;
+        let mut loads = Vec::new();
+        let mut flags_defs = Vec::new();
+        let mut flags_uses = Vec::new();
+        let mut flags_writers = Vec::new();
+
+        for (idx, &inst) in region.iter().enumerate() {
+            let opcode = func.dfg[inst].opcode();
+            self.node_of[inst] = idx;
+            self.nodes.push(Node {
+                inst,
+                latency: self.encinfo.latency(opcode),
+                height: 0,
+                preds: 0,
+                ready_at: 0,
+            });
+
+            // Data dependencies.
+            for &arg in func.dfg.inst_args(inst) {
+                if func.dfg.value_type(arg).is_flags() {
+                    flags_uses.push((func.dfg.resolve_aliases(arg), idx));
+                }
+                if let ValueDef::Result(def, _) = func.dfg.value_def(arg) {
+                    if let Some(from) = self.region_node(def) {
+                        let latency = self.nodes[from].latency;
+                        self.edges.push(Edge {
+                            from,
+                            to: idx,
+                            latency,
+                        });
+                    }
+                }
+            }
+
+            // Memory dependencies.
+            if opcode.can_store() || opcode.can_trap() {
+                for from in last_effect.into_iter().chain(loads.drain(..)) {
+                    self.edges.push(Edge {
+                        from,
+                        to: idx,
+                        latency: 1,
+                    });
+                }
+                last_effect = Some(idx);
+            } else if opcode.can_load() {
+                if let Some(from) = last_effect {
+                    self.edges.push(Edge {
+                        from,
+                        to: idx,
+                        latency: 1,
+                    });
+                }
+                loads.push(idx);
+            }
+
+            for &result in func.dfg.inst_results(inst) {
+                if func.dfg.value_type(result).is_flags() {
+                    flags_defs.push(idx);
+                }
+            }
+            if self.writes_flags(func, inst) {
+                flags_writers.push(idx);
+            }
+        }
+
+        // Keep the instructions writing the CPU flags out of the live ranges of flags values. In
+        // the original order, each writer is either above the definition or below all uses.
+        for &writer in &flags_writers {
+            for &def in &flags_defs {
+                if writer < def {
+                    self.edges.push(Edge {
+                        from: writer,
+                        to: def,
+                        latency: 1,
+                    });
+                }
+            }
+            for &(value, user) in &flags_uses {
+                let defined_above = match func.dfg.value_def(value) {
+                    ValueDef::Result(def, _) => self.region_node(def).map(|def| def < writer),
+                    ValueDef::Param(..) => None,
+                };
+                if user < writer && defined_above != Some(false) {
+                    self.edges.push(Edge {
+                        from: user,
+                        to: writer,
+                        latency: 1,
+                    });
+                }
+            }
+        }
+
+        // Index the edges by their source node, keeping the longest latency between two nodes.
+        self.edges
+            .sort_by_key(|e| (e.from, e.to, Reverse(e.latency)));
+        self.edges.dedup_by(|a, b| a.from == b.from && a.to == b.to);
+        self.first_edge.clear();
+        let mut e = 0;
+        for n in 0..=self.nodes.len() {
+            while e < self.edges.len() && self.edges[e].from < n {
+                e += 1;
+            }
+            self.first_edge.push(e);
+        }
+        for edge in &self.edges {
+            debug_assert!(edge.from < edge.to, "dependencies must point forward");
+            self.nodes[edge.to].preds += 1;
+        }
+
+        // Compute the heights bottom-up.
+        for n in (0..self.nodes.len()).rev() {
+            let mut height = self.nodes[n].latency;
+            for edge in &self.edges[self.first_edge[n]..self.first_edge[n + 1]] {
+                height = cmp::max(height, edge.latency + self.nodes[edge.to].height);
+            }
+            self.nodes[n].height = height;
+        }
+    }
+
+    /// Get the node of `inst` if it is in the current region.
+    fn region_node(&self, inst: Inst) -> Option<usize> {
+        let n = self.node_of[inst];
+        if n < self.nodes.len() && self.nodes[n].inst == inst {
+            Some(n)
+        } else {
+            None
+        }
+    }
+
+    /// Does `inst` overwrite the CPU flags?
+    fn writes_flags(&self, func: &Function, inst: Inst) -> bool {
+        self.encinfo
+            .operand_constraints(func.encodings[inst])
+            .map_or(false, |c| c.clobbers_flags)
+            || func
+                .dfg
+                .inst_results(inst)
+                .iter()
+                .any(|&v| func.dfg.value_type(v).is_flags())
+    }
+
+    /// Count the uses of the tracked values in the region, and find the values that die in it.
+    fn count_uses(&mut self, func: &Function) {
+        if self.liveness.is_none() {
+            return;
+        }
+        for tracked in self.tracked.values_mut() {
+            tracked.uses = 0;
+        }
+        for node in &self.nodes {
+            let args = func.dfg.inst_args(node.inst);
+            for (i, &arg) in args.iter().enumerate() {
+                if args[..i].contains(&arg) {
+                    continue;
+                }
+                if let Some(tracked) = self.tracked.get_mut(&arg) {
+                    tracked.uses += 1;
+                }
+            }
+        }
+        let nodes = &self.nodes;
+        let node_of = &self.node_of;
+        for tracked in self.tracked.values_mut() {
+            let n = node_of[tracked.endpoint];
+            tracked.dies = n < nodes.len() && nodes[n].inst == tracked.endpoint;
+        }
+    }
+
+    /// Compute a schedule for the current region.
+    ///
+    /// Returns the nodes in their new order.
+    fn list_schedule(&mut self, func: &Function) -> Vec<usize> {
+        let mut ready: Vec<usize> = (0..self.nodes.len())
+            .filter(|&n| self.nodes[n].preds == 0)
+            .collect();
+        let mut order = Vec::with_capacity(self.nodes.len());
+        let mut cycle = 0;
+
+        while !ready.is_empty() {
+            let pick = {
+                let mut best = 0;
+                let mut best_key = None;
+                for (i, &n) in ready.iter().enumerate() {
+                    let pressure = self.pressure_rank(func, n);
+                    let node = &self.nodes[n];
+                    let key = (pressure, node.ready_at <= cycle, node.height, Reverse(n));
+                    if Some(key) > best_key {
+                        best = i;
+                        best_key = Some(key);
+                    }
+                }
+                ready.swap_remove(best)
+            };
+
+            cycle = cmp::max(cycle, self.nodes[pick].ready_at);
+            for e in self.first_edge[pick]..self.first_edge[pick + 1] {
+                let edge = self.edges[e];
+                let succ = &mut self.nodes[edge.to];
+                succ.ready_at = cmp::max(succ.ready_at, cycle + edge.latency);
+                succ.preds -= 1;
+                if succ.preds == 0 {
+                    ready.push(edge.to);
+                }
+            }
+            cycle += 1;
+
+            self.issue(func, pick);
+            order.push(pick);
+        }
+        debug_assert_eq!(order.len(), self.nodes.len());
+        order
+    }
+
+    /// Rank node `n` by its effect on the register pressure. Higher is better.
+    ///
+    /// Nodes that fit in the available registers all rank equally, above nodes that don't fit.
+    /// Among the latter, nodes that increase the pressure the least rank higher.
+    fn pressure_rank(&mut self, func: &Function, n: usize) -> (bool, i32) {
+        if self.liveness.is_none() {
+            return (true, 0);
+        }
+        let inst = self.nodes[n].inst;
+        let kills = self.kills(func, inst);
+        for &rc in &kills {
+            self.pressure.free(rc);
+        }
+        let mut fits = true;
+        let mut defs = 0;
+        for result in func.dfg.inst_results(inst) {
+            if let Some(tracked) = self.tracked.get(result) {
+                if !tracked.dies || tracked.uses > 0 {
+                    defs += 1;
+                    fits &= self.pressure.take_transient(tracked.rc).is_ok();
+                }
+            }
+        }
+        self.pressure.reset_transient();
+        for &rc in &kills {
+            self.pressure.take(rc);
+        }
+        if fits {
+            (true, 0)
+        } else {
+            (false, kills.len() as i32 - defs)
+        }
+    }
+
+    /// Get the register classes of the values whose last use is `inst`.
+    fn kills(&self, func: &Function, inst: Inst) -> Vec<RegClass> {
+        let args = func.dfg.inst_args(inst);
+        let mut kills = Vec::new();
+        for (i, arg) in args.iter().enumerate() {
+            if let Some(tracked) = self.tracked.get(arg) {
+                if tracked.dies && tracked.uses == 1 && !args[..i].contains(arg) {
+                    kills.push(tracked.rc);
+                }
+            }
+        }
+        kills
+    }
+
+    /// Update the register pressure after issuing node `n`.
+    fn issue(&mut self, func: &Function, n: usize) {
+        if self.liveness.is_none() {
+            return;
+        }
+        let inst = self.nodes[n].inst;
+        for &rc in &self.kills(func, inst) {
+            self.pressure.free(rc);
+        }
+        let args = func.dfg.inst_args(inst);
+        for (i, arg) in args.iter().enumerate() {
+            if !args[..i].contains(arg) {
+                if let Some(tracked) = self.tracked.get_mut(arg) {
+                    tracked.uses -= 1;
+                }
+            }
+        }
+        for result in func.dfg.inst_results(inst) {
+            if let Some(tracked) = self.tracked.get(result) {
+                if !tracked.dies || tracked.uses > 0 {
+                    self.pressure.take(tracked.rc);
+                }
+            }
+        }
+    }
+}
+
+/// Is `opcode` an instruction that can't be moved by the scheduler?
+fn is_boundary(opcode: Opcode) -> bool {
+    opcode.is_branch()
+        || opcode.is_terminator()
+        || opcode.is_call()
+        || opcode.is_ghost()
+        || opcode.other_side_effects()
+}
+
+/// Start tracking `lv` if it needs a register. Returns its register class if it was tracked.
+fn track(
+    tracked: &mut FxHashMap<Value, Tracked>,
+    reginfo: &RegInfo,
+    lv: &LiveValue,
+) -> Option<RegClass> {
+    match lv.affinity {
+        Affinity::Reg(rci) => {
+            let rc = reginfo.rc(rci);
+            tracked.insert(
+                lv.value,
+                Tracked {
+                    rc,
+                    dies: false,
+                    uses: 0,
+                    endpoint: lv.endpoint,
+                },
+            );
+            Some(rc)
+        }
+        _ => None,
+    }
+}
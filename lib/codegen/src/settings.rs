@@ -371,12 +371,15 @@ mod tests {
             f.to_string(),
             "[shared]\n\
              opt_level = \"default\"\n\
+             regalloc = \"coloring\"\n\
+             scheduling = \"none\"\n\
              enable_verifier = true\n\
              is_pic = false\n\
              colocated_libcalls = false\n\
              avoid_div_traps = false\n\
              enable_float = true\n\
              enable_nan_canonicalization = false\n\
+             enable_spectre_mitigation = false\n\
              enable_simd = true\n\
              enable_atomics = true\n\
              baldrdash_prologue_words = 0\n\
@@ -384,7 +387,9 @@ mod tests {
              probestack_enabled = true\n\
              probestack_func_adjusts_sp = false\n\
              probestack_size_log2 = 12\n\
-             jump_tables_enabled = true\n"
+             jump_tables_enabled = true\n\
+             loop_unroll_factor = 0\n\
+             loop_peel_first_iteration = false\n"
         );
         assert_eq!(f.opt_level(), super::OptLevel::Default);
         assert_eq!(f.enable_simd(), true);
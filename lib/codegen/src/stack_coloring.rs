@@ -0,0 +1,441 @@
+//! Stack slot coloring.
+//!
+//! The register allocator creates a new spill slot for every spilled virtual register, and the
+//! stack layout gives each stack slot its own memory. This pass computes the live ranges of spill
+//! slots and explicit stack slots, and merges slots that are never live at the same time so they
+//! share memory.
+//!
+//! A stack slot is live from the instructions that write it to the instructions that read it:
+//!
+//! - Spill slots are written by `spill` and `regspill` instructions and by EBB parameters, and
+//!   read by `fill` and `regfill` instructions and other uses of the values assigned to them.
+//! - Explicit slots are written by `stack_store` and read by `stack_load`, or by a `store` or a
+//!   `load` whose address comes directly from `stack_addr` after legalization. A store that doesn't
+//!   cover the whole slot doesn't end the live range. Slots whose address is used in any other way
+//!   are never merged, since the memory could be accessed anywhere.
+//!
+//! Two slots interfere if one is written while the other is live, or if both are live on entry to
+//! the function. Slots are merged greedily, largest first, into a slot that is at least as large
+//! and as aligned.
+
+use entity::SecondaryMap;
+use ir::instructions::BranchInfo;
+use ir::stackslot::StackSize;
+use ir::{Ebb, Function, Inst, InstructionData, Opcode, StackSlot, StackSlotKind, Value, ValueLoc};
+use std::cmp::Reverse;
+use std::vec::Vec;
+use timing;
+
+/// How an instruction accesses a stack slot.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// Read from the slot.
+    Read,
+    /// Write part of the slot.
+    Write,
+    /// Overwrite the whole slot.
+    Kill,
+}
+
+/// A set of candidate slots, indexed by their number in `Coloring::slots`.
+#[derive(Clone, Default)]
+struct SlotSet {
+    words: Vec<u64>,
+}
+
+impl SlotSet {
+    fn new(len: usize) -> Self {
+        Self {
+            words: vec![0; (len + 63) / 64],
+        }
+    }
+
+    fn contains(&self, idx: usize) -> bool {
+        self.words[idx / 64] & (1 << (idx % 64)) != 0
+    }
+
+    fn insert(&mut self, idx: usize) {
+        self.words[idx / 64] |= 1 << (idx % 64);
+    }
+
+    fn remove(&mut self, idx: usize) {
+        self.words[idx / 64] &= !(1 << (idx % 64));
+    }
+
+    fn union(&mut self, other: &Self) {
+        for (word, &other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    fn members(&self) -> Vec<usize> {
+        let mut members = Vec::new();
+        for (i, &word) in self.words.iter().enumerate() {
+            let mut bits = word;
+            while bits != 0 {
+                members.push(i * 64 + bits.trailing_zeros() as usize);
+                bits &= bits - 1;
+            }
+        }
+        members
+    }
+}
+
+/// Get the alignment implied by a slot size.
+fn size_alignment(size: StackSize) -> StackSize {
+    size & size.wrapping_neg()
+}
+
+struct Coloring<'a> {
+    func: &'a mut Function,
+
+    /// The slots that may be merged.
+    slots: Vec<StackSlot>,
+
+    /// The index of each slot in `slots`.
+    index: SecondaryMap<StackSlot, Option<usize>>,
+
+    /// Values defined by `stack_addr`, with the slot and offset they point to.
+    addresses: SecondaryMap<Value, Option<(StackSlot, i32)>>,
+
+    /// Slots live on entry to each EBB.
+    live_in: SecondaryMap<Ebb, SlotSet>,
+
+    /// Interference rows for each slot in `slots`.
+    interference: Vec<SlotSet>,
+
+    /// Scratch space for the accesses of one instruction.
+    accesses: Vec<(usize, Access)>,
+}
+
+/// Merge the spill slots and explicit slots in `func` that are never live at the same time.
+pub fn do_stack_coloring(func: &mut Function) {
+    let _tt = timing::stack_coloring();
+    let mut coloring = Coloring {
+        func,
+        slots: Vec::new(),
+        index: SecondaryMap::new(),
+        addresses: SecondaryMap::new(),
+        live_in: SecondaryMap::new(),
+        interference: Vec::new(),
+        accesses: Vec::new(),
+    };
+    if coloring.find_candidates() < 2 {
+        return;
+    }
+    coloring.compute_liveness();
+    coloring.compute_interference();
+    coloring.merge_slots();
+}
+
+impl<'a> Coloring<'a> {
+    /// Find the slots that can be merged, and return their number.
+    fn find_candidates(&mut self) -> usize {
+        let func = &*self.func;
+        for ebb in func.layout.ebbs() {
+            for inst in func.layout.ebb_insts(ebb) {
+                if let InstructionData::StackLoad {
+                    opcode: Opcode::StackAddr,
+                    stack_slot,
+                    offset,
+                } = func.dfg[inst]
+                {
+                    let result = func.dfg.first_result(inst);
+                    self.addresses[result] = Some((stack_slot, offset.into()));
+                }
+            }
+        }
+
+        // The address of a slot may only be used directly by loads and stores.
+        let mut address_taken = SecondaryMap::<StackSlot, bool>::new();
+        for ebb in func.layout.ebbs() {
+            for inst in func.layout.ebb_insts(ebb) {
+                // Compare operand positions rather than values, since `store v1, v1` stores the
+                // address itself.
+                let address_index = match func.dfg[inst] {
+                    InstructionData::Load { .. } => Some(0),
+                    InstructionData::Store { .. } => Some(1),
+                    _ => None,
+                };
+                for (index, &arg) in func.dfg.inst_args(inst).iter().enumerate() {
+                    if let Some((ss, _)) = self.addresses[arg] {
+                        if Some(index) != address_index {
+                            address_taken[ss] = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        for (ss, data) in func.stack_slots.iter() {
+            let mergeable = match data.kind {
+                StackSlotKind::SpillSlot => true,
+                StackSlotKind::ExplicitSlot => !address_taken[ss],
+                _ => false,
+            };
+            if mergeable && data.size > 0 {
+                self.index[ss] = Some(self.slots.len());
+                self.slots.push(ss);
+            }
+        }
+        self.slots.len()
+    }
+
+    /// Collect the accesses of `inst` to candidate slots in `self.accesses`.
+    fn collect_accesses(&mut self, inst: Inst) {
+        self.accesses.clear();
+        let func = &*self.func;
+        let index = &self.index;
+        let addresses = &self.addresses;
+        let accesses = &mut self.accesses;
+        let mut add = |ss: StackSlot, access: Access| {
+            if let Some(idx) = index[ss] {
+                accesses.push((idx, access));
+            }
+        };
+
+        match func.dfg[inst] {
+            InstructionData::StackLoad {
+                opcode: Opcode::StackLoad,
+                stack_slot,
+                ..
+            } => add(stack_slot, Access::Read),
+            InstructionData::StackStore {
+                arg,
+                stack_slot,
+                offset,
+                ..
+            } => {
+                let size = func.dfg.value_type(arg).bytes();
+                let offset: i32 = offset.into();
+                if offset == 0 && size >= func.stack_slots[stack_slot].size {
+                    add(stack_slot, Access::Kill);
+                } else {
+                    add(stack_slot, Access::Write);
+                }
+            }
+            InstructionData::Load { arg, .. } => {
+                if let Some((ss, _)) = addresses[arg] {
+                    add(ss, Access::Read);
+                }
+            }
+            InstructionData::Store {
+                opcode,
+                args,
+                offset,
+                ..
+            } => {
+                if let Some((ss, base)) = addresses[args[1]] {
+                    let size = func.dfg.value_type(args[0]).bytes();
+                    let offset: i32 = offset.into();
+                    if opcode == Opcode::Store
+                        && base + offset == 0
+                        && size >= func.stack_slots[ss].size
+                    {
+                        add(ss, Access::Kill);
+                    } else {
+                        add(ss, Access::Write);
+                    }
+                }
+            }
+            InstructionData::RegSpill { dst, .. } => add(dst, Access::Kill),
+            InstructionData::RegFill { src, .. } => add(src, Access::Read),
+            _ => {}
+        }
+
+        for &arg in func.dfg.inst_args(inst) {
+            if let ValueLoc::Stack(ss) = func.locations[arg] {
+                add(ss, Access::Read);
+            }
+        }
+        for &res in func.dfg.inst_results(inst) {
+            if let ValueLoc::Stack(ss) = func.locations[res] {
+                add(ss, Access::Kill);
+            }
+        }
+    }
+
+    /// Add the slots live into the destinations of `inst` to `live`.
+    ///
+    /// Branches can appear anywhere in an EBB, so this must be done at each branch rather than
+    /// once at the end of the EBB.
+    fn add_branch_live_ins(&self, inst: Inst, live: &mut SlotSet) {
+        match self.func.dfg.analyze_branch(inst) {
+            BranchInfo::NotABranch => {}
+            BranchInfo::SingleDest(dest, _) => live.union(&self.live_in[dest]),
+            BranchInfo::Table(jt, default) => {
+                if let Some(dest) = default {
+                    live.union(&self.live_in[dest]);
+                }
+                for &dest in self.func.jump_tables[jt].iter() {
+                    live.union(&self.live_in[dest]);
+                }
+            }
+        }
+    }
+
+    /// Compute the live-in sets of all EBBs by iterating to a fixed point.
+    fn compute_liveness(&mut self) {
+        let ebbs: Vec<Ebb> = self.func.layout.ebbs().collect();
+        for &ebb in &ebbs {
+            self.live_in[ebb] = SlotSet::new(self.slots.len());
+        }
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &ebb in ebbs.iter().rev() {
+                let mut live = SlotSet::new(self.slots.len());
+                self.transfer_ebb(ebb, &mut live, false);
+                if live.words != self.live_in[ebb].words {
+                    self.live_in[ebb] = live;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    /// Compute the interference between candidate slots.
+    fn compute_interference(&mut self) {
+        let len = self.slots.len();
+        self.interference = vec![SlotSet::new(len); len];
+
+        let ebbs: Vec<Ebb> = self.func.layout.ebbs().collect();
+        for &ebb in &ebbs {
+            let mut live = SlotSet::new(self.slots.len());
+            self.transfer_ebb(ebb, &mut live, true);
+        }
+
+        // Slots that are live on entry may be read before they are written, and they are live at
+        // the same time.
+        if let Some(entry) = self.func.layout.entry_block() {
+            let live = self.live_in[entry].clone();
+            for idx in live.members() {
+                self.interfere(idx, &live);
+            }
+        }
+    }
+
+    /// Propagate `live` backwards through `ebb`, recording interference if `interfere` is set.
+    fn transfer_ebb(&mut self, ebb: Ebb, live: &mut SlotSet, interfere: bool) {
+        let mut pos = self.func.layout.last_inst(ebb);
+        while let Some(inst) = pos {
+            self.add_branch_live_ins(inst, live);
+            self.collect_accesses(inst);
+            self.transfer(live, interfere);
+            pos = self.func.layout.prev_inst(inst);
+        }
+
+        self.accesses.clear();
+        for &param in self.func.dfg.ebb_params(ebb) {
+            if let ValueLoc::Stack(ss) = self.func.locations[param] {
+                if let Some(idx) = self.index[ss] {
+                    self.accesses.push((idx, Access::Kill));
+                }
+            }
+        }
+        self.transfer(live, interfere);
+    }
+
+    /// Propagate `live` backwards through the accesses in `self.accesses`.
+    fn transfer(&mut self, live: &mut SlotSet, interfere: bool) {
+        for i in 0..self.accesses.len() {
+            let (idx, access) = self.accesses[i];
+            if access != Access::Read && interfere {
+                self.interfere(idx, live);
+            }
+        }
+        for &(idx, access) in &self.accesses {
+            if access == Access::Kill {
+                live.remove(idx);
+            }
+        }
+        for &(idx, access) in &self.accesses {
+            if access == Access::Read {
+                live.insert(idx);
+            }
+        }
+    }
+
+    /// Record that slot `idx` interferes with all slots in `live`.
+    fn interfere(&mut self, idx: usize, live: &SlotSet) {
+        for other in live.members() {
+            if other != idx {
+                self.interference[idx].insert(other);
+                self.interference[other].insert(idx);
+            }
+        }
+    }
+
+    /// Assign slots to colors and rewrite all references to the merged slots.
+    fn merge_slots(&mut self) {
+        let func = &mut *self.func;
+        let slots = &self.slots;
+
+        // Visit the largest slots first so they become the representatives of their colors.
+        let mut order: Vec<usize> = (0..slots.len()).collect();
+        order.sort_by_key(|&idx| (Reverse(func.stack_slots[slots[idx]].size), idx));
+
+        // Each color has a representative slot and the union of its members' interference.
+        let mut colors: Vec<(StackSlot, SlotSet)> = Vec::new();
+        let mut merged = SecondaryMap::<StackSlot, Option<StackSlot>>::new();
+        let mut num_merged = 0;
+        for idx in order {
+            let ss = slots[idx];
+            let size = func.stack_slots[ss].size;
+            let color = colors.iter_mut().find(|&&mut (rep, ref interference)| {
+                let rep_size = func.stack_slots[rep].size;
+                !interference.contains(idx)
+                    && rep_size >= size
+                    && size_alignment(rep_size) >= size_alignment(size)
+            });
+            match color {
+                Some(&mut (rep, ref mut interference)) => {
+                    interference.union(&self.interference[idx]);
+                    merged[ss] = Some(rep);
+                    num_merged += 1;
+                }
+                None => colors.push((ss, self.interference[idx].clone())),
+            }
+        }
+        if num_merged == 0 {
+            return;
+        }
+        debug!("Merging {} stack slots", num_merged);
+
+        for ebb in func.layout.ebbs() {
+            for inst in func.layout.ebb_insts(ebb) {
+                match func.dfg[inst] {
+                    InstructionData::StackLoad {
+                        ref mut stack_slot, ..
+                    }
+                    | InstructionData::StackStore {
+                        ref mut stack_slot, ..
+                    }
+                    | InstructionData::RegSpill {
+                        dst: ref mut stack_slot,
+                        ..
+                    }
+                    | InstructionData::RegFill {
+                        src: ref mut stack_slot,
+                        ..
+                    } => {
+                        if let Some(rep) = merged[*stack_slot] {
+                            *stack_slot = rep;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for value in func.dfg.values() {
+            if let ValueLoc::Stack(ss) = func.locations[value] {
+                if let Some(rep) = merged[ss] {
+                    func.locations[value] = ValueLoc::Stack(rep);
+                }
+            }
+        }
+
+        func.retain_stack_slots(|ss, _| merged[ss].is_none());
+    }
+}
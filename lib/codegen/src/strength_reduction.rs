@@ -0,0 +1,460 @@
+//! Strength reduction and linear-function test replacement.
+//!
+//! A derived induction variable that involves a multiplication, such as the `base + i * 8`
+//! address of an array element, is replaced by a new parameter of the loop header. It gets its
+//! initial value on the edges entering the loop, and is incremented by `8 * step` on the back
+//! edges instead of being recomputed from `i` on each iteration.
+//!
+//! When the trip count of the loop is a constant, the exit test comparing the basic induction
+//! variable is then replaced by a comparison of the new induction variable with its final value.
+//! This often leaves the basic induction variable unused, and it is removed.
+
+use cursor::{Cursor, FuncCursor};
+use dominator_tree::DominatorTree;
+use entity::SecondaryMap;
+use flowgraph::{BasicBlock, ControlFlowGraph};
+use induction_variables::{constant, mask, InductionVariables, LinearFunction};
+use ir::condcodes::IntCC;
+use ir::instructions::BranchInfo;
+use ir::{Ebb, Function, Inst, InstBuilder, Value, ValueDef};
+use loop_analysis::{Loop, LoopAnalysis};
+use std::vec::Vec;
+use timing;
+
+/// Perform strength reduction and linear-function test replacement on the loops in `func`.
+pub fn do_strength_reduction(
+    func: &mut Function,
+    cfg: &ControlFlowGraph,
+    domtree: &DominatorTree,
+    loop_analysis: &LoopAnalysis,
+) {
+    let _tt = timing::strength_reduction();
+    debug_assert!(cfg.is_valid());
+    debug_assert!(domtree.is_valid());
+    debug_assert!(loop_analysis.is_valid());
+
+    let mut ivs = InductionVariables::new();
+    ivs.compute(func, cfg, domtree, loop_analysis);
+    for lp in loop_analysis.loops() {
+        let mut reducer = match LoopReducer::new(func, cfg, loop_analysis, &ivs, lp) {
+            Some(reducer) => reducer,
+            None => continue,
+        };
+        let reduced = reducer.reduce(func, domtree);
+        if reduced.is_empty() {
+            continue;
+        }
+        remove_dead_arithmetic(func, &reducer);
+        for &(function, value) in &reduced {
+            reducer.replace_test(func, cfg, domtree, function, value);
+        }
+    }
+}
+
+/// Strength reduction of a single loop.
+struct LoopReducer<'a> {
+    ivs: &'a InductionVariables,
+    loop_analysis: &'a LoopAnalysis,
+    lp: Loop,
+    header: Ebb,
+    /// The EBBs in the loop.
+    ebbs: Vec<Ebb>,
+    /// The branches entering the loop.
+    entries: Vec<Inst>,
+    /// The branches back to the header.
+    latches: Vec<Inst>,
+}
+
+impl<'a> LoopReducer<'a> {
+    fn new(
+        func: &Function,
+        cfg: &ControlFlowGraph,
+        loop_analysis: &'a LoopAnalysis,
+        ivs: &'a InductionVariables,
+        lp: Loop,
+    ) -> Option<Self> {
+        if !ivs
+            .basic_induction_variables()
+            .iter()
+            .any(|basic| basic.lp == lp)
+        {
+            return None;
+        }
+        let header = loop_analysis.loop_header(lp);
+        let mut entries = Vec::new();
+        let mut latches = Vec::new();
+        for BasicBlock { ebb, inst } in cfg.pred_iter(header) {
+            match func.dfg.analyze_branch(inst) {
+                BranchInfo::SingleDest(_, _) => {}
+                _ => return None,
+            }
+            if loop_analysis.is_in_loop(ebb, lp) {
+                latches.push(inst);
+            } else {
+                entries.push(inst);
+            }
+        }
+        if entries.is_empty() {
+            return None;
+        }
+        let ebbs = func
+            .layout
+            .ebbs()
+            .filter(|&ebb| loop_analysis.is_in_loop(ebb, lp))
+            .collect();
+        Some(Self {
+            ivs,
+            loop_analysis,
+            lp,
+            header,
+            ebbs,
+            entries,
+            latches,
+        })
+    }
+
+    /// Get the linear function computed by `value` if it is a derived induction variable of this
+    /// loop involving a multiplication.
+    fn candidate(&self, func: &Function, value: Value) -> Option<LinearFunction> {
+        let function = self.ivs.linear_function(value)?;
+        if function.scale == 0 || function.scale == 1 {
+            return None;
+        }
+        if self.ivs.basic_induction_variable(function.iv)?.lp != self.lp {
+            return None;
+        }
+        match func.dfg.value_def(value) {
+            ValueDef::Result(inst, _) => {
+                let ebb = func.layout.inst_ebb(inst)?;
+                if self.loop_analysis.is_in_loop(ebb, self.lp) {
+                    Some(function)
+                } else {
+                    None
+                }
+            }
+            ValueDef::Param(_, _) => None,
+        }
+    }
+
+    /// Replace the candidates that are used by anything other than the computation of another
+    /// candidate with new induction variables. Returns the linear functions that were replaced,
+    /// and the header parameters replacing them.
+    fn reduce(
+        &mut self,
+        func: &mut Function,
+        domtree: &DominatorTree,
+    ) -> Vec<(LinearFunction, Value)> {
+        // Find the candidates with other uses, grouping them by the function they compute.
+        let mut groups: Vec<(LinearFunction, Vec<Value>)> = Vec::new();
+        let mut is_root = SecondaryMap::<Value, bool>::new();
+        for ebb in func.layout.ebbs() {
+            for inst in func.layout.ebb_insts(ebb) {
+                let user_is_candidate = match func.dfg.inst_results(inst).first() {
+                    Some(&result) => self.candidate(func, result).is_some(),
+                    None => false,
+                };
+                if user_is_candidate {
+                    continue;
+                }
+                for &arg in func.dfg.inst_args(inst) {
+                    let arg = func.dfg.resolve_aliases(arg);
+                    if is_root[arg] {
+                        continue;
+                    }
+                    if let Some(function) = self.candidate(func, arg) {
+                        is_root[arg] = true;
+                        match groups.iter().position(|&(f, _)| f == function) {
+                            Some(index) => groups[index].1.push(arg),
+                            None => groups.push((function, vec![arg])),
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut reduced = Vec::new();
+        for (function, values) in groups {
+            if let Some(base) = function.base {
+                // The base is invariant, so it should dominate the loop entries.
+                let dominates = |inst: &Inst| match func.dfg.value_def(base) {
+                    ValueDef::Result(def, _) => domtree.dominates(def, *inst, &func.layout),
+                    ValueDef::Param(ebb, _) => domtree.dominates(ebb, *inst, &func.layout),
+                };
+                if !self.entries.iter().all(dominates) {
+                    continue;
+                }
+            }
+            let value = self.add_induction_variable(func, function);
+            for value_to_replace in values {
+                let inst = match func.dfg.value_def(value_to_replace) {
+                    ValueDef::Result(inst, _) => inst,
+                    ValueDef::Param(_, _) => unreachable!(),
+                };
+                func.dfg.clear_results(inst);
+                func.dfg.change_to_alias(value_to_replace, value);
+                func.layout.remove_inst(inst);
+            }
+            reduced.push((function, value));
+        }
+        reduced
+    }
+
+    /// Add a header parameter computing `function`, and return it.
+    fn add_induction_variable(&self, func: &mut Function, function: LinearFunction) -> Value {
+        let ty = func.dfg.value_type(function.iv);
+        let bits = ty.bits();
+        let index = self.param_index(func, function.iv);
+        let step = self.ivs.basic_induction_variable(function.iv).unwrap().step;
+        let value = func.dfg.append_ebb_param(self.header, ty);
+
+        for &entry in &self.entries {
+            let init = func.dfg.inst_variable_args(entry)[index];
+            let constant_init = constant(func, init);
+            let mut pos = FuncCursor::new(func).at_inst(entry);
+            let init = match (constant_init, function.base) {
+                (Some(c), base) => {
+                    let c = truncate(
+                        c.wrapping_mul(function.scale).wrapping_add(function.offset),
+                        bits,
+                    );
+                    match base {
+                        Some(base) if c == 0 => base,
+                        Some(base) => pos.ins().iadd_imm(base, c),
+                        None => pos.ins().iconst(ty, c),
+                    }
+                }
+                (None, base) => {
+                    let mut init = pos.ins().imul_imm(init, truncate(function.scale, bits));
+                    if function.offset != 0 {
+                        init = pos.ins().iadd_imm(init, truncate(function.offset, bits));
+                    }
+                    match base {
+                        Some(base) => pos.ins().iadd(init, base),
+                        None => init,
+                    }
+                }
+            };
+            pos.func.dfg.append_inst_arg(entry, init);
+        }
+
+        let increment = truncate(function.scale.wrapping_mul(step), bits);
+        for &latch in &self.latches {
+            let mut pos = FuncCursor::new(func).at_inst(latch);
+            let next = pos.ins().iadd_imm(value, increment);
+            pos.func.dfg.append_inst_arg(latch, next);
+        }
+        value
+    }
+
+    /// Get the index of `param` among the header parameters.
+    fn param_index(&self, func: &Function, param: Value) -> usize {
+        match func.dfg.value_def(param) {
+            ValueDef::Param(ebb, num) if ebb == self.header => num,
+            _ => panic!("{} is not a parameter of {}", param, self.header),
+        }
+    }
+
+    /// Replace the exit test comparing the basic induction variable of `function` by a test of
+    /// `value`, which computes `function`. Then remove the basic induction variable if it is no
+    /// longer used.
+    ///
+    /// The trip count must be a constant, so the final value of `value` is known. The test must
+    /// be executed on every iteration, and it must also be the first time `value` takes its
+    /// final value.
+    fn replace_test(
+        &self,
+        func: &mut Function,
+        cfg: &ControlFlowGraph,
+        domtree: &DominatorTree,
+        function: LinearFunction,
+        value: Value,
+    ) {
+        let iv = function.iv;
+        if !func.dfg.value_is_attached(iv) {
+            return;
+        }
+        let step = self.ivs.basic_induction_variable(iv).unwrap().step;
+        let index = self.param_index(func, iv);
+        let bits = func.dfg.value_type(iv).bits();
+
+        let init = {
+            let mut inits = self
+                .entries
+                .iter()
+                .map(|&entry| constant(func, func.dfg.inst_variable_args(entry)[index]));
+            let first = inits.next().unwrap();
+            if inits.any(|init| init != first) {
+                return;
+            }
+            match first {
+                Some(init) => init,
+                None => return,
+            }
+        };
+
+        let exit = match self
+            .ivs
+            .exit_test(func, cfg, domtree, self.loop_analysis, iv)
+        {
+            Some(exit) => exit,
+            None => return,
+        };
+        // Only replace the test if that makes the basic induction variable unused.
+        let increments = match self.increments_if_unused(func, iv, index, exit.test) {
+            Some(increments) => increments,
+            None => return,
+        };
+        let trips = match exit.trip_count(bits, init, step) {
+            Some(trips) => trips,
+            None => return,
+        };
+
+        // The values of `value` on each iteration must differ from its final value.
+        let increment = function.scale.wrapping_mul(step) & mask(bits);
+        if increment == 0 {
+            return;
+        }
+        let period_bits = u32::from(bits) - increment.trailing_zeros();
+        if period_bits < 64 && trips >= 1 << period_bits {
+            return;
+        }
+
+        let final_value = function
+            .scale
+            .wrapping_mul(init.wrapping_add((trips as i64).wrapping_mul(step)))
+            .wrapping_add(function.offset);
+        let cond = if exit.exits_when {
+            IntCC::Equal
+        } else {
+            IntCC::NotEqual
+        };
+        {
+            let test = exit.test;
+            let mut pos = FuncCursor::new(func).at_inst(test);
+            match function.base {
+                Some(base) => {
+                    let final_value = pos.ins().iadd_imm(base, truncate(final_value, bits));
+                    pos.func.dfg.replace(test).icmp(cond, value, final_value);
+                }
+                None => {
+                    pos.func
+                        .dfg
+                        .replace(test)
+                        .icmp_imm(cond, value, truncate(final_value, bits));
+                }
+            }
+        }
+
+        self.remove_induction_variable(func, iv, index, &increments);
+    }
+
+    /// If the basic induction variable `iv`, which is the header parameter at `index`, is only
+    /// used by `test` and to compute its own increments, get the increments.
+    fn increments_if_unused(
+        &self,
+        func: &Function,
+        iv: Value,
+        index: usize,
+        test: Inst,
+    ) -> Option<Vec<Value>> {
+        let mut increments = Vec::new();
+        for &latch in &self.latches {
+            let arg = func
+                .dfg
+                .resolve_aliases(func.dfg.inst_variable_args(latch)[index]);
+            if arg == iv {
+                return None;
+            }
+            if !increments.contains(&arg) {
+                increments.push(arg);
+            }
+        }
+
+        // Each increment must only be used by the latches, and `iv` only by the increments.
+        let mut uses = SecondaryMap::<Value, usize>::new();
+        for ebb in func.layout.ebbs() {
+            for inst in func.layout.ebb_insts(ebb) {
+                if inst == test {
+                    continue;
+                }
+                for &arg in func.dfg.inst_args(inst) {
+                    uses[func.dfg.resolve_aliases(arg)] += 1;
+                }
+            }
+        }
+        let increment_uses: usize = increments.iter().map(|&increment| uses[increment]).sum();
+        if uses[iv] != increments.len() || increment_uses != self.latches.len() {
+            return None;
+        }
+        Some(increments)
+    }
+
+    /// Remove the basic induction variable `iv`, which is the header parameter at `index`, and
+    /// its `increments`.
+    fn remove_induction_variable(
+        &self,
+        func: &mut Function,
+        iv: Value,
+        index: usize,
+        increments: &[Value],
+    ) {
+        for &branch in self.entries.iter().chain(&self.latches) {
+            let num_fixed = func.dfg.inst_fixed_args(branch).len();
+            let mut args = func.dfg[branch].take_value_list().unwrap();
+            args.remove(num_fixed + index, &mut func.dfg.value_lists);
+            func.dfg[branch].put_value_list(args);
+        }
+        func.dfg.remove_ebb_param(iv);
+        for &increment in increments {
+            if let ValueDef::Result(inst, _) = func.dfg.value_def(increment) {
+                func.layout.remove_inst(inst);
+            }
+        }
+    }
+}
+
+/// Remove the instructions in the loop of `reducer` computing induction variables which are no
+/// longer used.
+fn remove_dead_arithmetic(func: &mut Function, reducer: &LoopReducer) {
+    let mut uses = SecondaryMap::<Value, usize>::new();
+    for ebb in func.layout.ebbs() {
+        for inst in func.layout.ebb_insts(ebb) {
+            for &arg in func.dfg.inst_args(inst) {
+                uses[func.dfg.resolve_aliases(arg)] += 1;
+            }
+        }
+    }
+
+    let mut worklist = Vec::new();
+    for &ebb in &reducer.ebbs {
+        for inst in func.layout.ebb_insts(ebb) {
+            worklist.push(inst);
+        }
+    }
+    while let Some(inst) = worklist.pop() {
+        if func.layout.inst_ebb(inst).is_none() {
+            continue;
+        }
+        if func.dfg.inst_results(inst).len() != 1 {
+            continue;
+        }
+        let result = func.dfg.first_result(inst);
+        if uses[result] != 0 || reducer.ivs.linear_function(result).is_none() {
+            continue;
+        }
+        for &arg in func.dfg.inst_args(inst) {
+            let arg = func.dfg.resolve_aliases(arg);
+            uses[arg] -= 1;
+            if let ValueDef::Result(def, _) = func.dfg.value_def(arg) {
+                worklist.push(def);
+            }
+        }
+        func.layout.remove_inst(inst);
+    }
+}
+
+/// Truncate `x` to `bits` bits and sign-extend it, which is how immediates are interpreted.
+fn truncate(x: i64, bits: u16) -> i64 {
+    let shift = 64 - u32::from(bits);
+    (x << shift) >> shift
+}
@@ -55,21 +55,36 @@ define_passes!{
     flowgraph: "Control flow graph",
     domtree: "Dominator tree",
     loop_analysis: "Loop analysis",
+    induction_variables: "Induction variable analysis",
+    edge_instrumentation: "Edge profiling instrumentation",
     postopt: "Post-legalization rewriting",
     preopt: "Pre-legalization rewriting",
+    peepholes: "Peephole rewrites",
     dce: "Dead code elimination",
+    dead_stores: "Dead store elimination",
+    if_conversion: "If-conversion",
     legalize: "Legalization",
     gvn: "Global value numbering",
     licm: "Loop invariant code motion",
+    inline: "Function inlining",
+    unroll: "Loop unrolling",
+    strength_reduction: "Strength reduction",
+    bounds_checks: "Bounds check elimination",
+    redundant_loads: "Redundant load elimination",
     unreachable_code: "Remove unreachable blocks",
+    block_placement: "Block placement",
+    scheduling: "Instruction scheduling",
 
     regalloc: "Register allocation",
     ra_liveness: "RA liveness analysis",
+    ra_splitting: "RA live range splitting",
     ra_cssa: "RA coalescing CSSA",
     ra_spilling: "RA spilling",
     ra_reload: "RA reloading",
     ra_coloring: "RA coloring",
+    ra_linear_scan: "RA linear scan",
 
+    stack_coloring: "Stack slot coloring",
     prologue_epilogue: "Prologue/epilogue insertion",
     shrink_instructions: "Instruction encoding shrinking",
     relax_branches: "Branch relaxation",
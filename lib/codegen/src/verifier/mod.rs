@@ -1655,19 +1655,28 @@ impl<'a> Verifier<'a> {
     ) -> VerifierStepResult<()> {
         let inst_data = &self.func.dfg[inst];
 
-        // If this is some sort of a store instruction, get the memflags, else, just return.
-        let memflags = match *inst_data {
+        // If this is some sort of a load or store instruction, get the memflags, else, just
+        // return.
+        let (memflags, is_store) = match *inst_data {
             ir::InstructionData::Store { flags, .. }
-            | ir::InstructionData::StoreComplex { flags, .. } => flags,
+            | ir::InstructionData::StoreComplex { flags, .. } => (flags, true),
+            ir::InstructionData::Load { flags, .. }
+            | ir::InstructionData::LoadComplex { flags, .. } => (flags, false),
             _ => return Ok(()),
         };
 
-        if memflags.readonly() {
+        if is_store && memflags.readonly() {
             fatal!(
                 errors,
                 inst,
                 "A store instruction cannot have the `readonly` MemFlag"
             )
+        } else if memflags.has_conflicting_alias_regions() {
+            fatal!(
+                errors,
+                inst,
+                "A memory instruction cannot be in more than one alias region"
+            )
         } else {
             Ok(())
         }
@@ -218,19 +218,22 @@ pub fn write_ebb_header(
     let regs = regs.as_ref();
 
     let mut args = func.dfg.ebb_params(ebb).iter().cloned();
-    match args.next() {
-        None => return writeln!(w, ":"),
-        Some(arg) => {
-            write!(w, "(")?;
+    if let Some(arg) = args.next() {
+        write!(w, "(")?;
+        write_arg(w, func, regs, arg)?;
+        // Remaining arguments.
+        for arg in args {
+            write!(w, ", ")?;
             write_arg(w, func, regs, arg)?;
         }
+        write!(w, ")")?;
     }
-    // Remaining arguments.
-    for arg in args {
-        write!(w, ", ")?;
-        write_arg(w, func, regs, arg)?;
+
+    let frequency = func.ebb_frequencies[ebb];
+    if !frequency.is_unknown() {
+        write!(w, " {}", frequency)?;
     }
-    writeln!(w, "):")
+    writeln!(w, ":")
 }
 
 fn decorate_ebb<FW: FuncWriter>(
@@ -276,16 +276,22 @@ impl Backend for FaerieBackend {
         &mut self,
         _func: &FaerieCompiledFunction,
         _namespace: &ModuleNamespace<Self>,
-    ) {
+    ) -> ModuleResult<()> {
         // Nothing to do.
+        Ok(())
     }
 
     fn get_finalized_function(&self, _func: &FaerieCompiledFunction) {
         // Nothing to do.
     }
 
-    fn finalize_data(&mut self, _data: &FaerieCompiledData, _namespace: &ModuleNamespace<Self>) {
+    fn finalize_data(
+        &mut self,
+        _data: &FaerieCompiledData,
+        _namespace: &ModuleNamespace<Self>,
+    ) -> ModuleResult<()> {
         // Nothing to do.
+        Ok(())
     }
 
     fn get_finalized_data(&self, _data: &FaerieCompiledData) {
@@ -45,19 +45,30 @@ mod runone;
 mod subtest;
 
 mod test_binemit;
+mod test_block_placement;
+mod test_bounds_checks;
 mod test_cat;
 mod test_compile;
 mod test_dce;
+mod test_dead_stores;
 mod test_domtree;
+mod test_edge_profile;
+mod test_if_conversion;
 mod test_legalizer;
+mod test_peepholes;
 mod test_licm;
 mod test_postopt;
 mod test_preopt;
 mod test_print_cfg;
+mod test_redundant_loads;
 mod test_regalloc;
+mod test_schedule;
 mod test_shrink;
 mod test_simple_gvn;
 mod test_simple_preopt;
+mod test_stack_coloring;
+mod test_strength_reduction;
+mod test_unroll;
 mod test_verifier;
 
 /// The result of running the test in a file.
@@ -122,18 +133,29 @@ pub fn run_passes(
 fn new_subtest(parsed: &TestCommand) -> subtest::SubtestResult<Box<subtest::SubTest>> {
     match parsed.command {
         "binemit" => test_binemit::subtest(parsed),
+        "block-placement" => test_block_placement::subtest(parsed),
+        "bounds-checks" => test_bounds_checks::subtest(parsed),
         "cat" => test_cat::subtest(parsed),
         "compile" => test_compile::subtest(parsed),
         "dce" => test_dce::subtest(parsed),
+        "dead-stores" => test_dead_stores::subtest(parsed),
         "domtree" => test_domtree::subtest(parsed),
+        "edge-profile" => test_edge_profile::subtest(parsed),
+        "if-conversion" => test_if_conversion::subtest(parsed),
         "legalizer" => test_legalizer::subtest(parsed),
         "licm" => test_licm::subtest(parsed),
+        "peepholes" => test_peepholes::subtest(parsed),
         "postopt" => test_postopt::subtest(parsed),
         "simple_preopt" => test_simple_preopt::subtest(parsed),
         "print-cfg" => test_print_cfg::subtest(parsed),
+        "redundant-loads" => test_redundant_loads::subtest(parsed),
         "regalloc" => test_regalloc::subtest(parsed),
+        "schedule" => test_schedule::subtest(parsed),
         "shrink" => test_shrink::subtest(parsed),
         "simple-gvn" => test_simple_gvn::subtest(parsed),
+        "stack-coloring" => test_stack_coloring::subtest(parsed),
+        "strength-reduction" => test_strength_reduction::subtest(parsed),
+        "unroll" => test_unroll::subtest(parsed),
         "verifier" => test_verifier::subtest(parsed),
         "preopt" => test_preopt::subtest(parsed),
         _ => Err(format!("unknown test command '{}'", parsed.command)),
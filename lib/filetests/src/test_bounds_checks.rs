@@ -0,0 +1,47 @@
+//! Test command for testing the redundant bounds check elimination pass.
+//!
+//! The `bounds-checks` test command runs each function through the redundant heap bounds check
+//! elimination pass.
+//!
+//! The resulting function is sent to `filecheck`.
+
+use cranelift_codegen;
+use cranelift_codegen::ir::Function;
+use cranelift_codegen::print_errors::pretty_error;
+use cranelift_reader::TestCommand;
+use std::borrow::Cow;
+use subtest::{run_filecheck, Context, SubTest, SubtestResult};
+
+struct TestBoundsChecks;
+
+pub fn subtest(parsed: &TestCommand) -> SubtestResult<Box<SubTest>> {
+    assert_eq!(parsed.command, "bounds-checks");
+    if !parsed.options.is_empty() {
+        Err(format!("No options allowed on {}", parsed))
+    } else {
+        Ok(Box::new(TestBoundsChecks))
+    }
+}
+
+impl SubTest for TestBoundsChecks {
+    fn name(&self) -> &'static str {
+        "bounds-checks"
+    }
+
+    fn is_mutating(&self) -> bool {
+        true
+    }
+
+    fn run(&self, func: Cow<Function>, context: &Context) -> SubtestResult<()> {
+        let mut comp_ctx = cranelift_codegen::Context::for_function(func.into_owned());
+
+        comp_ctx.flowgraph();
+        comp_ctx.compute_loop_analysis();
+        comp_ctx
+            .bounds_check_elim(context.flags_or_isa())
+            .map_err(|e| pretty_error(&comp_ctx.func, context.isa, Into::into(e)))?;
+
+        let text = comp_ctx.func.display(context.isa).to_string();
+        run_filecheck(&text, context)
+    }
+}
@@ -37,6 +37,11 @@
 //! with [`Variable::new(var_index)`] you should make sure that `var_index` is provided by a
 //! counter incremented by 1 each time you encounter a new mutable variable.
 //!
+//! Variables that can't be expressed this way, for example because their address is needed, can
+//! live in explicit stack slots instead. After the function is built,
+//! [`promote_stack_slots`](fn.promote_stack_slots.html) turns the stack slots whose address turns
+//! out not to escape into SSA values as if they had been variables all along.
+//!
 //! # Example
 //!
 //! Here is a pseudo-program we want to transform into Cranelift IR:
@@ -187,10 +192,12 @@ extern crate target_lexicon;
 extern crate log;
 
 pub use frontend::{FunctionBuilder, FunctionBuilderContext};
+pub use mem2reg::promote_stack_slots;
 pub use switch::Switch;
 pub use variable::Variable;
 
 mod frontend;
+mod mem2reg;
 mod ssa;
 mod switch;
 mod variable;
@@ -0,0 +1,504 @@
+//! Promotion of explicit stack slots to SSA values.
+//!
+//! Frontends that can't express all of their local variables with `Variable` allocate explicit
+//! stack slots for them instead, and access them with `stack_load`, `stack_store` and
+//! `stack_addr`. When the address of such a slot never escapes, the slot behaves just like a
+//! variable, and `promote_stack_slots` rewrites its loads and stores into SSA values, adding EBB
+//! parameters where the definitions of a slot merge. This is done with the same SSA construction
+//! algorithm that `FunctionBuilder` uses for variables.
+
+use cranelift_codegen::cursor::{Cursor, FuncCursor};
+use cranelift_codegen::entity::{EntityRef, SecondaryMap};
+use cranelift_codegen::ir::instructions::BranchInfo;
+use cranelift_codegen::ir::{
+    Ebb, Function, Inst, InstructionData, Opcode, StackSlot, StackSlotKind, Type, Value,
+};
+use ssa::{Block, SSABuilder};
+use std::vec::Vec;
+use Variable;
+
+/// How the instructions in a function use a stack slot.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SlotUse {
+    /// The stack slot is never used.
+    Unused,
+    /// All the uses of the stack slot are loads and stores of a `Type` at the same offset.
+    Access(Type, i64),
+    /// The stack slot can't be promoted.
+    Escaped,
+}
+
+impl Default for SlotUse {
+    fn default() -> Self {
+        SlotUse::Unused
+    }
+}
+
+/// How an instruction accesses a stack slot.
+enum Access {
+    /// A load of the slot, producing the instruction's result.
+    Load(StackSlot, Type, i64),
+    /// A store of a value to the slot.
+    Store(StackSlot, Value, i64),
+    /// The computation of the slot's address.
+    Addr(StackSlot),
+}
+
+/// Promote the explicit stack slots of `func` which are only ever loaded and stored whole to SSA
+/// values, and remove them from the function.
+///
+/// A stack slot can be promoted if:
+///
+/// - all the `stack_load` and `stack_store` instructions accessing it use the same type and
+///   offset, and
+/// - the address computed by each `stack_addr` instruction referring to it is only used as the
+///   address of plain `load` and `store` instructions, again of the same type and offset.
+///
+/// Loading from a promoted stack slot before storing to it yields zero.
+///
+/// Returns the number of stack slots that were promoted.
+pub fn promote_stack_slots(func: &mut Function) -> usize {
+    let slots = find_promotable_slots(func);
+    let mut promoted = Vec::new();
+    for (ss, &slot_use) in slots.iter() {
+        if let SlotUse::Access(_, _) = slot_use {
+            promoted.push(ss);
+        }
+    }
+    if promoted.is_empty() || !has_explicit_cfg(func) {
+        return 0;
+    }
+
+    rewrite_accesses(func, &slots);
+    func.retain_stack_slots(|ss, _| match slots[ss] {
+        SlotUse::Access(_, _) => false,
+        _ => true,
+    });
+    promoted.len()
+}
+
+/// Decide which stack slots can be promoted.
+fn find_promotable_slots(func: &Function) -> SecondaryMap<StackSlot, SlotUse> {
+    let mut slots = SecondaryMap::new();
+    let mut addrs = SecondaryMap::<Value, Option<(StackSlot, i64)>>::new();
+
+    // Find all the slot addresses first, since the layout order doesn't guarantee that we see
+    // them before their uses.
+    for ebb in func.layout.ebbs() {
+        for inst in func.layout.ebb_insts(ebb) {
+            if let InstructionData::StackLoad {
+                opcode: Opcode::StackAddr,
+                stack_slot,
+                offset,
+            } = func.dfg[inst]
+            {
+                let addr = func.dfg.first_result(inst);
+                addrs[addr] = Some((stack_slot, offset.into()));
+            }
+        }
+    }
+    let addr_of = |value: Value| addrs[func.dfg.resolve_aliases(value)];
+
+    for ebb in func.layout.ebbs() {
+        for inst in func.layout.ebb_insts(ebb) {
+            match access(func, inst, addr_of) {
+                Some(Access::Load(ss, ty, offset)) => note_access(func, &mut slots, ss, ty, offset),
+                Some(Access::Store(ss, value, offset)) => {
+                    let ty = func.dfg.value_type(value);
+                    note_access(func, &mut slots, ss, ty, offset);
+                    if let Some((addr_ss, _)) = addr_of(value) {
+                        slots[addr_ss] = SlotUse::Escaped;
+                    }
+                }
+                Some(Access::Addr(_)) => {}
+                None => {
+                    // Any other use of a slot address lets it escape.
+                    for &arg in func.dfg.inst_args(inst) {
+                        if let Some((ss, _)) = addr_of(arg) {
+                            slots[ss] = SlotUse::Escaped;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    slots
+}
+
+/// Record an access of type `ty` at `offset` in the stack slot `ss`.
+fn note_access(
+    func: &Function,
+    slots: &mut SecondaryMap<StackSlot, SlotUse>,
+    ss: StackSlot,
+    ty: Type,
+    offset: i64,
+) {
+    let data = &func.stack_slots[ss];
+    let in_bounds = offset >= 0 && offset + i64::from(ty.bytes()) <= i64::from(data.size);
+    slots[ss] = match slots[ss] {
+        _ if data.kind != StackSlotKind::ExplicitSlot || !in_bounds => SlotUse::Escaped,
+        SlotUse::Unused => SlotUse::Access(ty, offset),
+        SlotUse::Access(old_ty, old_offset) if old_ty == ty && old_offset == offset => {
+            SlotUse::Access(ty, offset)
+        }
+        _ => SlotUse::Escaped,
+    };
+}
+
+/// Determine how `inst` accesses a stack slot, if it does. `addr_of` maps the values computed by
+/// `stack_addr` instructions to their stack slot and offset.
+fn access<F>(func: &Function, inst: Inst, addr_of: F) -> Option<Access>
+where
+    F: Fn(Value) -> Option<(StackSlot, i64)>,
+{
+    match func.dfg[inst] {
+        InstructionData::StackLoad {
+            opcode,
+            stack_slot,
+            offset,
+        } => {
+            let offset: i64 = offset.into();
+            match opcode {
+                Opcode::StackLoad => Some(Access::Load(
+                    stack_slot,
+                    func.dfg.ctrl_typevar(inst),
+                    offset,
+                )),
+                Opcode::StackAddr => Some(Access::Addr(stack_slot)),
+                _ => None,
+            }
+        }
+        InstructionData::StackStore {
+            opcode: Opcode::StackStore,
+            arg,
+            stack_slot,
+            offset,
+        } => Some(Access::Store(stack_slot, arg, offset.into())),
+        InstructionData::Load {
+            opcode: Opcode::Load,
+            arg,
+            offset,
+            ..
+        } => addr_of(arg).map(|(ss, base)| {
+            let offset: i64 = offset.into();
+            Access::Load(ss, func.dfg.ctrl_typevar(inst), base + offset)
+        }),
+        InstructionData::Store {
+            opcode: Opcode::Store,
+            args,
+            offset,
+            ..
+        } => addr_of(args[1]).map(|(ss, base)| {
+            let offset: i64 = offset.into();
+            Access::Store(ss, args[0], base + offset)
+        }),
+        _ => None,
+    }
+}
+
+/// Check that all the branches in `func` have known destinations, and that the entry block
+/// isn't a branch target. New EBB parameters can only be supplied by such branches.
+fn has_explicit_cfg(func: &Function) -> bool {
+    let entry = func.layout.entry_block();
+    for ebb in func.layout.ebbs() {
+        for inst in func.layout.ebb_insts(ebb) {
+            let mut dests = Vec::new();
+            match func.dfg.analyze_branch(inst) {
+                BranchInfo::SingleDest(dest, _) => dests.push(dest),
+                BranchInfo::Table(jt, default) => {
+                    dests.extend(default);
+                    dests.extend(func.jump_tables[jt].iter().cloned());
+                }
+                BranchInfo::NotABranch => {
+                    if func.dfg[inst].opcode().is_branch() {
+                        return false;
+                    }
+                }
+            }
+            if dests.iter().any(|&dest| Some(dest) == entry) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Replace the loads and stores of the promotable stack slots in `func` with SSA values, and
+/// remove their `stack_addr` instructions.
+fn rewrite_accesses(func: &mut Function, slots: &SecondaryMap<StackSlot, SlotUse>) {
+    let is_promoted = |ss: StackSlot| match slots[ss] {
+        SlotUse::Access(_, _) => true,
+        _ => false,
+    };
+    let mut addrs = SecondaryMap::<Value, Option<(StackSlot, i64)>>::new();
+    for ebb in func.layout.ebbs() {
+        for inst in func.layout.ebb_insts(ebb) {
+            if let InstructionData::StackLoad {
+                opcode: Opcode::StackAddr,
+                stack_slot,
+                offset,
+            } = func.dfg[inst]
+            {
+                if is_promoted(stack_slot) {
+                    addrs[func.dfg.first_result(inst)] = Some((stack_slot, offset.into()));
+                }
+            }
+        }
+    }
+
+    let mut ssa = SSABuilder::new();
+    for index in 0..func.dfg.num_ebbs() {
+        ssa.declare_ebb_header_block(Ebb::new(index));
+    }
+
+    let mut pos = FuncCursor::new(func);
+    while let Some(ebb) = pos.next_ebb() {
+        let mut block = ssa.header_block(ebb);
+        while let Some(inst) = pos.next_inst() {
+            pos.func.dfg.resolve_aliases_in_arguments(inst);
+            match access(pos.func, inst, |value| addrs[value]) {
+                Some(Access::Load(ss, ty, _)) if is_promoted(ss) => {
+                    let value = ssa
+                        .use_var(pos.func, Variable::new(ss.index()), ty, block)
+                        .0;
+                    let result = pos.func.dfg.first_result(inst);
+                    pos.func.dfg.clear_results(inst);
+                    pos.func.dfg.change_to_alias(result, value);
+                    pos.remove_inst_and_step_back();
+                }
+                Some(Access::Store(ss, value, _)) if is_promoted(ss) => {
+                    ssa.def_var(Variable::new(ss.index()), value, block);
+                    pos.remove_inst_and_step_back();
+                }
+                Some(Access::Addr(ss)) if is_promoted(ss) => {
+                    pos.remove_inst_and_step_back();
+                }
+                _ => block = declare_successors(&mut ssa, pos.func, inst, block),
+            }
+        }
+    }
+
+    ssa.seal_all_ebb_header_blocks(func);
+
+    // Clean up the aliases left by the SSA construction.
+    let mut pos = FuncCursor::new(func);
+    while let Some(_ebb) = pos.next_ebb() {
+        while let Some(inst) = pos.next_inst() {
+            pos.func.dfg.resolve_aliases_in_arguments(inst);
+        }
+    }
+}
+
+/// Declare `block` as a predecessor of the destinations of `inst`, if it is a branch. Returns the
+/// block that the instructions following `inst` belong to.
+fn declare_successors(ssa: &mut SSABuilder, func: &Function, inst: Inst, block: Block) -> Block {
+    let mut dests = Vec::new();
+    match func.dfg.analyze_branch(inst) {
+        BranchInfo::SingleDest(dest, _) => dests.push(dest),
+        BranchInfo::Table(jt, default) => {
+            dests.extend(default);
+            dests.extend(func.jump_tables[jt].iter().cloned());
+        }
+        BranchInfo::NotABranch => return block,
+    }
+    // A jump table may branch to the same EBB more than once, but each predecessor must only be
+    // declared once.
+    dests.sort_unstable();
+    dests.dedup();
+    for dest in dests {
+        ssa.declare_ebb_predecessor(dest, block, inst);
+    }
+    if func.dfg[inst].opcode().is_terminator() {
+        block
+    } else {
+        ssa.declare_ebb_body_block(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::promote_stack_slots;
+    use cranelift_codegen::ir::types::*;
+    use cranelift_codegen::ir::{
+        AbiParam, ExternalName, Function, InstBuilder, MemFlags, Opcode, Signature, StackSlotData,
+        StackSlotKind,
+    };
+    use cranelift_codegen::isa::CallConv;
+    use cranelift_codegen::settings;
+    use cranelift_codegen::verifier::verify_function;
+    use frontend::{FunctionBuilder, FunctionBuilderContext};
+
+    fn new_function(params: &[AbiParam], returns: &[AbiParam]) -> Function {
+        let mut sig = Signature::new(CallConv::SystemV);
+        sig.params.extend_from_slice(params);
+        sig.returns.extend_from_slice(returns);
+        Function::with_name_signature(ExternalName::testcase("sample"), sig)
+    }
+
+    fn check(func: &Function) {
+        let flags = settings::Flags::new(settings::builder());
+        if let Err(errors) = verify_function(func, &flags) {
+            panic!("{}\n{}", func.display(None), errors)
+        }
+    }
+
+    fn count_memory_insts(func: &Function) -> usize {
+        func.layout
+            .ebbs()
+            .flat_map(|ebb| func.layout.ebb_insts(ebb))
+            .filter(|&inst| {
+                let opcode = func.dfg[inst].opcode();
+                opcode.can_load() || opcode.can_store() || opcode == Opcode::StackAddr
+            })
+            .count()
+    }
+
+    #[test]
+    fn diamond() {
+        let mut func = new_function(&[AbiParam::new(I32)], &[AbiParam::new(I32)]);
+        {
+            let mut fn_ctx = FunctionBuilderContext::new();
+            let mut builder = FunctionBuilder::new(&mut func, &mut fn_ctx);
+            let ss = builder.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 4));
+            let ebb0 = builder.create_ebb();
+            let ebb1 = builder.create_ebb();
+            let ebb2 = builder.create_ebb();
+            builder.append_ebb_params_for_function_params(ebb0);
+
+            builder.switch_to_block(ebb0);
+            let arg = builder.ebb_params(ebb0)[0];
+            builder.ins().stack_store(arg, ss, 0);
+            builder.ins().brz(arg, ebb2, &[]);
+            builder.ins().jump(ebb1, &[]);
+
+            builder.switch_to_block(ebb1);
+            let one = builder.ins().iconst(I32, 1);
+            builder.ins().stack_store(one, ss, 0);
+            builder.ins().jump(ebb2, &[]);
+
+            builder.switch_to_block(ebb2);
+            let value = builder.ins().stack_load(I32, ss, 0);
+            builder.ins().return_(&[value]);
+            builder.seal_all_blocks();
+            builder.finalize();
+        }
+
+        assert_eq!(promote_stack_slots(&mut func), 1);
+        check(&func);
+        assert_eq!(count_memory_insts(&func), 0);
+        assert_eq!(func.stack_slots.keys().count(), 0);
+        assert_eq!(
+            func.display(None).to_string(),
+            "function %sample(i32) -> i32 system_v {
+ebb0(v0: i32):
+    brz v0, ebb2(v0)
+    jump ebb1
+
+ebb1:
+    v1 = iconst.i32 1
+    jump ebb2(v1)
+
+ebb2(v3: i32):
+    v2 -> v3
+    return v3
+}
+"
+        );
+    }
+
+    #[test]
+    fn uninitialized() {
+        let mut func = new_function(&[], &[AbiParam::new(I64)]);
+        {
+            let mut fn_ctx = FunctionBuilderContext::new();
+            let mut builder = FunctionBuilder::new(&mut func, &mut fn_ctx);
+            let ss = builder.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 8));
+            let ebb0 = builder.create_ebb();
+            builder.switch_to_block(ebb0);
+            let value = builder.ins().stack_load(I64, ss, 0);
+            builder.ins().return_(&[value]);
+            builder.seal_all_blocks();
+            builder.finalize();
+        }
+
+        assert_eq!(promote_stack_slots(&mut func), 1);
+        check(&func);
+        assert_eq!(count_memory_insts(&func), 0);
+        let ebb0 = func.layout.entry_block().unwrap();
+        let first = func.layout.first_inst(ebb0).unwrap();
+        assert_eq!(func.dfg[first].opcode(), Opcode::Iconst);
+    }
+
+    #[test]
+    fn addresses() {
+        let mut func = new_function(&[AbiParam::new(I64)], &[AbiParam::new(I64)]);
+        {
+            let mut fn_ctx = FunctionBuilderContext::new();
+            let mut builder = FunctionBuilder::new(&mut func, &mut fn_ctx);
+            let local =
+                builder.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 8));
+            let escaped =
+                builder.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 8));
+            let ebb0 = builder.create_ebb();
+            builder.append_ebb_params_for_function_params(ebb0);
+            builder.switch_to_block(ebb0);
+            let arg = builder.ebb_params(ebb0)[0];
+            let flags = MemFlags::new();
+
+            // Accessing `local` through its address doesn't prevent promotion.
+            let addr = builder.ins().stack_addr(I64, local, 0);
+            builder.ins().store(flags, arg, addr, 0);
+            let value = builder.ins().load(I64, flags, addr, 0);
+
+            // Storing the address of `escaped` to memory does.
+            let addr = builder.ins().stack_addr(I64, escaped, 0);
+            builder.ins().stack_store(value, escaped, 0);
+            builder.ins().store(flags, addr, arg, 0);
+            let result = builder.ins().stack_load(I64, escaped, 0);
+            builder.ins().return_(&[result]);
+            builder.seal_all_blocks();
+            builder.finalize();
+        }
+
+        assert_eq!(promote_stack_slots(&mut func), 1);
+        check(&func);
+        assert_eq!(func.stack_slots.keys().count(), 1);
+        assert_eq!(
+            func.display(None).to_string(),
+            "function %sample(i64) -> i64 system_v {
+    ss0 = explicit_slot 8
+
+ebb0(v0: i64):
+    v2 -> v0
+    v3 = stack_addr.i64 ss0
+    stack_store v0, ss0
+    store v3, v0
+    v4 = stack_load.i64 ss0
+    return v4
+}
+"
+        );
+    }
+
+    #[test]
+    fn mismatched_types() {
+        let mut func = new_function(&[AbiParam::new(I64)], &[AbiParam::new(I32)]);
+        {
+            let mut fn_ctx = FunctionBuilderContext::new();
+            let mut builder = FunctionBuilder::new(&mut func, &mut fn_ctx);
+            let ss = builder.create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 8));
+            let ebb0 = builder.create_ebb();
+            builder.append_ebb_params_for_function_params(ebb0);
+            builder.switch_to_block(ebb0);
+            let arg = builder.ebb_params(ebb0)[0];
+            builder.ins().stack_store(arg, ss, 0);
+            let value = builder.ins().stack_load(I32, ss, 0);
+            builder.ins().return_(&[value]);
+            builder.seal_all_blocks();
+            builder.finalize();
+        }
+
+        assert_eq!(promote_stack_slots(&mut func), 0);
+        assert_eq!(count_memory_insts(&func), 2);
+    }
+}
@@ -100,26 +100,44 @@ where
 
     /// Perform all outstanding relocations on the given function. This requires all `Local`
     /// and `Export` entities referenced to be defined.
+    ///
+    /// `Import` entities are resolved by the backend, which reports the ones it can't find as
+    /// `ModuleError::UnresolvedSymbol`. A function that fails to finalize must be left unchanged.
     fn finalize_function(
         &mut self,
         func: &Self::CompiledFunction,
         namespace: &ModuleNamespace<Self>,
-    ) -> Self::FinalizedFunction;
+    ) -> ModuleResult<Self::FinalizedFunction>;
 
     /// Return the finalized artifact from the backend, if relevant.
     fn get_finalized_function(&self, func: &Self::CompiledFunction) -> Self::FinalizedFunction;
 
     /// Perform all outstanding relocations on the given data object. This requires all
     /// `Local` and `Export` entities referenced to be defined.
+    ///
+    /// Unresolved imports are reported the same way as for `finalize_function`.
     fn finalize_data(
         &mut self,
         data: &Self::CompiledData,
         namespace: &ModuleNamespace<Self>,
-    ) -> Self::FinalizedData;
+    ) -> ModuleResult<Self::FinalizedData>;
 
     /// Return the finalized artifact from the backend, if relevant.
     fn get_finalized_data(&self, data: &Self::CompiledData) -> Self::FinalizedData;
 
+    /// Release the resources held by a function definition which has been removed from the
+    /// module.
+    ///
+    /// The default implementation does nothing, which suits backends that don't hold on to
+    /// anything beyond the `CompiledFunction` itself.
+    fn free_function(&mut self, _func: Self::CompiledFunction) {}
+
+    /// Release the resources held by a data object definition which has been removed from the
+    /// module.
+    ///
+    /// The default implementation does nothing.
+    fn free_data(&mut self, _data: Self::CompiledData) {}
+
     /// "Publish" all finalized functions and data objects to their ultimate destinations.
     fn publish(&mut self);
 
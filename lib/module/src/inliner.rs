@@ -0,0 +1,158 @@
+//! Inlining of calls between the functions of a `Module`.
+
+use cranelift_codegen::entity::EntityRef;
+use cranelift_codegen::inline::inline_call;
+use cranelift_codegen::{ir, Context};
+use std::collections::HashMap;
+use std::vec::Vec;
+use {Backend, FuncId, Linkage, Module, ModuleResult};
+
+/// The default maximum number of instructions in a function that gets inlined.
+const DEFAULT_SIZE_LIMIT: usize = 20;
+
+/// The number of times calls are inlined into the bodies of inlined functions.
+const MAX_DEPTH: usize = 3;
+
+/// Collects the bodies of functions in a `Module` and defines them all at once, inlining calls
+/// to small functions first.
+///
+/// A call is inlined when the called function has been added to the `Inliner`, its body has at
+/// most `size_limit` instructions, and its definition can't be preempted by another one at link
+/// time.
+pub struct Inliner {
+    functions: Vec<(FuncId, ir::Function)>,
+    sizes: Vec<usize>,
+    indices: HashMap<FuncId, usize>,
+    size_limit: usize,
+}
+
+impl Default for Inliner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Inliner {
+    /// Create a new `Inliner` with no functions.
+    pub fn new() -> Self {
+        Self {
+            functions: Vec::new(),
+            sizes: Vec::new(),
+            indices: HashMap::new(),
+            size_limit: DEFAULT_SIZE_LIMIT,
+        }
+    }
+
+    /// Set the maximum number of instructions in a function that gets inlined.
+    pub fn size_limit(&mut self, limit: usize) -> &mut Self {
+        self.size_limit = limit;
+        self
+    }
+
+    /// Add the body of `func`, to be defined by `define_functions`.
+    pub fn add_function(&mut self, func: FuncId, body: ir::Function) {
+        let size = body
+            .layout
+            .ebbs()
+            .map(|ebb| body.layout.ebb_insts(ebb).count())
+            .sum();
+        self.indices.insert(func, self.functions.len());
+        self.functions.push((func, body));
+        self.sizes.push(size);
+    }
+
+    /// Inline calls into `body` according to the heuristic, and return the number of calls that
+    /// were inlined.
+    pub fn inline_calls<B>(&self, module: &Module<B>, body: &mut ir::Function) -> usize
+    where
+        B: Backend,
+    {
+        let mut inlined = 0;
+        for _ in 0..MAX_DEPTH {
+            let mut calls = Vec::new();
+            for ebb in body.layout.ebbs() {
+                for inst in body.layout.ebb_insts(ebb) {
+                    if let Some(callee) = self.inlinable_callee(module, body, inst) {
+                        calls.push((inst, callee));
+                    }
+                }
+            }
+            if calls.is_empty() {
+                break;
+            }
+            for (call, callee) in calls {
+                if inline_call(body, call, callee) {
+                    inlined += 1;
+                }
+            }
+        }
+        inlined
+    }
+
+    /// Inline calls into all the added functions, and define them in `module`.
+    ///
+    /// With the `std` feature, the functions are compiled concurrently by
+    /// `Module::define_functions`, and none of them is defined unless all of them compile.
+    pub fn define_functions<B>(self, module: &mut Module<B>) -> ModuleResult<()>
+    where
+        B: Backend,
+    {
+        let mut contexts = Vec::with_capacity(self.functions.len());
+        for &(func, ref body) in &self.functions {
+            let mut body = body.clone();
+            self.inline_calls(module, &mut body);
+            contexts.push((func, Context::for_function(body)));
+        }
+        define_contexts(module, contexts)
+    }
+
+    /// If `inst` is a call that should be inlined, return the body of the called function.
+    fn inlinable_callee<B>(
+        &self,
+        module: &Module<B>,
+        body: &ir::Function,
+        inst: ir::Inst,
+    ) -> Option<&ir::Function>
+    where
+        B: Backend,
+    {
+        let func_ref = match body.dfg[inst] {
+            ir::InstructionData::Call { func_ref, .. } => func_ref,
+            _ => return None,
+        };
+        let func = match body.dfg.ext_funcs[func_ref].name {
+            ir::ExternalName::User {
+                namespace: 0,
+                index,
+            } => FuncId::new(index as usize),
+            _ => return None,
+        };
+        let index = *self.indices.get(&func)?;
+        if module.get_function_decl(func).linkage == Linkage::Preemptible {
+            return None;
+        }
+        if self.sizes[index] > self.size_limit {
+            return None;
+        }
+        Some(&self.functions[index].1)
+    }
+}
+
+#[cfg(feature = "std")]
+fn define_contexts<B>(module: &mut Module<B>, contexts: Vec<(FuncId, Context)>) -> ModuleResult<()>
+where
+    B: Backend,
+{
+    module.define_functions(contexts)
+}
+
+#[cfg(not(feature = "std"))]
+fn define_contexts<B>(module: &mut Module<B>, contexts: Vec<(FuncId, Context)>) -> ModuleResult<()>
+where
+    B: Backend,
+{
+    for (func, mut ctx) in contexts {
+        module.define_function(func, &mut ctx)?;
+    }
+    Ok(())
+}
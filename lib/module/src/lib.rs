@@ -35,19 +35,26 @@ extern crate alloc;
 extern crate cranelift_codegen;
 #[macro_use]
 extern crate cranelift_entity;
+#[cfg(feature = "std")]
+extern crate crossbeam_utils;
 #[macro_use]
 extern crate failure;
 #[macro_use]
 extern crate log;
+#[cfg(feature = "std")]
+extern crate num_cpus;
 
 mod backend;
 mod data_context;
+mod inliner;
 mod module;
 
 pub use backend::Backend;
 pub use data_context::{DataContext, DataDescription, Init};
+pub use inliner::Inliner;
 pub use module::{
-    DataId, FuncId, FuncOrDataId, Linkage, Module, ModuleError, ModuleNamespace, ModuleResult,
+    DataId, FuncId, FuncOrDataId, FunctionDeclaration, Linkage, Module, ModuleError,
+    ModuleNamespace, ModuleResult,
 };
 
 /// This replaces `std` in builds with `core`.
@@ -7,11 +7,25 @@
 
 use cranelift_codegen::entity::{EntityRef, PrimaryMap};
 use cranelift_codegen::{binemit, ir, isa, CodegenError, Context};
+#[cfg(feature = "std")]
+use cranelift_codegen::{timing, CodegenResult};
+#[cfg(feature = "std")]
+use crossbeam_utils::thread;
 use data_context::DataContext;
+#[cfg(feature = "std")]
+use num_cpus;
 use std::borrow::ToOwned;
 use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::collections::HashSet;
 use std::string::String;
+#[cfg(feature = "std")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use std::sync::Mutex;
 use std::vec::Vec;
+#[cfg(feature = "std")]
+use std::{cmp, panic};
 use Backend;
 
 /// A function identifier for use in the `Module` interface.
@@ -112,8 +126,11 @@ impl From<FuncOrDataId> for ir::ExternalName {
 
 /// Information about a function which can be called.
 pub struct FunctionDeclaration {
+    /// The name the function was declared with.
     pub name: String,
+    /// Where the function is defined and who can see it.
     pub linkage: Linkage,
+    /// The signature of the function.
     pub signature: ir::Signature,
 }
 
@@ -144,6 +161,9 @@ pub enum ModuleError {
         _0
     )]
     InvalidImportDefinition(String),
+    /// Indicates an imported identifier could not be resolved by the backend
+    #[fail(display = "Unresolved symbol: {}", _0)]
+    UnresolvedSymbol(String),
     /// Wraps a `cranelift-codegen` error
     #[fail(display = "Compilation error: {}", _0)]
     Compilation(CodegenError),
@@ -164,6 +184,8 @@ where
     decl: FunctionDeclaration,
     /// The compiled artifact, once it's available.
     compiled: Option<B::CompiledFunction>,
+    /// Whether the definition was freed by `free_function` and not defined again.
+    freed: bool,
 }
 
 impl<B> ModuleFunction<B>
@@ -199,6 +221,8 @@ where
     decl: DataDeclaration,
     /// The "compiled" artifact, once it's available.
     compiled: Option<B::CompiledData>,
+    /// Whether the definition was freed by `free_data` and not defined again.
+    freed: bool,
 }
 
 impl<B> ModuleData<B>
@@ -353,6 +377,11 @@ where
         self.names.get(name).cloned()
     }
 
+    /// Get the declaration of the function `func`.
+    pub fn get_function_decl(&self, func: FuncId) -> &FunctionDeclaration {
+        &self.contents.functions[func].decl
+    }
+
     /// Return the target information needed by frontends to produce Cranelift IR
     /// for the current target.
     pub fn target_config(&self) -> isa::TargetFrontendConfig {
@@ -422,6 +451,7 @@ where
                         signature: signature.clone(),
                     },
                     compiled: None,
+                    freed: false,
                 });
                 entry.insert(FuncOrDataId::Func(id));
                 self.backend.declare_function(name, linkage);
@@ -461,6 +491,7 @@ where
                         writable,
                     },
                     compiled: None,
+                    freed: false,
                 });
                 entry.insert(FuncOrDataId::Data(id));
                 self.backend.declare_data(name, linkage, writable);
@@ -509,23 +540,96 @@ where
 
     /// Define a function, producing the function body from the given `Context`.
     pub fn define_function(&mut self, func: FuncId, ctx: &mut Context) -> ModuleResult<()> {
-        let compiled = {
-            let code_size = ctx.compile(self.backend.isa()).map_err(|e| {
-                info!(
-                    "defining function {}: {}",
-                    func,
-                    ctx.func.display(self.backend.isa())
-                );
-                ModuleError::Compilation(e)
-            })?;
+        let code_size = ctx.compile(self.backend.isa()).map_err(|e| {
+            info!(
+                "defining function {}: {}",
+                func,
+                ctx.func.display(self.backend.isa())
+            );
+            ModuleError::Compilation(e)
+        })?;
 
-            let info = &self.contents.functions[func];
-            if info.compiled.is_some() {
-                return Err(ModuleError::DuplicateDefinition(info.decl.name.clone()));
+        self.check_function_definable(func)?;
+        self.define_compiled_function(func, ctx, code_size)
+    }
+
+    /// Define several functions at once, producing each function body from its `Context`.
+    ///
+    /// The contexts are compiled concurrently on worker threads that share the backend's
+    /// `TargetIsa`. The compiled code is then handed to the backend on the calling thread in the
+    /// order the functions were given, so the result doesn't depend on thread scheduling. Pass
+    /// timings collected by the workers are added to the calling thread's timings.
+    ///
+    /// No function is defined unless all of them compile successfully. When several fail, the
+    /// error for the first one in iteration order is returned.
+    #[cfg(feature = "std")]
+    pub fn define_functions<I>(&mut self, funcs: I) -> ModuleResult<()>
+    where
+        I: IntoIterator<Item = (FuncId, Context)>,
+    {
+        let jobs: Vec<CompileJob> = funcs
+            .into_iter()
+            .map(|(func, ctx)| CompileJob {
+                func,
+                ctx,
+                result: None,
+            })
+            .collect();
+
+        // Reject bad definitions before spending any time compiling.
+        let mut seen = HashSet::with_capacity(jobs.len());
+        for job in &jobs {
+            self.check_function_definable(job.func)?;
+            if !seen.insert(job.func) {
+                let name = &self.contents.functions[job.func].decl.name;
+                return Err(ModuleError::DuplicateDefinition(name.clone()));
             }
-            if !info.decl.linkage.is_definable() {
-                return Err(ModuleError::InvalidImportDefinition(info.decl.name.clone()));
+        }
+
+        let mut jobs = compile_concurrently(jobs, self.backend.isa());
+
+        let mut code_sizes = Vec::with_capacity(jobs.len());
+        for job in &mut jobs {
+            match job.result.take().expect("function was not compiled") {
+                Ok(code_size) => code_sizes.push(code_size),
+                Err(e) => {
+                    info!(
+                        "defining function {}: {}",
+                        job.func,
+                        job.ctx.func.display(self.backend.isa())
+                    );
+                    return Err(ModuleError::Compilation(e));
+                }
             }
+        }
+
+        for (job, code_size) in jobs.iter().zip(code_sizes) {
+            self.define_compiled_function(job.func, &job.ctx, code_size)?;
+        }
+        Ok(())
+    }
+
+    /// Check that `func` can be given a definition.
+    fn check_function_definable(&self, func: FuncId) -> ModuleResult<()> {
+        let info = &self.contents.functions[func];
+        if info.compiled.is_some() {
+            return Err(ModuleError::DuplicateDefinition(info.decl.name.clone()));
+        }
+        if !info.decl.linkage.is_definable() {
+            return Err(ModuleError::InvalidImportDefinition(info.decl.name.clone()));
+        }
+        Ok(())
+    }
+
+    /// Hand the code compiled in `ctx` to the backend as the definition of `func`.
+    fn define_compiled_function(
+        &mut self,
+        func: FuncId,
+        ctx: &Context,
+        code_size: binemit::CodeOffset,
+    ) -> ModuleResult<()> {
+        let compiled = {
+            let info = &self.contents.functions[func];
             Some(self.backend.define_function(
                 &info.decl.name,
                 ctx,
@@ -536,6 +640,7 @@ where
             )?)
         };
         self.contents.functions[func].compiled = compiled;
+        self.contents.functions[func].freed = false;
         self.functions_to_finalize.push(func);
         Ok(())
     }
@@ -560,6 +665,7 @@ where
             )?)
         };
         self.contents.data_objects[data].compiled = compiled;
+        self.contents.data_objects[data].freed = false;
         self.data_objects_to_finalize.push(data);
         Ok(())
     }
@@ -611,13 +717,17 @@ where
     /// All symbols referenced in their bodies that are declared as needing a definition
     /// must be defined by this point.
     ///
+    /// If the backend can't resolve an imported symbol, this returns an error. The
+    /// definition which referenced it, and any definitions after it, are left unfinalized,
+    /// and nothing is published.
+    ///
     /// Use `get_finalized_function` and `get_finalized_data` to obtain the final
     /// artifacts.
-    pub fn finalize_definitions(&mut self) {
-        for func in self.functions_to_finalize.drain(..) {
-            let info = &self.contents.functions[func];
+    pub fn finalize_definitions(&mut self) -> ModuleResult<()> {
+        for i in 0..self.functions_to_finalize.len() {
+            let info = &self.contents.functions[self.functions_to_finalize[i]];
             debug_assert!(info.decl.linkage.is_definable());
-            self.backend.finalize_function(
+            let result = self.backend.finalize_function(
                 info.compiled
                     .as_ref()
                     .expect("function must be compiled before it can be finalized"),
@@ -625,11 +735,16 @@ where
                     contents: &self.contents,
                 },
             );
+            if let Err(err) = result {
+                self.functions_to_finalize.drain(..i);
+                return Err(err);
+            }
         }
-        for data in self.data_objects_to_finalize.drain(..) {
-            let info = &self.contents.data_objects[data];
+        self.functions_to_finalize.clear();
+        for i in 0..self.data_objects_to_finalize.len() {
+            let info = &self.contents.data_objects[self.data_objects_to_finalize[i]];
             debug_assert!(info.decl.linkage.is_definable());
-            self.backend.finalize_data(
+            let result = self.backend.finalize_data(
                 info.compiled
                     .as_ref()
                     .expect("data object must be compiled before it can be finalized"),
@@ -637,8 +752,14 @@ where
                     contents: &self.contents,
                 },
             );
+            if let Err(err) = result {
+                self.data_objects_to_finalize.drain(..i);
+                return Err(err);
+            }
         }
+        self.data_objects_to_finalize.clear();
         self.backend.publish();
+        Ok(())
     }
 
     /// Return the finalized artifact from the backend, if it provides one.
@@ -648,11 +769,12 @@ where
             !self.functions_to_finalize.iter().any(|x| *x == func),
             "function not yet finalized"
         );
-        self.backend.get_finalized_function(
-            info.compiled
-                .as_ref()
-                .expect("function must be compiled before it can be finalized"),
-        )
+        let compiled = match info.compiled {
+            Some(ref compiled) => compiled,
+            None if info.freed => panic!("function {} was freed", info.decl.name),
+            None => panic!("function must be compiled before it can be finalized"),
+        };
+        self.backend.get_finalized_function(compiled)
     }
 
     /// Return the finalized artifact from the backend, if it provides one.
@@ -662,11 +784,44 @@ where
             !self.data_objects_to_finalize.iter().any(|x| *x == data),
             "data object not yet finalized"
         );
-        self.backend.get_finalized_data(
-            info.compiled
-                .as_ref()
-                .expect("data object must be compiled before it can be finalized"),
-        )
+        let compiled = match info.compiled {
+            Some(ref compiled) => compiled,
+            None if info.freed => panic!("data object {} was freed", info.decl.name),
+            None => panic!("data object must be compiled before it can be finalized"),
+        };
+        self.backend.get_finalized_data(compiled)
+    }
+
+    /// Remove the definition of `func` from the module and let the backend release the resources
+    /// it holds, such as the memory its code lives in.
+    ///
+    /// Afterwards `func` is only declared: asking for its finalized artifact panics, and it may be
+    /// defined again. Nothing may still refer to the old definition, including relocations in
+    /// other functions and data objects, or pointers held by the caller.
+    pub fn free_function(&mut self, func: FuncId) {
+        let info = &mut self.contents.functions[func];
+        let compiled = info
+            .compiled
+            .take()
+            .expect("function must be defined before it can be freed");
+        info.freed = true;
+        self.functions_to_finalize.retain(|x| *x != func);
+        self.backend.free_function(compiled);
+    }
+
+    /// Remove the definition of `data` from the module and let the backend release the resources
+    /// it holds.
+    ///
+    /// As with `free_function`, nothing may still refer to the old definition.
+    pub fn free_data(&mut self, data: DataId) {
+        let info = &mut self.contents.data_objects[data];
+        let compiled = info
+            .compiled
+            .take()
+            .expect("data object must be defined before it can be freed");
+        info.freed = true;
+        self.data_objects_to_finalize.retain(|x| *x != data);
+        self.backend.free_data(compiled);
     }
 
     /// Consume the module and return the resulting `Product`. Some `Backend`
@@ -676,3 +831,76 @@ where
         self.backend.finish()
     }
 }
+
+/// A function waiting to be compiled by `Module::define_functions`.
+#[cfg(feature = "std")]
+struct CompileJob {
+    func: FuncId,
+    ctx: Context,
+    /// The code size or error returned by `Context::compile`, once it has run.
+    result: Option<CodegenResult<binemit::CodeOffset>>,
+}
+
+/// Compile all of `jobs` for `isa`, spreading the work over the available CPUs.
+///
+/// Jobs are handed out one at a time from a shared counter so that a few large functions don't
+/// leave the other threads idle. The jobs are returned in their original order.
+#[cfg(feature = "std")]
+fn compile_concurrently(jobs: Vec<CompileJob>, isa: &isa::TargetIsa) -> Vec<CompileJob> {
+    let num_threads = cmp::min(num_cpus::get(), jobs.len());
+    let jobs: Vec<Mutex<CompileJob>> = jobs.into_iter().map(Mutex::new).collect();
+    let next_job = AtomicUsize::new(0);
+
+    if num_threads > 1 {
+        // The scope joins all the workers before it returns, so they can borrow `jobs` and `isa`.
+        let joined = thread::scope(|scope| {
+            let mut workers = Vec::new();
+            for _ in 0..num_threads {
+                let spawned = scope.builder().spawn(|_| {
+                    run_compile_jobs(&jobs, &next_job, isa);
+                    timing::take_current()
+                });
+                match spawned {
+                    Ok(worker) => workers.push(worker),
+                    // The jobs the workers don't get to are compiled on this thread below.
+                    Err(_) => break,
+                }
+            }
+
+            let mut panicked = None;
+            for worker in workers {
+                match worker.join() {
+                    Ok(times) => timing::add_to_current(&times),
+                    Err(payload) => {
+                        panicked.get_or_insert(payload);
+                    }
+                }
+            }
+            panicked
+        });
+        match joined {
+            Ok(None) => {}
+            Ok(Some(payload)) | Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    run_compile_jobs(&jobs, &next_job, isa);
+
+    jobs.into_iter()
+        .map(|job| job.into_inner().unwrap())
+        .collect()
+}
+
+/// Compile jobs from `jobs` until `next_job` runs past the end.
+#[cfg(feature = "std")]
+fn run_compile_jobs(jobs: &[Mutex<CompileJob>], next_job: &AtomicUsize, isa: &isa::TargetIsa) {
+    loop {
+        let index = next_job.fetch_add(1, Ordering::Relaxed);
+        let mut job = match jobs.get(index) {
+            Some(job) => job.lock().unwrap(),
+            None => break,
+        };
+        let result = job.ctx.compile(isa);
+        job.result = Some(result);
+    }
+}
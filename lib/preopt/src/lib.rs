@@ -34,6 +34,7 @@ extern crate cranelift_codegen;
 // extern crate rustc_apfloat;
 
 mod constant_folding;
+mod sccp;
 
 use cranelift_codegen::{isa::TargetIsa, settings::FlagsOrIsa, CodegenResult, Context};
 
@@ -44,7 +45,7 @@ use cranelift_codegen::{isa::TargetIsa, settings::FlagsOrIsa, CodegenResult, Con
 /// if it's not used.
 pub fn optimize(ctx: &mut Context, isa: &TargetIsa) -> CodegenResult<()> {
     ctx.verify_if(isa)?;
-    fold_constants(ctx, isa)?;
+    propagate_constants(ctx, isa)?;
 
     Ok(())
 }
@@ -59,6 +60,17 @@ where
     Ok(())
 }
 
+/// Propagate constants across EBBs with sparse conditional constant propagation, and remove the
+/// branches and EBBs made unreachable.
+pub fn propagate_constants<'a, FOI>(ctx: &mut Context, fisa: FOI) -> CodegenResult<()>
+where
+    FOI: Into<FlagsOrIsa<'a>>,
+{
+    sccp::do_sccp(&mut ctx.func);
+    ctx.verify_if(fisa)?;
+    Ok(())
+}
+
 /// This replaces `std` in builds with `core`.
 #[cfg(not(feature = "std"))]
 mod std {
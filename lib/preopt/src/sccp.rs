@@ -0,0 +1,893 @@
+//! Sparse conditional constant propagation.
+//!
+//! This is the algorithm from Wegman, M. N. and Zadeck, F. K. (1991). Constant Propagation with
+//! Conditional Branches. ACM Transactions on Programming Languages and Systems, 13(2), 181-210,
+//! adapted to EBB parameters instead of phi nodes.
+//!
+//! Each value starts out undefined, and is lowered to a constant or to "overdefined" as the
+//! analysis proceeds. Only the EBBs found to be executable are evaluated, starting with the entry
+//! block, and a branch only makes its destination executable if its condition doesn't rule it
+//! out. An EBB parameter is constant when all the executable branches to its EBB pass the same
+//! constant.
+//!
+//! Afterwards, instructions and EBB parameters with constant values are replaced with constants,
+//! branches with constant conditions are resolved, conditional traps that always trap become
+//! `trap`, and the EBBs that were never found to be executable are removed.
+//!
+//! Floating point operations are evaluated with the host's IEEE 754 arithmetic, which rounds
+//! exactly like the target. Since the bits of a NaN produced by an operation are not specified,
+//! operations producing a NaN are left alone.
+
+use cranelift_codegen::cursor::{Cursor, FuncCursor};
+use cranelift_codegen::dominator_tree::DominatorTree;
+use cranelift_codegen::entity::{EntitySet, SecondaryMap};
+use cranelift_codegen::flowgraph::ControlFlowGraph;
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::immediates::{Ieee32, Ieee64};
+use cranelift_codegen::ir::instructions::BranchInfo;
+use cranelift_codegen::ir::types::{F32, F64};
+use cranelift_codegen::ir::{
+    DataFlowGraph, Ebb, Function, Inst, InstBuilder, InstructionData, Opcode, Type, Value,
+};
+use std::vec::Vec;
+
+/// A constant value.
+///
+/// Integers are kept zero-extended from the width of their type. Floating point numbers are kept
+/// as their bits.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Const {
+    Int(u64),
+    Bool(bool),
+    F32(u32),
+    F64(u64),
+}
+
+/// The lattice of values computed by the analysis.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Lattice {
+    /// No definition of the value has been found to be executed yet.
+    Undefined,
+    /// The value is always this constant.
+    Const(Const),
+    /// The value isn't known to be a constant.
+    Overdefined,
+}
+
+impl Default for Lattice {
+    fn default() -> Self {
+        Lattice::Undefined
+    }
+}
+
+impl Lattice {
+    fn meet(self, other: Self) -> Self {
+        match (self, other) {
+            (Lattice::Undefined, x) | (x, Lattice::Undefined) => x,
+            (Lattice::Const(a), Lattice::Const(b)) if a == b => self,
+            _ => Lattice::Overdefined,
+        }
+    }
+}
+
+/// Whether a conditional branch is taken.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Taken {
+    Always,
+    Never,
+    Unknown,
+    /// The condition hasn't been computed yet.
+    Undecided,
+}
+
+/// Propagate constants through `func`, and remove the code they make unreachable.
+pub fn do_sccp(func: &mut Function) {
+    let mut sccp = Sccp::new();
+    sccp.analyze(func);
+    sccp.rewrite(func);
+}
+
+struct Sccp {
+    values: SecondaryMap<Value, Lattice>,
+    executable: EntitySet<Ebb>,
+}
+
+impl Sccp {
+    fn new() -> Self {
+        Self {
+            values: SecondaryMap::new(),
+            executable: EntitySet::new(),
+        }
+    }
+
+    /// Compute the values and the executable EBBs.
+    fn analyze(&mut self, func: &Function) {
+        let entry = match func.layout.entry_block() {
+            Some(entry) => entry,
+            None => return,
+        };
+        self.executable.insert(entry);
+        for &param in func.dfg.ebb_params(entry) {
+            self.values[param] = Lattice::Overdefined;
+        }
+
+        // Visiting the EBBs in reverse post-order sees most definitions before their uses, so
+        // only a few iterations are needed to reach the fixed point.
+        let cfg = ControlFlowGraph::with_function(func);
+        let domtree = DominatorTree::with_function(func, &cfg);
+        let order: Vec<Ebb> = domtree.cfg_postorder().iter().rev().cloned().collect();
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &ebb in &order {
+                if self.executable.contains(ebb) {
+                    changed |= self.visit_ebb(func, ebb);
+                }
+            }
+        }
+    }
+
+    /// Evaluate the instructions in `ebb`. Returns true if anything changed.
+    fn visit_ebb(&mut self, func: &Function, ebb: Ebb) -> bool {
+        let mut changed = false;
+        for inst in func.layout.ebb_insts(ebb) {
+            let opcode = func.dfg[inst].opcode();
+            if opcode.is_branch() {
+                let taken = self.branch_taken(func, inst);
+                if taken != Taken::Never && taken != Taken::Undecided {
+                    changed |= self.visit_branch(func, inst);
+                }
+                if taken == Taken::Always || taken == Taken::Undecided {
+                    return changed;
+                }
+            } else if opcode.is_terminator() {
+                return changed;
+            } else if self.traps(func, inst) {
+                return changed;
+            } else {
+                let results = func.dfg.inst_results(inst);
+                let value = if results.len() == 1 {
+                    self.evaluate(&func.dfg, inst)
+                } else {
+                    Lattice::Overdefined
+                };
+                for &result in results {
+                    let old = self.values[result];
+                    let new = old.meet(value);
+                    if new != old {
+                        self.values[result] = new;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    /// Mark the destinations of the branch `inst` executable, and pass its arguments to them.
+    /// Returns true if anything changed.
+    fn visit_branch(&mut self, func: &Function, inst: Inst) -> bool {
+        let mut changed = false;
+        match func.dfg.analyze_branch(inst) {
+            BranchInfo::SingleDest(dest, args) => {
+                changed |= self.executable.insert(dest);
+                for (&param, &arg) in func.dfg.ebb_params(dest).iter().zip(args) {
+                    let old = self.values[param];
+                    let new = old.meet(self.value(&func.dfg, arg));
+                    if new != old {
+                        self.values[param] = new;
+                        changed = true;
+                    }
+                }
+            }
+            BranchInfo::Table(jt, default) => match self.table_dest(func, inst) {
+                Some(dest) => changed |= self.executable.insert(dest),
+                None => {
+                    for dest in default
+                        .into_iter()
+                        .chain(func.jump_tables[jt].iter().cloned())
+                    {
+                        changed |= self.executable.insert(dest);
+                    }
+                }
+            },
+            BranchInfo::NotABranch => {
+                // An indirect branch can go to any EBB in its table.
+                if let InstructionData::IndirectJump { table, .. } = func.dfg[inst] {
+                    for &dest in func.jump_tables[table].iter() {
+                        changed |= self.executable.insert(dest);
+                    }
+                }
+            }
+        }
+        changed
+    }
+
+    fn value(&self, dfg: &DataFlowGraph, value: Value) -> Lattice {
+        self.values[dfg.resolve_aliases(value)]
+    }
+
+    /// Determine whether the branch `inst` is taken.
+    fn branch_taken(&self, func: &Function, inst: Inst) -> Taken {
+        let dfg = &func.dfg;
+        let cond = match dfg[inst] {
+            InstructionData::Jump { .. } => return Taken::Always,
+            InstructionData::Branch {
+                opcode, ref args, ..
+            } => {
+                let cond = self.value(dfg, args.as_slice(&dfg.value_lists)[0]);
+                match cond {
+                    Lattice::Const(c) => {
+                        let nonzero = match c {
+                            Const::Int(x) => x != 0,
+                            Const::Bool(b) => b,
+                            _ => return Taken::Unknown,
+                        };
+                        Lattice::Const(Const::Bool(nonzero == (opcode == Opcode::Brnz)))
+                    }
+                    other => other,
+                }
+            }
+            InstructionData::BranchIcmp { cond, ref args, .. } => {
+                let args = args.as_slice(&dfg.value_lists);
+                let ty = dfg.value_type(args[0]);
+                match (self.value(dfg, args[0]), self.value(dfg, args[1])) {
+                    (Lattice::Const(Const::Int(x)), Lattice::Const(Const::Int(y))) => {
+                        Lattice::Const(Const::Bool(icmp(cond, ty, x, y)))
+                    }
+                    (Lattice::Overdefined, _) | (_, Lattice::Overdefined) => Lattice::Overdefined,
+                    _ => Lattice::Undefined,
+                }
+            }
+            InstructionData::BranchTable { arg, .. } => {
+                // `visit_branch` picks the destination.
+                match self.value(dfg, arg) {
+                    Lattice::Undefined => return Taken::Undecided,
+                    _ => return Taken::Always,
+                }
+            }
+            _ => {
+                return if dfg[inst].opcode().is_terminator() {
+                    Taken::Always
+                } else {
+                    Taken::Unknown
+                }
+            }
+        };
+        match cond {
+            Lattice::Undefined => Taken::Undecided,
+            Lattice::Const(Const::Bool(true)) => Taken::Always,
+            Lattice::Const(Const::Bool(false)) => Taken::Never,
+            _ => Taken::Unknown,
+        }
+    }
+
+    /// If the `br_table` instruction `inst` has a constant index, return its destination.
+    fn table_dest(&self, func: &Function, inst: Inst) -> Option<Ebb> {
+        if let InstructionData::BranchTable {
+            arg,
+            destination,
+            table,
+            ..
+        } = func.dfg[inst]
+        {
+            if let Lattice::Const(Const::Int(index)) = self.value(&func.dfg, arg) {
+                let entries = func.jump_tables[table].as_slice();
+                return Some(if index < entries.len() as u64 {
+                    entries[index as usize]
+                } else {
+                    destination
+                });
+            }
+        }
+        None
+    }
+
+    /// Does the conditional trap `inst` always trap?
+    fn traps(&self, func: &Function, inst: Inst) -> bool {
+        match func.dfg[inst] {
+            InstructionData::CondTrap { opcode, arg, .. } => {
+                let nonzero = match self.value(&func.dfg, arg) {
+                    Lattice::Const(Const::Int(x)) => x != 0,
+                    Lattice::Const(Const::Bool(b)) => b,
+                    _ => return false,
+                };
+                nonzero == (opcode == Opcode::Trapnz)
+            }
+            _ => false,
+        }
+    }
+
+    /// Compute the value of the single result of `inst`.
+    fn evaluate(&self, dfg: &DataFlowGraph, inst: Inst) -> Lattice {
+        let opcode = dfg[inst].opcode();
+        if opcode.can_load()
+            || opcode.can_store()
+            || opcode.is_call()
+            || opcode.other_side_effects()
+            || opcode.writes_cpu_flags()
+        {
+            return Lattice::Overdefined;
+        }
+
+        let args = dfg.inst_args(inst);
+        match opcode {
+            Opcode::Copy => return self.value(dfg, args[0]),
+            Opcode::Select => {
+                return match self.value(dfg, args[0]) {
+                    Lattice::Const(Const::Bool(b)) => self.value(dfg, args[if b { 1 } else { 2 }]),
+                    Lattice::Const(Const::Int(x)) => {
+                        self.value(dfg, args[if x != 0 { 1 } else { 2 }])
+                    }
+                    Lattice::Undefined => Lattice::Undefined,
+                    _ => self.value(dfg, args[1]).meet(self.value(dfg, args[2])),
+                }
+            }
+            _ => {}
+        }
+
+        let mut consts = Vec::with_capacity(args.len());
+        let mut undefined = false;
+        for &arg in args {
+            match self.value(dfg, arg) {
+                Lattice::Const(c) => consts.push(c),
+                Lattice::Undefined => undefined = true,
+                Lattice::Overdefined => return Lattice::Overdefined,
+            }
+        }
+        if undefined {
+            return Lattice::Undefined;
+        }
+        let ty = dfg.value_type(dfg.first_result(inst));
+        let arg_ty = args.first().map(|&arg| dfg.value_type(arg));
+        match fold(&dfg[inst], ty, arg_ty, &consts) {
+            Some(c) => Lattice::Const(c),
+            None => Lattice::Overdefined,
+        }
+    }
+
+    /// Rewrite `func` according to the results of the analysis.
+    fn rewrite(&self, func: &mut Function) {
+        let mut pos = FuncCursor::new(func);
+        while let Some(ebb) = pos.next_ebb() {
+            if !self.executable.contains(ebb) {
+                continue;
+            }
+            while let Some(inst) = pos.next_inst() {
+                if pos.func.dfg[inst].opcode().is_branch() {
+                    self.rewrite_branch(&mut pos, inst);
+                } else if self.traps(pos.func, inst) {
+                    // The analysis didn't look past the trap, so the branches after it may still
+                    // point at EBBs that are about to be removed.
+                    let code = match pos.func.dfg[inst] {
+                        InstructionData::CondTrap { code, .. } => code,
+                        _ => panic!("expected a conditional trap"),
+                    };
+                    pos.func.dfg.replace(inst).trap(code);
+                    remove_rest_of_ebb(pos.func, inst);
+                } else if !is_constant(pos.func.dfg[inst].opcode()) {
+                    if let Some(&result) = pos.func.dfg.inst_results(inst).first() {
+                        if let Lattice::Const(c) = self.values[result] {
+                            replace_with_constant(&mut pos.func.dfg, inst, c);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.remove_dead_ebbs(func);
+        self.replace_constant_params(func);
+    }
+
+    /// Resolve the branch `inst` if its condition is known.
+    fn rewrite_branch(&self, pos: &mut FuncCursor, inst: Inst) {
+        let taken = self.branch_taken(pos.func, inst);
+        let dest = match pos.func.dfg.analyze_branch(inst) {
+            BranchInfo::SingleDest(dest, args) => {
+                if taken == Taken::Never {
+                    pos.remove_inst_and_step_back();
+                    return;
+                }
+                let opcode = pos.func.dfg[inst].opcode();
+                if taken != Taken::Always || opcode == Opcode::Jump || opcode == Opcode::Fallthrough
+                {
+                    return;
+                }
+                Some((dest, args.to_vec()))
+            }
+            BranchInfo::Table(_, _) => self
+                .table_dest(pos.func, inst)
+                .map(|dest| (dest, Vec::new())),
+            BranchInfo::NotABranch => None,
+        };
+
+        if let Some((dest, args)) = dest {
+            pos.func.dfg.replace(inst).jump(dest, &args);
+            remove_rest_of_ebb(pos.func, inst);
+        }
+    }
+
+    /// Remove the EBBs that are never executed.
+    fn remove_dead_ebbs(&self, func: &mut Function) {
+        let mut pos = FuncCursor::new(func);
+        while let Some(ebb) = pos.next_ebb() {
+            if self.executable.contains(ebb) {
+                continue;
+            }
+            pos.prev_ebb();
+            while let Some(inst) = pos.func.layout.first_inst(ebb) {
+                pos.func.layout.remove_inst(inst);
+            }
+            pos.func.layout.remove_ebb(ebb);
+        }
+    }
+
+    /// Replace the EBB parameters with constant values with constants, and remove them and their
+    /// arguments.
+    fn replace_constant_params(&self, func: &mut Function) {
+        let entry = func.layout.entry_block();
+        let mut removed = SecondaryMap::<Ebb, Vec<usize>>::new();
+        let mut pos = FuncCursor::new(func);
+        while let Some(ebb) = pos.next_ebb() {
+            if Some(ebb) == entry {
+                continue;
+            }
+            let params = pos.func.dfg.ebb_params(ebb).to_vec();
+            for (index, &param) in params.iter().enumerate().rev() {
+                if let Lattice::Const(c) = self.values[param] {
+                    let ty = pos.func.dfg.value_type(param);
+                    let value = {
+                        let mut pos = FuncCursor::new(pos.func).at_first_insertion_point(ebb);
+                        materialize(&mut pos, ty, c)
+                    };
+                    pos.func.dfg.remove_ebb_param(param);
+                    pos.func.dfg.change_to_alias(param, value);
+                    removed[ebb].push(index);
+                }
+            }
+        }
+        if removed.values().all(|indices| indices.is_empty()) {
+            return;
+        }
+
+        // Remove the corresponding arguments from the branches. The indices are in decreasing
+        // order, so removing one doesn't shift the others.
+        let mut pos = FuncCursor::new(func);
+        while let Some(_ebb) = pos.next_ebb() {
+            while let Some(inst) = pos.next_inst() {
+                let (dest, num_fixed) = match pos.func.dfg.analyze_branch(inst) {
+                    BranchInfo::SingleDest(dest, args) => {
+                        (dest, pos.func.dfg.inst_args(inst).len() - args.len())
+                    }
+                    _ => continue,
+                };
+                if removed[dest].is_empty() {
+                    continue;
+                }
+                let dfg = &mut pos.func.dfg;
+                let mut list = dfg[inst].take_value_list().unwrap();
+                for &index in &removed[dest] {
+                    list.remove(num_fixed + index, &mut dfg.value_lists);
+                }
+                dfg[inst].put_value_list(list);
+            }
+        }
+    }
+}
+
+/// Remove the instructions following `inst`, which are unreachable.
+fn remove_rest_of_ebb(func: &mut Function, inst: Inst) {
+    while let Some(next) = func.layout.next_inst(inst) {
+        func.layout.remove_inst(next);
+    }
+}
+
+/// Does `opcode` produce a constant already?
+fn is_constant(opcode: Opcode) -> bool {
+    match opcode {
+        Opcode::Iconst | Opcode::Bconst | Opcode::F32const | Opcode::F64const => true,
+        _ => false,
+    }
+}
+
+/// Replace `inst` with an instruction producing `c`.
+fn replace_with_constant(dfg: &mut DataFlowGraph, inst: Inst, c: Const) {
+    let ty = dfg.ctrl_typevar(inst);
+    let ty = if ty.is_invalid() {
+        dfg.value_type(dfg.first_result(inst))
+    } else {
+        ty
+    };
+    match c {
+        Const::Int(x) => {
+            dfg.replace(inst).iconst(ty, sext(x, ty.bits()));
+        }
+        Const::Bool(b) => {
+            dfg.replace(inst).bconst(ty, b);
+        }
+        Const::F32(bits) => {
+            dfg.replace(inst).f32const(Ieee32::with_bits(bits));
+        }
+        Const::F64(bits) => {
+            dfg.replace(inst).f64const(Ieee64::with_bits(bits));
+        }
+    }
+}
+
+/// Insert an instruction producing `c` with type `ty` at `pos`.
+fn materialize(pos: &mut FuncCursor, ty: Type, c: Const) -> Value {
+    match c {
+        Const::Int(x) => pos.ins().iconst(ty, sext(x, ty.bits())),
+        Const::Bool(b) => pos.ins().bconst(ty, b),
+        Const::F32(bits) => pos.ins().f32const(Ieee32::with_bits(bits)),
+        Const::F64(bits) => pos.ins().f64const(Ieee64::with_bits(bits)),
+    }
+}
+
+/// Truncate `x` to `bits` bits.
+fn mask(x: u64, bits: u16) -> u64 {
+    if bits >= 64 {
+        x
+    } else {
+        x & ((1 << bits) - 1)
+    }
+}
+
+/// Sign-extend the low `bits` bits of `x`.
+fn sext(x: u64, bits: u16) -> i64 {
+    if bits >= 64 {
+        x as i64
+    } else {
+        let shift = 64 - bits;
+        ((x << shift) as i64) >> shift
+    }
+}
+
+/// The constant for the integer or boolean `x` of type `ty`.
+fn int(ty: Type, x: u64) -> Option<Const> {
+    if ty.is_int() && ty.bits() <= 64 {
+        Some(Const::Int(mask(x, ty.bits())))
+    } else {
+        None
+    }
+}
+
+/// Evaluate the integer comparison `cond` of `x` and `y` of type `ty`.
+fn icmp(cond: IntCC, ty: Type, x: u64, y: u64) -> bool {
+    let (sx, sy) = (sext(x, ty.bits()), sext(y, ty.bits()));
+    match cond {
+        IntCC::Equal => x == y,
+        IntCC::NotEqual => x != y,
+        IntCC::SignedLessThan => sx < sy,
+        IntCC::SignedGreaterThanOrEqual => sx >= sy,
+        IntCC::SignedGreaterThan => sx > sy,
+        IntCC::SignedLessThanOrEqual => sx <= sy,
+        IntCC::UnsignedLessThan => x < y,
+        IntCC::UnsignedGreaterThanOrEqual => x >= y,
+        IntCC::UnsignedGreaterThan => x > y,
+        IntCC::UnsignedLessThanOrEqual => x <= y,
+    }
+}
+
+/// Evaluate the floating point comparison `cond` of `x` and `y`.
+#[cfg_attr(feature = "cargo-clippy", allow(float_cmp))]
+fn fcmp(cond: FloatCC, x: f64, y: f64) -> bool {
+    let unordered = x.is_nan() || y.is_nan();
+    match cond {
+        FloatCC::Ordered => !unordered,
+        FloatCC::Unordered => unordered,
+        FloatCC::Equal => x == y,
+        FloatCC::NotEqual => x != y,
+        FloatCC::OrderedNotEqual => !unordered && x != y,
+        FloatCC::UnorderedOrEqual => unordered || x == y,
+        FloatCC::LessThan => x < y,
+        FloatCC::LessThanOrEqual => x <= y,
+        FloatCC::GreaterThan => x > y,
+        FloatCC::GreaterThanOrEqual => x >= y,
+        FloatCC::UnorderedOrLessThan => unordered || x < y,
+        FloatCC::UnorderedOrLessThanOrEqual => unordered || x <= y,
+        FloatCC::UnorderedOrGreaterThan => unordered || x > y,
+        FloatCC::UnorderedOrGreaterThanOrEqual => unordered || x >= y,
+    }
+}
+
+/// A floating point constant, widened to `f64`. Widening is exact.
+fn float(c: Const) -> Option<f64> {
+    match c {
+        Const::F32(bits) => Some(f64::from(f32::from_bits(bits))),
+        Const::F64(bits) => Some(f64::from_bits(bits)),
+        _ => None,
+    }
+}
+
+/// The constant for the result `x` of a floating point operation of type `ty`, rounding it if
+/// `ty` is `f32`, or `None` if it's a NaN.
+///
+/// Computing an `f32` operation in `f64` and rounding the result is exact for the operations
+/// folded here, since `f64` has more than twice the precision of `f32`.
+fn float_result(ty: Type, x: f64) -> Option<Const> {
+    let c = if ty == F32 {
+        let x = x as f32;
+        if x.is_nan() {
+            return None;
+        }
+        Const::F32(x.to_bits())
+    } else if ty == F64 {
+        if x.is_nan() {
+            return None;
+        }
+        Const::F64(x.to_bits())
+    } else {
+        return None;
+    };
+    Some(c)
+}
+
+/// The sign bit of a floating point constant.
+fn sign_bit(c: Const) -> Option<(u64, u64)> {
+    match c {
+        Const::F32(bits) => Some((u64::from(bits), 1 << 31)),
+        Const::F64(bits) => Some((bits, 1 << 63)),
+        _ => None,
+    }
+}
+
+/// Make a floating point constant of the same type as `like` from `bits`.
+fn with_bits(like: Const, bits: u64) -> Const {
+    match like {
+        Const::F32(_) => Const::F32(bits as u32),
+        _ => Const::F64(bits),
+    }
+}
+
+/// Compute `2^n` exactly.
+fn pow2(n: u16) -> f64 {
+    f64::from_bits((1023 + u64::from(n)) << 52)
+}
+
+/// Convert the float `x` to an integer of type `ty`, truncating towards zero. Returns `None` if
+/// the conversion would trap, unless `saturate` is set.
+fn float_to_int(x: f64, ty: Type, signed: bool, saturate: bool) -> Option<Const> {
+    let bits = ty.bits();
+    if !ty.is_int() || bits > 64 {
+        return None;
+    }
+    // These bounds are powers of two, so they are exact.
+    let (min, max) = if signed {
+        let max = pow2(bits - 1);
+        (-max, max)
+    } else {
+        (0.0, pow2(bits))
+    };
+    let result = if x.is_nan() {
+        if !saturate {
+            return None;
+        }
+        0
+    } else if x >= max || (x < min && !(x > min - 1.0)) {
+        if !saturate {
+            return None;
+        }
+        if x >= max {
+            if signed {
+                (1 << (bits - 1)) - 1
+            } else {
+                mask(!0, bits)
+            }
+        } else if signed {
+            1 << (bits - 1)
+        } else {
+            0
+        }
+    } else if signed {
+        x as i64 as u64
+    } else if x < 0.0 {
+        // Between -1 and 0, which truncates to zero.
+        0
+    } else {
+        x as u64
+    };
+    int(ty, result)
+}
+
+/// Evaluate the instruction `data` producing a result of type `ty`, with constant arguments
+/// `args`. The type of the first argument is `arg_ty`.
+///
+/// Returns `None` if the instruction can't be evaluated, or would trap.
+#[cfg_attr(
+    feature = "cargo-clippy",
+    allow(float_arithmetic, cyclomatic_complexity)
+)]
+fn fold(data: &InstructionData, ty: Type, arg_ty: Option<Type>, args: &[Const]) -> Option<Const> {
+    let bits = ty.bits();
+    match *data {
+        InstructionData::UnaryImm { imm, .. } => {
+            let imm: i64 = imm.into();
+            int(ty, imm as u64)
+        }
+        InstructionData::UnaryBool { imm, .. } => Some(Const::Bool(imm)),
+        InstructionData::UnaryIeee32 { imm, .. } => Some(Const::F32(imm.bits())),
+        InstructionData::UnaryIeee64 { imm, .. } => Some(Const::F64(imm.bits())),
+        InstructionData::Unary { opcode, .. } => {
+            let arg_ty = arg_ty?;
+            let arg_bits = arg_ty.bits();
+            match (opcode, args[0]) {
+                (Opcode::Bnot, Const::Int(x)) => int(ty, !x),
+                (Opcode::Bnot, Const::Bool(b)) => Some(Const::Bool(!b)),
+                (Opcode::Popcnt, Const::Int(x)) => int(ty, u64::from(x.count_ones())),
+                (Opcode::Clz, Const::Int(x)) => {
+                    int(ty, u64::from(x.leading_zeros()) - u64::from(64 - bits))
+                }
+                (Opcode::Ctz, Const::Int(x)) => {
+                    int(ty, u64::from(x.trailing_zeros()).min(u64::from(bits)))
+                }
+                (Opcode::Cls, Const::Int(x)) => {
+                    let x = sext(x, bits);
+                    let sign_bits = if x < 0 {
+                        (!x).leading_zeros()
+                    } else {
+                        x.leading_zeros()
+                    };
+                    int(ty, u64::from(sign_bits) - u64::from(64 - bits) - 1)
+                }
+                (Opcode::Bint, Const::Bool(b)) => int(ty, b as u64),
+                (Opcode::Bmask, Const::Bool(b)) => int(ty, if b { !0 } else { 0 }),
+                (Opcode::Bextend, Const::Bool(b)) | (Opcode::Breduce, Const::Bool(b)) => {
+                    Some(Const::Bool(b))
+                }
+                (Opcode::Ireduce, Const::Int(x)) | (Opcode::Uextend, Const::Int(x)) => int(ty, x),
+                (Opcode::Sextend, Const::Int(x)) => int(ty, sext(x, arg_bits) as u64),
+                (Opcode::Fneg, c) => {
+                    let (bits, sign) = sign_bit(c)?;
+                    Some(with_bits(c, bits ^ sign))
+                }
+                (Opcode::Fabs, c) => {
+                    let (bits, sign) = sign_bit(c)?;
+                    Some(with_bits(c, bits & !sign))
+                }
+                (Opcode::Fpromote, c) | (Opcode::Fdemote, c) => float_result(ty, float(c)?),
+                (Opcode::FcvtFromUint, Const::Int(x)) => {
+                    if ty == F32 {
+                        float_result(ty, f64::from(x as f32))
+                    } else {
+                        float_result(ty, x as f64)
+                    }
+                }
+                (Opcode::FcvtFromSint, Const::Int(x)) => {
+                    let x = sext(x, arg_bits);
+                    if ty == F32 {
+                        float_result(ty, f64::from(x as f32))
+                    } else {
+                        float_result(ty, x as f64)
+                    }
+                }
+                (Opcode::FcvtToSint, c) => float_to_int(float(c)?, ty, true, false),
+                (Opcode::FcvtToSintSat, c) => float_to_int(float(c)?, ty, true, true),
+                (Opcode::FcvtToUint, c) => float_to_int(float(c)?, ty, false, false),
+                (Opcode::FcvtToUintSat, c) => float_to_int(float(c)?, ty, false, true),
+                (Opcode::Bitcast, Const::Int(x)) if ty == F32 => Some(Const::F32(x as u32)),
+                (Opcode::Bitcast, Const::Int(x)) if ty == F64 => Some(Const::F64(x)),
+                (Opcode::Bitcast, Const::F32(x)) => int(ty, u64::from(x)),
+                (Opcode::Bitcast, Const::F64(x)) => int(ty, x),
+                _ => None,
+            }
+        }
+        InstructionData::Binary { opcode, .. } => fold_binary(opcode, ty, args[0], args[1]),
+        InstructionData::BinaryImm { opcode, imm, .. } => {
+            let imm: i64 = imm.into();
+            let (opcode, x, y) = match opcode {
+                Opcode::IaddImm => (Opcode::Iadd, args[0], imm as u64),
+                Opcode::ImulImm => (Opcode::Imul, args[0], imm as u64),
+                Opcode::UdivImm => (Opcode::Udiv, args[0], imm as u64),
+                Opcode::SdivImm => (Opcode::Sdiv, args[0], imm as u64),
+                Opcode::UremImm => (Opcode::Urem, args[0], imm as u64),
+                Opcode::SremImm => (Opcode::Srem, args[0], imm as u64),
+                Opcode::BandImm => (Opcode::Band, args[0], imm as u64),
+                Opcode::BorImm => (Opcode::Bor, args[0], imm as u64),
+                Opcode::BxorImm => (Opcode::Bxor, args[0], imm as u64),
+                Opcode::RotlImm => (Opcode::Rotl, args[0], imm as u64),
+                Opcode::RotrImm => (Opcode::Rotr, args[0], imm as u64),
+                Opcode::IshlImm => (Opcode::Ishl, args[0], imm as u64),
+                Opcode::UshrImm => (Opcode::Ushr, args[0], imm as u64),
+                Opcode::SshrImm => (Opcode::Sshr, args[0], imm as u64),
+                Opcode::IrsubImm => {
+                    return fold_binary(
+                        Opcode::Isub,
+                        ty,
+                        Const::Int(mask(imm as u64, bits)),
+                        args[0],
+                    )
+                }
+                _ => return None,
+            };
+            fold_binary(opcode, ty, x, Const::Int(mask(y, bits)))
+        }
+        InstructionData::IntCompare { cond, .. } => match (args[0], args[1]) {
+            (Const::Int(x), Const::Int(y)) => Some(Const::Bool(icmp(cond, arg_ty?, x, y))),
+            _ => None,
+        },
+        InstructionData::IntCompareImm { cond, imm, .. } => match args[0] {
+            Const::Int(x) => {
+                let arg_ty = arg_ty?;
+                let imm: i64 = imm.into();
+                let y = mask(imm as u64, arg_ty.bits());
+                Some(Const::Bool(icmp(cond, arg_ty, x, y)))
+            }
+            _ => None,
+        },
+        InstructionData::FloatCompare { cond, .. } => {
+            Some(Const::Bool(fcmp(cond, float(args[0])?, float(args[1])?)))
+        }
+        _ => None,
+    }
+}
+
+/// Evaluate the binary operation `opcode` on `x` and `y`, producing a result of type `ty`.
+#[cfg_attr(feature = "cargo-clippy", allow(float_arithmetic))]
+fn fold_binary(opcode: Opcode, ty: Type, x: Const, y: Const) -> Option<Const> {
+    let bits = ty.bits();
+    match (x, y) {
+        (Const::Int(x), Const::Int(y)) => {
+            let (sx, sy) = (sext(x, bits), sext(y, bits));
+            // Shift amounts are taken modulo the width of the type.
+            let amount = (y % u64::from(bits)) as u32;
+            let result = match opcode {
+                Opcode::Iadd => x.wrapping_add(y),
+                Opcode::Isub => x.wrapping_sub(y),
+                Opcode::Imul => x.wrapping_mul(y),
+                Opcode::Udiv if y != 0 => x / y,
+                Opcode::Urem if y != 0 => x % y,
+                Opcode::Sdiv if sy != 0 && !(sy == -1 && sx == sext(1 << (bits - 1), bits)) => {
+                    sx.wrapping_div(sy) as u64
+                }
+                Opcode::Srem if sy != 0 && !(sy == -1 && sx == sext(1 << (bits - 1), bits)) => {
+                    sx.wrapping_rem(sy) as u64
+                }
+                Opcode::Band => x & y,
+                Opcode::Bor => x | y,
+                Opcode::Bxor => x ^ y,
+                Opcode::BandNot => x & !y,
+                Opcode::BorNot => x | !y,
+                Opcode::BxorNot => x ^ !y,
+                Opcode::Ishl => x << amount,
+                Opcode::Ushr => x >> amount,
+                Opcode::Sshr => (sx >> amount) as u64,
+                Opcode::Rotl if amount == 0 => x,
+                Opcode::Rotl => (x << amount) | (x >> (u32::from(bits) - amount)),
+                Opcode::Rotr if amount == 0 => x,
+                Opcode::Rotr => (x >> amount) | (x << (u32::from(bits) - amount)),
+                _ => return None,
+            };
+            int(ty, result)
+        }
+        (Const::Bool(x), Const::Bool(y)) => {
+            let result = match opcode {
+                Opcode::Band => x & y,
+                Opcode::Bor => x | y,
+                Opcode::Bxor => x ^ y,
+                Opcode::BandNot => x & !y,
+                Opcode::BorNot => x | !y,
+                Opcode::BxorNot => x ^ !y,
+                _ => return None,
+            };
+            Some(Const::Bool(result))
+        }
+        (x, y) => {
+            if opcode == Opcode::Fcopysign {
+                let (x_bits, sign) = sign_bit(x)?;
+                let (y_bits, _) = sign_bit(y)?;
+                return Some(with_bits(x, (x_bits & !sign) | (y_bits & sign)));
+            }
+            let (fx, fy) = (float(x)?, float(y)?);
+            let result = match opcode {
+                Opcode::Fadd => fx + fy,
+                Opcode::Fsub => fx - fy,
+                Opcode::Fmul => fx * fy,
+                Opcode::Fdiv => fx / fy,
+                _ => return None,
+            };
+            float_result(ty, result)
+        }
+    }
+}
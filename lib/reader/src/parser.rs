@@ -7,10 +7,10 @@ use cranelift_codegen::ir::immediates::{Ieee32, Ieee64, Imm64, Offset32, Uimm32}
 use cranelift_codegen::ir::instructions::{InstructionData, InstructionFormat, VariableArgs};
 use cranelift_codegen::ir::types::INVALID;
 use cranelift_codegen::ir::{
-    AbiParam, ArgumentExtension, ArgumentLoc, Ebb, ExtFuncData, ExternalName, FuncRef, Function,
-    GlobalValue, GlobalValueData, Heap, HeapData, HeapStyle, JumpTable, JumpTableData, MemFlags,
-    Opcode, SigRef, Signature, StackSlot, StackSlotData, StackSlotKind, Table, TableData, Type,
-    Value, ValueLoc,
+    AbiParam, ArgumentExtension, ArgumentLoc, Ebb, EbbFrequency, ExtFuncData, ExternalName,
+    FuncRef, Function, GlobalValue, GlobalValueData, Heap, HeapData, HeapStyle, JumpTable,
+    JumpTableData, MemFlags, Opcode, SigRef, Signature, StackSlot, StackSlotData, StackSlotKind,
+    Table, TableData, Type, Value, ValueLoc,
 };
 use cranelift_codegen::isa::{self, CallConv, Encoding, RegUnit, TargetIsa};
 use cranelift_codegen::packed_option::ReservedValue;
@@ -1571,7 +1571,7 @@ impl<'a> Parser<'a> {
     // Parse an extended basic block, add contents to `ctx`.
     //
     // extended-basic-block ::= * ebb-header { instruction }
-    // ebb-header           ::= Ebb(ebb) [ebb-params] ":"
+    // ebb-header           ::= Ebb(ebb) [ebb-params] [ebb-frequency] ":"
     //
     fn parse_extended_basic_block(&mut self, ctx: &mut Context) -> ParseResult<()> {
         // Collect comments for the next ebb.
@@ -1581,8 +1581,15 @@ impl<'a> Parser<'a> {
         let ebb = ctx.add_ebb(ebb_num, self.loc)?;
 
         if !self.optional(Token::Colon) {
-            // ebb-header ::= Ebb(ebb) [ * ebb-params ] ":"
-            self.parse_ebb_params(ctx, ebb)?;
+            // ebb-header ::= Ebb(ebb) [ * ebb-params ] [ ebb-frequency ] ":"
+            match self.token() {
+                Some(Token::Identifier(_)) => {}
+                _ => self.parse_ebb_params(ctx, ebb)?,
+            }
+            // ebb-header ::= Ebb(ebb) [ ebb-params ] [ * ebb-frequency ] ":"
+            if let Some(Token::Identifier(_)) = self.token() {
+                ctx.function.ebb_frequencies[ebb] = self.parse_ebb_frequency()?;
+            }
             self.match_token(Token::Colon, "expected ':' after EBB parameters")?;
         }
 
@@ -1655,6 +1662,25 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    // Parse the execution frequency hint of an EBB.
+    //
+    // ebb-frequency ::= * "cold" | "hot" | "count" "=" Integer
+    fn parse_ebb_frequency(&mut self) -> ParseResult<EbbFrequency> {
+        if let Some(Token::Identifier("count")) = self.token() {
+            self.consume();
+            self.match_token(Token::Equal, "expected '=' after EBB count")?;
+            if let Some(Token::Integer(text)) = self.token() {
+                self.consume();
+                return text
+                    .parse()
+                    .map(EbbFrequency::Count)
+                    .map_err(|_| self.error("invalid EBB count"));
+            }
+            return err!(self.loc, "expected EBB count");
+        }
+        self.match_enum("expected EBB frequency hint")
+    }
+
     // Parse a single EBB parameter declaration, and append it to `ebb`.
     //
     // ebb-param ::= * Value(v) ":" Type(t) arg-loc?
@@ -2715,6 +2741,42 @@ mod tests {
         assert_eq!(func.dfg.value_type(ebb4_args[0]), types::I32);
     }
 
+    #[test]
+    fn ebb_frequency() {
+        let (func, _) = Parser::new(
+            "function %ebbs() system_v {
+                                     ebb0 hot:
+                                     ebb1(v3: i32) cold:
+                                     ebb2 count=12:
+                                     ebb3:
+                                     }",
+        ).parse_function(None)
+        .unwrap();
+
+        let frequencies: Vec<_> = func
+            .layout
+            .ebbs()
+            .map(|ebb| func.ebb_frequencies[ebb])
+            .collect();
+        assert_eq!(
+            frequencies,
+            [
+                EbbFrequency::Hot,
+                EbbFrequency::Cold,
+                EbbFrequency::Count(12),
+                EbbFrequency::Unknown
+            ]
+        );
+
+        let ParseError { location, message } = Parser::new(
+            "function %ebbs() system_v {
+                ebb0 warm:",
+        ).parse_function(None)
+        .unwrap_err();
+        assert_eq!(location.line_number, 2);
+        assert_eq!(message, "expected EBB frequency hint");
+    }
+
     #[test]
     fn duplicate_ebb() {
         let ParseError { location, message } = Parser::new(
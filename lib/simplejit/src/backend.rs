@@ -4,11 +4,14 @@ use cranelift_codegen::binemit::{Addend, CodeOffset, NullTrapSink, Reloc, RelocS
 use cranelift_codegen::isa::TargetIsa;
 use cranelift_codegen::{self, ir, settings};
 use cranelift_module::{
-    Backend, DataContext, DataDescription, Init, Linkage, ModuleNamespace, ModuleResult,
+    Backend, DataContext, DataDescription, Init, Linkage, ModuleError, ModuleNamespace,
+    ModuleResult,
 };
 use cranelift_native;
 use libc;
 use memory::Memory;
+use resolver::{DynamicLibrary, SymbolResolver};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::ffi::CString;
 use std::io::Write;
@@ -21,6 +24,7 @@ use winapi;
 pub struct SimpleJITBuilder {
     isa: Box<TargetIsa>,
     symbols: HashMap<String, *const u8>,
+    resolvers: Vec<Box<SymbolResolver>>,
 }
 
 impl SimpleJITBuilder {
@@ -37,14 +41,20 @@ impl SimpleJITBuilder {
     /// Create a new `SimpleJITBuilder` with an arbitrary target. This is mainly
     /// useful for testing.
     ///
-    /// SimpleJIT requires a `TargetIsa` configured for non-PIC.
+    /// The `TargetIsa` may be configured for either PIC or non-PIC code. PIC
+    /// relocations are resolved through GOT entries and PLT stubs which
+    /// SimpleJIT places next to each function.
     ///
     /// To create a `SimpleJITBuilder` for native use, use the `new` constructor
     /// instead.
     pub fn with_isa(isa: Box<TargetIsa>) -> Self {
-        debug_assert!(!isa.flags().is_pic(), "SimpleJIT requires non-PIC code");
         let symbols = HashMap::new();
-        Self { isa, symbols }
+        let resolvers = Vec::new();
+        Self {
+            isa,
+            symbols,
+            resolvers,
+        }
     }
 
     /// Define a symbol in the internal symbol table.
@@ -57,10 +67,13 @@ impl SimpleJITBuilder {
     /// If a symbol is defined more than once, the most recent definition will
     /// be retained.
     ///
-    /// If the JIT fails to find a symbol in its internal table, it will fall
-    /// back to a platform-specific search (this typically involves searching
-    /// the current process for public symbols, followed by searching the
-    /// platform's C runtime).
+    /// Symbols are looked up in any resolvers registered with `resolver` or
+    /// `library` first. If the JIT fails to find a symbol there or in its
+    /// internal table, it will fall back to a platform-specific search (this
+    /// typically involves searching the current process for public symbols,
+    /// followed by searching the platform's C runtime). Symbols which can't be
+    /// found anywhere are reported as `ModuleError::UnresolvedSymbol` by
+    /// `Module::finalize_definitions`.
     pub fn symbol<K>(&mut self, name: K, ptr: *const u8) -> &Self
     where
         K: Into<String>,
@@ -82,15 +95,44 @@ impl SimpleJITBuilder {
         }
         self
     }
+
+    /// Register a resolver for symbols which are declared, but not defined, in
+    /// the module being compiled.
+    ///
+    /// Resolvers are consulted in the order they were registered, before the
+    /// internal symbol table. This makes it possible to look up symbols lazily,
+    /// for example from a plugin registry, instead of defining them all up
+    /// front with `symbol`.
+    pub fn resolver<R>(&mut self, resolver: R) -> &Self
+    where
+        R: SymbolResolver + 'static,
+    {
+        self.resolvers.push(Box::new(resolver));
+        self
+    }
+
+    /// Resolve symbols to the exports of a shared library loaded with
+    /// `DynamicLibrary::open`.
+    ///
+    /// This is equivalent to calling `resolver` with the library. The backend
+    /// keeps the library loaded until it is dropped.
+    pub fn library(&mut self, library: DynamicLibrary) -> &Self {
+        self.resolver(library)
+    }
 }
 
 /// A `SimpleJITBackend` implements `Backend` and emits code and data into memory where it can be
 /// directly called and accessed.
 ///
+/// The backend owns that memory. Dropping it, usually by dropping the `Module` that contains it,
+/// unmaps all of the module's code and data, so pointers obtained from the `Module` must not be
+/// used after that.
+///
 /// See the `SimpleJITBuilder` for a convenient way to construct `SimpleJITBackend` instances.
 pub struct SimpleJITBackend {
     isa: Box<TargetIsa>,
     symbols: HashMap<String, *const u8>,
+    resolvers: Vec<Box<SymbolResolver>>,
     code_memory: Memory,
     readonly_memory: Memory,
     writable_memory: Memory,
@@ -108,19 +150,242 @@ pub struct SimpleJITCompiledFunction {
     code: *mut u8,
     size: usize,
     relocs: Vec<RelocRecord>,
+    /// The island is only allocated once the function is finalized and the addresses of its
+    /// targets are known, and only if any of them need an entry.
+    island: RefCell<Island>,
+}
+
+/// The size of an island entry: an 8-byte GOT entry and a 6-byte PLT stub.
+const ISLAND_ENTRY_SIZE: usize = 8 + 6;
+
+/// GOT entries and PLT stubs for the symbols referenced by one function.
+///
+/// The island is allocated from the code memory, usually right after the function's code, and the
+/// function must be able to reach it with a 32-bit displacement. It holds a GOT entry for each
+/// symbol, followed by a PLT stub for each symbol which jumps through the corresponding GOT entry.
+struct Island {
+    ptr: *mut u8,
+    names: Vec<ir::ExternalName>,
+}
+
+impl Island {
+    /// Create an island without any entries.
+    fn empty() -> Self {
+        Self {
+            ptr: ptr::null_mut(),
+            names: Vec::new(),
+        }
+    }
+
+    /// The size of the island in bytes.
+    fn size(&self) -> usize {
+        self.names.len() * ISLAND_ENTRY_SIZE
+    }
+
+    /// Fill in the GOT entry for `name` with `addr`, and return the entry's address.
+    fn got_entry(&self, name: &ir::ExternalName, addr: *const u8) -> *mut u8 {
+        let index = self.index(name);
+        unsafe {
+            let entry = self.ptr.add(index * 8);
+            #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
+            ptr::write_unaligned(entry as *mut u64, addr as u64);
+            entry
+        }
+    }
+
+    /// Fill in the PLT stub for `name` jumping to `addr`, and return the stub's address.
+    fn plt_stub(&self, name: &ir::ExternalName, addr: *const u8) -> *mut u8 {
+        let entry = self.got_entry(name, addr);
+        unsafe {
+            let stub = self.ptr.add(self.names.len() * 8 + self.index(name) * 6);
+            // jmp *disp32(%rip)
+            *stub = 0xff;
+            *stub.add(1) = 0x25;
+            let disp = pcrel32(entry, stub.add(6)).expect("island is out of range of itself");
+            #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
+            ptr::write_unaligned(stub.add(2) as *mut i32, disp);
+            stub
+        }
+    }
+
+    fn index(&self, name: &ir::ExternalName) -> usize {
+        self.names
+            .iter()
+            .position(|n| n == name)
+            .expect("no island entry for symbol")
+    }
+}
+
+/// Compute the 32-bit displacement from `at` to `what`, if it is in range.
+fn pcrel32(what: *const u8, at: *const u8) -> Option<i32> {
+    let pcrel = (what as isize).wrapping_sub(at as isize);
+    if pcrel as i32 as isize == pcrel {
+        Some(pcrel as i32)
+    } else {
+        None
+    }
+}
+
+/// The opcode of `lea`, which is the only instruction with an `X86PCRel4` relocation that can be
+/// redirected through a GOT entry.
+const LEA_OPCODE: u8 = 0x8d;
+
+/// The opcode of the `mov` loading from the same operand as a `lea`.
+const MOV_LOAD_OPCODE: u8 = 0x8b;
+
+impl SimpleJITCompiledFunction {
+    /// Collect the symbols which need an island entry, given the address of the target of each
+    /// relocation in `bases`.
+    ///
+    /// The GOT and PLT relocations always need one, while the other PC-relative relocations only
+    /// need one when their target is out of range.
+    fn island_names(&self, bases: &[*const u8]) -> ModuleResult<Vec<ir::ExternalName>> {
+        let mut names = Vec::new();
+        for (record, &base) in self.relocs.iter().zip(bases) {
+            let at = unsafe { self.code.offset(record.offset as isize) };
+            let in_range = pcrel32(base.wrapping_offset(record.addend as isize), at).is_some();
+            let needed = match record.reloc {
+                Reloc::X86PCRel4 if !in_range => {
+                    if unsafe { *at.offset(-2) } != LEA_OPCODE {
+                        return Err(ModuleError::Backend(format!(
+                            "PC-relative relocation to {} at offset {} is out of range",
+                            record.name, record.offset
+                        )));
+                    }
+                    true
+                }
+                Reloc::X86CallPCRel4 => !in_range,
+                Reloc::X86GOTPCRel4 | Reloc::X86CallPLTRel4 => true,
+                _ => false,
+            };
+            if needed && !names.contains(&record.name) {
+                names.push(record.name.clone());
+            }
+        }
+        Ok(names)
+    }
+
+    /// Can every relocation with an island entry reach `island`?
+    fn can_reach(&self, island: &Island) -> bool {
+        let start = island.ptr;
+        let end = island.ptr.wrapping_add(island.size());
+        self.relocs
+            .iter()
+            .filter(|record| island.names.contains(&record.name))
+            .all(|record| {
+                let at = unsafe { self.code.offset(record.offset as isize) };
+                pcrel32(start, at).is_some() && pcrel32(end, at).is_some()
+            })
+    }
+
+    /// Patch the relocations, given the address of the target of each one in `bases`.
+    ///
+    /// The island must have the entries returned by `island_names`, and be in range.
+    fn relocate(&self, bases: &[*const u8]) {
+        use std::ptr::write_unaligned;
+
+        let island = self.island.borrow();
+        for (
+            &RelocRecord {
+                reloc,
+                offset,
+                ref name,
+                addend,
+            },
+            &base,
+        ) in self.relocs.iter().zip(bases)
+        {
+            debug_assert!((offset as usize) < self.size);
+            let at = unsafe { self.code.offset(offset as isize) };
+            let what = base.wrapping_offset(addend as isize);
+            // For the PC-relative relocations, `addend` only adjusts for the distance from the
+            // displacement to the end of the instruction, and `base` is the actual target.
+            let pcrel = match reloc {
+                Reloc::Abs4 => {
+                    check_abs4(what);
+                    #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
+                    unsafe {
+                        write_unaligned(at as *mut u32, what as u32)
+                    };
+                    continue;
+                }
+                Reloc::Abs8 => {
+                    #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
+                    unsafe {
+                        write_unaligned(at as *mut u64, what as u64)
+                    };
+                    continue;
+                }
+                Reloc::X86PCRel4 => pcrel32(what, at).unwrap_or_else(|| {
+                    // The target is too far away for the `lea` computing its address. Load the
+                    // address from a GOT entry instead, by turning the `lea` into a `mov` with
+                    // the same operands.
+                    unsafe { *at.offset(-2) = MOV_LOAD_OPCODE };
+                    let entry = island.got_entry(name, base);
+                    pcrel32(entry.wrapping_offset(addend as isize), at).unwrap()
+                }),
+                Reloc::X86CallPCRel4 => pcrel32(what, at).unwrap_or_else(|| {
+                    // The callee is too far away for a direct call, so go through a stub.
+                    let stub = island.plt_stub(name, base);
+                    pcrel32(stub.wrapping_offset(addend as isize), at).unwrap()
+                }),
+                Reloc::X86GOTPCRel4 => {
+                    let entry = island.got_entry(name, base);
+                    pcrel32(entry.wrapping_offset(addend as isize), at).unwrap()
+                }
+                Reloc::X86CallPLTRel4 => {
+                    let stub = island.plt_stub(name, base);
+                    pcrel32(stub.wrapping_offset(addend as isize), at).unwrap()
+                }
+                _ => unimplemented!(),
+            };
+            #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
+            unsafe {
+                write_unaligned(at as *mut i32, pcrel)
+            };
+        }
+    }
 }
 
 pub struct SimpleJITCompiledData {
     storage: *mut u8,
     size: usize,
+    writable: bool,
     relocs: Vec<RelocRecord>,
 }
 
 impl SimpleJITBackend {
-    fn lookup_symbol(&self, name: &str) -> *const u8 {
-        match self.symbols.get(name) {
-            Some(&ptr) => ptr,
-            None => lookup_with_dlsym(name),
+    fn lookup_symbol(&self, name: &str) -> ModuleResult<*const u8> {
+        for resolver in &self.resolvers {
+            if let Some(ptr) = resolver.resolve(name) {
+                return Ok(ptr);
+            }
+        }
+        self.symbols
+            .get(name)
+            .cloned()
+            .or_else(|| lookup_with_dlsym(name))
+            .ok_or_else(|| ModuleError::UnresolvedSymbol(name.to_owned()))
+    }
+
+    /// Find the address of the function or data object `name` refers to.
+    fn get_address(
+        &self,
+        name: &ir::ExternalName,
+        namespace: &ModuleNamespace<Self>,
+    ) -> ModuleResult<*const u8> {
+        if namespace.is_function(name) {
+            let (def, name_str, _signature) = namespace.get_function_definition(&name);
+            match def {
+                Some(compiled) => Ok(compiled.code),
+                None => self.lookup_symbol(name_str),
+            }
+        } else {
+            let (def, name_str, _writable) = namespace.get_data_definition(&name);
+            match def {
+                Some(compiled) => Ok(compiled.storage),
+                None => self.lookup_symbol(name_str),
+            }
         }
     }
 }
@@ -149,6 +414,7 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
         Self {
             isa: builder.isa,
             symbols: builder.symbols,
+            resolvers: builder.resolvers,
             code_memory: Memory::new(),
             readonly_memory: Memory::new(),
             writable_memory: Memory::new(),
@@ -175,10 +441,28 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
         code_size: u32,
     ) -> ModuleResult<Self::CompiledFunction> {
         let size = code_size as usize;
+
+        // Emit the code into a temporary buffer first, since its relocations determine how large
+        // the function's island needs to be.
+        let mut code = vec![0; size];
+        let mut reloc_sink = SimpleJITRelocSink::new();
+        // Ignore traps for now. For now, frontends should just avoid generating code
+        // that traps.
+        let mut trap_sink = NullTrapSink {};
+        unsafe {
+            ctx.emit_to_memory(
+                &*self.isa,
+                code.as_mut_ptr(),
+                &mut reloc_sink,
+                &mut trap_sink,
+            )
+        };
+
         let ptr = self
             .code_memory
             .allocate(size)
             .expect("TODO: handle OOM etc.");
+        unsafe { ptr::copy_nonoverlapping(code.as_ptr(), ptr, size) };
 
         if cfg!(target_os = "linux") && ::std::env::var_os("PERF_BUILDID_DIR").is_some() {
             let mut map_file = ::std::fs::OpenOptions::new()
@@ -190,16 +474,11 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
             let _ = writeln!(map_file, "{:x} {:x} {}", ptr as usize, code_size, name);
         }
 
-        let mut reloc_sink = SimpleJITRelocSink::new();
-        // Ignore traps for now. For now, frontends should just avoid generating code
-        // that traps.
-        let mut trap_sink = NullTrapSink {};
-        unsafe { ctx.emit_to_memory(&*self.isa, ptr, &mut reloc_sink, &mut trap_sink) };
-
         Ok(Self::CompiledFunction {
             code: ptr,
             size,
             relocs: reloc_sink.relocs,
+            island: RefCell::new(Island::empty()),
         })
     }
 
@@ -268,6 +547,7 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
         Ok(Self::CompiledData {
             storage,
             size,
+            writable,
             relocs,
         })
     }
@@ -295,61 +575,36 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
         &mut self,
         func: &Self::CompiledFunction,
         namespace: &ModuleNamespace<Self>,
-    ) -> Self::FinalizedFunction {
-        use std::ptr::write_unaligned;
-
-        for &RelocRecord {
-            reloc,
-            offset,
-            ref name,
-            addend,
-        } in &func.relocs
+    ) -> ModuleResult<Self::FinalizedFunction> {
+        // Resolve every target and check that it can be reached before patching anything, so
+        // that an error leaves the function untouched.
+        let bases = func
+            .relocs
+            .iter()
+            .map(|record| self.get_address(&record.name, namespace))
+            .collect::<ModuleResult<Vec<_>>>()?;
+
+        let names = func.island_names(&bases)?;
         {
-            let ptr = func.code;
-            debug_assert!((offset as usize) < func.size);
-            let at = unsafe { ptr.offset(offset as isize) };
-            let base = if namespace.is_function(name) {
-                let (def, name_str, _signature) = namespace.get_function_definition(&name);
-                match def {
-                    Some(compiled) => compiled.code,
-                    None => self.lookup_symbol(name_str),
-                }
-            } else {
-                let (def, name_str, _writable) = namespace.get_data_definition(&name);
-                match def {
-                    Some(compiled) => compiled.storage,
-                    None => self.lookup_symbol(name_str),
-                }
-            };
-            // TODO: Handle overflow.
-            let what = unsafe { base.offset(addend as isize) };
-            match reloc {
-                Reloc::Abs4 => {
-                    // TODO: Handle overflow.
-                    #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
-                    unsafe {
-                        write_unaligned(at as *mut u32, what as u32)
-                    };
-                }
-                Reloc::Abs8 => {
-                    #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
-                    unsafe {
-                        write_unaligned(at as *mut u64, what as u64)
-                    };
-                }
-                Reloc::X86PCRel4 | Reloc::X86CallPCRel4 => {
-                    // TODO: Handle overflow.
-                    let pcrel = ((what as isize) - (at as isize)) as i32;
-                    #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
-                    unsafe {
-                        write_unaligned(at as *mut i32, pcrel)
-                    };
-                }
-                Reloc::X86GOTPCRel4 | Reloc::X86CallPLTRel4 => panic!("unexpected PIC relocation"),
-                _ => unimplemented!(),
+            let mut island = func.island.borrow_mut();
+            // An earlier attempt to finalize the function may already have allocated the island.
+            if island.names != names {
+                self.code_memory.free(island.ptr, island.size());
+                let ptr = self
+                    .code_memory
+                    .allocate(names.len() * ISLAND_ENTRY_SIZE)
+                    .expect("TODO: handle OOM etc.");
+                *island = Island { ptr, names };
+            }
+            if !func.can_reach(&island) {
+                return Err(ModuleError::Backend(
+                    "relocation island is out of range of the function".to_owned(),
+                ));
             }
         }
-        func.code
+
+        func.relocate(&bases);
+        Ok(func.code)
     }
 
     fn get_finalized_function(&self, func: &Self::CompiledFunction) -> Self::FinalizedFunction {
@@ -360,37 +615,32 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
         &mut self,
         data: &Self::CompiledData,
         namespace: &ModuleNamespace<Self>,
-    ) -> Self::FinalizedData {
+    ) -> ModuleResult<Self::FinalizedData> {
         use std::ptr::write_unaligned;
 
-        for &RelocRecord {
-            reloc,
-            offset,
-            ref name,
-            addend,
-        } in &data.relocs
+        let bases = data
+            .relocs
+            .iter()
+            .map(|record| self.get_address(&record.name, namespace))
+            .collect::<ModuleResult<Vec<_>>>()?;
+
+        for (
+            &RelocRecord {
+                reloc,
+                offset,
+                addend,
+                ..
+            },
+            &base,
+        ) in data.relocs.iter().zip(&bases)
         {
             let ptr = data.storage;
             debug_assert!((offset as usize) < data.size);
             let at = unsafe { ptr.offset(offset as isize) };
-            let base = if namespace.is_function(name) {
-                let (def, name_str, _signature) = namespace.get_function_definition(&name);
-                match def {
-                    Some(compiled) => compiled.code,
-                    None => self.lookup_symbol(name_str),
-                }
-            } else {
-                let (def, name_str, _writable) = namespace.get_data_definition(&name);
-                match def {
-                    Some(compiled) => compiled.storage,
-                    None => self.lookup_symbol(name_str),
-                }
-            };
-            // TODO: Handle overflow.
-            let what = unsafe { base.offset(addend as isize) };
+            let what = base.wrapping_offset(addend as isize);
             match reloc {
                 Reloc::Abs4 => {
-                    // TODO: Handle overflow.
+                    check_abs4(what);
                     #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
                     unsafe {
                         write_unaligned(at as *mut u32, what as u32)
@@ -409,40 +659,68 @@ impl<'simple_jit_backend> Backend for SimpleJITBackend {
                 _ => unimplemented!(),
             }
         }
-        (data.storage, data.size)
+        Ok((data.storage, data.size))
     }
 
     fn get_finalized_data(&self, data: &Self::CompiledData) -> Self::FinalizedData {
         (data.storage, data.size)
     }
 
+    fn free_function(&mut self, func: Self::CompiledFunction) {
+        self.code_memory.free(func.code, func.size);
+        let island = func.island.into_inner();
+        self.code_memory.free(island.ptr, island.size());
+    }
+
+    fn free_data(&mut self, data: Self::CompiledData) {
+        if data.writable {
+            self.writable_memory.free(data.storage, data.size);
+        } else {
+            self.readonly_memory.free(data.storage, data.size);
+        }
+    }
+
     fn publish(&mut self) {
-        // Now that we're done patching, prepare the memory for execution!
+        // Now that we're done patching, prepare the memory for execution! This only affects
+        // memory allocated since the last call, so previously published code and data is never
+        // made writable again and can keep running while new definitions are added.
         self.readonly_memory.set_readonly();
         self.code_memory.set_readable_and_executable();
     }
 
     /// SimpleJIT emits code and data into memory as it processes them, so it
     /// doesn't need to provide anything after the `Module` is complete.
+    ///
+    /// The memory is owned by the backend, so all of the module's code and data is freed here.
     fn finish(self) {}
 }
 
+/// Check that `what` can be written as an `Abs4` relocation.
+fn check_abs4(what: *const u8) {
+    assert!(
+        what as usize as u64 <= u64::from(u32::max_value()),
+        "Abs4 relocation target {:p} is out of range",
+        what
+    );
+}
+
 #[cfg(not(windows))]
-fn lookup_with_dlsym(name: &str) -> *const u8 {
-    let c_str = CString::new(name).unwrap();
+fn lookup_with_dlsym(name: &str) -> Option<*const u8> {
+    let c_str = CString::new(name).ok()?;
     let c_str_ptr = c_str.as_ptr();
     let sym = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c_str_ptr) };
     if sym.is_null() {
-        panic!("can't resolve symbol {}", name);
+        None
+    } else {
+        Some(sym as *const u8)
     }
-    sym as *const u8
 }
 
 #[cfg(windows)]
-fn lookup_with_dlsym(name: &str) -> *const u8 {
+fn lookup_with_dlsym(name: &str) -> Option<*const u8> {
     const MSVCRT_DLL: &[u8] = b"msvcrt.dll\0";
 
-    let c_str = CString::new(name).unwrap();
+    let c_str = CString::new(name).ok()?;
     let c_str_ptr = c_str.as_ptr();
 
     unsafe {
@@ -458,15 +736,10 @@ fn lookup_with_dlsym(name: &str) -> *const u8 {
             if addr.is_null() {
                 continue;
             }
-            return addr as *const u8;
+            return Some(addr as *const u8);
         }
 
-        let msg = if handles[1].is_null() {
-            "(msvcrt not loaded)"
-        } else {
-            ""
-        };
-        panic!("cannot resolve address of symbol {} {}", name, msg);
+        None
     }
 }
 
@@ -504,3 +777,134 @@ impl RelocSink for SimpleJITRelocSink {
         unimplemented!();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcrel32() {
+        let base = 0x1000_0000 as *const u8;
+        assert_eq!(pcrel32(base, base), Some(0));
+        assert_eq!(pcrel32(base.wrapping_offset(-16), base), Some(-16));
+        assert_eq!(
+            pcrel32(base.wrapping_offset(0x7fff_ffff), base),
+            Some(0x7fff_ffff)
+        );
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(pcrel32(base.wrapping_offset(0x8000_0000), base), None);
+        }
+    }
+
+    #[test]
+    fn test_island() {
+        let names = vec![ir::ExternalName::user(0, 0), ir::ExternalName::user(0, 1)];
+        let mut mem = vec![0u8; names.len() * ISLAND_ENTRY_SIZE];
+        let island = Island {
+            ptr: mem.as_mut_ptr(),
+            names,
+        };
+        let target = 0x1234_5678_9abc_def0u64 as usize as *const u8;
+
+        let stub = island.plt_stub(&ir::ExternalName::user(0, 1), target);
+        assert_eq!(stub as usize - mem.as_ptr() as usize, 2 * 8 + 6);
+        assert_eq!(
+            &mem[8..16],
+            &[0xf0, 0xde, 0xbc, 0x9a, 0x78, 0x56, 0x34, 0x12]
+        );
+        // jmp *-20(%rip), which is the GOT entry at offset 8.
+        assert_eq!(&mem[22..28], &[0xff, 0x25, 0xec, 0xff, 0xff, 0xff]);
+    }
+
+    /// Get the address that the 32-bit displacement at `offset` in `code` points to.
+    fn disp_target(code: &[u8], offset: usize) -> usize {
+        let disp = unsafe { ptr::read_unaligned(code.as_ptr().add(offset) as *const i32) };
+        (code.as_ptr() as usize + offset + 4).wrapping_add(disp as usize)
+    }
+
+    /// Read the 64-bit value at `offset` in `mem`.
+    fn read_u64(mem: &[u8], offset: usize) -> u64 {
+        unsafe { ptr::read_unaligned(mem.as_ptr().add(offset) as *const u64) }
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_out_of_range() {
+        // lea 0(%rip), %rax; call 0
+        let code = [0x48, 0x8d, 0x05, 0, 0, 0, 0, 0xe8, 0, 0, 0, 0];
+        // Keep the island right after the code, as it would usually be.
+        let mut mem = vec![0u8; code.len() + 2 * ISLAND_ENTRY_SIZE];
+        mem[..code.len()].copy_from_slice(&code);
+        let island_ptr = mem[code.len()..].as_mut_ptr();
+        let data = ir::ExternalName::user(1, 0);
+        let callee = ir::ExternalName::user(0, 0);
+        let func = SimpleJITCompiledFunction {
+            code: mem.as_mut_ptr(),
+            size: code.len(),
+            relocs: vec![
+                RelocRecord {
+                    offset: 3,
+                    reloc: Reloc::X86PCRel4,
+                    name: data.clone(),
+                    addend: -4,
+                },
+                RelocRecord {
+                    offset: 8,
+                    reloc: Reloc::X86CallPCRel4,
+                    name: callee.clone(),
+                    addend: -4,
+                },
+            ],
+            island: RefCell::new(Island::empty()),
+        };
+
+        // Targets in range don't need an island entry.
+        let near = func.code.wrapping_offset(0x1000) as *const u8;
+        assert!(func.island_names(&[near, near]).unwrap().is_empty());
+
+        let far = func.code.wrapping_offset(1 << 40) as *const u8;
+        let names = func.island_names(&[far, far]).unwrap();
+        assert_eq!(names, vec![data, callee]);
+
+        // The island must be in range too.
+        let island = Island {
+            ptr: far as *mut u8,
+            names,
+        };
+        assert!(!func.can_reach(&island));
+        *func.island.borrow_mut() = Island {
+            ptr: island_ptr,
+            ..island
+        };
+        assert!(func.can_reach(&func.island.borrow()));
+
+        func.relocate(&[far, far]);
+        // The `lea` is now a `mov` loading the address from the GOT entry.
+        assert_eq!(mem[1], 0x8b);
+        assert_eq!(disp_target(&mem, 3), island_ptr as usize);
+        assert_eq!(read_u64(&mem, code.len()), far as u64);
+        // The call goes through the PLT stub of the callee.
+        assert_eq!(disp_target(&mem, 8), island_ptr as usize + 2 * 8 + 6);
+        assert_eq!(read_u64(&mem, code.len() + 8), far as u64);
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_out_of_range_error() {
+        // add 0(%rip), %rax
+        let mut code = vec![0x48, 0x03, 0x05, 0, 0, 0, 0];
+        let func = SimpleJITCompiledFunction {
+            code: code.as_mut_ptr(),
+            size: code.len(),
+            relocs: vec![RelocRecord {
+                offset: 3,
+                reloc: Reloc::X86PCRel4,
+                name: ir::ExternalName::user(1, 0),
+                addend: -4,
+            }],
+            island: RefCell::new(Island::empty()),
+        };
+        let far = code.as_ptr().wrapping_offset(1 << 40);
+        assert!(func.island_names(&[far]).is_err());
+    }
+}
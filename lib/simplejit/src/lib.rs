@@ -42,5 +42,7 @@ extern crate winapi;
 
 mod backend;
 mod memory;
+mod resolver;
 
 pub use backend::{SimpleJITBackend, SimpleJITBuilder};
+pub use resolver::{DynamicLibrary, SymbolResolver};
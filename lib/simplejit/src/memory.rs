@@ -71,12 +71,71 @@ impl PtrLen {
     }
 }
 
+impl Drop for PtrLen {
+    #[cfg(not(target_os = "windows"))]
+    fn drop(&mut self) {
+        if self.len != 0 {
+            unsafe {
+                // The allocator may write its own bookkeeping into the pages, so they must be
+                // writable again before they are handed back.
+                region::protect(self.ptr, self.len, region::Protection::ReadWrite)
+                    .expect("unable to make memory writable before freeing it");
+                libc::free(self.ptr as *mut libc::c_void);
+            }
+        }
+    }
+
+    #[cfg(target_os = "windows")]
+    fn drop(&mut self) {
+        use winapi::um::memoryapi::VirtualFree;
+        use winapi::um::winnt::MEM_RELEASE;
+
+        if self.len != 0 {
+            unsafe {
+                VirtualFree(self.ptr as *mut _, 0, MEM_RELEASE);
+            }
+        }
+    }
+}
+
+/// A `PtrLen` along with the number of objects still living in it.
+struct Allocation {
+    mem: PtrLen,
+    live: usize,
+}
+
+impl Allocation {
+    /// Create a new empty `Allocation`.
+    fn new() -> Self {
+        Self {
+            mem: PtrLen::new(),
+            live: 0,
+        }
+    }
+
+    /// Does this allocation contain the `size` bytes at `ptr`?
+    fn contains(&self, ptr: *mut u8, size: usize) -> bool {
+        let start = self.mem.ptr as usize;
+        let addr = ptr as usize;
+        addr >= start && addr + size <= start + self.mem.len
+    }
+}
+
 /// JIT memory manager. This manages pages of suitably aligned and
 /// accessible memory.
+///
+/// Memory is handed out from writable pages. Once those pages are protected, they are never
+/// written to or reprotected again: later allocations always come from fresh pages, so code which
+/// is already running is unaffected by new definitions.
+///
+/// All of the memory is returned to the system when the `Memory` is dropped. Individual objects
+/// can be released earlier with `free`; the pages holding them are returned once every object
+/// allocated in them has been freed.
 pub struct Memory {
-    allocations: Vec<PtrLen>,
-    executable: usize,
-    current: PtrLen,
+    allocations: Vec<Allocation>,
+    /// The number of `allocations` which have already been protected.
+    protected: usize,
+    current: Allocation,
     position: usize,
 }
 
@@ -84,40 +143,82 @@ impl Memory {
     pub fn new() -> Self {
         Self {
             allocations: Vec::new(),
-            executable: 0,
-            current: PtrLen::new(),
+            protected: 0,
+            current: Allocation::new(),
             position: 0,
         }
     }
 
     fn finish_current(&mut self) {
         self.allocations
-            .push(mem::replace(&mut self.current, PtrLen::new()));
+            .push(mem::replace(&mut self.current, Allocation::new()));
         self.position = 0;
     }
 
     /// TODO: Use a proper error type.
     pub fn allocate(&mut self, size: usize) -> Result<*mut u8, String> {
-        if size <= self.current.len - self.position {
+        if size <= self.current.mem.len - self.position {
             // TODO: Ensure overflow is not possible.
-            let ptr = unsafe { self.current.ptr.add(self.position) };
+            let ptr = unsafe { self.current.mem.ptr.add(self.position) };
             self.position += size;
+            if size != 0 {
+                self.current.live += 1;
+            }
             return Ok(ptr);
         }
 
         self.finish_current();
 
         // TODO: Allocate more at a time.
-        self.current = PtrLen::with_size(size)?;
+        self.current = Allocation {
+            mem: PtrLen::with_size(size)?,
+            live: 1,
+        };
         self.position = size;
-        Ok(self.current.ptr)
+        Ok(self.current.mem.ptr)
     }
 
-    /// Set all memory allocated in this `Memory` up to now as readable and executable.
+    /// Release the `size` bytes at `ptr`, which must have been returned by `allocate`.
+    ///
+    /// Pages are only returned to the system when nothing else allocated in them is still live.
+    pub fn free(&mut self, ptr: *mut u8, size: usize) {
+        if size == 0 {
+            // Zero-sized objects don't hold on to any memory.
+            return;
+        }
+
+        if self.current.contains(ptr, size) {
+            self.current.live -= 1;
+            if self.current.live == 0 {
+                // Nothing in the current allocation has been protected yet, so just start
+                // handing it out again from the beginning.
+                self.position = 0;
+            }
+            return;
+        }
+
+        let allocation = self
+            .allocations
+            .iter_mut()
+            .find(|a| a.contains(ptr, size))
+            .expect("freeing memory which wasn't allocated here");
+        allocation.live -= 1;
+        if allocation.live == 0 {
+            // Dropping the old `PtrLen` frees it. Keep the empty slot so that the indices of the
+            // other allocations don't change.
+            allocation.mem = PtrLen::new();
+        }
+    }
+
+    /// Set all memory allocated in this `Memory` since the last call as readable and executable.
     pub fn set_readable_and_executable(&mut self) {
         self.finish_current();
 
-        for &PtrLen { ptr, len } in &self.allocations[self.executable..] {
+        for &Allocation {
+            mem: PtrLen { ptr, len },
+            ..
+        } in &self.allocations[self.protected..]
+        {
             if len != 0 {
                 unsafe {
                     region::protect(ptr, len, region::Protection::ReadExecute)
@@ -125,13 +226,18 @@ impl Memory {
                 }
             }
         }
+        self.protected = self.allocations.len();
     }
 
-    /// Set all memory allocated in this `Memory` up to now as readonly.
+    /// Set all memory allocated in this `Memory` since the last call as readonly.
     pub fn set_readonly(&mut self) {
         self.finish_current();
 
-        for &PtrLen { ptr, len } in &self.allocations[self.executable..] {
+        for &Allocation {
+            mem: PtrLen { ptr, len },
+            ..
+        } in &self.allocations[self.protected..]
+        {
             if len != 0 {
                 unsafe {
                     region::protect(ptr, len, region::Protection::Read)
@@ -139,11 +245,10 @@ impl Memory {
                 }
             }
         }
+        self.protected = self.allocations.len();
     }
 }
 
-// TODO: Implement Drop to unprotect and deallocate the memory?
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -155,4 +260,45 @@ mod tests {
         assert_eq!(round_up_to_page_size(4096, 4096), 4096);
         assert_eq!(round_up_to_page_size(4097, 4096), 8192);
     }
+
+    #[test]
+    fn test_free() {
+        let mut memory = Memory::new();
+        let a = memory.allocate(16).unwrap();
+        let b = memory.allocate(16).unwrap();
+        memory.free(a, 16);
+        memory.free(b, 16);
+
+        // Nothing is live in the current allocation any more, so it is reused.
+        assert_eq!(memory.allocate(16).unwrap(), a);
+
+        memory.set_readonly();
+        assert_eq!(memory.allocations.last().unwrap().mem.len, region::page::size());
+
+        // Freeing the last object in protected pages returns them to the system.
+        memory.free(a, 16);
+        assert_eq!(memory.allocations.last().unwrap().mem.len, 0);
+    }
+
+    #[test]
+    fn test_incremental_protection() {
+        let mut memory = Memory::new();
+        let a = memory.allocate(16).unwrap();
+        memory.set_readable_and_executable();
+
+        // New allocations must not share pages with published ones.
+        let b = memory.allocate(16).unwrap();
+        let page_size = region::page::size();
+        assert_ne!(a as usize / page_size, b as usize / page_size);
+        unsafe { *b = 0xc3 };
+
+        // Publishing again only touches the new pages. Change the protection of the first page
+        // behind the memory manager's back to observe that it is left alone.
+        unsafe { region::protect(a, 16, region::Protection::Read).unwrap() };
+        memory.set_readable_and_executable();
+        let old = region::query(a).unwrap();
+        let new = region::query(b).unwrap();
+        assert_eq!(old.protection, region::Protection::Read);
+        assert_eq!(new.protection, region::Protection::ReadExecute);
+    }
 }
@@ -0,0 +1,125 @@
+//! Hooks for resolving the symbols a SimpleJIT module imports.
+
+use cranelift_module::{ModuleError, ModuleResult};
+#[cfg(not(windows))]
+use libc;
+use std::ffi::{CStr, CString};
+use std::path::Path;
+#[cfg(windows)]
+use winapi;
+
+/// A source of addresses for symbols which are declared, but not defined, in a module.
+///
+/// Resolvers registered with `SimpleJITBuilder::resolver` are consulted, in the order they were
+/// registered, before the builder's symbol table.
+///
+/// Any `Fn(&str) -> Option<*const u8>` closure is a resolver.
+pub trait SymbolResolver {
+    /// Return the address of the symbol `name`, or `None` if this resolver doesn't know it.
+    fn resolve(&self, name: &str) -> Option<*const u8>;
+}
+
+impl<F> SymbolResolver for F
+where
+    F: Fn(&str) -> Option<*const u8>,
+{
+    fn resolve(&self, name: &str) -> Option<*const u8> {
+        self(name)
+    }
+}
+
+/// A shared library loaded at runtime, which resolves symbols to its exports.
+///
+/// The library stays loaded for as long as this value is alive. When it is registered with
+/// `SimpleJITBuilder::library`, that is as long as the `SimpleJITBackend` that uses it.
+pub struct DynamicLibrary {
+    #[cfg(not(windows))]
+    handle: *mut libc::c_void,
+    #[cfg(windows)]
+    handle: winapi::shared::minwindef::HMODULE,
+}
+
+impl DynamicLibrary {
+    /// Load the shared library at `path`.
+    ///
+    /// The path is interpreted by the platform's loader, so a bare file name is searched for in
+    /// the usual library locations.
+    pub fn open<P: AsRef<Path>>(path: P) -> ModuleResult<Self> {
+        let path = path.as_ref();
+        let c_path = path
+            .to_str()
+            .and_then(|s| CString::new(s).ok())
+            .ok_or_else(|| ModuleError::Backend(format!("invalid library path {:?}", path)))?;
+        Self::open_c_path(path, &c_path)
+    }
+
+    #[cfg(not(windows))]
+    fn open_c_path(path: &Path, c_path: &CStr) -> ModuleResult<Self> {
+        let handle = unsafe { libc::dlopen(c_path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            let msg = unsafe {
+                let err = libc::dlerror();
+                if err.is_null() {
+                    String::from("unknown error")
+                } else {
+                    CStr::from_ptr(err).to_string_lossy().into_owned()
+                }
+            };
+            return Err(ModuleError::Backend(format!(
+                "can't load library {}: {}",
+                path.display(),
+                msg
+            )));
+        }
+        Ok(Self { handle })
+    }
+
+    #[cfg(windows)]
+    fn open_c_path(path: &Path, c_path: &CStr) -> ModuleResult<Self> {
+        let handle = unsafe { winapi::um::libloaderapi::LoadLibraryA(c_path.as_ptr()) };
+        if handle.is_null() {
+            return Err(ModuleError::Backend(format!(
+                "can't load library {}: error {}",
+                path.display(),
+                unsafe { winapi::um::errhandlingapi::GetLastError() }
+            )));
+        }
+        Ok(Self { handle })
+    }
+}
+
+impl SymbolResolver for DynamicLibrary {
+    #[cfg(not(windows))]
+    fn resolve(&self, name: &str) -> Option<*const u8> {
+        let c_str = CString::new(name).ok()?;
+        let sym = unsafe { libc::dlsym(self.handle, c_str.as_ptr()) };
+        if sym.is_null() {
+            None
+        } else {
+            Some(sym as *const u8)
+        }
+    }
+
+    #[cfg(windows)]
+    fn resolve(&self, name: &str) -> Option<*const u8> {
+        let c_str = CString::new(name).ok()?;
+        let addr = unsafe { winapi::um::libloaderapi::GetProcAddress(self.handle, c_str.as_ptr()) };
+        if addr.is_null() {
+            None
+        } else {
+            Some(addr as *const u8)
+        }
+    }
+}
+
+impl Drop for DynamicLibrary {
+    #[cfg(not(windows))]
+    fn drop(&mut self) {
+        unsafe { libc::dlclose(self.handle) };
+    }
+
+    #[cfg(windows)]
+    fn drop(&mut self) {
+        unsafe { winapi::um::libloaderapi::FreeLibrary(self.handle) };
+    }
+}
@@ -2,6 +2,7 @@ extern crate cranelift_codegen;
 extern crate cranelift_entity;
 extern crate cranelift_frontend;
 extern crate cranelift_module;
+extern crate cranelift_native;
 extern crate cranelift_simplejit;
 
 use cranelift_codegen::ir::*;
@@ -61,11 +62,11 @@ fn double_finalize() {
     let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
 
     define_simple_function(&mut module);
-    module.finalize_definitions();
+    module.finalize_definitions().unwrap();
 
     // Calling `finalize_definitions` a second time without any new definitions
     // should have no effect.
-    module.finalize_definitions();
+    module.finalize_definitions().unwrap();
 }
 
 #[test]
@@ -74,7 +75,7 @@ fn panic_on_define_after_finalize() {
     let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
 
     define_simple_function(&mut module);
-    module.finalize_definitions();
+    module.finalize_definitions().unwrap();
     define_simple_function(&mut module);
 }
 
@@ -150,3 +151,499 @@ fn switch_error() {
         }
     }
 }
+
+/// Build a function named by `func_id` which returns the constant `value`.
+fn make_const_function(func_id: FuncId, sig: &Signature, value: i64) -> Context {
+    let mut ctx = Context::new();
+    ctx.func =
+        Function::with_name_signature(ExternalName::user(0, func_id.index() as u32), sig.clone());
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        let v = bcx.ins().iconst(types::I64, value);
+        bcx.ins().return_(&[v]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    ctx
+}
+
+#[test]
+fn define_functions_concurrently() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let sig = Signature {
+        params: vec![],
+        returns: vec![AbiParam::new(types::I64)],
+        call_conv: CallConv::SystemV,
+    };
+
+    let funcs: Vec<(FuncId, Context)> = (0..16)
+        .map(|i| {
+            let func_id = module
+                .declare_function(&format!("const{}", i), Linkage::Local, &sig)
+                .unwrap();
+            (func_id, make_const_function(func_id, &sig, i * 3))
+        })
+        .collect();
+    let ids: Vec<FuncId> = funcs.iter().map(|&(id, _)| id).collect();
+
+    module.define_functions(funcs).unwrap();
+    module.finalize_definitions().unwrap();
+
+    for (i, &func_id) in ids.iter().enumerate() {
+        let code = module.get_finalized_function(func_id);
+        let func = unsafe { std::mem::transmute::<_, extern "C" fn() -> i64>(code) };
+        assert_eq!(func(), i as i64 * 3);
+    }
+}
+
+#[test]
+fn define_functions_rejects_duplicates() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let sig = Signature {
+        params: vec![],
+        returns: vec![AbiParam::new(types::I64)],
+        call_conv: CallConv::SystemV,
+    };
+
+    let func_id = module
+        .declare_function("dup", Linkage::Local, &sig)
+        .unwrap();
+    let funcs = vec![
+        (func_id, make_const_function(func_id, &sig, 1)),
+        (func_id, make_const_function(func_id, &sig, 2)),
+    ];
+    match module.define_functions(funcs) {
+        Err(ModuleError::DuplicateDefinition(name)) => assert_eq!(name, "dup"),
+        _ => panic!("expected a duplicate definition error"),
+    }
+}
+
+#[test]
+fn free_and_redefine_function() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let sig = Signature {
+        params: vec![],
+        returns: vec![AbiParam::new(types::I64)],
+        call_conv: CallConv::SystemV,
+    };
+
+    let func_id = module
+        .declare_function("answer", Linkage::Local, &sig)
+        .unwrap();
+    module
+        .define_function(func_id, &mut make_const_function(func_id, &sig, 41))
+        .unwrap();
+    module.finalize_definitions().unwrap();
+    module.free_function(func_id);
+
+    module
+        .define_function(func_id, &mut make_const_function(func_id, &sig, 42))
+        .unwrap();
+    module.finalize_definitions().unwrap();
+    let code = module.get_finalized_function(func_id);
+    let func = unsafe { std::mem::transmute::<_, extern "C" fn() -> i64>(code) };
+    assert_eq!(func(), 42);
+}
+
+#[test]
+#[should_panic(expected = "function abc was freed")]
+fn panic_on_lookup_after_free() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+
+    let func_id = define_simple_function(&mut module);
+    module.finalize_definitions().unwrap();
+    module.free_function(func_id);
+    module.get_finalized_function(func_id);
+}
+
+#[test]
+fn free_data_object() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+
+    let data_id = module.declare_data("table", Linkage::Local, false).unwrap();
+    let mut data_ctx = DataContext::new();
+    data_ctx.define(vec![1, 2, 3, 4].into_boxed_slice());
+    module.define_data(data_id, &data_ctx).unwrap();
+    module.finalize_definitions().unwrap();
+    let (ptr, size) = module.get_finalized_data(data_id);
+    assert_eq!(
+        unsafe { std::slice::from_raw_parts(ptr, size) },
+        &[1, 2, 3, 4]
+    );
+
+    module.free_data(data_id);
+    module.define_data(data_id, &data_ctx).unwrap();
+}
+
+#[test]
+#[should_panic(expected = "data object table was freed")]
+fn panic_on_data_lookup_after_free() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+
+    let data_id = module.declare_data("table", Linkage::Local, false).unwrap();
+    let mut data_ctx = DataContext::new();
+    data_ctx.define(vec![1, 2, 3, 4].into_boxed_slice());
+    module.define_data(data_id, &data_ctx).unwrap();
+    module.finalize_definitions().unwrap();
+    module.free_data(data_id);
+    module.get_finalized_data(data_id);
+}
+
+#[test]
+fn define_after_running_earlier_functions() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let sig = Signature {
+        params: vec![],
+        returns: vec![AbiParam::new(types::I64)],
+        call_conv: CallConv::SystemV,
+    };
+
+    let callee_id = module
+        .declare_function("callee", Linkage::Local, &sig)
+        .unwrap();
+    module
+        .define_function(callee_id, &mut make_const_function(callee_id, &sig, 7))
+        .unwrap();
+    module.finalize_definitions().unwrap();
+    let callee = module.get_finalized_function(callee_id);
+    let callee = unsafe { std::mem::transmute::<_, extern "C" fn() -> i64>(callee) };
+    assert_eq!(callee(), 7);
+
+    // Add a caller of the already published function; it gets published on its own.
+    let caller_id = module
+        .declare_function("caller", Linkage::Local, &sig)
+        .unwrap();
+    let mut ctx = Context::new();
+    ctx.func =
+        Function::with_name_signature(ExternalName::user(0, caller_id.index() as u32), sig.clone());
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        let local_callee = module.declare_func_in_func(callee_id, &mut bcx.func);
+        let call = bcx.ins().call(local_callee, &[]);
+        let value = bcx.inst_results(call)[0];
+        let one = bcx.ins().iconst(types::I64, 1);
+        let sum = bcx.ins().iadd(value, one);
+        bcx.ins().return_(&[sum]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    module.define_function(caller_id, &mut ctx).unwrap();
+    module.finalize_definitions().unwrap();
+
+    let caller = module.get_finalized_function(caller_id);
+    let caller = unsafe { std::mem::transmute::<_, extern "C" fn() -> i64>(caller) };
+    assert_eq!(caller(), 8);
+    assert_eq!(callee(), 7);
+}
+
+extern "C" fn host_double(x: i64) -> i64 {
+    x * 2
+}
+
+#[cfg(target_arch = "x86_64")]
+#[test]
+fn pic_code() {
+    use cranelift_codegen::settings::{self, Configurable};
+
+    let mut flag_builder = settings::builder();
+    flag_builder.enable("is_pic").unwrap();
+    let isa_builder = cranelift_native::builder().unwrap();
+    let isa = isa_builder.finish(settings::Flags::new(flag_builder));
+    let mut builder = SimpleJITBuilder::with_isa(isa);
+    builder.symbol("host_double", host_double as *const u8);
+    let mut module: Module<SimpleJITBackend> = Module::new(builder);
+
+    // Imported and preemptible symbols aren't colocated, so PIC code reaches them through the GOT
+    // and PLT.
+    let mut double_sig = module.make_signature();
+    double_sig.params.push(AbiParam::new(types::I64));
+    double_sig.returns.push(AbiParam::new(types::I64));
+    let double_id = module
+        .declare_function("host_double", Linkage::Import, &double_sig)
+        .unwrap();
+
+    let mut const_sig = module.make_signature();
+    const_sig.returns.push(AbiParam::new(types::I64));
+    let helper_id = module
+        .declare_function("helper", Linkage::Preemptible, &const_sig)
+        .unwrap();
+    module
+        .define_function(
+            helper_id,
+            &mut make_const_function(helper_id, &const_sig, 100),
+        )
+        .unwrap();
+
+    let data_id = module
+        .declare_data("seven", Linkage::Preemptible, false)
+        .unwrap();
+    let mut data_ctx = DataContext::new();
+    data_ctx.define(vec![7, 0, 0, 0, 0, 0, 0, 0].into_boxed_slice());
+    module.define_data(data_id, &data_ctx).unwrap();
+
+    let entry_id = module
+        .declare_function("entry", Linkage::Export, &double_sig)
+        .unwrap();
+    let mut ctx = module.make_context();
+    ctx.func.signature = double_sig.clone();
+    ctx.func.name = ExternalName::user(0, entry_id.index() as u32);
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        bcx.append_ebb_params_for_function_params(ebb);
+        let x = bcx.ebb_params(ebb)[0];
+
+        let double = module.declare_func_in_func(double_id, &mut bcx.func);
+        let call = bcx.ins().call(double, &[x]);
+        let doubled = bcx.inst_results(call)[0];
+
+        let helper = module.declare_func_in_func(helper_id, &mut bcx.func);
+        let call = bcx.ins().call(helper, &[]);
+        let hundred = bcx.inst_results(call)[0];
+
+        let seven = module.declare_data_in_func(data_id, &mut bcx.func);
+        let addr = bcx.ins().symbol_value(types::I64, seven);
+        let seven = bcx.ins().load(types::I64, MemFlags::new(), addr, 0);
+
+        let sum = bcx.ins().iadd(doubled, hundred);
+        let sum = bcx.ins().iadd(sum, seven);
+        bcx.ins().return_(&[sum]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    module.define_function(entry_id, &mut ctx).unwrap();
+    module.finalize_definitions().unwrap();
+
+    let code = module.get_finalized_function(entry_id);
+    let entry = unsafe { std::mem::transmute::<_, extern "C" fn(i64) -> i64>(code) };
+    assert_eq!(entry(5), 117);
+}
+
+extern "C" fn host_triple(x: i64) -> i64 {
+    x * 3
+}
+
+/// Define a function "caller" which passes its argument on to the imported function `callee`.
+fn define_import_caller(module: &mut Module<SimpleJITBackend>, callee: &str) -> FuncId {
+    let mut sig = module.make_signature();
+    sig.params.push(AbiParam::new(types::I64));
+    sig.returns.push(AbiParam::new(types::I64));
+    let callee_id = module
+        .declare_function(callee, Linkage::Import, &sig)
+        .unwrap();
+    let caller_id = module
+        .declare_function("caller", Linkage::Local, &sig)
+        .unwrap();
+
+    let mut ctx = module.make_context();
+    ctx.func.signature = sig;
+    ctx.func.name = ExternalName::user(0, caller_id.index() as u32);
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut ctx.func, &mut func_ctx);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        bcx.append_ebb_params_for_function_params(ebb);
+        let x = bcx.ebb_params(ebb)[0];
+        let callee = module.declare_func_in_func(callee_id, &mut bcx.func);
+        let call = bcx.ins().call(callee, &[x]);
+        let result = bcx.inst_results(call)[0];
+        bcx.ins().return_(&[result]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    module.define_function(caller_id, &mut ctx).unwrap();
+    caller_id
+}
+
+#[test]
+fn resolver_before_symbol_table() {
+    let mut builder = SimpleJITBuilder::new();
+    builder.symbol("host_fn", host_double as *const u8);
+    builder.resolver(|name: &str| {
+        if name == "host_fn" {
+            Some(host_triple as *const u8)
+        } else {
+            None
+        }
+    });
+    let mut module: Module<SimpleJITBackend> = Module::new(builder);
+
+    let caller_id = define_import_caller(&mut module, "host_fn");
+    module.finalize_definitions().unwrap();
+
+    let caller = module.get_finalized_function(caller_id);
+    let caller = unsafe { std::mem::transmute::<_, extern "C" fn(i64) -> i64>(caller) };
+    assert_eq!(caller(5), 15);
+}
+
+#[test]
+fn error_on_unresolved_symbol() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+
+    define_import_caller(&mut module, "cranelift_simplejit_no_such_symbol");
+    match module.finalize_definitions() {
+        Err(ModuleError::UnresolvedSymbol(ref name)) => {
+            assert_eq!(name, "cranelift_simplejit_no_such_symbol")
+        }
+        _ => panic!("expected an unresolved symbol error"),
+    }
+
+    // The function is still waiting to be finalized.
+    module.finalize_definitions().err().unwrap();
+}
+
+#[test]
+fn error_on_missing_library() {
+    DynamicLibrary::open("libcranelift_simplejit_no_such_library.so")
+        .err()
+        .unwrap();
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[test]
+fn resolve_from_library() {
+    let mut builder = SimpleJITBuilder::new();
+    builder.library(DynamicLibrary::open("libc.so.6").unwrap());
+    let mut module: Module<SimpleJITBackend> = Module::new(builder);
+
+    let caller_id = define_import_caller(&mut module, "labs");
+    module.finalize_definitions().unwrap();
+
+    let caller = module.get_finalized_function(caller_id);
+    let caller = unsafe { std::mem::transmute::<_, extern "C" fn(i64) -> i64>(caller) };
+    assert_eq!(caller(-42), 42);
+}
+
+/// Build a function named by `func_id` which returns one more than the result of calling
+/// `callee_id`.
+fn make_increment_caller(
+    module: &Module<SimpleJITBackend>,
+    func_id: FuncId,
+    callee_id: FuncId,
+    sig: &Signature,
+) -> Function {
+    let mut func =
+        Function::with_name_signature(ExternalName::user(0, func_id.index() as u32), sig.clone());
+    let mut func_ctx = FunctionBuilderContext::new();
+    {
+        let mut bcx: FunctionBuilder = FunctionBuilder::new(&mut func, &mut func_ctx);
+        let ebb = bcx.create_ebb();
+        bcx.switch_to_block(ebb);
+        let callee = module.declare_func_in_func(callee_id, &mut bcx.func);
+        let call = bcx.ins().call(callee, &[]);
+        let result = bcx.inst_results(call)[0];
+        let incremented = bcx.ins().iadd_imm(result, 1);
+        bcx.ins().return_(&[incremented]);
+        bcx.seal_all_blocks();
+        bcx.finalize();
+    }
+    func
+}
+
+#[test]
+fn inline_small_functions() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let sig = Signature {
+        params: vec![],
+        returns: vec![AbiParam::new(types::I64)],
+        call_conv: CallConv::SystemV,
+    };
+
+    let local_id = module
+        .declare_function("local", Linkage::Local, &sig)
+        .unwrap();
+    let preemptible_id = module
+        .declare_function("preemptible", Linkage::Preemptible, &sig)
+        .unwrap();
+    let caller_id = module
+        .declare_function("caller", Linkage::Local, &sig)
+        .unwrap();
+    let other_caller_id = module
+        .declare_function("other_caller", Linkage::Local, &sig)
+        .unwrap();
+
+    let mut inliner = Inliner::new();
+    inliner.add_function(local_id, make_const_function(local_id, &sig, 7).func);
+    inliner.add_function(
+        preemptible_id,
+        make_const_function(preemptible_id, &sig, 9).func,
+    );
+    let caller = make_increment_caller(&module, caller_id, local_id, &sig);
+    let other_caller = make_increment_caller(&module, other_caller_id, preemptible_id, &sig);
+    assert_eq!(inliner.inline_calls(&module, &mut caller.clone()), 1);
+    assert_eq!(inliner.inline_calls(&module, &mut other_caller.clone()), 0);
+    inliner.add_function(caller_id, caller);
+    inliner.add_function(other_caller_id, other_caller);
+
+    inliner.define_functions(&mut module).unwrap();
+    module.finalize_definitions().unwrap();
+
+    let caller = module.get_finalized_function(caller_id);
+    let caller = unsafe { std::mem::transmute::<_, extern "C" fn() -> i64>(caller) };
+    assert_eq!(caller(), 8);
+    let other_caller = module.get_finalized_function(other_caller_id);
+    let other_caller = unsafe { std::mem::transmute::<_, extern "C" fn() -> i64>(other_caller) };
+    assert_eq!(other_caller(), 10);
+}
+
+#[test]
+fn inliner_size_limit() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let sig = Signature {
+        params: vec![],
+        returns: vec![AbiParam::new(types::I64)],
+        call_conv: CallConv::SystemV,
+    };
+
+    let callee_id = module
+        .declare_function("callee", Linkage::Local, &sig)
+        .unwrap();
+    let caller_id = module
+        .declare_function("caller", Linkage::Local, &sig)
+        .unwrap();
+
+    let mut inliner = Inliner::new();
+    inliner.size_limit(1);
+    inliner.add_function(callee_id, make_const_function(callee_id, &sig, 7).func);
+    let mut caller = make_increment_caller(&module, caller_id, callee_id, &sig);
+    assert_eq!(inliner.inline_calls(&module, &mut caller), 0);
+
+    inliner.size_limit(2);
+    assert_eq!(inliner.inline_calls(&module, &mut caller), 1);
+}
+
+#[test]
+fn inliner_skips_unknown_callees() {
+    let mut module: Module<SimpleJITBackend> = Module::new(SimpleJITBuilder::new());
+    let sig = Signature {
+        params: vec![],
+        returns: vec![AbiParam::new(types::I64)],
+        call_conv: CallConv::SystemV,
+    };
+
+    let callee_id = module
+        .declare_function("callee", Linkage::Local, &sig)
+        .unwrap();
+    let caller_id = module
+        .declare_function("caller", Linkage::Local, &sig)
+        .unwrap();
+
+    let mut inliner = Inliner::new();
+    inliner.add_function(callee_id, make_const_function(callee_id, &sig, 7).func);
+
+    // A name in the function namespace that wasn't declared in the module.
+    let mut caller = make_increment_caller(&module, caller_id, callee_id, &sig);
+    for (_, ext_func) in caller.dfg.ext_funcs.iter_mut() {
+        ext_func.name = ExternalName::user(0, 100);
+    }
+    assert_eq!(inliner.inline_calls(&module, &mut caller), 0);
+}